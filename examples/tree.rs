@@ -0,0 +1,7 @@
+use nusb::{MaybeFuture, UsbTree};
+
+fn main() {
+    env_logger::init();
+    let tree = UsbTree::new().wait().unwrap();
+    print!("{tree}");
+}
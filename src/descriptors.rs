@@ -689,6 +689,7 @@ impl<'a> Debug for EndpointDescriptor<'a> {
 
 /// Endpoint type.
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[allow(dead_code)]
 pub enum TransferType {
     /// Control endpoint.
@@ -704,6 +705,187 @@ pub enum TransferType {
     Interrupt = 3,
 }
 
+/// Owned copy of the fields of a [`DeviceDescriptor`].
+///
+/// With the `serde` feature, this and the other `Owned*` types implement
+/// `Serialize` and `Deserialize` with field names matching the accessor
+/// methods of the borrowed descriptor types.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct OwnedDeviceDescriptor {
+    /// `bcdUSB` descriptor field.
+    pub usb_version: u16,
+    /// `bDeviceClass` descriptor field.
+    pub class: u8,
+    /// `bDeviceSubClass` descriptor field.
+    pub subclass: u8,
+    /// `bDeviceProtocol` descriptor field.
+    pub protocol: u8,
+    /// `bMaxPacketSize0` descriptor field.
+    pub max_packet_size_0: u8,
+    /// `idVendor` descriptor field.
+    pub vendor_id: u16,
+    /// `idProduct` descriptor field.
+    pub product_id: u16,
+    /// `bcdDevice` descriptor field.
+    pub device_version: u16,
+    /// `iManufacturer` descriptor field.
+    pub manufacturer_string_index: Option<NonZeroU8>,
+    /// `iProduct` descriptor field.
+    pub product_string_index: Option<NonZeroU8>,
+    /// `iSerialNumber` descriptor field.
+    pub serial_number_string_index: Option<NonZeroU8>,
+    /// `bNumConfigurations` descriptor field.
+    pub num_configurations: u8,
+}
+
+impl From<&DeviceDescriptor> for OwnedDeviceDescriptor {
+    fn from(d: &DeviceDescriptor) -> Self {
+        OwnedDeviceDescriptor {
+            usb_version: d.usb_version(),
+            class: d.class(),
+            subclass: d.subclass(),
+            protocol: d.protocol(),
+            max_packet_size_0: d.max_packet_size_0(),
+            vendor_id: d.vendor_id(),
+            product_id: d.product_id(),
+            device_version: d.device_version(),
+            manufacturer_string_index: d.manufacturer_string_index(),
+            product_string_index: d.product_string_index(),
+            serial_number_string_index: d.serial_number_string_index(),
+            num_configurations: d.num_configurations(),
+        }
+    }
+}
+
+impl From<&OwnedDeviceDescriptor> for DeviceDescriptor {
+    fn from(d: &OwnedDeviceDescriptor) -> Self {
+        let index = |i: Option<NonZeroU8>| i.map_or(0, NonZeroU8::get);
+        DeviceDescriptor::from_fields(
+            d.usb_version,
+            d.class,
+            d.subclass,
+            d.protocol,
+            d.max_packet_size_0,
+            d.vendor_id,
+            d.product_id,
+            d.device_version,
+            index(d.manufacturer_string_index),
+            index(d.product_string_index),
+            index(d.serial_number_string_index),
+            d.num_configurations,
+        )
+    }
+}
+
+/// Owned copy of a [`ConfigurationDescriptor`] and the interface and endpoint
+/// descriptors it contains.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct OwnedConfigurationDescriptor {
+    /// `bConfigurationValue` descriptor field.
+    pub configuration_value: u8,
+    /// `bmAttributes` descriptor field.
+    pub attributes: u8,
+    /// `bMaxPower` descriptor field.
+    pub max_power: u8,
+    /// `iConfiguration` descriptor field.
+    pub string_index: Option<NonZeroU8>,
+    /// Interfaces of the configuration.
+    pub interfaces: Vec<OwnedInterfaceDescriptors>,
+}
+
+impl From<&ConfigurationDescriptor<'_>> for OwnedConfigurationDescriptor {
+    fn from(c: &ConfigurationDescriptor<'_>) -> Self {
+        OwnedConfigurationDescriptor {
+            configuration_value: c.configuration_value(),
+            attributes: c.attributes(),
+            max_power: c.max_power(),
+            string_index: c.string_index(),
+            interfaces: c.interfaces().map(|i| (&i).into()).collect(),
+        }
+    }
+}
+
+/// Owned copy of an [`InterfaceDescriptors`] group.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct OwnedInterfaceDescriptors {
+    /// `bInterfaceNumber` shared by the alternate settings.
+    pub interface_number: u8,
+    /// Alternate settings of the interface.
+    pub alt_settings: Vec<OwnedInterfaceDescriptor>,
+}
+
+impl From<&InterfaceDescriptors<'_>> for OwnedInterfaceDescriptors {
+    fn from(i: &InterfaceDescriptors<'_>) -> Self {
+        OwnedInterfaceDescriptors {
+            interface_number: i.interface_number(),
+            alt_settings: i.alt_settings().map(|a| (&a).into()).collect(),
+        }
+    }
+}
+
+/// Owned copy of an [`InterfaceDescriptor`] and its endpoint descriptors.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct OwnedInterfaceDescriptor {
+    /// `bAlternateSetting` descriptor field.
+    pub alternate_setting: u8,
+    /// `bInterfaceClass` descriptor field.
+    pub class: u8,
+    /// `bInterfaceSubClass` descriptor field.
+    pub subclass: u8,
+    /// `bInterfaceProtocol` descriptor field.
+    pub protocol: u8,
+    /// `iInterface` descriptor field.
+    pub string_index: Option<NonZeroU8>,
+    /// Endpoints of the alternate setting.
+    pub endpoints: Vec<OwnedEndpointDescriptor>,
+}
+
+impl From<&InterfaceDescriptor<'_>> for OwnedInterfaceDescriptor {
+    fn from(i: &InterfaceDescriptor<'_>) -> Self {
+        OwnedInterfaceDescriptor {
+            alternate_setting: i.alternate_setting(),
+            class: i.class(),
+            subclass: i.subclass(),
+            protocol: i.protocol(),
+            string_index: i.string_index(),
+            endpoints: i.endpoints().map(|e| (&e).into()).collect(),
+        }
+    }
+}
+
+/// Owned copy of an [`EndpointDescriptor`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct OwnedEndpointDescriptor {
+    /// `bEndpointAddress` descriptor field.
+    pub address: u8,
+    /// Transfer type from `bmAttributes`.
+    pub transfer_type: TransferType,
+    /// Maximum packet size, from `wMaxPacketSize`.
+    pub max_packet_size: usize,
+    /// Packets per microframe for high-speed high-bandwidth endpoints, from
+    /// `wMaxPacketSize`.
+    pub packets_per_microframe: u8,
+    /// `bInterval` descriptor field.
+    pub interval: u8,
+}
+
+impl From<&EndpointDescriptor<'_>> for OwnedEndpointDescriptor {
+    fn from(e: &EndpointDescriptor<'_>) -> Self {
+        OwnedEndpointDescriptor {
+            address: e.address(),
+            transfer_type: e.transfer_type(),
+            max_packet_size: e.max_packet_size(),
+            packets_per_microframe: e.packets_per_microframe(),
+            interval: e.interval(),
+        }
+    }
+}
+
 /// Split a chain of concatenated configuration descriptors by `wTotalLength`
 #[allow(unused)]
 pub(crate) fn parse_concatenated_config_descriptors(
@@ -875,6 +1057,41 @@ fn test_linux_root_hub() {
     assert!(alts.next().is_none());
 }
 
+#[test]
+#[rustfmt::skip]
+fn test_owned() {
+    let dev = DeviceDescriptor::new(&[
+        0x12, 0x01, 0x00, 0x02, 0x09, 0x00, 0x01, 0x40, 0x6b,
+        0x1d, 0x02, 0x00, 0x10, 0x05, 0x03, 0x02, 0x00, 0x01
+    ]).unwrap();
+    let owned = OwnedDeviceDescriptor::from(&dev);
+    assert_eq!(owned.vendor_id, 0x1d6b);
+    assert_eq!(owned.serial_number_string_index, None);
+    assert_eq!(DeviceDescriptor::from(&owned).as_bytes(), dev.as_bytes());
+
+    let c = ConfigurationDescriptor(&[
+        0x09, 0x02, 0x19, 0x00, 0x01, 0x01, 0x00, 0xe0, 0x00,
+        0x09, 0x04, 0x00, 0x00, 0x01, 0x09, 0x00, 0x00, 0x00,
+        0x07, 0x05, 0x81, 0x03, 0x04, 0x00, 0x0c
+    ]);
+    let owned = OwnedConfigurationDescriptor::from(&c);
+    assert_eq!(owned.configuration_value, 1);
+    assert_eq!(owned.interfaces.len(), 1);
+    assert_eq!(owned.interfaces[0].alt_settings[0].class, 9);
+    let ep = &owned.interfaces[0].alt_settings[0].endpoints[0];
+    assert_eq!(ep.address, 0x81);
+    assert_eq!(ep.transfer_type, TransferType::Interrupt);
+    assert_eq!(ep.max_packet_size, 4);
+
+    #[cfg(feature = "serde")]
+    {
+        let json = serde_json::to_string(&owned).unwrap();
+        assert!(json.contains("\"transfer_type\":\"Interrupt\""));
+        let back: OwnedConfigurationDescriptor = serde_json::from_str(&json).unwrap();
+        assert_eq!(back, owned);
+    }
+}
+
 #[test]
 #[rustfmt::skip]
 fn test_dell_webcam() {
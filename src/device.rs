@@ -1,25 +1,30 @@
 use crate::{
     descriptors::{
         decode_string_descriptor, validate_string_descriptor, ConfigurationDescriptor,
-        DeviceDescriptor, InterfaceDescriptor, DESCRIPTOR_TYPE_STRING,
+        DeviceDescriptor, InterfaceDescriptor, TransferType, DESCRIPTOR_TYPE_STRING,
     },
+    emulated::{EmulatedDevice, EmulatedEndpoint, EmulatedInterface},
+    fault::{self, EndpointFaults, FaultInjector},
     io::{EndpointRead, EndpointWrite, IsoReader},
+    maybe_future::Either,
     platform,
+    replay::{EventKind, Operation, Recorder, Submitted},
     transfer::{
         Buffer, BulkOrInterrupt, Completion, ControlIn, ControlOut, Direction, EndpointDirection,
-        EndpointType, In, Isochronous, Out, TransferError,
+        EndpointType, In, Isochronous, Out, TransferError, SETUP_PACKET_SIZE,
     },
     ActiveConfigurationError, DeviceInfo, Error, ErrorKind, GetDescriptorError, MaybeFuture, Speed,
 };
 use log::{debug, error, trace, warn};
 use std::{
+    collections::VecDeque,
     fmt::Debug,
     future::{poll_fn, Future},
     marker::PhantomData,
     num::NonZeroU8,
     sync::Arc,
     task::{Context, Poll},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 /// An opened USB device.
@@ -44,16 +49,55 @@ use std::{
 /// transfers.
 #[derive(Clone)]
 pub struct Device {
-    backend: Arc<crate::platform::Device>,
+    backend: DeviceBackend,
+    recorder: Option<Recorder>,
+    faults: Option<FaultInjector>,
+
+    /// Information the device was opened from, used to find it again after
+    /// a reset.
+    info: Option<Arc<DeviceInfo>>,
+}
+
+#[derive(Clone)]
+enum DeviceBackend {
+    Platform(Arc<platform::Device>),
+    Emulated(Arc<EmulatedDevice>),
 }
 
 impl Device {
     pub(crate) fn wrap(backend: Arc<platform::Device>) -> Device {
-        Device { backend }
+        Device {
+            backend: DeviceBackend::Platform(backend),
+            recorder: None,
+            faults: None,
+            info: None,
+        }
+    }
+
+    fn wrap_emulated(backend: Arc<EmulatedDevice>) -> Device {
+        Device {
+            backend: DeviceBackend::Emulated(backend),
+            recorder: None,
+            faults: None,
+            info: None,
+        }
     }
 
     pub(crate) fn open(d: &DeviceInfo) -> impl MaybeFuture<Output = Result<Device, Error>> {
-        platform::Device::from_device_info(d).map(|d| d.map(Device::wrap))
+        let info = Arc::new(d.clone());
+        let with_info = move |device: Device| Device {
+            info: Some(info),
+            ..device
+        };
+        if let Some(model) = &d.emulated {
+            return Either::Right(
+                EmulatedDevice::from_model(model.clone())
+                    .map(|d| d.map(Device::wrap_emulated).map(with_info)),
+            );
+        }
+        Either::Left(
+            platform::Device::from_device_info(d).map(|d| d.map(Device::wrap).map(with_info)),
+        )
     }
 
     /// Wrap a usbdevfs file descriptor that is already open.
@@ -70,15 +114,91 @@ impl Device {
         platform::Device::from_fd(fd).map(|d| d.map(Device::wrap))
     }
 
+    /// *(Linux-only)* Open the device connected at a port path, such as
+    /// `1-2.3` for port 3 of the hub on port 2 of bus 1.
+    ///
+    /// This reads the device's sysfs directory directly instead of listing
+    /// all devices. The path is in the format of [`PortPath`][crate::PortPath],
+    /// as returned by [`DeviceInfo::port_path`]. Fails with
+    /// [`ErrorKind::NotFound`] if no device is connected there.
+    ///
+    /// Use [`FsRoots::open_by_path`][crate::FsRoots::open_by_path] to open a
+    /// device under a relocated sysfs.
+    #[cfg(target_os = "linux")]
+    pub fn open_by_path(path: &str) -> impl MaybeFuture<Output = Result<Device, Error>> {
+        platform::FsRoots::system().open_by_path(path)
+    }
+
+    /// *(Linux-only)* Open the device with a bus number and device address,
+    /// from its node in `/dev/bus/usb` without listing devices.
+    ///
+    /// Fails with [`ErrorKind::Disconnected`] if there is no such device.
+    /// Addresses are reused after a device disconnects, so prefer
+    /// [`open_by_path`][Self::open_by_path] or [`DeviceInfo::open`] to
+    /// reopen a particular device later.
+    ///
+    /// Use [`FsRoots::open_by_id`][crate::FsRoots::open_by_id] to open a
+    /// device under a relocated usbfs.
+    #[cfg(target_os = "linux")]
+    pub fn open_by_id(busnum: u8, address: u8) -> impl MaybeFuture<Output = Result<Device, Error>> {
+        platform::FsRoots::system().open_by_id(busnum, address)
+    }
+
+    /// Record the operations performed on this device.
+    ///
+    /// Returns a handle to the same device that writes the transfers and
+    /// operations performed through it, and the [`Interface`]s and
+    /// [`Endpoint`]s opened from it, to `recorder`. Other handles to the
+    /// device are not affected.
+    ///
+    /// Isochronous endpoints can't be opened through the returned handle.
+    ///
+    /// See [`nusb::replay`][crate::replay] for details.
+    pub fn record(&self, recorder: &Recorder) -> Device {
+        recorder.header(self);
+        Device {
+            backend: self.backend.clone(),
+            recorder: Some(recorder.clone()),
+            faults: self.faults.clone(),
+            info: self.info.clone(),
+        }
+    }
+
+    /// Inject faults into the transfers performed on this device.
+    ///
+    /// Returns a handle to the same device that applies the rules of
+    /// `faults` to transfers and operations performed through it, and the
+    /// [`Interface`]s and [`Endpoint`]s opened from it. Other handles to the
+    /// device are not affected.
+    ///
+    /// See [`nusb::fault`][crate::fault] for details.
+    pub fn inject_faults(&self, faults: &FaultInjector) -> Device {
+        Device {
+            backend: self.backend.clone(),
+            recorder: self.recorder.clone(),
+            faults: Some(faults.clone()),
+            info: self.info.clone(),
+        }
+    }
+
     /// Open an interface of the device and claim it for exclusive use.
     pub fn claim_interface(
         &self,
         interface: u8,
     ) -> impl MaybeFuture<Output = Result<Interface, Error>> {
-        self.backend
-            .clone()
-            .claim_interface(interface)
-            .map(|i| i.map(Interface::wrap))
+        let claim = match &self.backend {
+            DeviceBackend::Platform(d) => Either::Left(
+                d.clone()
+                    .claim_interface(interface)
+                    .map(|i| i.map(InterfaceBackend::Platform)),
+            ),
+            DeviceBackend::Emulated(d) => Either::Right(
+                d.clone()
+                    .claim_interface(interface)
+                    .map(|i| i.map(InterfaceBackend::Emulated)),
+            ),
+        };
+        self.wrap_interface(interface, claim)
     }
 
     /// Detach kernel drivers and open an interface of the device and claim it for exclusive use.
@@ -86,14 +206,42 @@ impl Device {
     /// ### Platform-specific details
     /// This function can only detach kernel drivers on Linux. Calling on other platforms has
     /// the same effect as [`claim_interface`][`Device::claim_interface`].
+    ///
+    /// On Linux, this fails with [`ErrorKind::PermissionDenied`] after
+    /// [`drop_privileges`][`Device::drop_privileges`] has been called.
     pub fn detach_and_claim_interface(
         &self,
         interface: u8,
     ) -> impl MaybeFuture<Output = Result<Interface, Error>> {
-        self.backend
-            .clone()
-            .detach_and_claim_interface(interface)
-            .map(|i| i.map(Interface::wrap))
+        let claim = match &self.backend {
+            DeviceBackend::Platform(d) => Either::Left(
+                d.clone()
+                    .detach_and_claim_interface(interface)
+                    .map(|i| i.map(InterfaceBackend::Platform)),
+            ),
+            DeviceBackend::Emulated(d) => Either::Right(
+                d.clone()
+                    .claim_interface(interface)
+                    .map(|i| i.map(InterfaceBackend::Emulated)),
+            ),
+        };
+        self.wrap_interface(interface, claim)
+    }
+
+    fn wrap_interface(
+        &self,
+        interface: u8,
+        claim: impl MaybeFuture<Output = Result<InterfaceBackend, Error>>,
+    ) -> impl MaybeFuture<Output = Result<Interface, Error>> {
+        let (recorder, faults) = (self.recorder.clone(), self.faults.clone());
+        let claim = record_operation(&self.recorder, Operation::ClaimInterface(interface), claim);
+        fault::operation(&self.faults, claim).map(move |i| {
+            i.map(|backend| Interface {
+                backend,
+                recorder,
+                faults,
+            })
+        })
     }
 
     /// Detach kernel drivers for the specified interface.
@@ -103,7 +251,9 @@ impl Device {
     /// no effect.
     pub fn detach_kernel_driver(&self, interface: u8) -> Result<(), Error> {
         #[cfg(target_os = "linux")]
-        self.backend.detach_kernel_driver(interface)?;
+        if let DeviceBackend::Platform(d) = &self.backend {
+            d.detach_kernel_driver(interface)?;
+        }
         let _ = interface;
 
         Ok(())
@@ -116,22 +266,192 @@ impl Device {
     /// no effect.
     pub fn attach_kernel_driver(&self, interface: u8) -> Result<(), Error> {
         #[cfg(target_os = "linux")]
-        self.backend.attach_kernel_driver(interface)?;
+        if let DeviceBackend::Platform(d) = &self.backend {
+            d.attach_kernel_driver(interface)?;
+        }
         let _ = interface;
 
         Ok(())
     }
 
+    /// *(Linux-only)* Get the name of the kernel driver bound to the specified interface.
+    ///
+    /// Returns `None` if no driver is bound, and `usbfs` if the interface is
+    /// claimed through this library, by this or another process. This can be
+    /// used to check whether [`detach_and_claim_interface`][`Device::detach_and_claim_interface`]
+    /// is needed, and which driver it would detach.
+    ///
+    /// Always returns `None` for emulated devices.
+    #[cfg(target_os = "linux")]
+    pub fn kernel_driver(&self, interface: u8) -> Result<Option<String>, Error> {
+        match &self.backend {
+            DeviceBackend::Platform(d) => d.kernel_driver(interface),
+            DeviceBackend::Emulated(_) => Ok(None),
+        }
+    }
+
+    /// *(Linux-only)* Keep the device awake while it is open.
+    ///
+    /// This is the default when a device is opened, and undoes
+    /// [`allow_suspend`][Self::allow_suspend]. Wraps `USBDEVFS_FORBID_SUSPEND`.
+    ///
+    /// Has no effect on emulated devices.
+    #[cfg(target_os = "linux")]
+    pub fn forbid_suspend(&self) -> Result<(), Error> {
+        match &self.backend {
+            DeviceBackend::Platform(d) => d.forbid_suspend(),
+            DeviceBackend::Emulated(_) => Ok(()),
+        }
+    }
+
+    /// *(Linux-only)* Allow the kernel to autosuspend the device while it is
+    /// open.
+    ///
+    /// The device is only suspended if its power control policy is
+    /// [`PowerControl::Auto`][crate::PowerControl::Auto] and it has been idle
+    /// for the autosuspend delay; see [`DeviceInfo::set_power_control`]. Any
+    /// transfer resumes it. Use [`wait_for_resume`][Self::wait_for_resume] to
+    /// find out when it wakes up. Wraps `USBDEVFS_ALLOW_SUSPEND`.
+    ///
+    /// Has no effect on emulated devices.
+    #[cfg(target_os = "linux")]
+    pub fn allow_suspend(&self) -> Result<(), Error> {
+        match &self.backend {
+            DeviceBackend::Platform(d) => d.allow_suspend(),
+            DeviceBackend::Emulated(_) => Ok(()),
+        }
+    }
+
+    /// *(Linux-only)* Wait until the device is resumed after being suspended.
+    ///
+    /// After [`allow_suspend`][Self::allow_suspend], this completes once the
+    /// device has been suspended and then resumed, for example by remote
+    /// wakeup or by another process using it, and keeps it awake from then on
+    /// as if by [`forbid_suspend`][Self::forbid_suspend]. Transfers should not
+    /// be submitted while waiting, because they would resume the device.
+    /// Wraps `USBDEVFS_WAIT_FOR_RESUME`.
+    ///
+    /// There is no timeout: this doesn't complete until the device resumes or
+    /// is disconnected, and dropping the future doesn't cancel the wait. When
+    /// awaited, the wait runs on a dedicated thread rather than the async
+    /// runtime's blocking thread pool.
+    ///
+    /// Completes immediately for emulated devices.
+    #[cfg(target_os = "linux")]
+    pub fn wait_for_resume(&self) -> impl MaybeFuture<Output = Result<(), Error>> {
+        match &self.backend {
+            DeviceBackend::Platform(d) => Either::Left(d.clone().wait_for_resume()),
+            DeviceBackend::Emulated(_) => Either::Right(crate::maybe_future::Ready(Ok(()))),
+        }
+    }
+
+    /// Restrict the device to claiming only the given interfaces.
+    ///
+    /// After this call, claiming any other interface fails with
+    /// [`ErrorKind::PermissionDenied`], as do detaching kernel drivers and
+    /// resetting the device while a kernel driver is bound to another
+    /// interface. Claimed interfaces that are not in `allowed_interfaces` are
+    /// released. The restriction applies to the open file, including copies
+    /// of its file descriptor, and can't be undone.
+    ///
+    /// Together with [`Device::from_fd`], this allows passing a device to a
+    /// less trusted process that can only use some of its interfaces.
+    /// Wraps `USBDEVFS_DROP_PRIVILEGES`, which requires Linux 4.18 or later
+    /// and only takes interface numbers less than 32; fails with
+    /// [`ErrorKind::Unsupported`] on older kernels, for larger interface
+    /// numbers, and for emulated devices.
+    ///
+    /// The kernel refuses `USBDEVFS_DISCONNECT_CLAIM` on a device whose
+    /// privileges have been dropped, so
+    /// [`detach_and_claim_interface`][`Device::detach_and_claim_interface`]
+    /// fails with [`ErrorKind::PermissionDenied`] afterwards, even for allowed
+    /// interfaces. Use [`claim_interface`][`Device::claim_interface`] instead.
+    ///
+    /// *Supported on Linux and Android only.*
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    pub fn drop_privileges(&self, allowed_interfaces: &[u8]) -> Result<(), Error> {
+        let mut mask = 0u32;
+        for &interface in allowed_interfaces {
+            if interface >= 32 {
+                return Err(Error::new(
+                    ErrorKind::Unsupported,
+                    "interface number must be less than 32",
+                ));
+            }
+            mask |= 1 << interface;
+        }
+        match &self.backend {
+            DeviceBackend::Platform(d) => d.drop_privileges(mask),
+            DeviceBackend::Emulated(_) => Err(Error::new(
+                ErrorKind::Unsupported,
+                "not supported for emulated devices",
+            )),
+        }
+    }
+
+    /// Get the usbfs features available for this device.
+    ///
+    /// Wraps `USBDEVFS_GET_CAPABILITIES`.
+    ///
+    /// Returns an error with [`ErrorKind::Unsupported`] for emulated devices.
+    ///
+    /// *Supported on Linux and Android only.*
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    pub fn capabilities(&self) -> Result<crate::Capabilities, Error> {
+        match &self.backend {
+            DeviceBackend::Platform(d) => d.capabilities(),
+            DeviceBackend::Emulated(_) => Err(Error::new(
+                ErrorKind::Unsupported,
+                "not supported for emulated devices",
+            )),
+        }
+    }
+
+    /// Get the bus number, address, speed and port chain of the device from
+    /// the opened file descriptor.
+    ///
+    /// Unlike [`DeviceInfo`][crate::DeviceInfo], this does not need sysfs, so
+    /// it works for a device opened with [`Device::from_fd`]. Wraps
+    /// `USBDEVFS_CONNINFO_EX`, which requires Linux 5.6 or later; check
+    /// [`Capabilities::connection_info`][crate::Capabilities::connection_info].
+    ///
+    /// Returns an error with [`ErrorKind::Unsupported`] for emulated devices.
+    ///
+    /// *Supported on Linux and Android only.*
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    pub fn connection_info(&self) -> Result<crate::ConnectionInfo, Error> {
+        match &self.backend {
+            DeviceBackend::Platform(d) => d.connection_info(),
+            DeviceBackend::Emulated(_) => Err(Error::new(
+                ErrorKind::Unsupported,
+                "not supported for emulated devices",
+            )),
+        }
+    }
+
     /// Get the device descriptor.
     ///
     /// This returns cached data and does not perform IO.
     pub fn device_descriptor(&self) -> DeviceDescriptor {
-        self.backend.device_descriptor()
+        match &self.backend {
+            DeviceBackend::Platform(d) => d.device_descriptor(),
+            DeviceBackend::Emulated(d) => d.device_descriptor(),
+        }
     }
 
     /// Get the device's connection speed.
     pub fn speed(&self) -> Option<Speed> {
-        self.backend.speed()
+        match &self.backend {
+            DeviceBackend::Platform(d) => d.speed(),
+            DeviceBackend::Emulated(d) => d.speed(),
+        }
+    }
+
+    fn active_configuration_value(&self) -> u8 {
+        match &self.backend {
+            DeviceBackend::Platform(d) => d.active_configuration_value(),
+            DeviceBackend::Emulated(d) => d.active_configuration_value(),
+        }
     }
 
     /// Get information about the active configuration.
@@ -142,7 +462,7 @@ impl Device {
     pub fn active_configuration(
         &self,
     ) -> Result<ConfigurationDescriptor, ActiveConfigurationError> {
-        let active = self.backend.active_configuration_value();
+        let active = self.active_configuration_value();
 
         self.configurations()
             .find(|c| c.configuration_value() == active)
@@ -155,7 +475,10 @@ impl Device {
     ///
     /// This returns cached data and does not perform IO.
     pub fn configurations(&self) -> impl Iterator<Item = ConfigurationDescriptor> {
-        self.backend.configuration_descriptors()
+        match &self.backend {
+            DeviceBackend::Platform(d) => Either::Left(d.configuration_descriptors()),
+            DeviceBackend::Emulated(d) => Either::Right(d.configuration_descriptors()),
+        }
     }
 
     /// Set the device configuration.
@@ -170,7 +493,16 @@ impl Device {
         &self,
         configuration: u8,
     ) -> impl MaybeFuture<Output = Result<(), Error>> {
-        self.backend.clone().set_configuration(configuration)
+        let set = match &self.backend {
+            DeviceBackend::Platform(d) => Either::Left(d.clone().set_configuration(configuration)),
+            DeviceBackend::Emulated(d) => Either::Right(d.clone().set_configuration(configuration)),
+        };
+        let set = record_operation(
+            &self.recorder,
+            Operation::SetConfiguration(configuration),
+            set,
+        );
+        fault::operation(&self.faults, set)
     }
 
     /// Request a descriptor from the device.
@@ -189,33 +521,21 @@ impl Device {
         language_id: u16,
         timeout: Duration,
     ) -> impl MaybeFuture<Output = Result<Vec<u8>, GetDescriptorError>> {
-        #[cfg(target_os = "windows")]
-        {
-            let _ = timeout;
-            self.backend
-                .clone()
-                .get_descriptor(desc_type, desc_index, language_id)
-                .map(|r| r.map_err(GetDescriptorError::Transfer))
-        }
-
-        #[cfg(not(target_os = "windows"))]
-        {
-            const STANDARD_REQUEST_GET_DESCRIPTOR: u8 = 0x06;
-            use crate::transfer::{ControlType, Recipient};
-
-            self.control_in(
-                ControlIn {
-                    control_type: ControlType::Standard,
-                    recipient: Recipient::Device,
-                    request: STANDARD_REQUEST_GET_DESCRIPTOR,
-                    value: ((desc_type as u16) << 8) | desc_index as u16,
-                    index: language_id,
-                    length: 4096,
-                },
-                timeout,
-            )
-            .map(|r| r.map_err(GetDescriptorError::Transfer))
-        }
+        const STANDARD_REQUEST_GET_DESCRIPTOR: u8 = 0x06;
+        use crate::transfer::{ControlType, Recipient};
+
+        self.control_in_any(
+            ControlIn {
+                control_type: ControlType::Standard,
+                recipient: Recipient::Device,
+                request: STANDARD_REQUEST_GET_DESCRIPTOR,
+                value: ((desc_type as u16) << 8) | desc_index as u16,
+                index: language_id,
+                length: 4096,
+            },
+            timeout,
+        )
+        .map(|r| r.map_err(GetDescriptorError::Transfer))
     }
 
     /// Request the list of supported languages for string descriptors.
@@ -273,12 +593,96 @@ impl Device {
     /// Reset the device, forcing it to re-enumerate.
     ///
     /// This `Device` will no longer be usable, and you should drop it and call
-    /// [`list_devices`][`super::list_devices`] to find and re-open it again.
+    /// [`list_devices`][`super::list_devices`] to find and re-open it again,
+    /// or use [`reset_and_reopen`][Self::reset_and_reopen] instead.
     ///
     /// ### Platform-specific details
     /// * Not supported on Windows
     pub fn reset(&self) -> impl MaybeFuture<Output = Result<(), Error>> {
-        self.backend.clone().reset()
+        let reset = match &self.backend {
+            DeviceBackend::Platform(d) => Either::Left(d.clone().reset()),
+            DeviceBackend::Emulated(d) => Either::Right(d.clone().reset()),
+        };
+        let reset = record_operation(&self.recorder, Operation::Reset, reset);
+        fault::operation(&self.faults, reset)
+    }
+
+    /// Reset the device and open it again once it has reconnected.
+    ///
+    /// Devices that re-enumerate after a reset, for example to switch
+    /// between a bootloader and application firmware, come back as a new
+    /// device. This waits for a device on the same port or with the same
+    /// serial number to connect and opens it, for up to `timeout`. Fails with
+    /// [`ErrorKind::NotFound`] if none appears in time.
+    ///
+    /// This `Device` is no longer usable after the reset, as with
+    /// [`reset`][Self::reset]. It must have been opened with
+    /// [`DeviceInfo::open`], otherwise this fails with
+    /// [`ErrorKind::Unsupported`].
+    ///
+    /// ### Platform-specific details
+    /// * Not supported on Windows
+    /// * On Linux, a device that does not re-enumerate keeps its address,
+    ///   and is opened again immediately.
+    #[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
+    pub fn reset_and_reopen(
+        &self,
+        timeout: Duration,
+    ) -> impl MaybeFuture<Output = Result<Device, Error>> {
+        crate::reconnect::ResetAndReopen::new(self, self.info.clone(), timeout)
+    }
+
+    /// Wait for the device to be disconnected.
+    ///
+    /// This completes when the device is unplugged, so that the application
+    /// can stop using it without waiting for a transfer to fail. The returned
+    /// future keeps the device open until it completes or is dropped.
+    ///
+    /// ### Platform-specific details
+    /// * On Linux, the disconnection is reported by usbfs.
+    /// * On Windows and macOS, the disconnection is detected from hotplug
+    ///   events, and this completes immediately if the device is no longer
+    ///   listed when it is called. The device must have been opened with
+    ///   [`DeviceInfo::open`], otherwise this fails with
+    ///   [`ErrorKind::Unsupported`].
+    #[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
+    pub fn disconnected(&self) -> impl MaybeFuture<Output = Result<(), Error>> {
+        use crate::reconnect::Disconnected;
+
+        match &self.backend {
+            #[cfg(target_os = "linux")]
+            DeviceBackend::Platform(d) => Disconnected::Platform(d.clone()),
+            #[cfg(not(target_os = "linux"))]
+            DeviceBackend::Platform(_) => Disconnected::watch(self.info.as_deref()),
+            DeviceBackend::Emulated(d) => Disconnected::Emulated(d.clone()),
+        }
+    }
+
+    /// Submit a control IN transfer on whichever backend supports it.
+    ///
+    /// WinUSB can only send `GET_DESCRIPTOR` requests without claiming an
+    /// interface, which is the only use of this on Windows.
+    fn control_in_any(
+        &self,
+        data: ControlIn,
+        timeout: Duration,
+    ) -> impl MaybeFuture<Output = Result<Vec<u8>, TransferError>> {
+        let setup = data.setup_packet();
+        let transfer = match &self.backend {
+            #[cfg(not(target_os = "windows"))]
+            DeviceBackend::Platform(d) => Either::Left(d.clone().control_in(data, timeout)),
+            #[cfg(target_os = "windows")]
+            DeviceBackend::Platform(d) => Either::Left(d.clone().get_descriptor(
+                (data.value >> 8) as u8,
+                data.value as u8,
+                data.index,
+            )),
+            DeviceBackend::Emulated(d) => Either::Right(d.clone().control_in(data, timeout)),
+        };
+        fault::control_in(
+            &self.faults,
+            record_control_in(&self.recorder, setup, transfer),
+        )
     }
 
     /// Submit a single **IN (device-to-host)** transfer on the default **control** endpoint.
@@ -315,7 +719,7 @@ impl Device {
         data: ControlIn,
         timeout: Duration,
     ) -> impl MaybeFuture<Output = Result<Vec<u8>, TransferError>> {
-        self.backend.clone().control_in(data, timeout)
+        self.control_in_any(data, timeout)
     }
 
     /// Submit a single **OUT (host-to-device)** transfer on the default **control** endpoint.
@@ -352,7 +756,15 @@ impl Device {
         data: ControlOut,
         timeout: Duration,
     ) -> impl MaybeFuture<Output = Result<(), TransferError>> {
-        self.backend.clone().control_out(data, timeout)
+        // Only copy the data if it's going to be recorded
+        let setup = data.setup_packet();
+        let payload = self.recorder.as_ref().map(|_| data.data.to_vec());
+        let transfer = match &self.backend {
+            DeviceBackend::Platform(d) => Either::Left(d.clone().control_out(data, timeout)),
+            DeviceBackend::Emulated(d) => Either::Right(d.clone().control_out(data, timeout)),
+        };
+        let transfer = record_control_out(&self.recorder, setup, payload, transfer);
+        fault::control_out(&self.faults, transfer)
     }
 }
 
@@ -371,14 +783,18 @@ impl Debug for Device {
 /// associated [`Endpoint`]s are dropped.
 #[derive(Clone)]
 pub struct Interface {
-    backend: Arc<platform::Interface>,
+    backend: InterfaceBackend,
+    recorder: Option<Recorder>,
+    faults: Option<FaultInjector>,
 }
 
-impl Interface {
-    pub(crate) fn wrap(backend: Arc<platform::Interface>) -> Self {
-        Interface { backend }
-    }
+#[derive(Clone)]
+enum InterfaceBackend {
+    Platform(Arc<platform::Interface>),
+    Emulated(Arc<EmulatedInterface>),
+}
 
+impl Interface {
     /// Select the alternate setting of this interface.
     ///
     /// An alternate setting is a mode of the interface that makes particular endpoints available
@@ -388,12 +804,36 @@ impl Interface {
     /// You must not have any pending transfers or open `Endpoints` on this interface when changing
     /// the alternate setting.
     pub fn set_alt_setting(&self, alt_setting: u8) -> impl MaybeFuture<Output = Result<(), Error>> {
-        self.backend.clone().set_alt_setting(alt_setting)
+        let set = match &self.backend {
+            InterfaceBackend::Platform(i) => Either::Left(i.clone().set_alt_setting(alt_setting)),
+            InterfaceBackend::Emulated(i) => Either::Right(i.clone().set_alt_setting(alt_setting)),
+        };
+        let set = record_operation(
+            &self.recorder,
+            Operation::SetAltSetting(self.interface_number(), alt_setting),
+            set,
+        );
+        fault::operation(&self.faults, set)
     }
 
     /// Get the current alternate setting of this interface.
     pub fn get_alt_setting(&self) -> u8 {
-        self.backend.get_alt_setting()
+        match &self.backend {
+            InterfaceBackend::Platform(i) => i.get_alt_setting(),
+            InterfaceBackend::Emulated(i) => i.get_alt_setting(),
+        }
+    }
+
+    /// *(Linux-only)* Get the name of the kernel driver bound to this interface.
+    ///
+    /// This is normally `usbfs` while the interface is claimed. See
+    /// [`Device::kernel_driver`].
+    #[cfg(target_os = "linux")]
+    pub fn kernel_driver(&self) -> Result<Option<String>, Error> {
+        match &self.backend {
+            InterfaceBackend::Platform(i) => i.device.kernel_driver(i.interface_number),
+            InterfaceBackend::Emulated(_) => Ok(None),
+        }
     }
 
     /// Submit a single **IN (device-to-host)** transfer on the default **control** endpoint.
@@ -433,7 +873,15 @@ impl Interface {
         data: ControlIn,
         timeout: Duration,
     ) -> impl MaybeFuture<Output = Result<Vec<u8>, TransferError>> {
-        self.backend.clone().control_in(data, timeout)
+        let setup = data.setup_packet();
+        let transfer = match &self.backend {
+            InterfaceBackend::Platform(i) => Either::Left(i.control_in(data, timeout)),
+            InterfaceBackend::Emulated(i) => Either::Right(i.control_in(data, timeout)),
+        };
+        fault::control_in(
+            &self.faults,
+            record_control_in(&self.recorder, setup, transfer),
+        )
     }
 
     /// Submit a single **OUT (host-to-device)** transfer on the default
@@ -474,30 +922,52 @@ impl Interface {
         data: ControlOut,
         timeout: Duration,
     ) -> impl MaybeFuture<Output = Result<(), TransferError>> {
-        self.backend.clone().control_out(data, timeout)
+        // Only copy the data if it's going to be recorded
+        let setup = data.setup_packet();
+        let payload = self.recorder.as_ref().map(|_| data.data.to_vec());
+        let transfer = match &self.backend {
+            InterfaceBackend::Platform(i) => Either::Left(i.control_out(data, timeout)),
+            InterfaceBackend::Emulated(i) => Either::Right(i.control_out(data, timeout)),
+        };
+        let transfer = record_control_out(&self.recorder, setup, payload, transfer);
+        fault::control_out(&self.faults, transfer)
     }
 
     /// Get the interface number.
     pub fn interface_number(&self) -> u8 {
-        self.backend.interface_number
+        match &self.backend {
+            InterfaceBackend::Platform(i) => i.interface_number,
+            InterfaceBackend::Emulated(i) => i.interface_number,
+        }
+    }
+
+    fn active_configuration(&self) -> Option<ConfigurationDescriptor<'_>> {
+        match &self.backend {
+            InterfaceBackend::Platform(i) => {
+                let active = i.device.active_configuration_value();
+                i.device
+                    .configuration_descriptors()
+                    .find(|c| c.configuration_value() == active)
+            }
+            InterfaceBackend::Emulated(i) => {
+                let active = i.device.active_configuration_value();
+                i.device
+                    .configuration_descriptors()
+                    .find(|c| c.configuration_value() == active)
+            }
+        }
     }
 
     /// Get the interface descriptors for the alternate settings of this interface.
     ///
     /// This returns cached data and does not perform IO.
     pub fn descriptors(&self) -> impl Iterator<Item = InterfaceDescriptor> {
-        let active = self.backend.device.active_configuration_value();
-
-        let configuration = self
-            .backend
-            .device
-            .configuration_descriptors()
-            .find(|c| c.configuration_value() == active);
+        let interface_number = self.interface_number();
 
-        configuration
+        self.active_configuration()
             .into_iter()
             .flat_map(|i| i.interface_alt_settings())
-            .filter(|g| g.interface_number() == self.backend.interface_number)
+            .filter(move |g| g.interface_number() == interface_number)
     }
 
     /// Get the interface descriptor for the current alternate setting.
@@ -534,9 +1004,25 @@ impl Interface {
             return Err(Error::new(ErrorKind::Other, "incorrect endpoint type"));
         }
 
-        let backend = self.backend.endpoint(ep_desc)?;
+        if self.recorder.is_some() && EpType::TYPE == TransferType::Isochronous {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "isochronous transfers can't be recorded",
+            ));
+        }
+
+        let backend = match &self.backend {
+            InterfaceBackend::Platform(i) => EndpointBackend::Platform(i.endpoint(ep_desc)?),
+            InterfaceBackend::Emulated(i) => EndpointBackend::Emulated(i.endpoint(ep_desc)?),
+        };
         Ok(Endpoint {
             backend,
+            recorder: self.recorder.clone(),
+            submitted: VecDeque::new(),
+            faults: self
+                .faults
+                .as_ref()
+                .map(|faults| EndpointFaults::new(faults, address)),
             ep_type: PhantomData,
             ep_dir: PhantomData,
         })
@@ -546,7 +1032,7 @@ impl Interface {
 impl Debug for Interface {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Interface")
-            .field("number", &self.backend.interface_number)
+            .field("number", &self.interface_number())
             .finish()
     }
 }
@@ -576,11 +1062,91 @@ impl Debug for Interface {
 /// [`std::io::Write`], use the adapters from [`nusb::io`][`crate::io`]. See
 /// [`Self::reader`] and [`Self::writer`].
 pub struct Endpoint<EpType, Dir> {
-    backend: platform::Endpoint,
+    backend: EndpointBackend,
+    recorder: Option<Recorder>,
+    submitted: VecDeque<Submitted>,
+    faults: Option<EndpointFaults>,
     ep_type: PhantomData<EpType>,
     ep_dir: PhantomData<Dir>,
 }
 
+enum EndpointBackend {
+    Platform(platform::Endpoint),
+    Emulated(EmulatedEndpoint),
+}
+
+impl EndpointBackend {
+    fn endpoint_address(&self) -> u8 {
+        match self {
+            EndpointBackend::Platform(e) => e.endpoint_address(),
+            EndpointBackend::Emulated(e) => e.endpoint_address(),
+        }
+    }
+
+    fn max_packet_size(&self) -> usize {
+        match self {
+            EndpointBackend::Platform(e) => e.max_packet_size,
+            EndpointBackend::Emulated(e) => e.max_packet_size,
+        }
+    }
+
+    fn pending(&self) -> usize {
+        match self {
+            EndpointBackend::Platform(e) => e.pending(),
+            EndpointBackend::Emulated(e) => e.pending(),
+        }
+    }
+
+    fn cancel_all(&mut self) {
+        match self {
+            EndpointBackend::Platform(e) => e.cancel_all(),
+            EndpointBackend::Emulated(e) => e.cancel_all(),
+        }
+    }
+
+    fn submit(&mut self, buf: Buffer) {
+        match self {
+            EndpointBackend::Platform(e) => e.submit(buf),
+            EndpointBackend::Emulated(e) => e.submit(buf),
+        }
+    }
+
+    fn submit_err(&mut self, buf: Buffer, error: TransferError) {
+        match self {
+            EndpointBackend::Platform(e) => e.submit_err(buf, error),
+            EndpointBackend::Emulated(e) => e.submit_err(buf, error),
+        }
+    }
+
+    fn start_iso(&mut self, buf: Buffer, iso_packets: usize, iso_packet_size: usize) {
+        match self {
+            EndpointBackend::Platform(e) => e.start_iso(buf, iso_packets, iso_packet_size),
+            EndpointBackend::Emulated(e) => e.start_iso(buf, iso_packets, iso_packet_size),
+        }
+    }
+
+    fn allocate_iso(&self, len: usize) -> std::io::Result<Buffer> {
+        match self {
+            EndpointBackend::Platform(e) => Ok(e.allocate(len)?),
+            EndpointBackend::Emulated(_) => Ok(Buffer::new(len)),
+        }
+    }
+
+    fn poll_next_complete(&mut self, cx: &mut Context<'_>) -> Poll<Completion> {
+        match self {
+            EndpointBackend::Platform(e) => e.poll_next_complete(cx),
+            EndpointBackend::Emulated(e) => e.poll_next_complete(cx),
+        }
+    }
+
+    fn wait_next_complete(&mut self, timeout: Duration) -> Option<Completion> {
+        match self {
+            EndpointBackend::Platform(e) => e.wait_next_complete(timeout),
+            EndpointBackend::Emulated(e) => e.wait_next_complete(timeout),
+        }
+    }
+}
+
 /// Methods for all endpoints.
 impl<EpType: EndpointType, Dir: EndpointDirection> Endpoint<EpType, Dir> {
     /// Get the endpoint address.
@@ -593,13 +1159,13 @@ impl<EpType: EndpointType, Dir: EndpointDirection> Endpoint<EpType, Dir> {
     /// Transfers can consist of multiple packets, but are split into packets
     /// of this size on the bus.
     pub fn max_packet_size(&self) -> usize {
-        self.backend.max_packet_size
+        self.backend.max_packet_size()
     }
 
     /// Get the number of transfers that have been submitted with `submit` that
     /// have not yet been returned from `next_complete`.
     pub fn pending(&self) -> usize {
-        self.backend.pending()
+        self.backend.pending() + self.faults.as_ref().map_or(0, |f| f.pending())
     }
 
     /// Request cancellation of all pending transfers.
@@ -651,8 +1217,8 @@ impl<EpType: BulkOrInterrupt, Dir: EndpointDirection> Endpoint<EpType, Dir> {
     /// on other platforms, or if the memory allocation fails.
     pub fn allocate(&self, len: usize) -> Buffer {
         #[cfg(any(target_os = "linux"))] // target_os = "android")
-        {
-            if let Ok(b) = self.backend.allocate(len) {
+        if let EndpointBackend::Platform(backend) = &self.backend {
+            if let Ok(b) = backend.allocate(len) {
                 return b;
             }
         }
@@ -677,6 +1243,17 @@ impl<EpType: BulkOrInterrupt, Dir: EndpointDirection> Endpoint<EpType, Dir> {
     /// max_packet_size` packets will be received, ending early when any packet
     /// is shorter than `max_packet_size`.
     pub fn submit(&mut self, buf: Buffer) {
+        if self.recorder.is_some() {
+            self.submitted.push_back(Submitted {
+                start: Instant::now(),
+                requested_len: buf.requested_len(),
+                data: match Dir::DIR {
+                    Direction::Out => buf.to_vec(),
+                    Direction::In => Vec::new(),
+                },
+            });
+        }
+
         if Dir::DIR == Direction::In {
             let req_len = buf.requested_len();
             if req_len == 0 || req_len % self.max_packet_size() != 0 {
@@ -720,7 +1297,22 @@ impl<EpType: BulkOrInterrupt, Dir: EndpointDirection> Endpoint<EpType, Dir> {
     ///  * if there are no transfers pending (that is, if [`Self::pending()`]
     ///    would return 0).
     pub fn poll_next_complete(&mut self, cx: &mut Context<'_>) -> Poll<Completion> {
-        self.backend.poll_next_complete(cx)
+        let Endpoint {
+            backend,
+            recorder,
+            submitted,
+            faults,
+            ..
+        } = self;
+        let mut poll = |cx: &mut Context<'_>| {
+            backend.poll_next_complete(cx).map(|c| {
+                Self::record_completion(recorder, submitted, backend.endpoint_address(), c)
+            })
+        };
+        match faults {
+            Some(faults) => faults.poll_next_complete(cx, poll),
+            None => poll(cx),
+        }
     }
 
     /// Wait for a pending transfer completion.
@@ -735,7 +1327,49 @@ impl<EpType: BulkOrInterrupt, Dir: EndpointDirection> Endpoint<EpType, Dir> {
     ///  * if there are no transfers pending (that is, if [`Self::pending()`]
     ///    would return 0).
     pub fn wait_next_complete(&mut self, timeout: Duration) -> Option<Completion> {
-        self.backend.wait_next_complete(timeout)
+        let Endpoint {
+            backend,
+            recorder,
+            submitted,
+            faults,
+            ..
+        } = self;
+        let mut wait = |timeout| {
+            backend.wait_next_complete(timeout).map(|c| {
+                Self::record_completion(recorder, submitted, backend.endpoint_address(), c)
+            })
+        };
+        match faults {
+            Some(faults) => faults.wait_next_complete(timeout, wait),
+            None => wait(timeout),
+        }
+    }
+
+    fn record_completion(
+        recorder: &Option<Recorder>,
+        submitted: &mut VecDeque<Submitted>,
+        endpoint: u8,
+        completion: Completion,
+    ) -> Completion {
+        let (Some(recorder), Some(submitted)) = (recorder, submitted.pop_front()) else {
+            return completion;
+        };
+        let event = match Dir::DIR {
+            Direction::In => EventKind::In {
+                endpoint,
+                requested_len: submitted.requested_len,
+                status: completion.status,
+                data: completion.buffer[..].to_vec(),
+            },
+            Direction::Out => EventKind::Out {
+                endpoint,
+                data: submitted.data,
+                status: completion.status,
+                actual_len: completion.actual_len,
+            },
+        };
+        recorder.event(submitted.start, event);
+        completion
     }
 
     /// Clear the endpoint's halt / stall condition.
@@ -750,7 +1384,16 @@ impl<EpType: BulkOrInterrupt, Dir: EndpointDirection> Endpoint<EpType, Dir> {
     ///
     /// This should not be called when transfers are pending on the endpoint.
     pub fn clear_halt(&mut self) -> impl MaybeFuture<Output = Result<(), Error>> {
-        self.backend.clear_halt()
+        let clear = match &self.backend {
+            EndpointBackend::Platform(e) => Either::Left(e.clear_halt()),
+            EndpointBackend::Emulated(e) => Either::Right(e.clear_halt()),
+        };
+        let clear = record_operation(
+            &self.recorder,
+            Operation::ClearHalt(self.endpoint_address()),
+            clear,
+        );
+        fault::clear_halt(self.faults.as_ref(), clear)
     }
 }
 
@@ -784,7 +1427,7 @@ impl Endpoint<Isochronous, In> {
         trace!("Buffer size: {buffer_size}; iso_packets: {iso_packets}; iso_packet_size: {iso_packet_size}");
 
         for _ in 0..tranfser_amount {
-            let buffer = self.backend.allocate(buffer_size)?;
+            let buffer = self.backend.allocate_iso(buffer_size)?;
 
             for _ in 0..iso_packets {
                 self.backend.start_iso(buffer, iso_packets, iso_packet_size);
@@ -801,7 +1444,7 @@ impl Endpoint<Isochronous, In> {
         iso_packets: usize,
         iso_packet_size: usize,
     ) -> std::io::Result<()> {
-        let buffer = self.backend.allocate(buffer_size)?;
+        let buffer = self.backend.allocate_iso(buffer_size)?;
 
         for _ in 0..iso_packets {
             self.backend.start_iso(buffer, iso_packets, iso_packet_size);
@@ -826,6 +1469,72 @@ impl Endpoint<Isochronous, In> {
     }
 }
 
+fn record_operation<T>(
+    recorder: &Option<Recorder>,
+    op: Operation,
+    f: impl MaybeFuture<Output = Result<T, Error>>,
+) -> impl MaybeFuture<Output = Result<T, Error>> {
+    let recorder = recorder.clone();
+    let start = Instant::now();
+    f.map(move |r| {
+        if let Some(recorder) = recorder {
+            let result = r.as_ref().map(|_| ()).map_err(|e| e.kind());
+            recorder.event(start, EventKind::Operation { op, result });
+        }
+        r
+    })
+}
+
+fn record_control_in(
+    recorder: &Option<Recorder>,
+    setup: [u8; SETUP_PACKET_SIZE],
+    f: impl MaybeFuture<Output = Result<Vec<u8>, TransferError>>,
+) -> impl MaybeFuture<Output = Result<Vec<u8>, TransferError>> {
+    let recorder = recorder.clone();
+    let start = Instant::now();
+    f.map(move |r| {
+        if let Some(recorder) = recorder {
+            let (status, data) = match &r {
+                Ok(data) => (Ok(()), data.clone()),
+                Err(e) => (Err(*e), Vec::new()),
+            };
+            recorder.event(
+                start,
+                EventKind::ControlIn {
+                    setup,
+                    status,
+                    data,
+                },
+            );
+        }
+        r
+    })
+}
+
+fn record_control_out(
+    recorder: &Option<Recorder>,
+    setup: [u8; SETUP_PACKET_SIZE],
+    data: Option<Vec<u8>>,
+    f: impl MaybeFuture<Output = Result<(), TransferError>>,
+) -> impl MaybeFuture<Output = Result<(), TransferError>> {
+    let recorder = recorder.clone();
+    let start = Instant::now();
+    f.map(move |r| {
+        if let (Some(recorder), Some(data)) = (recorder, data) {
+            let status = r;
+            recorder.event(
+                start,
+                EventKind::ControlOut {
+                    setup,
+                    data,
+                    status,
+                },
+            );
+        }
+        r
+    })
+}
+
 #[test]
 fn assert_send_sync() {
     use crate::transfer::{Bulk, In, Interrupt, Out};
@@ -0,0 +1,414 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        Arc, Mutex, Weak,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use log::debug;
+
+use super::{
+    transfer::{Completer, Inflight, TransferData},
+    Model,
+};
+use crate::{
+    bitset::EndpointBitSet,
+    descriptors::{
+        parse_concatenated_config_descriptors, ConfigurationDescriptor, DeviceDescriptor,
+        EndpointDescriptor, TransferType, DESCRIPTOR_LEN_DEVICE,
+    },
+    maybe_future::Ready,
+    timer,
+    transfer::{
+        internal::{take_completed_from_queue, Idle, Notify, Pending, TransferFuture},
+        Buffer, Completion, ControlIn, ControlOut, TransferError,
+    },
+    Error, ErrorKind, MaybeFuture, Speed,
+};
+
+pub(crate) struct EmulatedDevice {
+    model: Arc<dyn Model>,
+    descriptors: Vec<u8>,
+    active_config: AtomicU8,
+    claimed: Mutex<Vec<u8>>,
+    inflight: Arc<Inflight>,
+}
+
+impl EmulatedDevice {
+    pub(crate) fn from_model(
+        model: Arc<dyn Model>,
+    ) -> impl MaybeFuture<Output = Result<Arc<EmulatedDevice>, Error>> {
+        Ready(()).map(move |()| {
+            model.open()?;
+            let descriptors = model.descriptors();
+            if DeviceDescriptor::new(&descriptors).is_none() {
+                model.close();
+                return Err(Error::new(
+                    ErrorKind::Other,
+                    "emulated device has an invalid device descriptor",
+                ));
+            }
+            debug!("Opened emulated device");
+            Ok(Arc::new(EmulatedDevice {
+                active_config: AtomicU8::new(model.active_configuration()),
+                model,
+                descriptors,
+                claimed: Mutex::new(Vec::new()),
+                inflight: Arc::new(Inflight::default()),
+            }))
+        })
+    }
+
+    pub(crate) fn device_descriptor(&self) -> DeviceDescriptor {
+        DeviceDescriptor::new(&self.descriptors).unwrap()
+    }
+
+    pub(crate) fn configuration_descriptors(
+        &self,
+    ) -> impl Iterator<Item = ConfigurationDescriptor<'_>> {
+        parse_concatenated_config_descriptors(&self.descriptors[DESCRIPTOR_LEN_DEVICE as usize..])
+    }
+
+    pub(crate) fn active_configuration_value(&self) -> u8 {
+        self.active_config.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn speed(&self) -> Option<Speed> {
+        self.model.speed()
+    }
+
+    pub(crate) fn set_configuration(
+        self: Arc<Self>,
+        configuration: u8,
+    ) -> impl MaybeFuture<Output = Result<(), Error>> {
+        Ready(()).map(move |()| {
+            if !self.claimed.lock().unwrap().is_empty() {
+                return Err(Error::new(ErrorKind::Busy, "device is busy"));
+            }
+            self.model.set_configuration(configuration)?;
+            self.active_config.store(configuration, Ordering::SeqCst);
+            Ok(())
+        })
+    }
+
+    pub(crate) fn reset(self: Arc<Self>) -> impl MaybeFuture<Output = Result<(), Error>> {
+        Ready(()).map(move |()| self.model.reset())
+    }
+
+    pub(crate) fn poll_disconnected(&self, cx: &mut Context<'_>) -> Poll<()> {
+        self.model.poll_disconnected(cx)
+    }
+
+    pub(crate) fn control_in(
+        self: Arc<Self>,
+        data: ControlIn,
+        timeout: Duration,
+    ) -> impl MaybeFuture<Output = Result<Vec<u8>, TransferError>> {
+        let t = TransferData::new_control_in(data);
+        TransferFuture::new(t, |t| self.submit_timeout(t, timeout)).map(move |t| {
+            drop(self); // ensure device stays alive
+            t.status()?;
+            Ok(t.control_in_data().to_owned())
+        })
+    }
+
+    pub(crate) fn control_out(
+        self: Arc<Self>,
+        data: ControlOut,
+        timeout: Duration,
+    ) -> impl MaybeFuture<Output = Result<(), TransferError>> {
+        let t = TransferData::new_control_out(data);
+        TransferFuture::new(t, |t| self.submit_timeout(t, timeout)).map(move |t| {
+            drop(self); // ensure device stays alive
+            t.status()?;
+            Ok(())
+        })
+    }
+
+    pub(crate) fn claim_interface(
+        self: Arc<Self>,
+        interface_number: u8,
+    ) -> impl MaybeFuture<Output = Result<Arc<EmulatedInterface>, Error>> {
+        Ready(()).map(move |()| {
+            let mut claimed = self.claimed.lock().unwrap();
+            if claimed.contains(&interface_number) {
+                return Err(Error::new(ErrorKind::Busy, "interface is busy"));
+            }
+            self.model.claim_interface(interface_number)?;
+            claimed.push(interface_number);
+            drop(claimed);
+
+            debug!("Claimed interface {interface_number} on emulated device");
+            Ok(Arc::new(EmulatedInterface {
+                device: self,
+                interface_number,
+                state: Mutex::new(Default::default()),
+            }))
+        })
+    }
+
+    fn submit(&self, mut transfer: Idle<TransferData>) -> Pending<TransferData> {
+        transfer.id = self.inflight.next_id();
+        let request = transfer.request();
+        let id = request.id;
+        let pending = transfer.pre_submit();
+
+        // Insert before handing the transfer to the model, which may complete
+        // it before `submit` returns.
+        self.inflight.insert(&pending);
+        self.model
+            .submit(request, Completer::new(id, Arc::downgrade(&self.inflight)));
+        pending
+    }
+
+    fn submit_timeout(
+        self: &Arc<Self>,
+        transfer: Idle<TransferData>,
+        timeout: Duration,
+    ) -> Pending<TransferData> {
+        let pending = self.submit(transfer);
+        let id = pending.id();
+        let device = Arc::downgrade(self);
+        timer::schedule(Instant::now() + timeout, move || {
+            if let Some(device) = Weak::upgrade(&device) {
+                device.cancel(id);
+            }
+        });
+        pending
+    }
+
+    fn cancel(&self, id: u64) {
+        if self
+            .inflight
+            .finish(id, |t| t.set_status(Err(TransferError::Cancelled)))
+        {
+            debug!("Cancelled emulated transfer {id}");
+            self.model.cancel(id);
+        }
+    }
+
+    fn release_interface(&self, interface_number: u8) {
+        self.claimed
+            .lock()
+            .unwrap()
+            .retain(|&i| i != interface_number);
+        self.model.release_interface(interface_number);
+        debug!("Released interface {interface_number} on emulated device");
+    }
+}
+
+impl Drop for EmulatedDevice {
+    fn drop(&mut self) {
+        // Control transfers whose futures were dropped before completion are
+        // still owned by `inflight`.
+        self.inflight.fail_all(TransferError::Cancelled);
+        self.model.close();
+    }
+}
+
+pub(crate) struct EmulatedInterface {
+    pub(crate) interface_number: u8,
+    pub(crate) device: Arc<EmulatedDevice>,
+    state: Mutex<InterfaceState>,
+}
+
+#[derive(Default)]
+struct InterfaceState {
+    endpoints: EndpointBitSet,
+    alt_setting: u8,
+}
+
+impl EmulatedInterface {
+    pub(crate) fn control_in(
+        &self,
+        data: ControlIn,
+        timeout: Duration,
+    ) -> impl MaybeFuture<Output = Result<Vec<u8>, TransferError>> {
+        self.device.clone().control_in(data, timeout)
+    }
+
+    pub(crate) fn control_out(
+        &self,
+        data: ControlOut,
+        timeout: Duration,
+    ) -> impl MaybeFuture<Output = Result<(), TransferError>> {
+        self.device.clone().control_out(data, timeout)
+    }
+
+    pub(crate) fn get_alt_setting(&self) -> u8 {
+        self.state.lock().unwrap().alt_setting
+    }
+
+    pub(crate) fn set_alt_setting(
+        self: Arc<Self>,
+        alt_setting: u8,
+    ) -> impl MaybeFuture<Output = Result<(), Error>> {
+        Ready(()).map(move |()| {
+            let mut state = self.state.lock().unwrap();
+            if !state.endpoints.is_empty() {
+                return Err(Error::new(
+                    ErrorKind::Busy,
+                    "can't change alternate setting while endpoints are in use",
+                ));
+            }
+            self.device
+                .model
+                .set_alt_setting(self.interface_number, alt_setting)?;
+            state.alt_setting = alt_setting;
+            Ok(())
+        })
+    }
+
+    pub(crate) fn endpoint(
+        self: &Arc<Self>,
+        descriptor: EndpointDescriptor,
+    ) -> Result<EmulatedEndpoint, Error> {
+        let address = descriptor.address();
+        let ep_type = descriptor.transfer_type();
+        let max_packet_size = descriptor.max_packet_size();
+
+        let mut state = self.state.lock().unwrap();
+
+        if state.endpoints.is_set(address) {
+            return Err(Error::new(ErrorKind::Busy, "endpoint already in use"));
+        }
+        state.endpoints.set(address);
+
+        Ok(EmulatedEndpoint {
+            inner: Arc::new(EndpointInner {
+                address,
+                ep_type,
+                interface: self.clone(),
+                notify: Notify::new(),
+            }),
+            max_packet_size,
+            pending: VecDeque::new(),
+            idle_transfer: None,
+        })
+    }
+}
+
+impl Drop for EmulatedInterface {
+    fn drop(&mut self) {
+        self.device.release_interface(self.interface_number);
+    }
+}
+
+pub(crate) struct EmulatedEndpoint {
+    inner: Arc<EndpointInner>,
+
+    pub(crate) max_packet_size: usize,
+
+    /// A queue of pending transfers, expected to complete in order
+    pending: VecDeque<Pending<TransferData>>,
+
+    idle_transfer: Option<Idle<TransferData>>,
+}
+
+struct EndpointInner {
+    interface: Arc<EmulatedInterface>,
+    address: u8,
+    ep_type: TransferType,
+    notify: Notify,
+}
+
+impl EmulatedEndpoint {
+    pub(crate) fn endpoint_address(&self) -> u8 {
+        self.inner.address
+    }
+
+    pub(crate) fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub(crate) fn cancel_all(&mut self) {
+        // Cancel transfers in reverse order to ensure subsequent transfers
+        // can't complete out of order while we're going through them.
+        for transfer in self.pending.iter().rev() {
+            self.inner.interface.device.cancel(transfer.id());
+        }
+    }
+
+    fn get_transfer(&mut self) -> Idle<TransferData> {
+        self.idle_transfer.take().unwrap_or_else(|| {
+            Idle::new(
+                self.inner.clone(),
+                TransferData::new(self.inner.address, self.inner.ep_type),
+            )
+        })
+    }
+
+    pub(crate) fn submit(&mut self, data: Buffer) {
+        let mut transfer = self.get_transfer();
+        transfer.set_buffer(data);
+        self.pending
+            .push_back(self.inner.interface.device.submit(transfer));
+    }
+
+    pub(crate) fn start_iso(&mut self, data: Buffer, iso_packets: usize, iso_packet_size: usize) {
+        debug_assert_eq!(self.inner.ep_type, TransferType::Isochronous);
+        let mut transfer = self.get_transfer();
+        transfer.set_iso_buffer(data, iso_packets, iso_packet_size);
+        self.pending
+            .push_back(self.inner.interface.device.submit(transfer));
+    }
+
+    pub(crate) fn submit_err(&mut self, data: Buffer, error: TransferError) {
+        let mut transfer = self.get_transfer();
+        transfer.set_buffer(data);
+        transfer.set_status(Err(error));
+        self.pending.push_back(transfer.simulate_complete());
+    }
+
+    pub(crate) fn poll_next_complete(&mut self, cx: &mut Context) -> Poll<Completion> {
+        self.inner.notify.subscribe(cx);
+        if let Some(mut transfer) = take_completed_from_queue(&mut self.pending) {
+            let completion = transfer.take_completion();
+            self.idle_transfer = Some(transfer);
+            Poll::Ready(completion)
+        } else {
+            Poll::Pending
+        }
+    }
+
+    pub(crate) fn wait_next_complete(&mut self, timeout: Duration) -> Option<Completion> {
+        self.inner.notify.wait_timeout(timeout, || {
+            take_completed_from_queue(&mut self.pending).map(|mut transfer| {
+                let completion = transfer.take_completion();
+                self.idle_transfer = Some(transfer);
+                completion
+            })
+        })
+    }
+
+    pub(crate) fn clear_halt(&self) -> impl MaybeFuture<Output = Result<(), Error>> {
+        let inner = self.inner.clone();
+        Ready(()).map(move |()| {
+            let endpoint = inner.address;
+            debug!("Clear halt, emulated endpoint {endpoint:02x}");
+            inner.interface.device.model.clear_halt(endpoint)
+        })
+    }
+}
+
+impl Drop for EmulatedEndpoint {
+    fn drop(&mut self) {
+        self.cancel_all();
+    }
+}
+
+impl AsRef<Notify> for EndpointInner {
+    fn as_ref(&self) -> &Notify {
+        &self.notify
+    }
+}
+
+impl Drop for EndpointInner {
+    fn drop(&mut self) {
+        let mut state = self.interface.state.lock().unwrap();
+        state.endpoints.clear(self.address);
+    }
+}
@@ -0,0 +1,81 @@
+//! Backend for devices that are implemented in software rather than opened
+//! through the OS.
+//!
+//! An emulated device is described by a [`Model`], which sees the same
+//! operations that the platform backends pass to the kernel. The handles
+//! returned by [`Device`][crate::Device] dispatch to [`EmulatedDevice`]
+//! instead of `crate::platform` when the `DeviceInfo` was created with
+//! [`DeviceInfo::emulated`][crate::DeviceInfo::emulated].
+
+mod device;
+pub(crate) use device::{EmulatedDevice, EmulatedEndpoint, EmulatedInterface};
+
+mod transfer;
+pub(crate) use transfer::{Completer, Request};
+
+use std::task::{Context, Poll};
+
+use crate::{Error, Speed};
+
+/// Behavior of an emulated device.
+///
+/// Methods are called synchronously from the thread performing the
+/// operation, and must not block for long. Transfers are completed through
+/// the [`Completer`] passed to [`Model::submit`], which may be kept and used
+/// from another thread to complete the transfer later.
+pub(crate) trait Model: Send + Sync + 'static {
+    /// Device descriptor followed by all configuration descriptors.
+    fn descriptors(&self) -> Vec<u8>;
+
+    /// Connection speed reported by [`Device::speed`][crate::Device::speed].
+    fn speed(&self) -> Option<Speed>;
+
+    /// Configuration that is active when the device is opened.
+    fn active_configuration(&self) -> u8;
+
+    /// Called when the device is opened.
+    fn open(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Called when the last handle to the opened device is dropped.
+    fn close(&self) {}
+
+    fn set_configuration(&self, configuration: u8) -> Result<(), Error>;
+
+    fn claim_interface(&self, interface: u8) -> Result<(), Error>;
+
+    /// Called when the last handle to a claimed interface is dropped.
+    fn release_interface(&self, interface: u8) {
+        let _ = interface;
+    }
+
+    fn set_alt_setting(&self, interface: u8, alt_setting: u8) -> Result<(), Error>;
+
+    fn clear_halt(&self, endpoint: u8) -> Result<(), Error>;
+
+    fn reset(&self) -> Result<(), Error>;
+
+    /// Start a transfer.
+    fn submit(&self, request: Request, completer: Completer);
+
+    /// Notification that the transfer with the given [`Request::id`] was
+    /// cancelled or timed out.
+    ///
+    /// The transfer has already been completed with
+    /// [`TransferError::Cancelled`][crate::transfer::TransferError::Cancelled]
+    /// and its `Completer` no longer has any effect.
+    fn cancel(&self, id: u64) {
+        let _ = id;
+    }
+
+    /// Return `Ready` once the device has been disconnected, or arrange for
+    /// the context's waker to be notified when it is.
+    ///
+    /// The default implementation is for devices that are never
+    /// disconnected.
+    fn poll_disconnected(&self, cx: &mut Context<'_>) -> Poll<()> {
+        let _ = cx;
+        Poll::Pending
+    }
+}
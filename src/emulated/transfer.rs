@@ -0,0 +1,336 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Debug,
+    mem,
+    ptr::addr_of,
+    sync::{Mutex, Weak},
+};
+
+use crate::{
+    descriptors::TransferType,
+    transfer::{
+        internal::{notify_completion, Pending},
+        Buffer, Completion, ControlIn, ControlOut, Direction, IsoStatus, TransferError,
+        SETUP_PACKET_SIZE,
+    },
+};
+
+/// Emulated transfer state.
+///
+/// While the transfer is pending, this is only accessed by [`Inflight`] when
+/// the model completes the transfer, in the same way that the kernel owns a
+/// URB between submission and reaping on Linux.
+pub(crate) struct TransferData {
+    /// Identifier assigned on submission, used to cancel the transfer.
+    pub(super) id: u64,
+    endpoint: u8,
+    transfer_type: TransferType,
+    setup: Option<[u8; SETUP_PACKET_SIZE]>,
+    buffer: Buffer,
+    iso_packets: usize,
+    iso_packet_size: usize,
+    status: Result<(), TransferError>,
+    actual_len: usize,
+    iso_status: Vec<Result<IsoStatus, TransferError>>,
+}
+
+impl Debug for TransferData {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TransferData")
+            .field("id", &self.id)
+            .field("endpoint", &format_args!("0x{:02x}", self.endpoint))
+            .field("transfer_type", &self.transfer_type)
+            .field("setup", &self.setup)
+            .field("buffer", &self.buffer)
+            .field("status", &self.status)
+            .field("actual_len", &self.actual_len)
+            .finish()
+    }
+}
+
+impl TransferData {
+    pub(super) fn new(endpoint: u8, transfer_type: TransferType) -> TransferData {
+        TransferData {
+            id: 0,
+            endpoint,
+            transfer_type,
+            setup: None,
+            buffer: Buffer::new(0),
+            iso_packets: 0,
+            iso_packet_size: 0,
+            status: Ok(()),
+            actual_len: 0,
+            iso_status: Vec::new(),
+        }
+    }
+
+    pub(super) fn new_control_in(data: ControlIn) -> TransferData {
+        let mut t = TransferData::new(0x80, TransferType::Control);
+        t.setup = Some(data.setup_packet());
+        t.buffer = Buffer::new(data.length as usize);
+        t
+    }
+
+    pub(super) fn new_control_out(data: ControlOut) -> TransferData {
+        let mut t = TransferData::new(0x00, TransferType::Control);
+        t.setup = Some(data.setup_packet());
+        t.buffer = data.data.to_vec().into();
+        t
+    }
+
+    pub(super) fn set_buffer(&mut self, buf: Buffer) {
+        self.buffer = buf;
+        self.iso_packets = 0;
+        self.iso_packet_size = 0;
+    }
+
+    pub(super) fn set_iso_buffer(&mut self, buf: Buffer, iso_packets: usize, packet_size: usize) {
+        self.buffer = buf;
+        self.iso_packets = iso_packets;
+        self.iso_packet_size = packet_size;
+    }
+
+    pub(super) fn set_status(&mut self, status: Result<(), TransferError>) {
+        self.status = status;
+    }
+
+    /// Copy out the parts of the transfer that the model needs to see.
+    pub(super) fn request(&self) -> Request {
+        let direction = Direction::from_address(self.endpoint);
+        Request {
+            id: self.id,
+            endpoint: self.endpoint,
+            transfer_type: self.transfer_type,
+            setup: self.setup,
+            data: match direction {
+                Direction::Out => self.buffer[..].to_vec(),
+                Direction::In => Vec::new(),
+            },
+            requested_len: match direction {
+                Direction::Out => self.buffer.len(),
+                Direction::In => self.buffer.requested_len(),
+            },
+            iso_packet_lengths: vec![self.iso_packet_size as u32; self.iso_packets],
+        }
+    }
+
+    pub(super) fn status(&self) -> Result<(), TransferError> {
+        self.status
+    }
+
+    pub(super) fn control_in_data(&self) -> &[u8] {
+        &self.buffer[..]
+    }
+
+    pub(super) fn take_completion(&mut self) -> Completion {
+        Completion {
+            buffer: mem::replace(&mut self.buffer, Buffer::new(0)),
+            actual_len: mem::take(&mut self.actual_len),
+            status: mem::replace(&mut self.status, Ok(())),
+            iso_status: mem::take(&mut self.iso_status),
+        }
+    }
+
+    fn complete_in(&mut self, status: Result<(), TransferError>, data: &[u8]) {
+        let len = data
+            .len()
+            .min(self.buffer.requested_len())
+            .min(self.buffer.capacity());
+        self.buffer.clear();
+        self.buffer.extend_from_slice(&data[..len]);
+        self.actual_len = len;
+        self.status = status;
+    }
+
+    fn complete_out(&mut self, status: Result<(), TransferError>, actual_len: usize) {
+        self.actual_len = actual_len.min(self.buffer.len());
+        self.status = status;
+    }
+
+    fn complete_iso(
+        &mut self,
+        status: Result<(), TransferError>,
+        data: &[u8],
+        packets: Vec<IsoStatus>,
+    ) {
+        if Direction::from_address(self.endpoint) == Direction::In {
+            self.complete_in(status, data);
+        } else {
+            self.complete_out(status, self.buffer.len());
+        }
+        self.actual_len = packets.iter().map(|p| p.actual_length as usize).sum();
+        self.iso_status = packets.into_iter().map(Ok).collect();
+    }
+}
+
+impl Pending<TransferData> {
+    pub(super) fn id(&self) -> u64 {
+        // Read the id without dereferencing as `TransferData`, because
+        // `Inflight` may be concurrently completing the transfer. The id is
+        // not modified while the transfer is pending.
+        unsafe { *addr_of!((*self.as_ptr()).id) }
+    }
+}
+
+/// A transfer as seen by the model.
+#[derive(Debug, Clone)]
+pub(crate) struct Request {
+    /// Unique identifier of the transfer on this opened device.
+    pub id: u64,
+
+    /// Endpoint address. Control transfers use `0x00` or `0x80`.
+    pub endpoint: u8,
+
+    /// Type of the endpoint.
+    pub transfer_type: TransferType,
+
+    /// Setup packet of a control transfer.
+    pub setup: Option<[u8; SETUP_PACKET_SIZE]>,
+
+    /// Data of an OUT transfer.
+    pub data: Vec<u8>,
+
+    /// Length of an OUT transfer, or number of bytes requested by an IN transfer.
+    #[allow(dead_code)]
+    pub requested_len: usize,
+
+    /// Requested length of each packet of an isochronous transfer.
+    #[allow(dead_code)]
+    pub iso_packet_lengths: Vec<u32>,
+}
+
+impl Request {
+    pub fn direction(&self) -> Direction {
+        Direction::from_address(self.endpoint)
+    }
+}
+
+struct PendingPtr(*mut TransferData);
+
+// SAFETY: the pointer is only dereferenced by the one party that removes it
+// from the map, and `TransferData` is `Send`.
+unsafe impl Send for PendingPtr {}
+
+/// Transfers that have been submitted to a model and not yet completed.
+#[derive(Default)]
+pub(crate) struct Inflight {
+    next_id: Mutex<u64>,
+    transfers: Mutex<BTreeMap<u64, PendingPtr>>,
+}
+
+impl Inflight {
+    pub(super) fn next_id(&self) -> u64 {
+        let mut next_id = self.next_id.lock().unwrap();
+        *next_id += 1;
+        *next_id
+    }
+
+    pub(super) fn insert(&self, pending: &Pending<TransferData>) {
+        self.transfers
+            .lock()
+            .unwrap()
+            .insert(pending.id(), PendingPtr(pending.as_ptr()));
+    }
+
+    /// Complete the transfer with the specified id, if it is still pending.
+    pub(super) fn finish(&self, id: u64, f: impl FnOnce(&mut TransferData)) -> bool {
+        let Some(PendingPtr(ptr)) = self.transfers.lock().unwrap().remove(&id) else {
+            return false;
+        };
+
+        // SAFETY: the pointer came from a pending transfer, which remains
+        // valid until `notify_completion`, and removing it from the map
+        // gives us exclusive access.
+        unsafe {
+            f(&mut *ptr);
+            notify_completion::<TransferData>(ptr);
+        }
+        true
+    }
+
+    /// Complete all pending transfers with an error.
+    pub(super) fn fail_all(&self, error: TransferError) {
+        let transfers = mem::take(&mut *self.transfers.lock().unwrap());
+        for (_, PendingPtr(ptr)) in transfers {
+            // SAFETY: as in `finish`
+            unsafe {
+                (*ptr).status = Err(error);
+                notify_completion::<TransferData>(ptr);
+            }
+        }
+    }
+}
+
+/// Handle used by a model to complete a transfer, either immediately from
+/// `submit` or later from another thread.
+///
+/// Dropping a `Completer` without calling one of its methods completes the
+/// transfer with [`TransferError::Disconnected`], since the model is no longer
+/// able to respond to it.
+pub(crate) struct Completer {
+    id: u64,
+    inflight: Weak<Inflight>,
+    done: bool,
+}
+
+impl Completer {
+    pub(super) fn new(id: u64, inflight: Weak<Inflight>) -> Self {
+        Completer {
+            id,
+            inflight,
+            done: false,
+        }
+    }
+
+    /// Identifier of the transfer, matching [`Request::id`].
+    #[allow(dead_code)]
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    fn finish(mut self, f: impl FnOnce(&mut TransferData)) -> bool {
+        self.done = true;
+        self.inflight
+            .upgrade()
+            .is_some_and(|inflight| inflight.finish(self.id, f))
+    }
+
+    /// Complete an IN transfer with the received data.
+    ///
+    /// Data beyond the requested length is discarded. Returns `false` if the
+    /// transfer was already cancelled.
+    pub fn complete_in(self, status: Result<(), TransferError>, data: &[u8]) -> bool {
+        self.finish(|t| t.complete_in(status, data))
+    }
+
+    /// Complete an OUT transfer, reporting how many bytes were accepted.
+    pub fn complete_out(self, status: Result<(), TransferError>, actual_len: usize) -> bool {
+        self.finish(|t| t.complete_out(status, actual_len))
+    }
+
+    /// Complete a transfer of either direction with an error and no data.
+    pub fn fail(self, error: TransferError) -> bool {
+        self.finish(|t| t.status = Err(error))
+    }
+
+    /// Complete an isochronous transfer with per-packet results.
+    #[allow(dead_code)]
+    pub fn complete_iso(
+        self,
+        status: Result<(), TransferError>,
+        data: &[u8],
+        packets: Vec<IsoStatus>,
+    ) -> bool {
+        self.finish(|t| t.complete_iso(status, data, packets))
+    }
+}
+
+impl Drop for Completer {
+    fn drop(&mut self) {
+        if !self.done {
+            if let Some(inflight) = self.inflight.upgrade() {
+                inflight.finish(self.id, |t| t.status = Err(TransferError::Disconnected));
+            }
+        }
+    }
+}
@@ -4,12 +4,140 @@ use std::ffi::{OsStr, OsString};
 #[cfg(any(target_os = "linux"))]
 use crate::platform::SysfsPath;
 
-use crate::{Device, Error, MaybeFuture};
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
+use std::time::Duration;
+use std::{fmt::Display, str::FromStr, sync::Arc};
+
+use crate::{
+    descriptors::{
+        parse_concatenated_config_descriptors, ConfigurationDescriptor, DeviceDescriptor,
+        DESCRIPTOR_LEN_DEVICE,
+    },
+    emulated::Model,
+    Device, Error, MaybeFuture,
+};
 
 /// Opaque device identifier
 #[derive(Copy, Clone, PartialEq, Eq, Debug, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DeviceId(pub(crate) crate::platform::DeviceId);
 
+/// Location of a device in the USB topology, as a bus number and a chain of
+/// hub port numbers.
+///
+/// It is written in the notation Linux uses for device names in sysfs, such as
+/// `1-2.3` for port 3 of a hub connected to port 2 of the root hub of bus 1,
+/// or `usb1` for the root hub itself. `PortPath` implements [`FromStr`] and
+/// [`Display`] for this notation.
+///
+/// ```
+/// use nusb::PortPath;
+///
+/// let path: PortPath = "1-2.3".parse().unwrap();
+/// assert_eq!(path.bus(), 1);
+/// assert_eq!(path.ports(), [2, 3]);
+/// assert_eq!(path.to_string(), "1-2.3");
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct PortPath {
+    bus: u8,
+    ports: Vec<u8>,
+}
+
+impl PortPath {
+    /// Create a `PortPath` from a bus number and chain of port numbers, such
+    /// as the ones returned by [`DeviceInfo::port_chain`].
+    pub fn new(bus: u8, ports: &[u8]) -> PortPath {
+        PortPath {
+            bus,
+            ports: ports.to_vec(),
+        }
+    }
+
+    /// Bus number.
+    pub fn bus(&self) -> u8 {
+        self.bus
+    }
+
+    /// Port numbers, starting at the root hub. Empty for the root hub itself.
+    pub fn ports(&self) -> &[u8] {
+        &self.ports
+    }
+
+    /// Whether this is the path of a root hub.
+    pub fn is_root_hub(&self) -> bool {
+        self.ports.is_empty()
+    }
+
+    /// Path of the hub this device is connected to, or `None` for a root hub.
+    pub fn parent(&self) -> Option<PortPath> {
+        let (_, parent) = self.ports.split_last()?;
+        Some(PortPath::new(self.bus, parent))
+    }
+}
+
+impl Display for PortPath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Some((first, rest)) = self.ports.split_first() else {
+            return write!(f, "usb{}", self.bus);
+        };
+        write!(f, "{}-{}", self.bus, first)?;
+        for port in rest {
+            write!(f, ".{port}")?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for PortPath {
+    type Err = ParsePortPathError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = |message| ParsePortPathError {
+            path: s.to_owned(),
+            message,
+        };
+        let parse_num = |n: &str, message| match n.parse::<u8>() {
+            Ok(v) if v != 0 && !n.starts_with(['0', '+']) => Ok(v),
+            _ => Err(err(message)),
+        };
+
+        if let Some(bus) = s.strip_prefix("usb") {
+            return Ok(PortPath::new(parse_num(bus, "invalid bus number")?, &[]));
+        }
+
+        let (bus, ports) = s.split_once('-').ok_or_else(|| err("missing `-`"))?;
+        let bus = parse_num(bus, "invalid bus number")?;
+        let ports = ports
+            .split('.')
+            .map(|p| parse_num(p, "invalid port number"))
+            .collect::<Result<Vec<u8>, _>>()?;
+        if ports.len() > MAX_PORT_DEPTH {
+            return Err(err("too many tiers"));
+        }
+        Ok(PortPath { bus, ports })
+    }
+}
+
+/// USB allows at most 7 tiers, counting the root hub as the first, so a port
+/// chain has at most one port number for each of the other 6.
+const MAX_PORT_DEPTH: usize = 6;
+
+/// Error returned when parsing a [`PortPath`] from a string fails.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParsePortPathError {
+    path: String,
+    message: &'static str,
+}
+
+impl Display for ParsePortPathError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} in port path `{}`", self.message, self.path)
+    }
+}
+
+impl std::error::Error for ParsePortPathError {}
+
 /// Information about a device that can be obtained without opening it.
 ///
 /// `DeviceInfo` is returned by [`list_devices`][crate::list_devices].
@@ -17,17 +145,50 @@ pub struct DeviceId(pub(crate) crate::platform::DeviceId);
 /// ### Platform-specific notes
 ///
 /// * Some fields are platform-specific
-///     * Linux: `sysfs_path`, `busnum`
+///     * Linux: `sysfs_path`, `busnum`, `removable`, `max_power`, and the
+///       runtime power management attributes
 ///     * Windows: `instance_id`, `parent_instance_id`, `port_number`, `driver`
 ///     * macOS: `registry_id`, `location_id`
+///
+/// ### Serialization
+///
+/// With the `serde` feature, a `DeviceInfo` is serialized as a map with these
+/// fields, named after the accessor methods:
+///
+/// * Always present: `vendor_id`, `product_id`, `usb_version`, `class`,
+///   `subclass`, `protocol`
+/// * Optional: `speed`, `manufacturer_string`, `product_string`,
+///   `serial_number`, `interfaces`, `bus_id`, `device_address`,
+///   `port_chain`, `device_version`
+/// * Linux: `busnum`, `sysfs_path`, `authorized`, `removable`, `max_power`,
+///   and `emulated_id` for emulated devices
+/// * Windows: `devinst` (required), `instance_id`, `parent_instance_id`,
+///   `location_paths`, `port_number`, `driver`
+/// * macOS: `registry_entry_id` (required), `location_id`
+///
+/// Missing optional fields take their default value, and fields for other
+/// platforms are ignored. Interfaces have the fields `interface_number`,
+/// `class`, `subclass`, `protocol`, and optionally `interface_string`, plus
+/// `sysfs_path`, `driver` and `authorized` on Linux. The cached descriptors
+/// are not serialized, so a deserialized `DeviceInfo` is like one for a device
+/// whose descriptors can't be read without opening it.
 #[derive(Clone)]
 pub struct DeviceInfo {
     #[cfg(target_os = "linux")]
     pub(crate) path: SysfsPath,
 
+    /// Locations the device was listed from, and will be opened from.
+    #[cfg(target_os = "linux")]
+    pub(crate) roots: Arc<crate::platform::FsRoots>,
+
     #[cfg(any(target_os = "linux", target_os = "android"))]
     pub(crate) busnum: u8,
 
+    /// Number of an emulated device, which tells it apart from other
+    /// emulated devices on bus 0. Always 0 for real devices.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub(crate) emulated_id: u32,
+
     #[cfg(target_os = "windows")]
     pub(crate) instance_id: OsString,
 
@@ -84,9 +245,167 @@ pub struct DeviceInfo {
     pub(crate) serial_number: Option<String>,
 
     pub(crate) interfaces: Vec<InterfaceInfo>,
+
+    #[cfg(target_os = "linux")]
+    pub(crate) authorized: bool,
+
+    #[cfg(target_os = "linux")]
+    pub(crate) removable: Removable,
+
+    /// `bMaxPower` of the active configuration in mA.
+    #[cfg(target_os = "linux")]
+    pub(crate) max_power: Option<u16>,
+
+    /// Device descriptor followed by the configuration descriptors, or empty
+    /// if they can't be obtained without opening the device.
+    pub(crate) descriptors: Vec<u8>,
+
+    /// Model for a device that is emulated rather than opened through the OS.
+    pub(crate) emulated: Option<Arc<dyn Model>>,
 }
 
 impl DeviceInfo {
+    /// Describe an emulated device, filling in the fields that would come
+    /// from the OS with values derived from its descriptors.
+    pub(crate) fn emulated(model: Arc<dyn Model>) -> DeviceInfo {
+        use std::sync::atomic::{AtomicU32, Ordering};
+
+        // Give each emulated device a distinct number, used in place of the
+        // OS's identifiers so that `DeviceId`s don't collide with real devices
+        // or with each other. The device address alone is too narrow for that.
+        static NEXT_ID: AtomicU32 = AtomicU32::new(1);
+        let emulated_id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let device_address = emulated_id as u8;
+
+        let descriptors = model.descriptors();
+        let device = DeviceDescriptor::new(&descriptors);
+        let active = model.active_configuration();
+        let configuration = descriptors
+            .get(DESCRIPTOR_LEN_DEVICE as usize..)
+            .into_iter()
+            .flat_map(parse_concatenated_config_descriptors)
+            .find(|c| c.configuration_value() == active);
+
+        // `bMaxPower` is in units of 8mA for SuperSpeed and 2mA otherwise
+        #[cfg(target_os = "linux")]
+        let max_power = configuration.as_ref().map(|c| {
+            let unit = if device.as_ref().map_or(0, |d| d.usb_version()) >= 0x0300 {
+                8
+            } else {
+                2
+            };
+            u16::from(c.max_power()) * unit
+        });
+
+        let interfaces = configuration
+            .into_iter()
+            .flat_map(|c| c.interfaces())
+            .map(|i| {
+                let desc = i.first_alt_setting();
+                InterfaceInfo {
+                    interface_number: desc.interface_number(),
+                    class: desc.class(),
+                    subclass: desc.subclass(),
+                    protocol: desc.protocol(),
+                    interface_string: None,
+
+                    #[cfg(target_os = "linux")]
+                    path: SysfsPath(Default::default()),
+
+                    #[cfg(target_os = "linux")]
+                    driver: None,
+
+                    #[cfg(target_os = "linux")]
+                    authorized: true,
+                }
+            })
+            .collect();
+
+        DeviceInfo {
+            #[cfg(target_os = "linux")]
+            path: SysfsPath(Default::default()),
+
+            #[cfg(target_os = "linux")]
+            roots: crate::platform::FsRoots::system(),
+
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            busnum: 0,
+
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            emulated_id,
+
+            #[cfg(target_os = "windows")]
+            instance_id: OsString::new(),
+
+            #[cfg(target_os = "windows")]
+            location_paths: Vec::new(),
+
+            #[cfg(target_os = "windows")]
+            parent_instance_id: OsString::new(),
+
+            #[cfg(target_os = "windows")]
+            port_number: 0,
+
+            #[cfg(target_os = "windows")]
+            devinst: crate::platform::DevInst::emulated(emulated_id),
+
+            #[cfg(target_os = "windows")]
+            driver: None,
+
+            #[cfg(target_os = "macos")]
+            registry_id: 0xFFFF_FFFF_0000_0000 | emulated_id as u64,
+
+            #[cfg(target_os = "macos")]
+            location_id: 0,
+
+            #[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
+            bus_id: "emulated".into(),
+
+            #[cfg(any(
+                target_os = "linux",
+                target_os = "macos",
+                target_os = "windows",
+                target_os = "android"
+            ))]
+            device_address,
+
+            #[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
+            port_chain: Vec::new(),
+
+            vendor_id: device.as_ref().map_or(0, |d| d.vendor_id()),
+            product_id: device.as_ref().map_or(0, |d| d.product_id()),
+
+            #[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
+            device_version: device.as_ref().map_or(0, |d| d.device_version()),
+
+            usb_version: device.as_ref().map_or(0, |d| d.usb_version()),
+            class: device.as_ref().map_or(0, |d| d.class()),
+            subclass: device.as_ref().map_or(0, |d| d.subclass()),
+            protocol: device.as_ref().map_or(0, |d| d.protocol()),
+
+            speed: model.speed(),
+
+            manufacturer_string: None,
+            product_string: None,
+            serial_number: None,
+
+            interfaces,
+
+            #[cfg(target_os = "linux")]
+            authorized: true,
+
+            #[cfg(target_os = "linux")]
+            removable: Removable::Unknown,
+
+            #[cfg(target_os = "linux")]
+            max_power,
+
+            descriptors,
+
+            emulated: Some(model),
+        }
+    }
+
     /// Opaque identifier for the device.
     pub fn id(&self) -> DeviceId {
         #[cfg(target_os = "windows")]
@@ -99,6 +418,7 @@ impl DeviceInfo {
             DeviceId(crate::platform::DeviceId {
                 bus: self.busnum,
                 addr: self.device_address,
+                emulated: self.emulated_id,
             })
         }
 
@@ -158,6 +478,13 @@ impl DeviceInfo {
         &self.port_chain
     }
 
+    /// *(Linux-only)* Bus number and port chain of the device as a
+    /// [`PortPath`], which formats as its sysfs name such as `1-2.3`.
+    #[cfg(target_os = "linux")]
+    pub fn port_path(&self) -> PortPath {
+        PortPath::new(self.busnum, &self.port_chain)
+    }
+
     /// *(Windows-only)* Driver associated with the device as a whole
     #[cfg(target_os = "windows")]
     pub fn driver(&self) -> Option<&str> {
@@ -268,8 +595,8 @@ impl DeviceInfo {
     /// opening them.
     ///
     /// Additional information about interfaces can be found in the
-    /// configuration descriptor after opening the device by calling
-    /// [`Device::active_configuration`].
+    /// configuration descriptors returned by [`configurations`][Self::configurations],
+    /// or after opening the device by calling [`Device::active_configuration`].
     ///
     /// ### Platform-specific notes:
     ///   * Windows: this is only available for composite devices bound to the
@@ -283,10 +610,188 @@ impl DeviceInfo {
         self.interfaces.iter()
     }
 
+    /// Get the device descriptor without opening the device.
+    ///
+    /// ### Platform-specific notes:
+    ///   * Linux: this is read from sysfs when the device is listed.
+    ///   * Windows and macOS: this is only available for emulated devices,
+    ///     and returns `None` otherwise.
+    pub fn device_descriptor(&self) -> Option<DeviceDescriptor> {
+        DeviceDescriptor::new(&self.descriptors)
+    }
+
+    /// Get an iterator returning information about each configuration of the
+    /// device, without opening the device.
+    ///
+    /// ### Platform-specific notes:
+    ///   * Linux: this is read from sysfs when the device is listed.
+    ///   * Windows and macOS: this is only available for emulated devices,
+    ///     and is empty otherwise.
+    pub fn configurations(&self) -> impl Iterator<Item = ConfigurationDescriptor<'_>> {
+        self.descriptors
+            .get(DESCRIPTOR_LEN_DEVICE as usize..)
+            .into_iter()
+            .flat_map(parse_concatenated_config_descriptors)
+    }
+
+    /// *(Linux-only)* Whether the device was authorized for use when it was
+    /// listed.
+    ///
+    /// The kernel does not bind drivers to, and this library cannot open, an
+    /// unauthorized device. See the [kernel documentation][authorization].
+    ///
+    /// [authorization]: https://www.kernel.org/doc/html/latest/usb/authorization.html
+    #[cfg(target_os = "linux")]
+    pub fn authorized(&self) -> bool {
+        self.authorized
+    }
+
+    /// *(Linux-only)* Authorize or deauthorize the device.
+    ///
+    /// Deauthorizing unbinds the drivers of all its interfaces, and
+    /// authorizing it again binds them. This writes the sysfs `authorized`
+    /// attribute, which requires root or a udev rule granting write access,
+    /// and fails with [`ErrorKind::PermissionDenied`][crate::ErrorKind::PermissionDenied]
+    /// otherwise.
+    ///
+    /// This does not update the `DeviceInfo`; list the devices again to see
+    /// the new state.
+    #[cfg(target_os = "linux")]
+    pub fn set_authorized(&self, authorized: bool) -> Result<(), Error> {
+        self.sysfs()?
+            .write_attr("authorized", if authorized { "1" } else { "0" })
+    }
+
+    /// *(Linux-only)* Whether the device can be unplugged from its port.
+    ///
+    /// This comes from the hub descriptor of the parent hub or from platform
+    /// firmware, and is `Unknown` for root hubs and emulated devices.
+    #[cfg(target_os = "linux")]
+    pub fn removable(&self) -> Removable {
+        self.removable
+    }
+
+    /// *(Linux-only)* Maximum power drawn from the bus by the device in its
+    /// active configuration, in milliamps.
+    ///
+    /// Returns `None` if the device is not configured.
+    #[cfg(target_os = "linux")]
+    #[doc(alias = "bMaxPower")]
+    pub fn max_power(&self) -> Option<u16> {
+        self.max_power
+    }
+
+    /// *(Linux-only)* Get the runtime power management policy of the device.
+    ///
+    /// Unlike the other fields, this and the other power management
+    /// attributes are read from sysfs on each call rather than when the device
+    /// is listed.
+    #[cfg(target_os = "linux")]
+    pub fn power_control(&self) -> Result<PowerControl, Error> {
+        Ok(self
+            .sysfs()?
+            .parse_attr("power/control", |s| PowerControl::from_str(s).ok_or(()))?)
+    }
+
+    /// *(Linux-only)* Set the runtime power management policy of the device.
+    ///
+    /// This usually requires root or a udev rule granting write access to
+    /// the `power/control` attribute.
+    #[cfg(target_os = "linux")]
+    pub fn set_power_control(&self, control: PowerControl) -> Result<(), Error> {
+        self.sysfs()?.write_attr("power/control", control.as_str())
+    }
+
+    /// *(Linux-only)* Get how long the device must be idle before the kernel
+    /// suspends it, or `None` if autosuspend is disabled.
+    #[cfg(target_os = "linux")]
+    pub fn autosuspend_delay(&self) -> Result<Option<Duration>, Error> {
+        let ms: i64 = self.sysfs()?.read_attr("power/autosuspend_delay_ms")?;
+        Ok(u64::try_from(ms).ok().map(Duration::from_millis))
+    }
+
+    /// *(Linux-only)* Set how long the device must be idle before the kernel
+    /// suspends it, or pass `None` to disable autosuspend.
+    ///
+    /// This only takes effect if the power management policy is
+    /// [`PowerControl::Auto`].
+    #[cfg(target_os = "linux")]
+    pub fn set_autosuspend_delay(&self, delay: Option<Duration>) -> Result<(), Error> {
+        let ms = delay.map_or(-1, |d| d.as_millis().min(i32::MAX as u128) as i32);
+        self.sysfs()?
+            .write_attr("power/autosuspend_delay_ms", &ms.to_string())
+    }
+
+    /// *(Linux-only)* Get the current runtime power state of the device.
+    #[cfg(target_os = "linux")]
+    pub fn runtime_status(&self) -> Result<RuntimeStatus, Error> {
+        Ok(self.sysfs()?.parse_attr("power/runtime_status", |s| {
+            RuntimeStatus::from_str(s).ok_or(())
+        })?)
+    }
+
+    /// *(Linux-only)* Get how long the device has been connected.
+    #[cfg(target_os = "linux")]
+    pub fn connected_duration(&self) -> Result<Duration, Error> {
+        let ms = self.sysfs()?.read_attr("power/connected_duration")?;
+        Ok(Duration::from_millis(ms))
+    }
+
+    #[cfg(target_os = "linux")]
+    fn sysfs(&self) -> Result<&SysfsPath, Error> {
+        // An emulated device that went through serde has no model, but its
+        // path is still empty.
+        if self.emulated.is_some() || self.path.0.as_os_str().is_empty() {
+            return Err(Error::new(
+                crate::ErrorKind::Unsupported,
+                "not supported for emulated devices",
+            ));
+        }
+        Ok(&self.path)
+    }
+
     /// Open the device
     pub fn open(&self) -> impl MaybeFuture<Output = Result<Device, Error>> {
         Device::open(self)
     }
+
+    /// Wait for this device to connect again after it disconnects or
+    /// re-enumerates, for up to `timeout`.
+    ///
+    /// Returns the first newly connected device that is on the same port or
+    /// has the same serial number as this one, and matches `filter`. Use
+    /// the filter to select the firmware the device is expected to come back
+    /// with, for example by product ID, or pass
+    /// [`DeviceFilter::new()`][crate::DeviceFilter::new] to accept any. Fails
+    /// with [`ErrorKind::NotFound`][crate::ErrorKind::NotFound] if no such
+    /// device connects in time.
+    ///
+    /// Events are watched from the time this is called, rather than when it
+    /// is awaited, so call it before triggering the re-enumeration:
+    ///
+    /// ```no_run
+    /// use std::time::Duration;
+    /// use nusb::{DeviceFilter, MaybeFuture};
+    /// # fn detach(_: &nusb::Device) {}
+    ///
+    /// let info = nusb::list_devices().wait().unwrap().next().unwrap();
+    /// let device = info.open().wait().unwrap();
+    ///
+    /// let reconnect = info.wait_for_reconnect(
+    ///     DeviceFilter::new().with_product_id(0xdf11),
+    ///     Duration::from_secs(5),
+    /// );
+    /// detach(&device);
+    /// let bootloader = reconnect.wait().unwrap().open().wait().unwrap();
+    /// ```
+    #[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
+    pub fn wait_for_reconnect(
+        &self,
+        filter: crate::DeviceFilter,
+        timeout: Duration,
+    ) -> impl MaybeFuture<Output = Result<DeviceInfo, Error>> {
+        crate::reconnect::WaitForReconnect::new(self, filter, timeout)
+    }
 }
 
 // Not derived so that we can format some fields in hex
@@ -319,7 +824,10 @@ impl std::fmt::Debug for DeviceInfo {
 
         #[cfg(target_os = "linux")]
         {
-            s.field("sysfs_path", &self.path);
+            s.field("sysfs_path", &self.path)
+                .field("authorized", &self.authorized)
+                .field("removable", &self.removable)
+                .field("max_power", &self.max_power);
         }
 
         #[cfg(target_os = "windows")]
@@ -342,12 +850,17 @@ impl std::fmt::Debug for DeviceInfo {
 
         s.field("interfaces", &self.interfaces);
 
+        if self.emulated.is_some() {
+            s.field("emulated", &true);
+        }
+
         s.finish()
     }
 }
 
 /// USB connection speed
 #[derive(Copy, Clone, Eq, PartialOrd, Ord, PartialEq, Hash, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[non_exhaustive]
 pub enum Speed {
     /// Low speed (1.5 Mbit)
@@ -380,6 +893,268 @@ impl Speed {
     }
 }
 
+/// *(Linux-only)* Runtime power management policy of a device.
+///
+/// See [`DeviceInfo::power_control`].
+#[cfg(target_os = "linux")]
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub enum PowerControl {
+    /// The kernel may suspend the device when it is idle (`auto`).
+    Auto,
+
+    /// The device is kept awake (`on`).
+    On,
+}
+
+#[cfg(target_os = "linux")]
+impl PowerControl {
+    pub(crate) fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "auto" => Some(PowerControl::Auto),
+            "on" => Some(PowerControl::On),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            PowerControl::Auto => "auto",
+            PowerControl::On => "on",
+        }
+    }
+}
+
+/// *(Linux-only)* Runtime power state of a device.
+///
+/// See [`DeviceInfo::runtime_status`].
+#[cfg(target_os = "linux")]
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub enum RuntimeStatus {
+    /// The device is awake.
+    Active,
+
+    /// The device is suspended.
+    Suspended,
+
+    /// The device is being suspended.
+    Suspending,
+
+    /// The device is being resumed.
+    Resuming,
+
+    /// Runtime power management failed, and is disabled for the device.
+    Error,
+
+    /// Runtime power management is disabled for the device.
+    Unsupported,
+}
+
+#[cfg(target_os = "linux")]
+impl RuntimeStatus {
+    pub(crate) fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "active" => Some(RuntimeStatus::Active),
+            "suspended" => Some(RuntimeStatus::Suspended),
+            "suspending" => Some(RuntimeStatus::Suspending),
+            "resuming" => Some(RuntimeStatus::Resuming),
+            "error" => Some(RuntimeStatus::Error),
+            "unsupported" => Some(RuntimeStatus::Unsupported),
+            _ => None,
+        }
+    }
+}
+
+/// *(Linux-only)* Whether a device can be unplugged from its port.
+///
+/// See [`DeviceInfo::removable`].
+#[cfg(target_os = "linux")]
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Removable {
+    /// The device is connected to an external port.
+    Removable,
+
+    /// The device is built in.
+    Fixed,
+
+    /// The parent hub or firmware does not say.
+    Unknown,
+}
+
+#[cfg(target_os = "linux")]
+impl Removable {
+    pub(crate) fn from_str(s: &str) -> Self {
+        match s {
+            "removable" => Removable::Removable,
+            "fixed" => Removable::Fixed,
+            _ => Removable::Unknown,
+        }
+    }
+}
+
+/// *(Linux-only)* Whether devices newly connected to a bus are authorized.
+///
+/// See [`BusInfo::authorized_default`].
+#[cfg(target_os = "linux")]
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub enum AuthorizedDefault {
+    /// New devices must be authorized individually (`0`).
+    Unauthorized,
+
+    /// New devices are authorized (`1`).
+    Authorized,
+
+    /// Only devices connected to internal ports are authorized (`2`).
+    InternalOnly,
+}
+
+#[cfg(target_os = "linux")]
+impl AuthorizedDefault {
+    pub(crate) fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "0" => Some(AuthorizedDefault::Unauthorized),
+            "1" => Some(AuthorizedDefault::Authorized),
+            "2" => Some(AuthorizedDefault::InternalOnly),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            AuthorizedDefault::Unauthorized => "0",
+            AuthorizedDefault::Authorized => "1",
+            AuthorizedDefault::InternalOnly => "2",
+        }
+    }
+}
+
+/// Features of usbfs that are available for an opened device.
+///
+/// See [`Device::capabilities`]. usbfs does not report whether bulk streams
+/// are supported.
+///
+/// *Supported on Linux and Android only.*
+#[cfg(any(target_os = "android", target_os = "linux"))]
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub struct Capabilities(pub(crate) u32);
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+impl Capabilities {
+    const ZERO_PACKET: u32 = 0x01;
+    const BULK_CONTINUATION: u32 = 0x02;
+    const NO_PACKET_SIZE_LIM: u32 = 0x04;
+    const BULK_SCATTER_GATHER: u32 = 0x08;
+    const REAP_AFTER_DISCONNECT: u32 = 0x10;
+    const MMAP: u32 = 0x20;
+    const DROP_PRIVILEGES: u32 = 0x40;
+    const CONNINFO_EX: u32 = 0x80;
+    const SUSPEND: u32 = 0x100;
+
+    /// The raw `USBDEVFS_CAP_*` flags.
+    pub fn bits(&self) -> u32 {
+        self.0
+    }
+
+    /// OUT transfers can be terminated with a zero-length packet.
+    pub fn zero_packet(&self) -> bool {
+        self.0 & Self::ZERO_PACKET != 0
+    }
+
+    /// A short packet ends the remaining transfers of a split bulk IN
+    /// transfer.
+    pub fn bulk_continuation(&self) -> bool {
+        self.0 & Self::BULK_CONTINUATION != 0
+    }
+
+    /// Transfers are not limited to the usbfs buffer size.
+    pub fn no_packet_size_limit(&self) -> bool {
+        self.0 & Self::NO_PACKET_SIZE_LIM != 0
+    }
+
+    /// The host controller supports scatter-gather, so large bulk transfers
+    /// are not split.
+    pub fn bulk_scatter_gather(&self) -> bool {
+        self.0 & Self::BULK_SCATTER_GATHER != 0
+    }
+
+    /// Completed transfers can still be reaped after the device is
+    /// disconnected.
+    pub fn reap_after_disconnect(&self) -> bool {
+        self.0 & Self::REAP_AFTER_DISCONNECT != 0
+    }
+
+    /// Zero-copy buffers from [`Endpoint::allocate`][crate::Endpoint::allocate]
+    /// are supported.
+    pub fn zero_copy(&self) -> bool {
+        self.0 & Self::MMAP != 0
+    }
+
+    /// [`Device::drop_privileges`] is supported.
+    pub fn drop_privileges(&self) -> bool {
+        self.0 & Self::DROP_PRIVILEGES != 0
+    }
+
+    /// [`Device::connection_info`] is supported.
+    pub fn connection_info(&self) -> bool {
+        self.0 & Self::CONNINFO_EX != 0
+    }
+
+    /// [`Device::allow_suspend`], [`Device::forbid_suspend`] and
+    /// [`Device::wait_for_resume`] are supported.
+    pub fn suspend(&self) -> bool {
+        self.0 & Self::SUSPEND != 0
+    }
+}
+
+/// Location and speed of an opened device, as reported by usbfs.
+///
+/// See [`Device::connection_info`].
+///
+/// *Supported on Linux and Android only.*
+#[cfg(any(target_os = "android", target_os = "linux"))]
+#[derive(Clone, Eq, PartialEq, Hash, Debug)]
+pub struct ConnectionInfo {
+    pub(crate) busnum: u8,
+    pub(crate) device_address: u8,
+    pub(crate) speed: Option<Speed>,
+    pub(crate) port_chain: Vec<u8>,
+}
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+impl ConnectionInfo {
+    /// Number identifying the device's bus.
+    pub fn busnum(&self) -> u8 {
+        self.busnum
+    }
+
+    /// Number identifying the device within the bus.
+    pub fn device_address(&self) -> u8 {
+        self.device_address
+    }
+
+    /// Connection speed, if known.
+    pub fn speed(&self) -> Option<Speed> {
+        self.speed
+    }
+
+    /// Path of port numbers identifying the port where the device is
+    /// connected, starting at the root hub.
+    pub fn port_chain(&self) -> &[u8] {
+        &self.port_chain
+    }
+
+    /// Bus number and port chain as a [`PortPath`].
+    pub fn port_path(&self) -> PortPath {
+        PortPath::new(self.busnum, &self.port_chain)
+    }
+}
+
 /// Summary information about a device's interface, available before opening a device.
 #[derive(Clone)]
 pub struct InterfaceInfo {
@@ -388,6 +1163,15 @@ pub struct InterfaceInfo {
     pub(crate) subclass: u8,
     pub(crate) protocol: u8,
     pub(crate) interface_string: Option<String>,
+
+    #[cfg(target_os = "linux")]
+    pub(crate) path: SysfsPath,
+
+    #[cfg(target_os = "linux")]
+    pub(crate) driver: Option<String>,
+
+    #[cfg(target_os = "linux")]
+    pub(crate) authorized: bool,
 }
 
 impl InterfaceInfo {
@@ -415,23 +1199,74 @@ impl InterfaceInfo {
     pub fn interface_string(&self) -> Option<&str> {
         self.interface_string.as_deref()
     }
+
+    /// *(Linux-only)* Name of the kernel driver bound to the interface when
+    /// the device was listed, such as `cdc_acm`, `usbhid`, or `usbfs` if the
+    /// interface is claimed by a program using this library.
+    ///
+    /// Use [`Device::kernel_driver`] to query the current driver of an
+    /// opened device.
+    #[cfg(target_os = "linux")]
+    pub fn driver(&self) -> Option<&str> {
+        self.driver.as_deref()
+    }
+
+    /// *(Linux-only)* Sysfs path for the interface.
+    #[cfg(target_os = "linux")]
+    pub fn sysfs_path(&self) -> &std::path::Path {
+        &self.path.0
+    }
+
+    /// *(Linux-only)* Whether the interface was authorized for use when the
+    /// device was listed.
+    ///
+    /// The kernel does not bind a driver to an unauthorized interface.
+    #[cfg(target_os = "linux")]
+    pub fn authorized(&self) -> bool {
+        self.authorized
+    }
+
+    /// *(Linux-only)* Authorize or deauthorize the interface.
+    ///
+    /// Deauthorizing unbinds its driver. Authorizing it again does not bind
+    /// the driver automatically. This writes the sysfs `authorized`
+    /// attribute, which requires root or a udev rule granting write access,
+    /// and fails with [`ErrorKind::PermissionDenied`][crate::ErrorKind::PermissionDenied]
+    /// otherwise.
+    #[cfg(target_os = "linux")]
+    pub fn set_authorized(&self, authorized: bool) -> Result<(), Error> {
+        if self.path.0.as_os_str().is_empty() {
+            return Err(Error::new(
+                crate::ErrorKind::Unsupported,
+                "not supported for emulated devices",
+            ));
+        }
+        self.path
+            .write_attr("authorized", if authorized { "1" } else { "0" })
+    }
 }
 
 // Not derived so that we can format some fields in hex
 impl std::fmt::Debug for InterfaceInfo {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("InterfaceInfo")
-            .field("interface_number", &self.interface_number)
+        let mut s = f.debug_struct("InterfaceInfo");
+        s.field("interface_number", &self.interface_number)
             .field("class", &format_args!("0x{:02X}", self.class))
             .field("subclass", &format_args!("0x{:02X}", self.subclass))
             .field("protocol", &format_args!("0x{:02X}", self.protocol))
-            .field("interface_string", &self.interface_string)
-            .finish()
+            .field("interface_string", &self.interface_string);
+
+        #[cfg(target_os = "linux")]
+        s.field("driver", &self.driver)
+            .field("authorized", &self.authorized);
+
+        s.finish()
     }
 }
 
 /// USB host controller type
 #[derive(Copy, Clone, Eq, PartialOrd, Ord, PartialEq, Hash, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[non_exhaustive]
 pub enum UsbControllerType {
     /// xHCI controller (USB 3.0+)
@@ -548,6 +1383,30 @@ impl BusInfo {
         &self.root_hub
     }
 
+    /// *(Linux-only)* Get whether devices newly connected to the bus are
+    /// authorized.
+    ///
+    /// This is read from the root hub's sysfs `authorized_default` attribute
+    /// on each call.
+    #[cfg(target_os = "linux")]
+    pub fn authorized_default(&self) -> Result<AuthorizedDefault, Error> {
+        Ok(self.path.parse_attr("authorized_default", |s| {
+            AuthorizedDefault::from_str(s).ok_or(())
+        })?)
+    }
+
+    /// *(Linux-only)* Set whether devices newly connected to the bus are
+    /// authorized.
+    ///
+    /// Devices that are already connected keep their current state. This
+    /// requires root or a udev rule granting write access, and fails with
+    /// [`ErrorKind::PermissionDenied`][crate::ErrorKind::PermissionDenied]
+    /// otherwise.
+    #[cfg(target_os = "linux")]
+    pub fn set_authorized_default(&self, policy: AuthorizedDefault) -> Result<(), Error> {
+        self.path.write_attr("authorized_default", policy.as_str())
+    }
+
     /// *(Windows-only)* Instance ID path of this device
     #[cfg(target_os = "windows")]
     pub fn instance_id(&self) -> &OsStr {
@@ -681,3 +1540,52 @@ impl std::fmt::Debug for BusInfo {
         s.finish()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn port_path() {
+        for s in ["1-2", "3-1.4.2", "usb2", "255-7.7.7.7.7.7"] {
+            assert_eq!(s.parse::<PortPath>().unwrap().to_string(), s);
+        }
+        assert_eq!("usb1".parse::<PortPath>().unwrap(), PortPath::new(1, &[]));
+        assert_eq!(
+            "1-2.3".parse::<PortPath>().unwrap().parent(),
+            Some(PortPath::new(1, &[2]))
+        );
+        assert_eq!(PortPath::new(1, &[]).parent(), None);
+
+        for s in [
+            "", "1", "1-", "0-1", "1-0", "01-2", "1-2.", "1-256", "1-+2", "usb",
+        ] {
+            assert!(s.parse::<PortPath>().is_err(), "{s}");
+        }
+        assert_eq!(
+            "1-2:1.0".parse::<PortPath>().unwrap_err().to_string(),
+            "invalid port number in port path `1-2:1.0`"
+        );
+        let too_deep = "1-1.1.1.1.1.1.1".parse::<PortPath>().unwrap_err();
+        assert_eq!(
+            too_deep.to_string(),
+            "too many tiers in port path `1-1.1.1.1.1.1.1`"
+        );
+    }
+
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    #[test]
+    fn capabilities() {
+        // As reported by Linux 6.x for a device on an xHCI controller
+        let caps = Capabilities(0x1fd);
+        assert!(caps.zero_packet() && !caps.bulk_continuation());
+        assert!(caps.no_packet_size_limit() && caps.bulk_scatter_gather());
+        assert!(caps.reap_after_disconnect() && caps.zero_copy());
+        assert!(caps.drop_privileges() && caps.connection_info() && caps.suspend());
+
+        // Linux 4.18 predates USBDEVFS_CONNINFO_EX and the suspend ioctls
+        let caps = Capabilities(0x7f);
+        assert!(caps.drop_privileges());
+        assert!(!caps.connection_info() && !caps.suspend());
+    }
+}
@@ -0,0 +1,704 @@
+//! Inject errors into transfers to test error handling.
+//!
+//! A [`FaultInjector`] holds a list of [`FaultRule`]s. Attach it to a
+//! [`Device`][crate::Device] with
+//! [`Device::inject_faults`][crate::Device::inject_faults], and the rules are
+//! applied to the results of control transfers on that device and its
+//! [`Interface`][crate::Interface]s, and to the [`Completion`]s returned by its
+//! bulk and interrupt [`Endpoint`][crate::Endpoint]s. The transfers are still
+//! performed on the device, and only the result seen by the application is
+//! modified.
+//!
+//! This works with real devices as well as with [`replay`][crate::replay] and
+//! mock devices.
+//!
+//! ### Example
+//!
+//! ```no_run
+//! use std::time::Duration;
+//! use nusb::{
+//!     fault::{Fault, FaultInjector, FaultRule},
+//!     transfer::TransferError,
+//!     MaybeFuture,
+//! };
+//! # fn run(device: &nusb::Device) {}
+//!
+//! let faults = FaultInjector::new()
+//!     // Stall endpoint 0x81 after 10 successful transfers
+//!     .with_rule(FaultRule::new(Fault::Stall).on_endpoint(0x81).after(10).times(1))
+//!     // Fail 1% of control transfers
+//!     .with_rule(FaultRule::new(Fault::Error(TransferError::Fault)).on_control().with_probability(0.01))
+//!     // Delay every transfer on endpoint 0x02
+//!     .with_rule(FaultRule::new(Fault::Delay(Duration::from_millis(20))).on_endpoint(0x02));
+//!
+//! let device_info = nusb::list_devices().wait().unwrap()
+//!     .find(|dev| dev.vendor_id() == 0xAAAA && dev.product_id() == 0xBBBB)
+//!     .expect("device not connected");
+//! let device = device_info.open().wait().unwrap().inject_faults(&faults);
+//! run(&device);
+//! println!("injected {} faults", faults.injected());
+//! ```
+//!
+//! Isochronous transfers are not affected.
+
+use std::{
+    collections::BTreeSet,
+    future::{Future, IntoFuture},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::debug;
+
+use crate::{
+    maybe_future::NonWasmSend,
+    timer::Deadline,
+    transfer::{Completion, Direction, TransferError},
+    Error, ErrorKind, MaybeFuture,
+};
+
+/// A fault to inject into a transfer.
+#[derive(Copy, Clone, Debug, PartialEq)]
+#[non_exhaustive]
+pub enum Fault {
+    /// Complete the transfer with the specified error, discarding any data.
+    Error(TransferError),
+
+    /// Halt the endpoint.
+    ///
+    /// The transfer fails with [`TransferError::Stall`], as do all following
+    /// transfers on the endpoint until
+    /// [`Endpoint::clear_halt`][crate::Endpoint::clear_halt] is called. On
+    /// the control endpoint, only the affected transfer fails.
+    Stall,
+
+    /// Simulate the device being unplugged.
+    ///
+    /// The transfer fails with [`TransferError::Disconnected`], as do all
+    /// following transfers on the device. Operations like
+    /// [`Device::claim_interface`][crate::Device::claim_interface] fail with
+    /// [`ErrorKind::Disconnected`].
+    Disconnect,
+
+    /// Truncate the data returned by an IN transfer to at most this many
+    /// bytes. OUT transfers are not affected.
+    ShortRead(usize),
+
+    /// Delay delivery of the transfer's completion.
+    Delay(Duration),
+}
+
+/// A rule selecting which transfers a [`Fault`] is injected into.
+///
+/// By default, a rule applies to every transfer on the device. Use the
+/// builder methods to restrict it.
+#[derive(Clone, Debug)]
+pub struct FaultRule {
+    fault: Fault,
+    target: Target,
+    after: u64,
+    times: Option<u64>,
+    probability: f64,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Target {
+    Any,
+    Control,
+    Endpoint(u8),
+}
+
+impl FaultRule {
+    /// Create a rule injecting `fault` into every transfer.
+    pub fn new(fault: Fault) -> FaultRule {
+        FaultRule {
+            fault,
+            target: Target::Any,
+            after: 0,
+            times: None,
+            probability: 1.0,
+        }
+    }
+
+    /// Apply only to transfers on the endpoint with the specified address.
+    pub fn on_endpoint(mut self, address: u8) -> Self {
+        self.target = Target::Endpoint(address);
+        self
+    }
+
+    /// Apply only to control transfers.
+    pub fn on_control(mut self) -> Self {
+        self.target = Target::Control;
+        self
+    }
+
+    /// Let the first `n` matching transfers complete normally.
+    pub fn after(mut self, n: u64) -> Self {
+        self.after = n;
+        self
+    }
+
+    /// Inject the fault at most `n` times.
+    pub fn times(mut self, n: u64) -> Self {
+        self.times = Some(n);
+        self
+    }
+
+    /// Inject the fault into each matching transfer with probability `p`
+    /// between 0 and 1.
+    pub fn with_probability(mut self, p: f64) -> Self {
+        self.probability = p;
+        self
+    }
+
+    fn matches(&self, endpoint: Option<u8>) -> bool {
+        match (self.target, endpoint) {
+            (Target::Any, _) => true,
+            (Target::Control, None) => true,
+            (Target::Endpoint(a), Some(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+/// A set of [`FaultRule`]s and the state of the faults they injected.
+///
+/// The injector can be cloned cheaply, and all clones share the same rules
+/// and state. Rules can be added while the device is in use.
+///
+/// Probabilities are evaluated with a pseudo-random generator with a fixed
+/// default seed, so a test performing the same sequence of transfers sees the
+/// same faults each time it runs.
+#[derive(Clone)]
+pub struct FaultInjector {
+    inner: Arc<Mutex<InjectorState>>,
+}
+
+struct InjectorState {
+    rules: Vec<RuleState>,
+    rng: u64,
+    disconnected: bool,
+    halted: BTreeSet<u8>,
+    injected: u64,
+}
+
+struct RuleState {
+    rule: FaultRule,
+    seen: u64,
+    fired: u64,
+}
+
+/// Combined effect of the rules on one transfer.
+#[derive(Default)]
+struct Decision {
+    error: Option<TransferError>,
+    short: Option<usize>,
+    delay: Duration,
+}
+
+impl Default for FaultInjector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FaultInjector {
+    /// Create an injector with no rules.
+    pub fn new() -> FaultInjector {
+        FaultInjector {
+            inner: Arc::new(Mutex::new(InjectorState {
+                rules: Vec::new(),
+                rng: 0x2545_f491_4f6c_dd1d,
+                disconnected: false,
+                halted: BTreeSet::new(),
+                injected: 0,
+            })),
+        }
+    }
+
+    /// Set the seed used for [`FaultRule::with_probability`].
+    pub fn with_seed(self, seed: u64) -> Self {
+        // xorshift gets stuck at zero
+        self.inner.lock().unwrap().rng = seed.max(1);
+        self
+    }
+
+    /// Add a rule.
+    pub fn with_rule(self, rule: FaultRule) -> Self {
+        self.add_rule(rule);
+        self
+    }
+
+    /// Add a rule to an injector that may already be in use.
+    pub fn add_rule(&self, rule: FaultRule) {
+        self.inner.lock().unwrap().rules.push(RuleState {
+            rule,
+            seen: 0,
+            fired: 0,
+        });
+    }
+
+    /// Remove all rules, and clear any injected stall or disconnection.
+    pub fn clear(&self) {
+        let mut state = self.inner.lock().unwrap();
+        state.rules.clear();
+        state.disconnected = false;
+        state.halted.clear();
+    }
+
+    /// Get the number of times a fault has been injected.
+    pub fn injected(&self) -> u64 {
+        self.inner.lock().unwrap().injected
+    }
+
+    /// Evaluate the rules for a transfer on `endpoint`, or on the control
+    /// endpoint if `None`.
+    fn decide(&self, endpoint: Option<u8>) -> Decision {
+        let mut state = self.inner.lock().unwrap();
+        let state = &mut *state;
+        let mut decision = Decision::default();
+
+        for r in state.rules.iter_mut().filter(|r| r.rule.matches(endpoint)) {
+            r.seen += 1;
+            if r.seen <= r.rule.after || r.rule.times.is_some_and(|n| r.fired >= n) {
+                continue;
+            }
+            if r.rule.probability < 1.0 {
+                // xorshift64
+                state.rng ^= state.rng << 13;
+                state.rng ^= state.rng >> 7;
+                state.rng ^= state.rng << 17;
+                if (state.rng >> 11) as f64 / (1u64 << 53) as f64 >= r.rule.probability {
+                    continue;
+                }
+            }
+
+            r.fired += 1;
+            state.injected += 1;
+            debug!(
+                "Injecting {:?} on endpoint {:02x}",
+                r.rule.fault,
+                endpoint.unwrap_or(0)
+            );
+            match r.rule.fault {
+                Fault::Error(e) => {
+                    decision.error.get_or_insert(e);
+                }
+                Fault::Stall => {
+                    if let Some(endpoint) = endpoint {
+                        state.halted.insert(endpoint);
+                    }
+                    decision.error.get_or_insert(TransferError::Stall);
+                }
+                Fault::Disconnect => {
+                    state.disconnected = true;
+                }
+                Fault::ShortRead(n) => {
+                    decision.short = Some(decision.short.map_or(n, |s| s.min(n)));
+                }
+                Fault::Delay(d) => {
+                    decision.delay += d;
+                }
+            }
+        }
+
+        // Faults injected by earlier transfers take precedence.
+        if state.disconnected {
+            decision.error = Some(TransferError::Disconnected);
+        } else if endpoint.is_some_and(|ep| state.halted.contains(&ep)) {
+            decision.error = Some(TransferError::Stall);
+        }
+
+        decision
+    }
+
+    fn apply_completion(&self, endpoint: u8, c: &mut Completion) -> Duration {
+        let decision = self.decide(Some(endpoint));
+        if let Some(error) = decision.error {
+            c.status = Err(error);
+            c.actual_len = 0;
+            c.buffer.clear();
+        } else if let Some(n) = decision.short {
+            if Direction::from_address(endpoint) == Direction::In {
+                c.actual_len = c.actual_len.min(n);
+                c.buffer.len = c.buffer.len.min(n as u32);
+            }
+        }
+        decision.delay
+    }
+
+    fn apply_control<T>(
+        &self,
+        r: Result<T, TransferError>,
+        truncate: impl FnOnce(&mut T, usize),
+    ) -> (Result<T, TransferError>, Duration) {
+        let decision = self.decide(None);
+        let r = match (r, decision.error, decision.short) {
+            (_, Some(error), _) => Err(error),
+            (Ok(mut data), None, Some(n)) => {
+                truncate(&mut data, n);
+                Ok(data)
+            }
+            (r, None, _) => r,
+        };
+        (r, decision.delay)
+    }
+
+    fn check_connected(&self) -> Result<(), Error> {
+        if self.inner.lock().unwrap().disconnected {
+            return Err(Error::new(ErrorKind::Disconnected, "device disconnected"));
+        }
+        Ok(())
+    }
+
+    fn clear_halt(&self, endpoint: u8) {
+        self.inner.lock().unwrap().halted.remove(&endpoint);
+    }
+}
+
+/// Fault injection state of an [`Endpoint`][crate::Endpoint].
+pub(crate) struct EndpointFaults {
+    injector: FaultInjector,
+    endpoint: u8,
+
+    /// Completion held back by [`Fault::Delay`], and when to deliver it.
+    delayed: Option<(Deadline, Completion)>,
+}
+
+impl EndpointFaults {
+    pub(crate) fn new(injector: &FaultInjector, endpoint: u8) -> Self {
+        EndpointFaults {
+            injector: injector.clone(),
+            endpoint,
+            delayed: None,
+        }
+    }
+
+    /// Number of completions held back by a delay.
+    pub(crate) fn pending(&self) -> usize {
+        self.delayed.is_some() as usize
+    }
+
+    pub(crate) fn poll_next_complete(
+        &mut self,
+        cx: &mut Context<'_>,
+        poll: impl FnOnce(&mut Context<'_>) -> Poll<Completion>,
+    ) -> Poll<Completion> {
+        if self.delayed.is_none() {
+            let Poll::Ready(mut c) = poll(cx) else {
+                return Poll::Pending;
+            };
+            let delay = self.injector.apply_completion(self.endpoint, &mut c);
+            if delay.is_zero() {
+                return Poll::Ready(c);
+            }
+            self.delayed = Some((Deadline::new(Instant::now() + delay), c));
+        }
+
+        let (deadline, _) = self.delayed.as_mut().unwrap();
+        std::task::ready!(deadline.poll(cx));
+        Poll::Ready(self.delayed.take().unwrap().1)
+    }
+
+    pub(crate) fn wait_next_complete(
+        &mut self,
+        timeout: Duration,
+        wait: impl FnOnce(Duration) -> Option<Completion>,
+    ) -> Option<Completion> {
+        let timeout_at = Instant::now() + timeout;
+        if self.delayed.is_none() {
+            let mut c = wait(timeout)?;
+            let delay = self.injector.apply_completion(self.endpoint, &mut c);
+            if delay.is_zero() {
+                return Some(c);
+            }
+            self.delayed = Some((Deadline::new(Instant::now() + delay), c));
+        }
+
+        let deadline = self.delayed.as_ref().unwrap().0.at();
+        let now = Instant::now();
+        if deadline > now {
+            thread::sleep(deadline.min(timeout_at) - now);
+        }
+        if Instant::now() >= deadline {
+            return Some(self.delayed.take().unwrap().1);
+        }
+        None
+    }
+}
+
+pub(crate) fn control_in(
+    faults: &Option<FaultInjector>,
+    f: impl MaybeFuture<Output = Result<Vec<u8>, TransferError>>,
+) -> impl MaybeFuture<Output = Result<Vec<u8>, TransferError>> {
+    let faults = faults.clone();
+    Delayed(f.map(move |r| match faults {
+        Some(faults) => faults.apply_control(r, |data, n| data.truncate(n)),
+        None => (r, Duration::ZERO),
+    }))
+}
+
+pub(crate) fn control_out(
+    faults: &Option<FaultInjector>,
+    f: impl MaybeFuture<Output = Result<(), TransferError>>,
+) -> impl MaybeFuture<Output = Result<(), TransferError>> {
+    let faults = faults.clone();
+    Delayed(f.map(move |r| match faults {
+        Some(faults) => faults.apply_control(r, |_, _| ()),
+        None => (r, Duration::ZERO),
+    }))
+}
+
+/// Fail an operation if a disconnection has been injected.
+pub(crate) fn operation<T>(
+    faults: &Option<FaultInjector>,
+    f: impl MaybeFuture<Output = Result<T, Error>>,
+) -> impl MaybeFuture<Output = Result<T, Error>> {
+    let faults = faults.clone();
+    f.map(move |r| {
+        if let Some(faults) = faults {
+            faults.check_connected()?;
+        }
+        r
+    })
+}
+
+/// Clear an injected stall along with the real one.
+pub(crate) fn clear_halt(
+    faults: Option<&EndpointFaults>,
+    f: impl MaybeFuture<Output = Result<(), Error>>,
+) -> impl MaybeFuture<Output = Result<(), Error>> {
+    let faults = faults.map(|f| (f.injector.clone(), f.endpoint));
+    f.map(move |r| {
+        if let Some((injector, endpoint)) = faults {
+            injector.check_connected()?;
+            if r.is_ok() {
+                injector.clear_halt(endpoint);
+            }
+        }
+        r
+    })
+}
+
+/// Delay the output of a `MaybeFuture` by the duration it returns.
+struct Delayed<F>(F);
+
+impl<F: MaybeFuture<Output = (T, Duration)>, T> IntoFuture for Delayed<F> {
+    type Output = T;
+    type IntoFuture = DelayedFut<F::IntoFuture, T>;
+
+    fn into_future(self) -> Self::IntoFuture {
+        DelayedFut {
+            wrapped: self.0.into_future(),
+            ready: None,
+        }
+    }
+}
+
+impl<F: MaybeFuture<Output = (T, Duration)>, T: NonWasmSend> MaybeFuture for Delayed<F> {
+    #[cfg(not(target_arch = "wasm32"))]
+    fn wait(self) -> Self::Output {
+        let (r, delay) = self.0.wait();
+        thread::sleep(delay);
+        r
+    }
+}
+
+struct DelayedFut<F, T> {
+    wrapped: F,
+    ready: Option<(Deadline, T)>,
+}
+
+impl<F: Future<Output = (T, Duration)>, T> Future for DelayedFut<F, T> {
+    type Output = T;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if self.ready.is_none() {
+            // SAFETY: structural pin projection: `self.wrapped` is always pinned.
+            let wrapped = unsafe { self.as_mut().map_unchecked_mut(|s| &mut s.wrapped) };
+            let Poll::Ready((r, delay)) = wrapped.poll(cx) else {
+                return Poll::Pending;
+            };
+            let ready = Some((Deadline::new(Instant::now() + delay), r));
+            // SAFETY: `self.ready` is never pinned.
+            unsafe { self.as_mut().get_unchecked_mut().ready = ready };
+        }
+
+        // SAFETY: `self.ready` is never pinned.
+        let ready = unsafe { &mut self.as_mut().get_unchecked_mut().ready };
+        std::task::ready!(ready.as_mut().unwrap().0.poll(cx));
+        Poll::Ready(ready.take().unwrap().1)
+    }
+}
+
+// The tests send control requests to the device, which isn't supported on
+// Windows.
+#[cfg(all(test, not(target_os = "windows")))]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use super::{Fault, FaultInjector, FaultRule};
+    use crate::{
+        emulated::{Completer, Model, Request},
+        transfer::{Buffer, Bulk, ControlIn, ControlType, Direction, In, Recipient, TransferError},
+        DeviceInfo, Error, ErrorKind, MaybeFuture, Speed,
+    };
+
+    /// Device that answers every IN transfer with 100 bytes.
+    struct Echo;
+
+    impl Model for Echo {
+        fn descriptors(&self) -> Vec<u8> {
+            let mut d = vec![
+                0x12, 0x01, 0x00, 0x02, 0xff, 0x00, 0x00, 0x40, 0xaa, 0xaa, 0x55, 0x55, 0x00, 0x01,
+                0x00, 0x00, 0x00, 0x01,
+            ];
+            d.extend([0x09, 0x02, 0x19, 0x00, 0x01, 0x01, 0x00, 0x80, 0x32]);
+            d.extend([0x09, 0x04, 0x00, 0x00, 0x01, 0xff, 0x00, 0x00, 0x00]);
+            d.extend([0x07, 0x05, 0x81, 0x02, 0x00, 0x02, 0x00]);
+            d
+        }
+
+        fn speed(&self) -> Option<Speed> {
+            Some(Speed::High)
+        }
+
+        fn active_configuration(&self) -> u8 {
+            1
+        }
+
+        fn set_configuration(&self, _: u8) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn claim_interface(&self, _: u8) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn set_alt_setting(&self, _: u8, _: u8) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn clear_halt(&self, _: u8) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn reset(&self) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn submit(&self, request: Request, completer: Completer) {
+            match request.direction() {
+                Direction::In => completer.complete_in(Ok(()), &[0xAA; 100]),
+                Direction::Out => completer.complete_out(Ok(()), request.data.len()),
+            };
+        }
+    }
+
+    const TIMEOUT: Duration = Duration::from_millis(500);
+
+    fn control_in() -> ControlIn {
+        ControlIn {
+            control_type: ControlType::Vendor,
+            recipient: Recipient::Device,
+            request: 1,
+            value: 0,
+            index: 0,
+            length: 64,
+        }
+    }
+
+    #[test]
+    fn stall_after_and_clear_halt() {
+        let faults = FaultInjector::new().with_rule(
+            FaultRule::new(Fault::Stall)
+                .on_endpoint(0x81)
+                .after(2)
+                .times(1),
+        );
+        let device = DeviceInfo::emulated(Arc::new(Echo))
+            .open()
+            .wait()
+            .unwrap()
+            .inject_faults(&faults);
+        let interface = device.claim_interface(0).wait().unwrap();
+        let mut ep = interface.endpoint::<Bulk, In>(0x81).unwrap();
+
+        let mut status = Vec::new();
+        for _ in 0..4 {
+            ep.submit(Buffer::new(512));
+            status.push(ep.wait_next_complete(TIMEOUT).unwrap().status);
+        }
+        let stall = Err(TransferError::Stall);
+        assert_eq!(status, [Ok(()), Ok(()), stall, stall]);
+        assert_eq!(faults.injected(), 1);
+
+        ep.clear_halt().wait().unwrap();
+        ep.submit(Buffer::new(512));
+        assert_eq!(ep.wait_next_complete(TIMEOUT).unwrap().status, Ok(()));
+
+        // Control transfers are unaffected by the endpoint rule
+        assert_eq!(
+            device
+                .control_in(control_in(), TIMEOUT)
+                .wait()
+                .unwrap()
+                .len(),
+            64
+        );
+    }
+
+    #[test]
+    fn short_read_delay_and_disconnect() {
+        let faults = FaultInjector::new()
+            .with_rule(FaultRule::new(Fault::ShortRead(10)).times(2))
+            .with_rule(FaultRule::new(Fault::Delay(Duration::from_millis(50))).on_endpoint(0x81));
+        let device = DeviceInfo::emulated(Arc::new(Echo))
+            .open()
+            .wait()
+            .unwrap()
+            .inject_faults(&faults);
+        let interface = device.claim_interface(0).wait().unwrap();
+        let mut ep = interface.endpoint::<Bulk, In>(0x81).unwrap();
+
+        assert_eq!(
+            device
+                .control_in(control_in(), TIMEOUT)
+                .wait()
+                .unwrap()
+                .len(),
+            10
+        );
+
+        ep.submit(Buffer::new(512));
+        assert!(ep.wait_next_complete(Duration::from_millis(5)).is_none());
+        assert_eq!(ep.pending(), 1);
+        let c = ep.wait_next_complete(TIMEOUT).unwrap();
+        assert_eq!((c.buffer.len(), c.actual_len), (10, 10));
+
+        ep.submit(Buffer::new(512));
+        let c = futures_lite::future::block_on(ep.next_complete());
+        assert_eq!(c.buffer.len(), 100);
+
+        faults.add_rule(FaultRule::new(Fault::Disconnect).on_control());
+        assert_eq!(
+            device.control_in(control_in(), TIMEOUT).wait(),
+            Err(TransferError::Disconnected)
+        );
+        ep.submit(Buffer::new(512));
+        assert_eq!(
+            ep.wait_next_complete(TIMEOUT).unwrap().status,
+            Err(TransferError::Disconnected)
+        );
+        assert_eq!(
+            interface.set_alt_setting(0).wait().unwrap_err().kind(),
+            ErrorKind::Disconnected
+        );
+
+        faults.clear();
+        assert!(device.control_in(control_in(), TIMEOUT).wait().is_ok());
+    }
+}
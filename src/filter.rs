@@ -0,0 +1,447 @@
+use std::{fmt::Display, ops::RangeInclusive, str::FromStr};
+
+use crate::{
+    maybe_future::blocking::Blocking, platform, Device, DeviceInfo, Error, ErrorKind, MaybeFuture,
+};
+
+/// Criteria for selecting devices by their [`DeviceInfo`].
+///
+/// A filter matches a device if the device meets every criterion that has
+/// been set; an empty filter matches all devices.
+///
+/// ### Example
+///
+/// ```no_run
+/// use nusb::{DeviceFilter, MaybeFuture};
+///
+/// let filter = DeviceFilter::new()
+///     .with_vendor_id(0x1234)
+///     .with_product_id_range(0x0100..=0x01ff);
+/// for device in filter.list_devices().wait().unwrap() {
+///     println!("{device:?}");
+/// }
+///
+/// // The same filter in string form, e.g. from a configuration file:
+/// let filter: DeviceFilter = "1234:0100-01ff".parse().unwrap();
+/// let device = filter.open_first().wait().unwrap();
+/// ```
+///
+/// ### String syntax
+///
+/// A filter is parsed from a comma-separated list of terms:
+///
+/// * `VID:PID`: vendor and product ID in hex. Either can be a range such as
+///   `0100-01ff`, or `*` to match any ID.
+/// * `vid=VID`, `pid=PID`: vendor or product ID alone, with the same syntax.
+/// * `class=CC[:SS[:PP]]`: device class, subclass and protocol in hex, where
+///   `*` matches any subclass or protocol.
+/// * `interface_class=CC[:SS[:PP]]`: class of any of the device's interfaces.
+/// * `serial=S`, `manufacturer=S`, `product=S`: string descriptors.
+/// * `bus=ID`: bus ID. Leading zeros are ignored, so `bus=1` matches `001`.
+/// * `port=P.P.P`: port chain, in decimal.
+/// * `driver=NAME`: driver name.
+///
+/// For example, `1234:5678,serial=A01` or `bus=1,port=2.3`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeviceFilter {
+    vendor_id: Option<RangeInclusive<u16>>,
+    product_id: Option<RangeInclusive<u16>>,
+    class: Option<ClassMatch>,
+    interface_class: Option<ClassMatch>,
+    serial_number: Option<String>,
+    manufacturer_string: Option<String>,
+    product_string: Option<String>,
+    bus_id: Option<String>,
+    port_chain: Option<Vec<u8>>,
+    driver: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ClassMatch {
+    class: u8,
+    subclass: Option<u8>,
+    protocol: Option<u8>,
+}
+
+impl ClassMatch {
+    fn matches(&self, class: u8, subclass: u8, protocol: u8) -> bool {
+        self.class == class
+            && self.subclass.map_or(true, |s| s == subclass)
+            && self.protocol.map_or(true, |p| p == protocol)
+    }
+}
+
+impl DeviceFilter {
+    /// Create a filter that matches all devices.
+    pub fn new() -> DeviceFilter {
+        DeviceFilter::default()
+    }
+
+    /// Match devices with the given vendor ID.
+    pub fn with_vendor_id(self, vendor_id: u16) -> Self {
+        self.with_vendor_id_range(vendor_id..=vendor_id)
+    }
+
+    /// Match devices with a vendor ID in the given range.
+    pub fn with_vendor_id_range(mut self, vendor_id: RangeInclusive<u16>) -> Self {
+        self.vendor_id = Some(vendor_id);
+        self
+    }
+
+    /// Match devices with the given product ID.
+    pub fn with_product_id(self, product_id: u16) -> Self {
+        self.with_product_id_range(product_id..=product_id)
+    }
+
+    /// Match devices with a product ID in the given range.
+    pub fn with_product_id_range(mut self, product_id: RangeInclusive<u16>) -> Self {
+        self.product_id = Some(product_id);
+        self
+    }
+
+    /// Match devices with the given device class, and subclass and protocol
+    /// if not `None`.
+    pub fn with_class(mut self, class: u8, subclass: Option<u8>, protocol: Option<u8>) -> Self {
+        self.class = Some(ClassMatch {
+            class,
+            subclass,
+            protocol,
+        });
+        self
+    }
+
+    /// Match devices with an interface of the given class, and subclass and
+    /// protocol if not `None`.
+    ///
+    /// This is checked against the interfaces listed by
+    /// [`DeviceInfo::interfaces`].
+    pub fn with_interface_class(
+        mut self,
+        class: u8,
+        subclass: Option<u8>,
+        protocol: Option<u8>,
+    ) -> Self {
+        self.interface_class = Some(ClassMatch {
+            class,
+            subclass,
+            protocol,
+        });
+        self
+    }
+
+    /// Match devices with the given serial number string.
+    pub fn with_serial_number(mut self, serial_number: impl Into<String>) -> Self {
+        self.serial_number = Some(serial_number.into());
+        self
+    }
+
+    /// Match devices with the given manufacturer string.
+    pub fn with_manufacturer_string(mut self, manufacturer: impl Into<String>) -> Self {
+        self.manufacturer_string = Some(manufacturer.into());
+        self
+    }
+
+    /// Match devices with the given product string.
+    pub fn with_product_string(mut self, product: impl Into<String>) -> Self {
+        self.product_string = Some(product.into());
+        self
+    }
+
+    /// Match devices on the bus with the given [bus ID][DeviceInfo::bus_id].
+    ///
+    /// Leading zeros are ignored when comparing.
+    pub fn with_bus_id(mut self, bus_id: impl Into<String>) -> Self {
+        self.bus_id = Some(bus_id.into());
+        self
+    }
+
+    /// Match devices connected at the given [port chain][DeviceInfo::port_chain].
+    pub fn with_port_chain(mut self, port_chain: impl Into<Vec<u8>>) -> Self {
+        self.port_chain = Some(port_chain.into());
+        self
+    }
+
+    /// Match devices using the given driver.
+    ///
+    /// ### Platform-specific notes
+    ///
+    /// * On Linux, this matches the driver bound to any of the device's
+    ///   interfaces, such as `cdc_acm`.
+    /// * On Windows, this matches the driver of the device.
+    /// * On macOS, no devices match.
+    pub fn with_driver(mut self, driver: impl Into<String>) -> Self {
+        self.driver = Some(driver.into());
+        self
+    }
+
+    /// Check whether a device matches the filter.
+    pub fn matches(&self, device: &DeviceInfo) -> bool {
+        fn eq_opt(expected: &Option<String>, actual: Option<&str>) -> bool {
+            expected.as_deref().map_or(true, |e| actual == Some(e))
+        }
+
+        self.vendor_id
+            .as_ref()
+            .map_or(true, |r| r.contains(&device.vendor_id()))
+            && self
+                .product_id
+                .as_ref()
+                .map_or(true, |r| r.contains(&device.product_id()))
+            && self.class.map_or(true, |c| {
+                c.matches(device.class(), device.subclass(), device.protocol())
+            })
+            && self.interface_class.map_or(true, |c| {
+                device
+                    .interfaces()
+                    .any(|i| c.matches(i.class(), i.subclass(), i.protocol()))
+            })
+            && eq_opt(&self.serial_number, device.serial_number())
+            && eq_opt(&self.manufacturer_string, device.manufacturer_string())
+            && eq_opt(&self.product_string, device.product_string())
+            && self
+                .bus_id
+                .as_deref()
+                .map_or(true, |b| trim_zeros(b) == trim_zeros(device.bus_id()))
+            && self
+                .port_chain
+                .as_deref()
+                .map_or(true, |p| p == device.port_chain())
+            && self
+                .driver
+                .as_deref()
+                .map_or(true, |d| driver_matches(device, d))
+    }
+
+    /// List the connected devices that match the filter.
+    ///
+    /// This is [`list_devices`][crate::list_devices] with the results
+    /// filtered by [`matches`][Self::matches].
+    pub fn list_devices(
+        &self,
+    ) -> impl MaybeFuture<Output = Result<impl Iterator<Item = DeviceInfo>, Error>> {
+        let filter = self.clone();
+        platform::list_devices().map(move |devices| {
+            devices.map(move |devices| devices.filter(move |d| filter.matches(d)))
+        })
+    }
+
+    /// Open the first connected device that matches the filter.
+    ///
+    /// Returns an error with [`ErrorKind::NotFound`] if no device matches.
+    pub fn open_first(&self) -> impl MaybeFuture<Output = Result<Device, Error>> {
+        let filter = self.clone();
+        Blocking::new(move || {
+            let device = filter.list_devices().wait()?.next().ok_or(Error::new(
+                ErrorKind::NotFound,
+                "no device matches the filter",
+            ))?;
+            device.open().wait()
+        })
+    }
+}
+
+fn trim_zeros(s: &str) -> &str {
+    match s.trim_start_matches('0') {
+        "" if !s.is_empty() => "0",
+        t => t,
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn driver_matches(device: &DeviceInfo, driver: &str) -> bool {
+    device.interfaces().any(|i| i.driver() == Some(driver))
+}
+
+#[cfg(target_os = "windows")]
+fn driver_matches(device: &DeviceInfo, driver: &str) -> bool {
+    device.driver() == Some(driver)
+}
+
+#[cfg(target_os = "macos")]
+fn driver_matches(_device: &DeviceInfo, _driver: &str) -> bool {
+    false
+}
+
+/// Error returned when parsing a [`DeviceFilter`] from a string fails.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseFilterError {
+    term: String,
+    message: &'static str,
+}
+
+impl Display for ParseFilterError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} in device filter term `{}`", self.message, self.term)
+    }
+}
+
+impl std::error::Error for ParseFilterError {}
+
+fn parse_hex<T: TryFrom<u32>>(s: &str) -> Option<T> {
+    let s = s.trim();
+    let s = s
+        .strip_prefix("0x")
+        .or_else(|| s.strip_prefix("0X"))
+        .unwrap_or(s);
+    u32::from_str_radix(s, 16).ok()?.try_into().ok()
+}
+
+/// Parse a hex ID or range, or `*` for `None`.
+fn parse_id(s: &str) -> Result<Option<RangeInclusive<u16>>, &'static str> {
+    if s.trim() == "*" {
+        return Ok(None);
+    }
+    let (start, end) = s.split_once('-').unwrap_or((s, s));
+    match (parse_hex(start), parse_hex(end)) {
+        (Some(start), Some(end)) if start <= end => Ok(Some(start..=end)),
+        _ => Err("invalid ID or range"),
+    }
+}
+
+fn parse_class(s: &str) -> Result<ClassMatch, &'static str> {
+    let mut parts = s.split(':');
+    let mut next = || match parts.next().map(str::trim) {
+        None | Some("*") => Ok(None),
+        Some(p) => parse_hex(p).map(Some).ok_or("invalid class"),
+    };
+    let class = next()?.ok_or("invalid class")?;
+    let subclass = next()?;
+    let protocol = next()?;
+    if parts.next().is_some() {
+        return Err("too many class fields");
+    }
+    Ok(ClassMatch {
+        class,
+        subclass,
+        protocol,
+    })
+}
+
+fn parse_port_chain(s: &str) -> Result<Vec<u8>, &'static str> {
+    s.split('.')
+        .map(|p| p.trim().parse().map_err(|_| "invalid port chain"))
+        .collect()
+}
+
+impl DeviceFilter {
+    fn parse_term(&mut self, term: &str) -> Result<(), &'static str> {
+        if let Some((key, value)) = term.split_once('=') {
+            match key.trim() {
+                "vid" => self.vendor_id = parse_id(value)?,
+                "pid" => self.product_id = parse_id(value)?,
+                "class" => self.class = Some(parse_class(value)?),
+                "interface_class" => self.interface_class = Some(parse_class(value)?),
+                "serial" => self.serial_number = Some(value.to_owned()),
+                "manufacturer" => self.manufacturer_string = Some(value.to_owned()),
+                "product" => self.product_string = Some(value.to_owned()),
+                "bus" => self.bus_id = Some(value.trim().to_owned()),
+                "port" => self.port_chain = Some(parse_port_chain(value)?),
+                "driver" => self.driver = Some(value.trim().to_owned()),
+                _ => return Err("unknown key"),
+            }
+        } else if let Some((vid, pid)) = term.split_once(':') {
+            self.vendor_id = parse_id(vid)?;
+            self.product_id = parse_id(pid)?;
+        } else {
+            return Err("expected `VID:PID` or `key=value`");
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for DeviceFilter {
+    type Err = ParseFilterError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut filter = DeviceFilter::new();
+        for term in s.split(',').map(str::trim).filter(|t| !t.is_empty()) {
+            filter
+                .parse_term(term)
+                .map_err(|message| ParseFilterError {
+                    term: term.to_owned(),
+                    message,
+                })?;
+        }
+        Ok(filter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            "1234:5678".parse(),
+            Ok(DeviceFilter::new()
+                .with_vendor_id(0x1234)
+                .with_product_id(0x5678))
+        );
+        assert_eq!(
+            "0x1234:0100-01ff, class=ef:02:*, serial=A,B".parse::<DeviceFilter>(),
+            Err(ParseFilterError {
+                term: "B".into(),
+                message: "expected `VID:PID` or `key=value`"
+            })
+        );
+        assert_eq!(
+            "1234:*,interface_class=ff:*:01,serial=A01".parse(),
+            Ok(DeviceFilter::new()
+                .with_vendor_id(0x1234)
+                .with_interface_class(0xff, None, Some(0x01))
+                .with_serial_number("A01"))
+        );
+        assert_eq!(
+            "bus=1,port=2.3,driver=cdc_acm".parse(),
+            Ok(DeviceFilter::new()
+                .with_bus_id("1")
+                .with_port_chain([2, 3])
+                .with_driver("cdc_acm"))
+        );
+        assert!("vid=fffff".parse::<DeviceFilter>().is_err());
+        assert!("pid=2-1".parse::<DeviceFilter>().is_err());
+        assert!("port=1.x".parse::<DeviceFilter>().is_err());
+        assert!("speed=high".parse::<DeviceFilter>().is_err());
+    }
+
+    #[test]
+    fn bus_id_zeros() {
+        assert_eq!(trim_zeros("001"), "1");
+        assert_eq!(trim_zeros("000"), "0");
+        assert_eq!(trim_zeros("1"), "1");
+    }
+
+    #[cfg(feature = "mock")]
+    #[test]
+    fn matches() {
+        use crate::mock::MockDevice;
+
+        let device = MockDevice::new([
+            18, 0x01, 0x00, 0x02, 0xef, 0x02, 0x01, 64, 0x34, 0x12, 0x78, 0x56, 0x00, 0x01, 0, 0,
+            0, 1, //
+            9, 0x02, 18, 0, 1, 1, 0, 0x80, 50, //
+            9, 0x04, 0, 0, 0, 0xff, 0x42, 0x01, 0,
+        ])
+        .device_info();
+
+        assert!(DeviceFilter::new().matches(&device));
+        assert!(DeviceFilter::new()
+            .with_vendor_id(0x1234)
+            .with_product_id_range(0x5600..=0x56ff)
+            .with_class(0xef, Some(0x02), None)
+            .with_interface_class(0xff, Some(0x42), None)
+            .matches(&device));
+        assert!(!DeviceFilter::new().with_product_id(0x5679).matches(&device));
+        assert!(!DeviceFilter::new()
+            .with_class(0xef, Some(0x02), Some(0x02))
+            .matches(&device));
+        assert!(!DeviceFilter::new()
+            .with_interface_class(0xfe, None, None)
+            .matches(&device));
+        assert!(!DeviceFilter::new()
+            .with_serial_number("A01")
+            .matches(&device));
+        assert!(DeviceFilter::new().with_bus_id("emulated").matches(&device));
+    }
+}
@@ -0,0 +1,176 @@
+//! Wrappers for the Linux native AIO syscalls, translated from
+//! [`linux/aio_abi.h`][uapi].
+//!
+//! FunctionFS endpoint files don't support `poll`, so this is the only way to
+//! have multiple transfers in flight on an endpoint without a thread per
+//! transfer.
+//!
+//! [uapi]: https://github.com/torvalds/linux/blob/master/include/uapi/linux/aio_abi.h
+
+use std::{
+    io,
+    os::fd::{AsRawFd, BorrowedFd},
+    time::Duration,
+};
+
+use rustix::io::Errno;
+
+const IOCB_CMD_PREAD: u16 = 0;
+const IOCB_CMD_PWRITE: u16 = 1;
+
+/// Notify the eventfd in `aio_resfd` on completion.
+const IOCB_FLAG_RESFD: u32 = 1 << 0;
+
+#[repr(C)]
+pub struct Iocb {
+    pub aio_data: u64,
+    #[cfg(target_endian = "little")]
+    pub aio_key: u32,
+    pub aio_rw_flags: i32,
+    #[cfg(target_endian = "big")]
+    pub aio_key: u32,
+    pub aio_lio_opcode: u16,
+    pub aio_reqprio: i16,
+    pub aio_fildes: u32,
+    pub aio_buf: u64,
+    pub aio_nbytes: u64,
+    pub aio_offset: i64,
+    pub aio_reserved2: u64,
+    pub aio_flags: u32,
+    pub aio_resfd: u32,
+}
+
+impl Iocb {
+    /// Describe a read (`write == false`) or write of `len` bytes at `buf`,
+    /// signalling `eventfd` on completion.
+    pub fn new(
+        id: u64,
+        fd: BorrowedFd,
+        write: bool,
+        buf: *mut u8,
+        len: usize,
+        eventfd: BorrowedFd,
+    ) -> Iocb {
+        Iocb {
+            aio_data: id,
+            aio_key: 0,
+            aio_rw_flags: 0,
+            aio_lio_opcode: if write {
+                IOCB_CMD_PWRITE
+            } else {
+                IOCB_CMD_PREAD
+            },
+            aio_reqprio: 0,
+            aio_fildes: fd.as_raw_fd() as u32,
+            aio_buf: buf as u64,
+            aio_nbytes: len as u64,
+            aio_offset: 0,
+            aio_reserved2: 0,
+            aio_flags: IOCB_FLAG_RESFD,
+            aio_resfd: eventfd.as_raw_fd() as u32,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct IoEvent {
+    pub data: u64,
+    pub obj: u64,
+    pub res: i64,
+    pub res2: i64,
+}
+
+fn check(ret: libc::c_long) -> Result<usize, Errno> {
+    if ret < 0 {
+        let e = io::Error::last_os_error();
+        Err(Errno::from_io_error(&e).unwrap_or(Errno::IO))
+    } else {
+        Ok(ret as usize)
+    }
+}
+
+/// An AIO context, destroyed on drop.
+pub struct Context(libc::c_ulong);
+
+impl Context {
+    pub fn new(max_events: u32) -> Result<Context, Errno> {
+        let mut ctx: libc::c_ulong = 0;
+        check(unsafe {
+            libc::syscall(
+                libc::SYS_io_setup,
+                max_events as libc::c_long,
+                &mut ctx as *mut libc::c_ulong,
+            )
+        })?;
+        Ok(Context(ctx))
+    }
+
+    /// Submit an operation.
+    ///
+    /// ### Safety
+    /// The iocb and the buffer it points to must remain valid until the
+    /// operation completes, which is guaranteed at the latest when the
+    /// context is dropped.
+    pub unsafe fn submit(&self, iocb: &mut Iocb) -> Result<(), Errno> {
+        let mut iocbs = [iocb as *mut Iocb];
+        check(unsafe {
+            libc::syscall(
+                libc::SYS_io_submit,
+                self.0,
+                1 as libc::c_long,
+                iocbs.as_mut_ptr(),
+            )
+        })?;
+        Ok(())
+    }
+
+    /// Request cancellation of an operation. Its completion event is still
+    /// delivered through [`Context::get_events`].
+    pub fn cancel(&self, iocb: &mut Iocb) -> Result<(), Errno> {
+        let mut event = IoEvent::default();
+        check(unsafe {
+            libc::syscall(
+                libc::SYS_io_cancel,
+                self.0,
+                iocb as *mut Iocb,
+                &mut event as *mut IoEvent,
+            )
+        })?;
+        Ok(())
+    }
+
+    /// Collect completion events, waiting up to `timeout` for at least
+    /// `min` of them.
+    pub fn get_events<'a>(
+        &self,
+        min: usize,
+        events: &'a mut [IoEvent],
+        timeout: Duration,
+    ) -> Result<&'a [IoEvent], Errno> {
+        let ts = libc::timespec {
+            tv_sec: timeout.as_secs().min(libc::time_t::MAX as u64) as libc::time_t,
+            tv_nsec: timeout.subsec_nanos() as _,
+        };
+        let n = check(unsafe {
+            libc::syscall(
+                libc::SYS_io_getevents,
+                self.0,
+                min as libc::c_long,
+                events.len() as libc::c_long,
+                events.as_mut_ptr(),
+                &ts as *const libc::timespec,
+            )
+        })?;
+        Ok(&events[..n])
+    }
+}
+
+impl Drop for Context {
+    fn drop(&mut self) {
+        // Blocks until all outstanding operations have completed.
+        unsafe {
+            libc::syscall(libc::SYS_io_destroy, self.0);
+        }
+    }
+}
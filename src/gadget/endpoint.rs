@@ -0,0 +1,279 @@
+use std::{
+    collections::VecDeque,
+    fmt::Debug,
+    future::{poll_fn, Future},
+    os::fd::{AsFd, OwnedFd},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use log::{debug, error, warn};
+use rustix::{
+    event::{eventfd, EventfdFlags},
+    io::Errno,
+    ioctl,
+};
+
+use super::aio::{self, IoEvent, Iocb};
+use crate::{
+    platform::{errno_to_transfer_error, Async},
+    transfer::{Buffer, Completion, Direction},
+    Error, ErrorKind,
+};
+
+/// Maximum number of transfers in flight on an endpoint.
+const MAX_PENDING: u32 = 256;
+
+/// `_IO('g', 3)`
+const FUNCTIONFS_CLEAR_HALT: ioctl::Opcode = ioctl::opcode::none(b'g', 3);
+
+/// An endpoint of a FunctionFS function, opened with
+/// [`Function::endpoint`][super::Function::endpoint].
+///
+/// This has the same transfer queue interface as a host-side
+/// [`Endpoint`][crate::Endpoint], with the directions seen from the host: a
+/// buffer submitted to an IN endpoint is sent to the host when it requests
+/// data, and a buffer submitted to an OUT endpoint receives up to its
+/// `requested_len` bytes from the host. Unlike on the host side, there is no
+/// requirement for OUT transfers to be a multiple of the packet size.
+pub struct Endpoint {
+    address: u8,
+    file: OwnedFd,
+
+    /// Declared before `pending` so that it is dropped first, waiting for
+    /// the kernel to release the buffers.
+    aio: aio::Context,
+    notify: Async<OwnedFd>,
+
+    pending: VecDeque<Pending>,
+    next_id: u64,
+}
+
+struct Pending {
+    iocb: Box<Iocb>,
+    buffer: Buffer,
+
+    /// Result of the operation once complete: a length or negated errno.
+    result: Option<i64>,
+}
+
+impl Endpoint {
+    pub(super) fn new(address: u8, file: OwnedFd) -> Result<Endpoint, Error> {
+        let notify = eventfd(0, EventfdFlags::CLOEXEC | EventfdFlags::NONBLOCK)
+            .map_err(|e| Error::new_os(ErrorKind::Other, "failed to create eventfd", e))?;
+        let aio = aio::Context::new(MAX_PENDING)
+            .map_err(|e| Error::new_os(ErrorKind::Other, "failed to create AIO context", e))?;
+        Ok(Endpoint {
+            address,
+            file,
+            aio,
+            notify: Async::new(notify)?,
+            pending: VecDeque::new(),
+            next_id: 0,
+        })
+    }
+
+    /// Get the endpoint address.
+    pub fn endpoint_address(&self) -> u8 {
+        self.address
+    }
+
+    /// Get the number of transfers that have been submitted with `submit` that
+    /// have not yet been returned from `next_complete`.
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Begin a transfer on the endpoint.
+    ///
+    /// Submitted transfers are queued and completed in order. Any error in
+    /// submitting or performing the transfer is deferred until
+    /// [`next_complete`][Self::next_complete].
+    pub fn submit(&mut self, mut buffer: Buffer) {
+        let write = Direction::from_address(self.address) == Direction::In;
+        let len = if write {
+            buffer.len()
+        } else {
+            buffer.len = 0;
+            buffer.requested_len()
+        };
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let mut iocb = Box::new(Iocb::new(
+            id,
+            self.file.as_fd(),
+            write,
+            buffer.ptr,
+            len,
+            self.notify.inner.as_fd(),
+        ));
+
+        // SAFETY: the iocb and buffer are kept in `self.pending` until the
+        // operation completes or `self.aio` is destroyed.
+        let result = match unsafe { self.aio.submit(&mut iocb) } {
+            Ok(()) => None,
+            Err(e) => {
+                warn!(
+                    "Failed to submit transfer on gadget endpoint {:02x}: {e}",
+                    self.address
+                );
+                Some(-(e.raw_os_error() as i64))
+            }
+        };
+
+        self.pending.push_back(Pending {
+            iocb,
+            buffer,
+            result,
+        });
+    }
+
+    /// Request cancellation of all pending transfers.
+    ///
+    /// The transfers are cancelled asynchronously. Once cancelled, they will
+    /// be returned from calls to `next_complete` so you can tell which were
+    /// completed, partially-completed, or cancelled.
+    pub fn cancel_all(&mut self) {
+        for p in self.pending.iter_mut().filter(|p| p.result.is_none()) {
+            match self.aio.cancel(&mut p.iocb) {
+                // The kernel reports cancellation as in progress, and
+                // delivers the completion event later.
+                Ok(()) | Err(Errno::INPROGRESS) => {}
+
+                // Already completed
+                Err(Errno::AGAIN) | Err(Errno::INVAL) => {}
+
+                Err(e) => error!(
+                    "Failed to cancel transfer on gadget endpoint {:02x}: {e}",
+                    self.address
+                ),
+            }
+        }
+    }
+
+    /// Return a `Future` that waits for the next pending transfer to complete.
+    ///
+    /// ## Panics
+    /// * if there are no transfers pending (that is, if [`Self::pending()`]
+    ///   would return 0).
+    pub fn next_complete(&mut self) -> impl Future<Output = Completion> + Send + Sync + '_ {
+        poll_fn(|cx| self.poll_next_complete(cx))
+    }
+
+    /// Poll for a pending transfer completion.
+    ///
+    /// ## Panics
+    ///  * if there are no transfers pending (that is, if [`Self::pending()`]
+    ///    would return 0).
+    pub fn poll_next_complete(&mut self, cx: &mut Context<'_>) -> Poll<Completion> {
+        assert!(!self.pending.is_empty(), "no transfers pending");
+
+        // Reset the eventfd counter before reaping, so that a completion
+        // after the reap leaves it readable.
+        let _ = rustix::io::read(&self.notify.inner, &mut [0; 8]);
+        self.reap(0, Duration::ZERO);
+        if let Some(c) = self.take_completed() {
+            return Poll::Ready(c);
+        }
+
+        if let Err(e) = self.notify.register(cx.waker()) {
+            error!("Failed to register gadget endpoint eventfd with epoll: {e}");
+        }
+        Poll::Pending
+    }
+
+    /// Wait for a pending transfer completion.
+    ///
+    /// Blocks for up to `timeout` waiting for a transfer to complete, or
+    /// returns `None` if the timeout is reached.
+    ///
+    /// ## Panics
+    ///  * if there are no transfers pending (that is, if [`Self::pending()`]
+    ///    would return 0).
+    pub fn wait_next_complete(&mut self, timeout: Duration) -> Option<Completion> {
+        assert!(!self.pending.is_empty(), "no transfers pending");
+
+        let deadline = Instant::now() + timeout;
+        self.reap(0, Duration::ZERO);
+        loop {
+            if let Some(c) = self.take_completed() {
+                return Some(c);
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return None;
+            }
+            self.reap(1, remaining);
+        }
+    }
+
+    /// Clear the endpoint's halt condition after the host cleared it.
+    pub fn clear_halt(&mut self) -> Result<(), Error> {
+        unsafe { ioctl::ioctl(&self.file, ioctl::NoArg::<FUNCTIONFS_CLEAR_HALT>::new()) }
+            .map_err(|e| Error::new_os(ErrorKind::Other, "failed to clear halt", e))
+    }
+
+    fn reap(&mut self, min: usize, timeout: Duration) {
+        let mut events = [IoEvent::default(); 16];
+        let events = match self.aio.get_events(min, &mut events, timeout) {
+            Ok(events) => events,
+            Err(Errno::INTR) => return,
+            Err(e) => {
+                error!("io_getevents failed: {e}");
+                return;
+            }
+        };
+        for event in events {
+            if let Some(p) = self
+                .pending
+                .iter_mut()
+                .find(|p| p.iocb.aio_data == event.data)
+            {
+                p.result = Some(event.res);
+            }
+        }
+    }
+
+    fn take_completed(&mut self) -> Option<Completion> {
+        let result = self.pending.front()?.result?;
+        let Pending { mut buffer, .. } = self.pending.pop_front().unwrap();
+
+        let write = Direction::from_address(self.address) == Direction::In;
+        let (status, actual_len) = if result < 0 {
+            let e = Errno::from_raw_os_error(-result as i32);
+            debug!(
+                "Transfer on gadget endpoint {:02x} failed: {e}",
+                self.address
+            );
+            (Err(errno_to_transfer_error(e)), 0)
+        } else {
+            (Ok(()), result as usize)
+        };
+        if !write {
+            buffer.len = actual_len as u32;
+        }
+
+        Some(Completion {
+            buffer,
+            actual_len,
+            status,
+            iso_status: Vec::new(),
+        })
+    }
+}
+
+impl Drop for Endpoint {
+    fn drop(&mut self) {
+        self.cancel_all();
+    }
+}
+
+impl Debug for Endpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Endpoint")
+            .field("address", &format_args!("0x{:02x}", self.address))
+            .field("pending", &self.pending.len())
+            .finish()
+    }
+}
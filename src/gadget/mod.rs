@@ -0,0 +1,646 @@
+//! Implement a USB function on the device side with Linux FunctionFS.
+//!
+//! While the rest of nusb talks to devices from the host, this module runs
+//! on a Linux board with a USB device controller and implements the device.
+//! [`Function`] writes a [`Descriptors`] table to a FunctionFS instance,
+//! reports control requests from the host as [`Event`]s, and opens the
+//! function's endpoints as transfer queues with the same interface as a
+//! host-side [`Endpoint`][crate::Endpoint].
+//!
+//! The function must first be created in a gadget with configfs and its
+//! FunctionFS instance mounted, e.g. with `mount -t functionfs example
+//! /dev/ffs-example`. The gadget can only be bound to a UDC once the
+//! function has been opened with [`Function::open`].
+//!
+//! Interface numbers, endpoint numbers and string indices in the descriptors
+//! are local to the function; the kernel renumbers them when the function is
+//! combined with others in a configuration, and translates the `wIndex` of
+//! control requests back. Endpoint addresses are those in the descriptors.
+//!
+//! The `dummy_hcd` kernel module provides a virtual UDC connected to a
+//! virtual host controller on the same machine, so a gadget can be tested
+//! against host-side nusb code without hardware.
+//!
+//! ```no_run
+//! use std::time::Duration;
+//! use nusb::descriptors::ConfigurationDescriptor;
+//! use nusb::gadget::{Descriptors, Event, Function};
+//! use nusb::transfer::ControlType;
+//!
+//! // Only the descriptors following the configuration descriptor are used.
+//! let config = [
+//!     9, 0x02, 32, 0, 1, 1, 0, 0x80, 50,
+//!     9, 0x04, 0, 0, 2, 0xff, 0, 0, 1,
+//!     7, 0x05, 0x01, 0x02, 0x00, 0x02, 0,
+//!     7, 0x05, 0x81, 0x02, 0x00, 0x02, 0,
+//! ];
+//! let descriptors = Descriptors::new()
+//!     .with_high_speed(ConfigurationDescriptor::new(&config).unwrap())
+//!     .with_strings(0x0409, &["Example interface"]);
+//!
+//! let mut function = Function::open("/dev/ffs-example", &descriptors)?;
+//! let mut ep_in = None;
+//! while let Some(event) = function.wait_next_event(Duration::from_secs(10))? {
+//!     match event {
+//!         Event::Enable => ep_in = Some(function.endpoint(0x81)?),
+//!         Event::Disable => ep_in = None,
+//!         Event::ControlIn(req) if req.control_type == ControlType::Vendor => {
+//!             function.respond_in(b"hello")?;
+//!         }
+//!         Event::ControlOut(req) if req.control_type == ControlType::Vendor => {
+//!             let data = function.receive_out()?;
+//!             println!("received {data:?}");
+//!         }
+//!         _ => {}
+//!     }
+//! }
+//! # drop(ep_in);
+//! # Ok::<(), nusb::Error>(())
+//! ```
+
+use std::{
+    collections::VecDeque,
+    fmt::Debug,
+    future::{poll_fn, Future},
+    os::fd::OwnedFd,
+    path::{Path, PathBuf},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use log::debug;
+use rustix::{
+    event::{PollFd, PollFlags, Timespec},
+    fs::{Mode, OFlags},
+    io::Errno,
+};
+
+use crate::{
+    descriptors::ConfigurationDescriptor,
+    platform::Async,
+    transfer::{parse_request_type, ControlIn, ControlType, Direction, Recipient},
+    Error, ErrorKind,
+};
+
+mod aio;
+
+mod endpoint;
+pub use endpoint::Endpoint;
+
+const FUNCTIONFS_DESCRIPTORS_MAGIC_V2: u32 = 3;
+const FUNCTIONFS_STRINGS_MAGIC: u32 = 2;
+
+const FUNCTIONFS_HAS_FS_DESC: u32 = 1;
+const FUNCTIONFS_HAS_HS_DESC: u32 = 2;
+const FUNCTIONFS_HAS_SS_DESC: u32 = 4;
+const FUNCTIONFS_VIRTUAL_ADDR: u32 = 16;
+const FUNCTIONFS_ALL_CTRL_RECIP: u32 = 64;
+
+/// Size of `struct usb_functionfs_event`.
+const EVENT_SIZE: usize = 12;
+
+const FUNCTIONFS_BIND: u8 = 0;
+const FUNCTIONFS_UNBIND: u8 = 1;
+const FUNCTIONFS_ENABLE: u8 = 2;
+const FUNCTIONFS_DISABLE: u8 = 3;
+const FUNCTIONFS_SETUP: u8 = 4;
+const FUNCTIONFS_SUSPEND: u8 = 5;
+const FUNCTIONFS_RESUME: u8 = 6;
+
+/// Descriptors and strings of a function, written to FunctionFS by
+/// [`Function::open`].
+///
+/// The descriptors for each speed are taken from a [`ConfigurationDescriptor`]
+/// whose interface, endpoint and other descriptors make up the function. The
+/// configuration descriptor itself is only a container and is not used.
+#[derive(Debug, Clone, Default)]
+pub struct Descriptors {
+    full_speed: Option<(u32, Vec<u8>)>,
+    high_speed: Option<(u32, Vec<u8>)>,
+    super_speed: Option<(u32, Vec<u8>)>,
+    strings: Vec<(u16, Vec<String>)>,
+    all_control_requests: bool,
+}
+
+impl Descriptors {
+    /// Create an empty descriptor table.
+    pub fn new() -> Descriptors {
+        Descriptors::default()
+    }
+
+    fn speed(config: ConfigurationDescriptor) -> (u32, Vec<u8>) {
+        let descriptors = config.descriptors();
+        let bytes = descriptors.as_bytes().to_vec();
+        (descriptors.count() as u32, bytes)
+    }
+
+    /// Set the descriptors used when connected at full speed.
+    pub fn with_full_speed(mut self, config: ConfigurationDescriptor) -> Self {
+        self.full_speed = Some(Self::speed(config));
+        self
+    }
+
+    /// Set the descriptors used when connected at high speed.
+    pub fn with_high_speed(mut self, config: ConfigurationDescriptor) -> Self {
+        self.high_speed = Some(Self::speed(config));
+        self
+    }
+
+    /// Set the descriptors used when connected at SuperSpeed or faster.
+    pub fn with_super_speed(mut self, config: ConfigurationDescriptor) -> Self {
+        self.super_speed = Some(Self::speed(config));
+        self
+    }
+
+    /// Add the strings for a language.
+    ///
+    /// String index 1 in the descriptors refers to the first string. Every
+    /// language must have the same number of strings.
+    pub fn with_strings(mut self, language: u16, strings: &[&str]) -> Self {
+        self.strings
+            .push((language, strings.iter().map(|s| s.to_string()).collect()));
+        self
+    }
+
+    /// Receive control requests to any recipient, not only those addressed
+    /// to the function's interfaces and endpoints.
+    ///
+    /// Standard requests handled by the kernel, such as `GET_DESCRIPTOR` for
+    /// the device, are never received.
+    pub fn with_all_control_requests(mut self) -> Self {
+        self.all_control_requests = true;
+        self
+    }
+
+    fn descriptors_blob(&self) -> Vec<u8> {
+        let speeds = [
+            (FUNCTIONFS_HAS_FS_DESC, &self.full_speed),
+            (FUNCTIONFS_HAS_HS_DESC, &self.high_speed),
+            (FUNCTIONFS_HAS_SS_DESC, &self.super_speed),
+        ];
+
+        let mut flags = FUNCTIONFS_VIRTUAL_ADDR;
+        if self.all_control_requests {
+            flags |= FUNCTIONFS_ALL_CTRL_RECIP;
+        }
+        for (flag, speed) in &speeds {
+            if speed.is_some() {
+                flags |= flag;
+            }
+        }
+
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&FUNCTIONFS_DESCRIPTORS_MAGIC_V2.to_le_bytes());
+        buf.extend_from_slice(&0u32.to_le_bytes());
+        buf.extend_from_slice(&flags.to_le_bytes());
+        for (count, _) in speeds.iter().filter_map(|(_, s)| s.as_ref()) {
+            buf.extend_from_slice(&count.to_le_bytes());
+        }
+        for (_, bytes) in speeds.iter().filter_map(|(_, s)| s.as_ref()) {
+            buf.extend_from_slice(bytes);
+        }
+        let len = buf.len() as u32;
+        buf[4..8].copy_from_slice(&len.to_le_bytes());
+        buf
+    }
+
+    fn strings_blob(&self) -> Result<Vec<u8>, Error> {
+        let str_count = self.strings.first().map_or(0, |(_, s)| s.len());
+        if self.strings.iter().any(|(_, s)| s.len() != str_count) {
+            return Err(Error::new(
+                ErrorKind::Other,
+                "all languages must have the same number of strings",
+            ));
+        }
+
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&FUNCTIONFS_STRINGS_MAGIC.to_le_bytes());
+        buf.extend_from_slice(&0u32.to_le_bytes());
+        buf.extend_from_slice(&(str_count as u32).to_le_bytes());
+        buf.extend_from_slice(&(self.strings.len() as u32).to_le_bytes());
+        for (language, strings) in &self.strings {
+            buf.extend_from_slice(&language.to_le_bytes());
+            for s in strings {
+                buf.extend_from_slice(s.as_bytes());
+                buf.push(0);
+            }
+        }
+        let len = buf.len() as u32;
+        buf[4..8].copy_from_slice(&len.to_le_bytes());
+        Ok(buf)
+    }
+}
+
+/// Event received by a [`Function`].
+#[derive(Debug, Clone, Copy)]
+#[non_exhaustive]
+pub enum Event {
+    /// The gadget was bound to a UDC.
+    Bind,
+
+    /// The gadget was unbound from its UDC.
+    Unbind,
+
+    /// The host selected a configuration or alternate setting that enables
+    /// the function. Its endpoints can now transfer data.
+    Enable,
+
+    /// The function was disabled by a reset, disconnection or configuration
+    /// change. Pending transfers on its endpoints fail.
+    Disable,
+
+    /// The bus was suspended.
+    Suspend,
+
+    /// The bus was resumed.
+    Resume,
+
+    /// The host sent a control request with an IN data stage.
+    ///
+    /// Respond with [`Function::respond_in`] or [`Function::stall`].
+    ControlIn(ControlIn),
+
+    /// The host sent a control request with an OUT data stage, or no data
+    /// stage.
+    ///
+    /// Accept it with [`Function::receive_out`] or reject it with
+    /// [`Function::stall`].
+    ControlOut(ControlOutRequest),
+}
+
+/// SETUP packet of a control OUT request received by a [`Function`].
+///
+/// The data is read with [`Function::receive_out`].
+#[derive(Debug, Clone, Copy)]
+pub struct ControlOutRequest {
+    /// Request type from the `bmRequestType` field of the SETUP packet.
+    #[doc(alias = "bmRequestType")]
+    pub control_type: ControlType,
+
+    /// Recipient from the `bmRequestType` field of the SETUP packet.
+    #[doc(alias = "bmRequestType")]
+    pub recipient: Recipient,
+
+    /// `bRequest` field of the SETUP packet.
+    #[doc(alias = "bRequest")]
+    pub request: u8,
+
+    /// `wValue` field of the SETUP packet.
+    #[doc(alias = "wValue")]
+    pub value: u16,
+
+    /// `wIndex` field of the SETUP packet.
+    #[doc(alias = "wIndex")]
+    pub index: u16,
+
+    /// Number of bytes in the data stage.
+    #[doc(alias = "wLength")]
+    pub length: u16,
+}
+
+/// Parse a `struct usb_functionfs_event`.
+fn parse_event(buf: &[u8]) -> Option<Event> {
+    match buf[8] {
+        FUNCTIONFS_BIND => Some(Event::Bind),
+        FUNCTIONFS_UNBIND => Some(Event::Unbind),
+        FUNCTIONFS_ENABLE => Some(Event::Enable),
+        FUNCTIONFS_DISABLE => Some(Event::Disable),
+        FUNCTIONFS_SUSPEND => Some(Event::Suspend),
+        FUNCTIONFS_RESUME => Some(Event::Resume),
+        FUNCTIONFS_SETUP => {
+            let (direction, control_type, recipient) = parse_request_type(buf[0])?;
+            let request = buf[1];
+            let value = u16::from_le_bytes([buf[2], buf[3]]);
+            let index = u16::from_le_bytes([buf[4], buf[5]]);
+            let length = u16::from_le_bytes([buf[6], buf[7]]);
+            Some(match direction {
+                Direction::In => Event::ControlIn(ControlIn {
+                    control_type,
+                    recipient,
+                    request,
+                    value,
+                    index,
+                    length,
+                }),
+                Direction::Out => Event::ControlOut(ControlOutRequest {
+                    control_type,
+                    recipient,
+                    request,
+                    value,
+                    index,
+                    length,
+                }),
+            })
+        }
+        _ => None,
+    }
+}
+
+/// A FunctionFS function, opened with [`Function::open`].
+///
+/// The function stays registered with the kernel as long as this is open;
+/// dropping it unbinds the gadget.
+pub struct Function {
+    dir: PathBuf,
+    ep0: Async<OwnedFd>,
+    events: VecDeque<Event>,
+
+    /// Direction and length of the control request awaiting a response.
+    setup: Option<(Direction, u16)>,
+}
+
+impl Function {
+    /// Open the FunctionFS instance mounted at `dir` and register the
+    /// function's descriptors and strings.
+    pub fn open(dir: impl AsRef<Path>, descriptors: &Descriptors) -> Result<Function, Error> {
+        let dir = dir.as_ref().to_owned();
+        let strings = descriptors.strings_blob()?;
+
+        let ep0 = rustix::fs::open(
+            dir.join("ep0"),
+            OFlags::RDWR | OFlags::CLOEXEC | OFlags::NONBLOCK,
+            Mode::empty(),
+        )
+        .map_err(|e| {
+            match e {
+                Errno::NOENT => Error::new_os(ErrorKind::NotFound, "FunctionFS ep0 not found", e),
+                Errno::ACCESS | Errno::PERM => {
+                    Error::new_os(ErrorKind::PermissionDenied, "permission denied", e)
+                }
+                Errno::BUSY => Error::new_os(ErrorKind::Busy, "function is already open", e),
+                e => Error::new_os(ErrorKind::Other, "failed to open FunctionFS ep0", e),
+            }
+            .log_debug()
+        })?;
+
+        rustix::io::write(&ep0, &descriptors.descriptors_blob()).map_err(|e| {
+            Error::new_os(ErrorKind::Other, "FunctionFS rejected the descriptors", e).log_debug()
+        })?;
+        rustix::io::write(&ep0, &strings).map_err(|e| {
+            Error::new_os(ErrorKind::Other, "FunctionFS rejected the strings", e).log_debug()
+        })?;
+
+        Ok(Function {
+            dir,
+            ep0: Async::new(ep0)?,
+            events: VecDeque::new(),
+            setup: None,
+        })
+    }
+
+    /// Open the endpoint with the given address from the descriptors.
+    ///
+    /// Transfers should be submitted only after [`Event::Enable`].
+    pub fn endpoint(&self, address: u8) -> Result<Endpoint, Error> {
+        let file = rustix::fs::open(
+            self.dir.join(format!("ep{address:02x}")),
+            OFlags::RDWR | OFlags::CLOEXEC,
+            Mode::empty(),
+        )
+        .map_err(|e| match e {
+            Errno::NOENT => Error::new_os(ErrorKind::NotFound, "endpoint not found", e),
+            e => Error::new_os(ErrorKind::Other, "failed to open endpoint", e),
+        })?;
+        Endpoint::new(address, file)
+    }
+
+    /// Return a `Future` that waits for the next event.
+    ///
+    /// A control request that has not been responded to when the next
+    /// event is requested is stalled.
+    pub fn next_event(&mut self) -> impl Future<Output = Result<Event, Error>> + Send + Sync + '_ {
+        poll_fn(|cx| self.poll_next_event(cx))
+    }
+
+    /// Poll for the next event.
+    pub fn poll_next_event(&mut self, cx: &mut Context<'_>) -> Poll<Result<Event, Error>> {
+        loop {
+            if let Some(event) = self.events.pop_front() {
+                return Poll::Ready(Ok(event));
+            }
+            match self.read_events() {
+                Ok(true) => continue,
+                Ok(false) => {}
+                Err(e) => return Poll::Ready(Err(e)),
+            }
+            if let Err(e) = self.ep0.register(cx.waker()) {
+                return Poll::Ready(Err(e));
+            }
+            return Poll::Pending;
+        }
+    }
+
+    /// Wait for the next event.
+    ///
+    /// Blocks for up to `timeout` waiting for an event, or returns `None` if
+    /// the timeout is reached.
+    pub fn wait_next_event(&mut self, timeout: Duration) -> Result<Option<Event>, Error> {
+        let deadline = Instant::now().checked_add(timeout);
+        loop {
+            if let Some(event) = self.events.pop_front() {
+                return Ok(Some(event));
+            }
+            if self.read_events()? {
+                continue;
+            }
+
+            let remaining = deadline.map(|d| d.saturating_duration_since(Instant::now()));
+            if remaining.is_some_and(|r| r.is_zero()) {
+                return Ok(None);
+            }
+            let timeout = remaining.and_then(|r| Timespec::try_from(r).ok());
+            let mut fds = [PollFd::new(&self.ep0.inner, PollFlags::IN)];
+            match rustix::event::poll(&mut fds, timeout.as_ref()) {
+                Ok(_) | Err(Errno::INTR) => {}
+                Err(e) => return Err(Error::new_os(ErrorKind::Other, "poll failed", e)),
+            }
+        }
+    }
+
+    /// Read available events into `self.events`, returning `false` if none
+    /// were available.
+    fn read_events(&mut self) -> Result<bool, Error> {
+        if self.setup.is_some() {
+            debug!("Stalling unhandled control request");
+            self.stall()?;
+        }
+
+        let mut buf = [0; EVENT_SIZE * 4];
+        let len = match rustix::io::read(&self.ep0.inner, &mut buf) {
+            Ok(len) => len,
+            Err(Errno::AGAIN) | Err(Errno::INTR) => return Ok(false),
+            Err(Errno::NODEV) => {
+                return Err(Error::new_os(
+                    ErrorKind::Disconnected,
+                    "FunctionFS instance was unmounted",
+                    Errno::NODEV,
+                ))
+            }
+            Err(e) => {
+                return Err(Error::new_os(
+                    ErrorKind::Other,
+                    "failed to read FunctionFS events",
+                    e,
+                ))
+            }
+        };
+
+        for raw in buf[..len].chunks_exact(EVENT_SIZE) {
+            // The kernel expects a response to every SETUP event, including
+            // ones that can't be parsed and are stalled on the next read.
+            if raw[8] == FUNCTIONFS_SETUP {
+                let direction = if raw[0] & 0x80 != 0 {
+                    Direction::In
+                } else {
+                    Direction::Out
+                };
+                self.setup = Some((direction, u16::from_le_bytes([raw[6], raw[7]])));
+            }
+            match parse_event(raw) {
+                Some(event) => self.events.push_back(event),
+                None => debug!("Ignoring FunctionFS event {raw:02x?}"),
+            }
+        }
+        Ok(true)
+    }
+
+    /// Send the data stage of the pending [`Event::ControlIn`] request.
+    ///
+    /// `data` is truncated to the requested length. This blocks until the
+    /// host has read the data.
+    pub fn respond_in(&mut self, data: &[u8]) -> Result<(), Error> {
+        let length = match self.setup {
+            Some((Direction::In, length)) => length,
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::Other,
+                    "no IN control request pending",
+                ))
+            }
+        };
+        self.setup = None;
+        let len = data.len().min(length as usize);
+        rustix::io::write(&self.ep0.inner, &data[..len]).map_err(setup_error)?;
+        Ok(())
+    }
+
+    /// Read the data stage of the pending [`Event::ControlOut`] request and
+    /// acknowledge it.
+    ///
+    /// This blocks until the host has sent the data.
+    pub fn receive_out(&mut self) -> Result<Vec<u8>, Error> {
+        let length = match self.setup {
+            Some((Direction::Out, length)) => length,
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::Other,
+                    "no OUT control request pending",
+                ))
+            }
+        };
+        self.setup = None;
+        let mut data = vec![0; length as usize];
+        let len = rustix::io::read(&self.ep0.inner, &mut data).map_err(setup_error)?;
+        data.truncate(len);
+        Ok(data)
+    }
+
+    /// Reject the pending control request with a STALL.
+    pub fn stall(&mut self) -> Result<(), Error> {
+        let Some((direction, _)) = self.setup.take() else {
+            return Err(Error::new(ErrorKind::Other, "no control request pending"));
+        };
+
+        // FunctionFS stalls ep0 when asked to transfer in the opposite
+        // direction of the request.
+        let res = match direction {
+            Direction::In => rustix::io::read(&self.ep0.inner, &mut [0u8; 0]),
+            Direction::Out => rustix::io::write(&self.ep0.inner, &[]),
+        };
+        match res {
+            Ok(_) | Err(Errno::L2HLT) | Err(Errno::IDRM) => Ok(()),
+            Err(e) => Err(setup_error(e)),
+        }
+    }
+}
+
+fn setup_error(e: Errno) -> Error {
+    match e {
+        Errno::IDRM => Error::new_os(
+            ErrorKind::Other,
+            "control request was cancelled by the host",
+            e,
+        ),
+        e => Error::new_os(ErrorKind::Other, "failed to respond to control request", e),
+    }
+}
+
+impl Debug for Function {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Function").field("dir", &self.dir).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn descriptor_blobs() {
+        let config = [
+            9, 0x02, 25, 0, 1, 1, 0, 0x80, 50, // configuration
+            9, 0x04, 0, 0, 1, 0xff, 0, 0, 1, // interface
+            7, 0x05, 0x81, 0x02, 0x40, 0x00, 0, // endpoint
+        ];
+        let descriptors = Descriptors::new()
+            .with_full_speed(ConfigurationDescriptor::new(&config).unwrap())
+            .with_strings(0x0409, &["ab"]);
+
+        let blob = descriptors.descriptors_blob();
+        assert_eq!(
+            &blob[..16],
+            &[3, 0, 0, 0, 32, 0, 0, 0, 17, 0, 0, 0, 2, 0, 0, 0]
+        );
+        assert_eq!(&blob[16..], &config[9..]);
+
+        let strings = descriptors.strings_blob().unwrap();
+        assert_eq!(
+            strings,
+            [2, 0, 0, 0, 21, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 0x09, 0x04, b'a', b'b', 0]
+        );
+
+        let mismatched = descriptors.with_strings(0x0407, &[]);
+        assert!(mismatched.strings_blob().is_err());
+    }
+
+    #[test]
+    fn events() {
+        assert!(matches!(
+            parse_event(&[0, 0, 0, 0, 0, 0, 0, 0, FUNCTIONFS_ENABLE, 0, 0, 0]),
+            Some(Event::Enable)
+        ));
+        assert!(matches!(
+            parse_event(&[0xc1, 0x05, 0x34, 0x12, 0x02, 0x00, 0x40, 0x00, 4, 0, 0, 0]),
+            Some(Event::ControlIn(ControlIn {
+                control_type: ControlType::Vendor,
+                recipient: Recipient::Interface,
+                request: 0x05,
+                value: 0x1234,
+                index: 2,
+                length: 64,
+            }))
+        ));
+        assert!(matches!(
+            parse_event(&[0x21, 0x09, 0, 0, 0, 0, 0, 0, 4, 0, 0, 0]),
+            Some(Event::ControlOut(ControlOutRequest {
+                control_type: ControlType::Class,
+                recipient: Recipient::Interface,
+                request: 0x09,
+                value: 0,
+                index: 0,
+                length: 0,
+            }))
+        ));
+        assert!(parse_event(&[0x60, 0, 0, 0, 0, 0, 0, 0, 4, 0, 0, 0]).is_none());
+    }
+}
@@ -3,15 +3,81 @@
 //!
 //! See [`super::watch_devices`] for a usage example.
 
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    task::ready,
+};
+
 use futures_core::Stream;
 
-use crate::{DeviceId, DeviceInfo};
+use crate::{DeviceFilter, DeviceId, DeviceInfo, Error, MaybeFuture};
 
 /// Stream of device connection / disconnection events.
 ///
 /// Call [`super::watch_devices`] to begin watching device
 /// events and create a `HotplugWatch`.
-pub struct HotplugWatch(pub(crate) crate::platform::HotplugWatch);
+pub struct HotplugWatch {
+    platform: crate::platform::HotplugWatch,
+
+    /// Events for the initial devices, returned before any from `platform`.
+    initial: VecDeque<HotplugEvent>,
+
+    /// Devices reported in `initial`, for which a `Connected` event from
+    /// `platform` is a duplicate.
+    initial_ids: HashSet<DeviceId>,
+
+    /// Last known information about each connected device that has been
+    /// seen, returned with its `Disconnected` event.
+    devices: HashMap<DeviceId, DeviceInfo>,
+
+    filter: Option<(DeviceFilter, HashSet<DeviceId>)>,
+}
+
+impl HotplugWatch {
+    pub(crate) fn new(platform: crate::platform::HotplugWatch) -> Self {
+        HotplugWatch {
+            platform,
+            initial: VecDeque::new(),
+            initial_ids: HashSet::new(),
+            devices: HashMap::new(),
+            filter: None,
+        }
+    }
+
+    /// Begin the stream with a `Connected` event for each device that is
+    /// already connected, followed by [`HotplugEvent::EnumerationComplete`].
+    ///
+    /// The devices are listed after the watch has started, so no device is
+    /// missed, and a device that is both listed and reported by the watch
+    /// is only returned once.
+    ///
+    /// This blocks while listing the devices.
+    pub fn with_initial_devices(mut self) -> Result<Self, Error> {
+        #[cfg(target_os = "linux")]
+        let devices = crate::platform::list_devices_in(self.platform.roots());
+        #[cfg(not(target_os = "linux"))]
+        let devices = crate::platform::list_devices();
+
+        for device in devices.wait()? {
+            self.initial_ids.insert(device.id());
+            self.initial.push_back(HotplugEvent::Connected(device));
+        }
+        self.initial.push_back(HotplugEvent::EnumerationComplete);
+        Ok(self)
+    }
+
+    /// Only return events for devices that match `filter`.
+    ///
+    /// A `Disconnected` event is returned only if the `Connected` event for
+    /// the device was returned by this watch, so disconnection of a matching
+    /// device that was already connected when the watch was created is not
+    /// reported unless [`with_initial_devices`][Self::with_initial_devices]
+    /// is used.
+    pub fn with_filter(mut self, filter: DeviceFilter) -> Self {
+        self.filter = Some((filter, HashSet::new()));
+        self
+    }
+}
 
 impl Stream for HotplugWatch {
     type Item = HotplugEvent;
@@ -20,19 +86,151 @@ impl Stream for HotplugWatch {
         mut self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Option<Self::Item>> {
-        self.0.poll_next(cx).map(Some)
+        loop {
+            let event = match self.initial.pop_front() {
+                Some(event) => event,
+                None => {
+                    let event = ready!(self.platform.poll_next(cx));
+                    match &event {
+                        HotplugEvent::Connected(d) if self.initial_ids.remove(&d.id()) => continue,
+                        HotplugEvent::Disconnected(id, _) => {
+                            self.initial_ids.remove(id);
+                        }
+                        _ => {}
+                    }
+                    event
+                }
+            };
+            let event = match event {
+                HotplugEvent::Connected(d) => {
+                    self.devices.insert(d.id(), d.clone());
+                    HotplugEvent::Connected(d)
+                }
+                HotplugEvent::Disconnected(id, None) => {
+                    let info = self.devices.remove(&id);
+                    HotplugEvent::Disconnected(id, info)
+                }
+                event => event,
+            };
+            let Some((filter, matched)) = &mut self.filter else {
+                return std::task::Poll::Ready(Some(event));
+            };
+            let pass = match &event {
+                HotplugEvent::Connected(d) => filter.matches(d) && matched.insert(d.id()),
+                HotplugEvent::Disconnected(id, _) => matched.remove(id),
+                HotplugEvent::EnumerationComplete => true,
+                HotplugEvent::InterfaceBound { device, .. }
+                | HotplugEvent::InterfaceUnbound { device, .. }
+                | HotplugEvent::InterfaceChanged { device, .. }
+                | HotplugEvent::ConfigurationChanged { device, .. } => matched.contains(device),
+            };
+            if pass {
+                return std::task::Poll::Ready(Some(event));
+            }
+        }
     }
 }
 
 /// Event returned from the [`HotplugWatch`] stream.
+///
+/// New kinds of events may be added in minor releases, so a `match` on a
+/// `HotplugEvent` needs a wildcard arm.
 #[derive(Debug)]
 #[allow(clippy::large_enum_variant)]
+#[non_exhaustive]
 pub enum HotplugEvent {
     /// A device has been connected.
     Connected(DeviceInfo),
 
     /// A device has been disconnected.
-    Disconnected(DeviceId),
+    ///
+    /// The `DeviceInfo` is the last known information about the device, from
+    /// its `Connected` event. It is `None` if that event was not seen by this
+    /// watch, because the device was already connected when the watch was
+    /// created without [`HotplugWatch::with_initial_devices`].
+    Disconnected(DeviceId, Option<DeviceInfo>),
+
+    /// All devices that were connected when the watch was created have been
+    /// reported.
+    ///
+    /// Only returned when the watch was created with
+    /// [`HotplugWatch::with_initial_devices`].
+    EnumerationComplete,
+
+    /// *(Linux-only)* A kernel driver was bound to an interface of a device.
+    InterfaceBound {
+        /// The device.
+        device: DeviceId,
+        /// Interface number.
+        interface: u8,
+        /// Name of the driver, such as `cdc_acm` or `usbfs`.
+        driver: String,
+    },
+
+    /// *(Linux-only)* The kernel driver was unbound from an interface of a
+    /// device.
+    ///
+    /// Also reported for each interface with a bound driver when the device
+    /// is disconnected, before the [`Disconnected`][Self::Disconnected]
+    /// event.
+    InterfaceUnbound {
+        /// The device.
+        device: DeviceId,
+        /// Interface number.
+        interface: u8,
+        /// Name of the driver, if its binding was seen by this watch.
+        driver: Option<String>,
+    },
+
+    /// *(Linux-only)* The kernel reported a change to an interface of a
+    /// device.
+    InterfaceChanged {
+        /// The device.
+        device: DeviceId,
+        /// Interface number.
+        interface: u8,
+        /// Name of the driver bound to the interface, if any.
+        driver: Option<String>,
+    },
+
+    /// *(Linux-only)* The active configuration of a device changed.
+    ConfigurationChanged {
+        /// The device.
+        device: DeviceId,
+        /// `bConfigurationValue` of the new configuration, or 0 if the device
+        /// is unconfigured.
+        configuration: u8,
+    },
+}
+
+/// *(Linux-only)* Source of the events of a [`HotplugWatch`], selected with
+/// [`watch_devices_with_source`][crate::watch_devices_with_source].
+#[cfg(target_os = "linux")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub enum HotplugSource {
+    /// Use `Udev` if udevd is running, otherwise `Kernel` if the process is
+    /// not in a user namespace, otherwise `Inotify`.
+    #[default]
+    Auto,
+
+    /// Uevents broadcast by udevd after its rules have run, so device nodes
+    /// already have their final permissions.
+    Udev,
+
+    /// Uevents broadcast by the kernel, for systems without udevd.
+    ///
+    /// Events may arrive before device nodes are created or their
+    /// permissions are set, so opening a device on `Connected` may need to
+    /// be retried. Not delivered inside a user namespace, as used by most
+    /// rootless containers.
+    Kernel,
+
+    /// Watch `/dev/bus/usb` with inotify, for containers where no uevents
+    /// are received.
+    ///
+    /// Only `Connected` and `Disconnected` events are reported.
+    Inotify,
 }
 
 #[test]
@@ -40,3 +238,76 @@ fn assert_send_sync() {
     fn require_send_sync<T: Send + Sync>() {}
     require_send_sync::<HotplugWatch>();
 }
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use std::fs;
+
+    use futures_lite::stream::block_on;
+
+    use super::{HotplugEvent, HotplugSource};
+    use crate::{platform::sysfs_fixture, FsRoots};
+
+    #[test]
+    fn disconnected_info() {
+        let root = std::env::temp_dir().join(format!("nusb-hotplug-{}", std::process::id()));
+        sysfs_fixture(&root.join("sys"));
+        let usbfs = root.join("usbfs");
+        fs::create_dir_all(usbfs.join("001")).unwrap();
+        fs::write(usbfs.join("001/003"), "").unwrap();
+        let roots = FsRoots::new()
+            .with_sysfs(root.join("sys"))
+            .with_usbfs(&usbfs);
+        let watch = roots.watch_devices_with_source(HotplugSource::Inotify);
+        let mut events = block_on(watch.unwrap());
+
+        // Connected before the watch was created, so nothing is known about it
+        fs::remove_file(usbfs.join("001/003")).unwrap();
+        let Some(HotplugEvent::Disconnected(id, None)) = events.next() else {
+            panic!("expected Disconnected without info");
+        };
+
+        fs::write(usbfs.join("001/003"), "").unwrap();
+        assert!(matches!(events.next(), Some(HotplugEvent::Connected(d)) if d.id() == id));
+        fs::remove_file(usbfs.join("001/003")).unwrap();
+        assert!(matches!(
+            events.next(),
+            Some(HotplugEvent::Disconnected(d, Some(info)))
+                if d == id && info.id() == id && info.product_id() == 0x5678
+        ));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn initial_devices() {
+        let root = std::env::temp_dir().join(format!("nusb-initial-{}", std::process::id()));
+        sysfs_fixture(&root.join("sys"));
+        let usbfs = root.join("usbfs");
+        fs::create_dir_all(usbfs.join("001")).unwrap();
+        let roots = FsRoots::new()
+            .with_sysfs(root.join("sys"))
+            .with_usbfs(&usbfs);
+        let watch = roots.watch_devices_with_source(HotplugSource::Inotify);
+        let mut events = block_on(watch.unwrap().with_initial_devices().unwrap());
+
+        let Some(HotplugEvent::Connected(info)) = events.next() else {
+            panic!("expected Connected for the listed device");
+        };
+        assert_eq!(info.product_id(), 0x5678);
+        assert!(matches!(
+            events.next(),
+            Some(HotplugEvent::EnumerationComplete)
+        ));
+
+        // The watch also reports the device, which was listed already
+        fs::write(usbfs.join("001/003"), "").unwrap();
+        fs::remove_file(usbfs.join("001/003")).unwrap();
+        assert!(matches!(
+            events.next(),
+            Some(HotplugEvent::Disconnected(id, Some(_))) if id == info.id()
+        ));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,705 @@
+//! USB hub class requests.
+//!
+//! A [`Hub`] wraps an opened hub [`Device`] (`bDeviceClass` 0x09) to read its
+//! hub descriptor and port status, and to switch port power, reset ports, and
+//! control port indicators in the same way as tools like `uhubctl`.
+//!
+//! ### Example
+//!
+//! Power-cycle the port that a device is connected to:
+//!
+//! ```no_run
+//! # #[cfg(not(target_os = "windows"))] {
+//! use std::{thread::sleep, time::Duration};
+//! use nusb::{hub::{self, Hub}, MaybeFuture};
+//!
+//! let timeout = Duration::from_secs(1);
+//! let device = nusb::list_devices().wait().unwrap().next().unwrap();
+//! let (hub_info, port) = hub::parent_port(&device).wait().unwrap();
+//! let hub = Hub::new(hub_info.open().wait().unwrap(), timeout).wait().unwrap();
+//!
+//! hub.set_port_power(port, false, timeout).wait().unwrap();
+//! sleep(Duration::from_secs(2));
+//! hub.set_port_power(port, true, timeout).wait().unwrap();
+//! # }
+//! ```
+//!
+//! ### Platform-specific notes
+//!
+//! * Linux: Hub requests are sent without claiming an interface, so they
+//!   work while the kernel hub driver is bound. The kernel may power a port
+//!   back on if a device is connected while it is powered off.
+//! * Windows: `Hub` is not available, because hubs are not bound to WinUSB
+//!   and so can't be opened to send requests to.
+//! * Many hubs do not actually switch port power, even if their descriptor
+//!   reports per-port power switching.
+
+use std::time::Duration;
+
+use crate::{MaybeFuture, Speed};
+
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "android"))]
+use crate::{
+    transfer::{ControlIn, ControlOut, ControlType, Recipient, TransferError},
+    Device, GetDescriptorError,
+};
+
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
+use crate::{maybe_future::blocking::Blocking, platform, DeviceInfo, Error, ErrorKind};
+
+/// `bDeviceClass` of hubs.
+pub const CLASS_HUB: u8 = 0x09;
+
+const DESCRIPTOR_TYPE_HUB: u8 = 0x29;
+const DESCRIPTOR_TYPE_SUPERSPEED_HUB: u8 = 0x2A;
+
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "android"))]
+const REQUEST_GET_STATUS: u8 = 0x00;
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "android"))]
+const REQUEST_CLEAR_FEATURE: u8 = 0x01;
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "android"))]
+const REQUEST_SET_FEATURE: u8 = 0x03;
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "android"))]
+const REQUEST_GET_DESCRIPTOR: u8 = 0x06;
+
+/// An opened hub.
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "android"))]
+#[derive(Debug, Clone)]
+pub struct Hub {
+    device: Device,
+    descriptor: HubDescriptor,
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "android"))]
+impl Hub {
+    /// Read the hub descriptor of `device` and wrap it as a `Hub`.
+    ///
+    /// The SuperSpeed hub descriptor is read if the device reports USB 3.0 or
+    /// later in `bcdUSB`. Fails with a [`TransferError::Stall`] if the device
+    /// is not a hub.
+    pub fn new(
+        device: Device,
+        timeout: Duration,
+    ) -> impl MaybeFuture<Output = Result<Hub, GetDescriptorError>> {
+        let desc_type = if device.device_descriptor().usb_version() >= 0x0300 {
+            DESCRIPTOR_TYPE_SUPERSPEED_HUB
+        } else {
+            DESCRIPTOR_TYPE_HUB
+        };
+        device
+            .control_in(
+                ControlIn {
+                    control_type: ControlType::Class,
+                    recipient: Recipient::Device,
+                    request: REQUEST_GET_DESCRIPTOR,
+                    value: (desc_type as u16) << 8,
+                    index: 0,
+                    length: 64,
+                },
+                timeout,
+            )
+            .map(move |r| {
+                let data = r.map_err(GetDescriptorError::Transfer)?;
+                let descriptor =
+                    HubDescriptor::new(&data).ok_or(GetDescriptorError::InvalidDescriptor)?;
+                Ok(Hub { device, descriptor })
+            })
+    }
+
+    /// The hub device.
+    pub fn device(&self) -> &Device {
+        &self.device
+    }
+
+    /// The hub descriptor read when the `Hub` was created.
+    pub fn descriptor(&self) -> &HubDescriptor {
+        &self.descriptor
+    }
+
+    /// Read the status and change bits of a port.
+    ///
+    /// Ports are numbered from 1.
+    pub fn port_status(
+        &self,
+        port: u8,
+        timeout: Duration,
+    ) -> impl MaybeFuture<Output = Result<PortStatus, TransferError>> {
+        let superspeed = self.descriptor.is_superspeed();
+        self.device
+            .control_in(
+                ControlIn {
+                    control_type: ControlType::Class,
+                    recipient: Recipient::Other,
+                    request: REQUEST_GET_STATUS,
+                    value: 0,
+                    index: port.into(),
+                    length: 4,
+                },
+                timeout,
+            )
+            .map(move |r| {
+                let data = r?;
+                if data.len() < 4 {
+                    return Err(TransferError::Fault);
+                }
+                Ok(PortStatus {
+                    status: u16::from_le_bytes([data[0], data[1]]),
+                    change: u16::from_le_bytes([data[2], data[3]]),
+                    superspeed,
+                })
+            })
+    }
+
+    /// Send a `SET_FEATURE` request for a port.
+    pub fn set_port_feature(
+        &self,
+        port: u8,
+        feature: PortFeature,
+        timeout: Duration,
+    ) -> impl MaybeFuture<Output = Result<(), TransferError>> {
+        self.port_request(REQUEST_SET_FEATURE, feature as u16, port.into(), timeout)
+    }
+
+    /// Send a `CLEAR_FEATURE` request for a port.
+    ///
+    /// Clearing one of the `*Change` features acknowledges the corresponding
+    /// change bit in the [`PortStatus`].
+    pub fn clear_port_feature(
+        &self,
+        port: u8,
+        feature: PortFeature,
+        timeout: Duration,
+    ) -> impl MaybeFuture<Output = Result<(), TransferError>> {
+        self.port_request(REQUEST_CLEAR_FEATURE, feature as u16, port.into(), timeout)
+    }
+
+    /// Switch the power of a port on or off.
+    ///
+    /// See [`HubDescriptor::power_switching`] for whether the hub switches
+    /// ports individually.
+    pub fn set_port_power(
+        &self,
+        port: u8,
+        on: bool,
+        timeout: Duration,
+    ) -> impl MaybeFuture<Output = Result<(), TransferError>> {
+        let request = if on {
+            REQUEST_SET_FEATURE
+        } else {
+            REQUEST_CLEAR_FEATURE
+        };
+        self.port_request(request, PortFeature::Power as u16, port.into(), timeout)
+    }
+
+    /// Start a reset of the device connected to a port.
+    ///
+    /// The reset has completed when [`PortStatus::reset_changed`] is set.
+    pub fn reset_port(
+        &self,
+        port: u8,
+        timeout: Duration,
+    ) -> impl MaybeFuture<Output = Result<(), TransferError>> {
+        self.set_port_feature(port, PortFeature::Reset, timeout)
+    }
+
+    /// Set the port indicator LED of a port.
+    ///
+    /// Only supported by USB 2.0 hubs for which
+    /// [`HubDescriptor::has_port_indicators`] is true.
+    pub fn set_port_indicator(
+        &self,
+        port: u8,
+        indicator: PortIndicator,
+        timeout: Duration,
+    ) -> impl MaybeFuture<Output = Result<(), TransferError>> {
+        let index = (indicator as u16) << 8 | port as u16;
+        self.port_request(
+            REQUEST_SET_FEATURE,
+            PortFeature::Indicator as u16,
+            index,
+            timeout,
+        )
+    }
+
+    fn port_request(
+        &self,
+        request: u8,
+        value: u16,
+        index: u16,
+        timeout: Duration,
+    ) -> impl MaybeFuture<Output = Result<(), TransferError>> {
+        self.device.control_out(
+            ControlOut {
+                control_type: ControlType::Class,
+                recipient: Recipient::Other,
+                request,
+                value,
+                index,
+                data: &[],
+            },
+            timeout,
+        )
+    }
+}
+
+/// Find the hub and port that a device is connected to.
+///
+/// Fails with [`ErrorKind::NotFound`] if the device is connected directly to
+/// a root hub, which is not listed as a device, or if the hub was not listed.
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
+pub fn parent_port(
+    device: &DeviceInfo,
+) -> impl MaybeFuture<Output = Result<(DeviceInfo, u8), Error>> {
+    let bus_id = device.bus_id().to_owned();
+    let port_chain = device.port_chain().to_vec();
+    Blocking::new(move || {
+        let not_found = || Error::new(ErrorKind::NotFound, "parent hub not found");
+        let (&port, hub_chain) = port_chain.split_last().ok_or_else(not_found)?;
+        if hub_chain.is_empty() {
+            return Err(not_found());
+        }
+        let hub = platform::list_devices()
+            .wait()?
+            .find(|d| d.bus_id() == bus_id && d.port_chain() == hub_chain)
+            .ok_or_else(not_found)?;
+        Ok((hub, port))
+    })
+}
+
+/// Hub descriptor or SuperSpeed hub descriptor.
+#[derive(Clone, PartialEq, Eq)]
+pub struct HubDescriptor {
+    superspeed: bool,
+    num_ports: u8,
+    characteristics: u16,
+    power_on_to_good: u8,
+    controller_current: u8,
+    device_removable: Vec<u8>,
+}
+
+impl HubDescriptor {
+    /// Parse a hub descriptor (type 0x29) or SuperSpeed hub descriptor
+    /// (type 0x2A), or return `None` if the data is not valid.
+    pub fn new(buf: &[u8]) -> Option<HubDescriptor> {
+        if buf.len() < 7 || (buf[0] as usize) > buf.len() || buf[0] < 7 {
+            return None;
+        }
+        let buf = &buf[..buf[0] as usize];
+        let num_ports = buf[2];
+        let device_removable = match buf[1] {
+            DESCRIPTOR_TYPE_HUB => {
+                let len = (num_ports as usize + 1).div_ceil(8);
+                buf.get(7..7 + len)?.to_vec()
+            }
+            DESCRIPTOR_TYPE_SUPERSPEED_HUB => buf.get(10..12)?.to_vec(),
+            _ => return None,
+        };
+        Some(HubDescriptor {
+            superspeed: buf[1] == DESCRIPTOR_TYPE_SUPERSPEED_HUB,
+            num_ports,
+            characteristics: u16::from_le_bytes([buf[3], buf[4]]),
+            power_on_to_good: buf[5],
+            controller_current: buf[6],
+            device_removable,
+        })
+    }
+
+    /// Whether this is a SuperSpeed hub descriptor.
+    pub fn is_superspeed(&self) -> bool {
+        self.superspeed
+    }
+
+    /// Number of downstream ports, from the `bNbrPorts` field.
+    #[doc(alias = "bNbrPorts")]
+    pub fn num_ports(&self) -> u8 {
+        self.num_ports
+    }
+
+    /// `wHubCharacteristics` field.
+    #[doc(alias = "wHubCharacteristics")]
+    pub fn characteristics(&self) -> u16 {
+        self.characteristics
+    }
+
+    /// Power switching mode, from `wHubCharacteristics`.
+    pub fn power_switching(&self) -> PowerSwitching {
+        match self.characteristics & 0x03 {
+            0 => PowerSwitching::Ganged,
+            1 => PowerSwitching::Individual,
+            _ => PowerSwitching::None,
+        }
+    }
+
+    /// Whether the hub is part of a compound device, from
+    /// `wHubCharacteristics`.
+    pub fn is_compound(&self) -> bool {
+        self.characteristics & 0x04 != 0
+    }
+
+    /// Over-current protection mode, from `wHubCharacteristics`.
+    pub fn overcurrent_protection(&self) -> OvercurrentProtection {
+        match (self.characteristics >> 3) & 0x03 {
+            0 => OvercurrentProtection::Global,
+            1 => OvercurrentProtection::Individual,
+            _ => OvercurrentProtection::None,
+        }
+    }
+
+    /// Whether the ports have indicator LEDs, from `wHubCharacteristics`.
+    ///
+    /// Always false for SuperSpeed hubs.
+    pub fn has_port_indicators(&self) -> bool {
+        !self.superspeed && self.characteristics & 0x80 != 0
+    }
+
+    /// Time from switching a port on until its power is good, from the
+    /// `bPwrOn2PwrGood` field.
+    #[doc(alias = "bPwrOn2PwrGood")]
+    pub fn power_on_to_good(&self) -> Duration {
+        Duration::from_millis(self.power_on_to_good as u64 * 2)
+    }
+
+    /// Maximum current required by the hub controller in mA, from the
+    /// `bHubContrCurrent` field.
+    #[doc(alias = "bHubContrCurrent")]
+    pub fn controller_current(&self) -> u8 {
+        self.controller_current
+    }
+
+    /// Whether the device attached to a port is removable, from the
+    /// `DeviceRemovable` field.
+    ///
+    /// Ports are numbered from 1.
+    pub fn is_removable(&self, port: u8) -> bool {
+        let byte = self.device_removable.get(port as usize / 8).copied();
+        byte.map_or(true, |b| b & (1 << (port % 8)) == 0)
+    }
+}
+
+impl std::fmt::Debug for HubDescriptor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HubDescriptor")
+            .field("superspeed", &self.superspeed)
+            .field("num_ports", &self.num_ports)
+            .field(
+                "characteristics",
+                &format_args!("0x{:04X}", self.characteristics),
+            )
+            .field("power_on_to_good", &self.power_on_to_good())
+            .field("controller_current", &self.controller_current)
+            .finish()
+    }
+}
+
+/// Power switching mode of a hub.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerSwitching {
+    /// All ports are switched together.
+    Ganged,
+
+    /// Each port is switched individually.
+    Individual,
+
+    /// Ports are always powered.
+    None,
+}
+
+/// Over-current protection mode of a hub.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OvercurrentProtection {
+    /// Over-current is reported for all ports together.
+    Global,
+
+    /// Over-current is reported for each port.
+    Individual,
+
+    /// No over-current protection.
+    None,
+}
+
+/// Port feature selector for [`Hub::set_port_feature`] and
+/// [`Hub::clear_port_feature`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u16)]
+#[non_exhaustive]
+pub enum PortFeature {
+    /// `PORT_CONNECTION`
+    Connection = 0,
+
+    /// `PORT_ENABLE`
+    Enable = 1,
+
+    /// `PORT_SUSPEND` (USB 2.0 only)
+    Suspend = 2,
+
+    /// `PORT_OVER_CURRENT`
+    OverCurrent = 3,
+
+    /// `PORT_RESET`
+    Reset = 4,
+
+    /// `PORT_LINK_STATE` (SuperSpeed only)
+    LinkState = 5,
+
+    /// `PORT_POWER`
+    Power = 8,
+
+    /// `C_PORT_CONNECTION`
+    ConnectionChange = 16,
+
+    /// `C_PORT_ENABLE` (USB 2.0 only)
+    EnableChange = 17,
+
+    /// `C_PORT_SUSPEND` (USB 2.0 only)
+    SuspendChange = 18,
+
+    /// `C_PORT_OVER_CURRENT`
+    OverCurrentChange = 19,
+
+    /// `C_PORT_RESET`
+    ResetChange = 20,
+
+    /// `PORT_INDICATOR` (USB 2.0 only); use [`Hub::set_port_indicator`].
+    Indicator = 22,
+}
+
+/// Port indicator LED state for [`Hub::set_port_indicator`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum PortIndicator {
+    /// Controlled automatically by the hub.
+    Automatic = 0,
+
+    /// Amber.
+    Amber = 1,
+
+    /// Green.
+    Green = 2,
+
+    /// Off.
+    Off = 3,
+}
+
+/// Status and change bits of a hub port, returned by [`Hub::port_status`].
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct PortStatus {
+    status: u16,
+    change: u16,
+    superspeed: bool,
+}
+
+impl PortStatus {
+    /// `wPortStatus` field.
+    #[doc(alias = "wPortStatus")]
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    /// `wPortChange` field.
+    #[doc(alias = "wPortChange")]
+    pub fn change(&self) -> u16 {
+        self.change
+    }
+
+    /// A device is connected to the port.
+    pub fn connected(&self) -> bool {
+        self.status & 0x0001 != 0
+    }
+
+    /// The port is enabled.
+    pub fn enabled(&self) -> bool {
+        self.status & 0x0002 != 0
+    }
+
+    /// The port is suspended, or for SuperSpeed ports, in link state U3.
+    pub fn suspended(&self) -> bool {
+        if self.superspeed {
+            self.link_state() == Some(3)
+        } else {
+            self.status & 0x0004 != 0
+        }
+    }
+
+    /// An over-current condition exists on the port.
+    pub fn overcurrent(&self) -> bool {
+        self.status & 0x0008 != 0
+    }
+
+    /// The port is being reset.
+    pub fn resetting(&self) -> bool {
+        self.status & 0x0010 != 0
+    }
+
+    /// The port is powered.
+    pub fn powered(&self) -> bool {
+        if self.superspeed {
+            self.status & 0x0200 != 0
+        } else {
+            self.status & 0x0100 != 0
+        }
+    }
+
+    /// Speed of the connected device, or `None` if no device is connected.
+    pub fn speed(&self) -> Option<Speed> {
+        if !self.connected() {
+            None
+        } else if self.superspeed {
+            Some(Speed::Super)
+        } else if self.status & 0x0200 != 0 {
+            Some(Speed::Low)
+        } else if self.status & 0x0400 != 0 {
+            Some(Speed::High)
+        } else {
+            Some(Speed::Full)
+        }
+    }
+
+    /// Link state of a SuperSpeed port, such as 0 for U0 or 3 for U3.
+    pub fn link_state(&self) -> Option<u8> {
+        self.superspeed.then_some(((self.status >> 5) & 0x0f) as u8)
+    }
+
+    /// The connection status has changed.
+    pub fn connection_changed(&self) -> bool {
+        self.change & 0x0001 != 0
+    }
+
+    /// The port was disabled due to an error (USB 2.0 only).
+    pub fn enable_changed(&self) -> bool {
+        self.change & 0x0002 != 0
+    }
+
+    /// The port has resumed from suspend (USB 2.0 only).
+    pub fn suspend_changed(&self) -> bool {
+        self.change & 0x0004 != 0
+    }
+
+    /// The over-current status has changed.
+    pub fn overcurrent_changed(&self) -> bool {
+        self.change & 0x0008 != 0
+    }
+
+    /// A port reset has completed.
+    pub fn reset_changed(&self) -> bool {
+        self.change & 0x0010 != 0
+    }
+}
+
+impl std::fmt::Debug for PortStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PortStatus")
+            .field("status", &format_args!("0x{:04X}", self.status))
+            .field("change", &format_args!("0x{:04X}", self.change))
+            .field("connected", &self.connected())
+            .field("enabled", &self.enabled())
+            .field("powered", &self.powered())
+            .field("speed", &self.speed())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn descriptors() {
+        let hub = HubDescriptor::new(&[9, 0x29, 4, 0x89, 0x00, 50, 100, 0x04, 0xff]).unwrap();
+        assert!(!hub.is_superspeed());
+        assert_eq!(hub.num_ports(), 4);
+        assert_eq!(hub.power_switching(), PowerSwitching::Individual);
+        assert_eq!(
+            hub.overcurrent_protection(),
+            OvercurrentProtection::Individual
+        );
+        assert!(hub.has_port_indicators());
+        assert_eq!(hub.power_on_to_good(), Duration::from_millis(100));
+        assert!(hub.is_removable(1));
+        assert!(!hub.is_removable(2));
+
+        let ss = HubDescriptor::new(&[12, 0x2a, 4, 0x09, 0x00, 50, 0, 0, 0, 0, 0, 0]).unwrap();
+        assert!(ss.is_superspeed());
+        assert!(!ss.has_port_indicators());
+
+        assert!(HubDescriptor::new(&[9, 0x29, 4, 0x89, 0x00]).is_none());
+        assert!(HubDescriptor::new(&[9, 0x02, 4, 0x89, 0x00, 50, 100, 0x04, 0xff]).is_none());
+    }
+
+    #[test]
+    fn port_status() {
+        let status = PortStatus {
+            status: 0x0503,
+            change: 0x0011,
+            superspeed: false,
+        };
+        assert!(status.connected() && status.enabled() && status.powered());
+        assert!(!status.suspended());
+        assert_eq!(status.speed(), Some(Speed::High));
+        assert!(status.connection_changed() && status.reset_changed());
+        assert!(!status.enable_changed());
+
+        let status = PortStatus {
+            status: 0x0263,
+            change: 0,
+            superspeed: true,
+        };
+        assert!(status.powered() && status.suspended());
+        assert_eq!(status.link_state(), Some(3));
+        assert_eq!(status.speed(), Some(Speed::Super));
+    }
+
+    #[cfg(all(
+        feature = "mock",
+        any(target_os = "linux", target_os = "macos", target_os = "android")
+    ))]
+    #[test]
+    fn mock_hub() {
+        use std::sync::{Arc, Mutex};
+
+        use crate::mock::MockDevice;
+
+        let timeout = Duration::from_secs(1);
+        let mock = MockDevice::new([
+            18, 0x01, 0x00, 0x02, 0x09, 0x00, 0x01, 64, 0x34, 0x12, 0x78, 0x56, 0x00, 0x01, 0, 0,
+            0, 1, //
+            9, 0x02, 25, 0, 1, 1, 0, 0xe0, 0, //
+            9, 0x04, 0, 0, 1, 0x09, 0, 0, 0, //
+            7, 0x05, 0x81, 0x03, 1, 0, 12,
+        ]);
+        let power = Arc::new(Mutex::new(0x000f_u8));
+        let p = power.clone();
+        mock.on_control_in(move |req| match (req.recipient, req.request) {
+            (Recipient::Device, REQUEST_GET_DESCRIPTOR) if req.value == 0x2900 => {
+                Ok(vec![9, 0x29, 4, 0x89, 0x00, 50, 100, 0x00, 0xff])
+            }
+            (Recipient::Other, REQUEST_GET_STATUS) => {
+                let powered = *p.lock().unwrap() & (1 << (req.index - 1)) != 0;
+                Ok(vec![0, powered as u8, 0, 0])
+            }
+            _ => Err(TransferError::Stall),
+        });
+        let p = power.clone();
+        mock.on_control_out(move |req| {
+            let bit = 1 << (req.index - 1);
+            match (req.request, req.value) {
+                (REQUEST_SET_FEATURE, 8) => *p.lock().unwrap() |= bit,
+                (REQUEST_CLEAR_FEATURE, 8) => *p.lock().unwrap() &= !bit,
+                _ => return Err(TransferError::Stall),
+            }
+            Ok(())
+        });
+
+        let device = mock.device_info().open().wait().unwrap();
+        let hub = Hub::new(device, timeout).wait().unwrap();
+        assert_eq!(hub.descriptor().num_ports(), 4);
+
+        assert!(hub.port_status(2, timeout).wait().unwrap().powered());
+        hub.set_port_power(2, false, timeout).wait().unwrap();
+        assert_eq!(*power.lock().unwrap(), 0x0d);
+        assert!(!hub.port_status(2, timeout).wait().unwrap().powered());
+        hub.set_port_power(2, true, timeout).wait().unwrap();
+        assert!(hub.port_status(2, timeout).wait().unwrap().powered());
+
+        assert_eq!(hub.reset_port(1, timeout).wait(), Err(TransferError::Stall));
+    }
+}
@@ -178,26 +178,117 @@
 //!
 //! These features do not affect and are not required for transfers, which are
 //! implemented on top of natively-async OS APIs.
+//!
+//! ## Testing without hardware
+//!
+//! The [`replay`] module records a session with a real device and replays it
+//! later. With the `mock` cargo feature, the `mock` module provides virtual
+//! devices whose behavior is scripted by the test. In both cases, the code
+//! under test uses the same [`Device`], [`Interface`], and [`Endpoint`] types
+//! as with real hardware.
+//!
+//! The [`fault`] module injects stalls, disconnections and other errors into
+//! transfers on real or emulated devices to exercise error handling.
+//!
+//! Unlike `mock`, [`replay`] and [`fault`] are not behind a cargo feature.
+//! They are attached at runtime to a [`Device`] opened from real hardware, so
+//! a session can be recorded from the application as it is normally built. A
+//! device without a recorder or fault injector only pays for checking that
+//! none is attached.
+//!
+//! ## Reconnecting devices
+//!
+//! [`Device::reset_and_reopen`] and [`DeviceInfo::wait_for_reconnect`] find a
+//! device again after it resets or re-enumerates. For long-running programs,
+//! the [`managed`] module provides a handle that opens the device and claims
+//! its interfaces again each time it is plugged back in.
+//!
+//! ## Remote devices
+//!
+//! With the `usbip` cargo feature, the `usbip` module lists and opens devices
+//! exported by a USB/IP server over TCP, without the kernel `vhci` driver, and
+//! provides a server that exports local devices to USB/IP clients.
+//!
+//! ## Device side
+//!
+//! With the `gadget` cargo feature on Linux, the `gadget` module implements a
+//! USB function in userspace with FunctionFS, so a Linux board can act as the
+//! device that nusb talks to on the host side.
+//!
+//! ## Serialization
+//!
+//! With the `serde` cargo feature, [`DeviceInfo`], [`InterfaceInfo`],
+//! `BusInfo`, [`Speed`], [`UsbControllerType`], [`DeviceId`] and the owned
+//! descriptor types in [`descriptors`] such as
+//! [`descriptors::OwnedConfigurationDescriptor`] implement `Serialize` and
+//! `Deserialize`, for storing or sending device inventories. `DeviceInfo` is
+//! serialized with fields named after its accessors, like `vendor_id` and
+//! `serial_number`; see its documentation for the details.
 
 mod platform;
 
 pub mod descriptors;
 mod enumeration;
+#[cfg(feature = "serde")]
+mod serde_repr;
 #[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
 pub use enumeration::BusInfo;
-pub use enumeration::{DeviceId, DeviceInfo, InterfaceInfo, Speed, UsbControllerType};
+#[cfg(target_os = "linux")]
+pub use enumeration::{AuthorizedDefault, PowerControl, Removable, RuntimeStatus};
+#[cfg(any(target_os = "android", target_os = "linux"))]
+pub use enumeration::{Capabilities, ConnectionInfo};
+pub use enumeration::{
+    DeviceId, DeviceInfo, InterfaceInfo, ParsePortPathError, PortPath, Speed, UsbControllerType,
+};
+#[cfg(target_os = "linux")]
+pub use platform::FsRoots;
+
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
+mod filter;
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
+pub use filter::{DeviceFilter, ParseFilterError};
+
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
+mod reconnect;
+
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
+pub mod managed;
+
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
+mod tree;
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
+pub use tree::{UsbTree, UsbTreeBus, UsbTreeNode};
 
 mod device;
 pub use device::{Device, Endpoint, Interface};
 
 pub mod transfer;
 
+pub mod hub;
+
 #[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
 pub mod hotplug;
 
 mod maybe_future;
 pub use maybe_future::MaybeFuture;
 
+mod emulated;
+mod signal;
+mod timer;
+
+pub mod replay;
+
+pub mod fault;
+
+#[cfg(feature = "mock")]
+pub mod mock;
+
+#[cfg(feature = "usbip")]
+pub mod usbip;
+
+#[cfg(all(feature = "gadget", any(target_os = "linux", target_os = "android")))]
+pub mod gadget;
+
 mod bitset;
 
 pub mod io;
@@ -215,6 +306,8 @@ pub use error::{ActiveConfigurationError, Error, ErrorKind, GetDescriptorError};
 ///     .find(|dev| dev.vendor_id() == 0xAAAA && dev.product_id() == 0xBBBB)
 ///     .expect("device not connected");
 /// ```
+///
+/// See [`DeviceFilter`] for selecting devices by common criteria.
 #[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
 pub fn list_devices() -> impl MaybeFuture<Output = Result<impl Iterator<Item = DeviceInfo>, Error>>
 {
@@ -251,36 +344,58 @@ pub fn list_buses() -> impl MaybeFuture<Output = Result<impl Iterator<Item = Bus
 ///
 /// Events will be returned for devices connected or disconnected beginning at
 /// the time this function is called. To maintain a list of connected devices,
-/// call [`list_devices`] after creating the watch with this function to avoid
-/// potentially missing a newly-attached device:
+/// use [`HotplugWatch::with_initial_devices`][hotplug::HotplugWatch::with_initial_devices]
+/// to also receive a `Connected` event for each device that is already
+/// connected, without missing a newly-attached device:
 ///
 /// ## Example
 ///
 /// ```no_run
 /// use std::collections::HashMap;
-/// use nusb::{MaybeFuture, DeviceInfo, DeviceId, hotplug::HotplugEvent};
-/// let watch = nusb::watch_devices().unwrap();
-/// let mut devices: HashMap<DeviceId, DeviceInfo> = nusb::list_devices().wait().unwrap()
-///     .map(|d| (d.id(), d)).collect();
+/// use nusb::{DeviceInfo, DeviceId, hotplug::HotplugEvent};
+/// let watch = nusb::watch_devices().unwrap().with_initial_devices().unwrap();
+/// let mut devices: HashMap<DeviceId, DeviceInfo> = HashMap::new();
 /// for event in futures_lite::stream::block_on(watch) {
 ///     match event {
 ///         HotplugEvent::Connected(d) => {
 ///             devices.insert(d.id(), d);
 ///         }
-///         HotplugEvent::Disconnected(id) => {
+///         HotplugEvent::Disconnected(id, _) => {
 ///             devices.remove(&id);
 ///         }
+///         HotplugEvent::EnumerationComplete => {
+///             println!("{} devices connected", devices.len());
+///         }
+///         _ => {}
 ///     }
 /// }
 /// ```
 ///
 /// ### Platform-specific notes:
 ///
+///   * On Linux, events come from udevd if it is running. Without udevd, such
+///     as in containers, kernel uevents or inotify on `/dev/bus/usb` are used
+///     instead; see [`watch_devices_with_source`].
 ///   * On Windows, the interfaces of a composite device might not be ready
 ///     when the `Connected` event is emitted. If you are immediately opening the device
 ///     and claiming an interface when receiving a `Connected` event,
 ///     you should retry after a short delay if opening or claiming fails.
 #[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
 pub fn watch_devices() -> Result<hotplug::HotplugWatch, Error> {
-    Ok(hotplug::HotplugWatch(platform::HotplugWatch::new()?))
+    Ok(hotplug::HotplugWatch::new(platform::HotplugWatch::new()?))
+}
+
+/// *(Linux-only)* Get a [`Stream`][`futures_core::Stream`] of hotplug events
+/// from the specified source.
+///
+/// [`watch_devices`] uses [`HotplugSource::Auto`][hotplug::HotplugSource::Auto].
+/// Use this to choose a source explicitly on systems where the detection
+/// picks one that does not receive events.
+#[cfg(target_os = "linux")]
+pub fn watch_devices_with_source(
+    source: hotplug::HotplugSource,
+) -> Result<hotplug::HotplugWatch, Error> {
+    Ok(hotplug::HotplugWatch::new(
+        platform::HotplugWatch::with_source(platform::FsRoots::system(), source)?,
+    ))
 }
@@ -0,0 +1,837 @@
+//! Device handles that survive the device being unplugged and plugged back in.
+//!
+//! A [`ManagedDevice`] watches for hotplug events for a device matching a
+//! [`DeviceFilter`]. Whenever such a device connects, it is opened and the
+//! interfaces listed in the [`DeviceSetup`] are claimed and set to their
+//! alternate settings, from a background thread. When the device is
+//! unplugged, its pending transfers fail with
+//! [`TransferError::Disconnected`], as do other operations with
+//! [`ErrorKind::Disconnected`], until it is connected and set up again.
+//!
+//! [`ManagedEndpoint`] submits transfers to an endpoint of whichever device
+//! is currently connected, opening the endpoint again after a reconnection,
+//! and [`ConnectionStates`] reports each connection and disconnection.
+//!
+//! ### Example
+//!
+//! A data logger that keeps reading while the device comes and goes:
+//!
+//! ```no_run
+//! use std::time::Duration;
+//! use nusb::{
+//!     managed::{DeviceSetup, ManagedDevice},
+//!     transfer::{Buffer, Bulk, In},
+//!     DeviceFilter,
+//! };
+//!
+//! let setup = DeviceSetup::new(DeviceFilter::new().with_vendor_id(0xAAAA).with_product_id(0xBBBB))
+//!     .with_interface(0, 0);
+//! let device = ManagedDevice::start(setup).unwrap();
+//! let mut states = device.states();
+//! let mut ep = device.endpoint::<Bulk, In>(0, 0x81);
+//!
+//! loop {
+//!     while ep.pending() < 4 {
+//!         ep.submit(Buffer::new(512));
+//!     }
+//!     let c = ep.wait_next_complete(Duration::MAX).unwrap();
+//!     match c.status {
+//!         Ok(()) => println!("read {} bytes", c.actual_len),
+//!         Err(e) => {
+//!             println!("read failed: {e}");
+//!             // Wait for the device to be connected again
+//!             states.wait_next(Duration::from_secs(1));
+//!         }
+//!     }
+//! }
+//! ```
+
+use std::{
+    collections::VecDeque,
+    fmt::Debug,
+    future::{poll_fn, Future},
+    pin::Pin,
+    sync::{Arc, Mutex, Weak},
+    task::{Context, Poll, Waker},
+    thread,
+    time::{Duration, Instant},
+};
+
+use futures_core::Stream;
+use log::{debug, warn};
+
+use crate::{
+    hotplug::HotplugEvent,
+    maybe_future::{block_on, Either, Ready},
+    reconnect::RETRY_DELAY,
+    timer::Deadline,
+    transfer::{Buffer, BulkOrInterrupt, Completion, EndpointDirection, TransferError},
+    Device, DeviceFilter, DeviceId, DeviceInfo, Endpoint, Error, ErrorKind, Interface, MaybeFuture,
+};
+
+/// Number of times to try opening and setting up a newly connected device.
+const SETUP_ATTEMPTS: u32 = 10;
+
+type Events = Box<dyn Stream<Item = HotplugEvent> + Send + Unpin>;
+
+/// The device that a [`ManagedDevice`] looks for, and how to set it up once
+/// it is opened.
+#[derive(Debug, Clone)]
+pub struct DeviceSetup {
+    filter: DeviceFilter,
+    interfaces: Vec<(u8, u8)>,
+}
+
+impl DeviceSetup {
+    /// Manage the first connected device that matches `filter`, with no
+    /// interfaces claimed.
+    pub fn new(filter: DeviceFilter) -> DeviceSetup {
+        DeviceSetup {
+            filter,
+            interfaces: Vec::new(),
+        }
+    }
+
+    /// Claim `interface` and select `alt_setting` each time the device is
+    /// connected.
+    pub fn with_interface(mut self, interface: u8, alt_setting: u8) -> Self {
+        self.interfaces.retain(|&(i, _)| i != interface);
+        self.interfaces.push((interface, alt_setting));
+        self
+    }
+
+    fn open(&self, info: &DeviceInfo) -> Result<Connection, Error> {
+        let device = info.open().wait()?;
+        let mut interfaces = Vec::with_capacity(self.interfaces.len());
+        for &(number, alt_setting) in &self.interfaces {
+            let interface = device.claim_interface(number).wait()?;
+            if interface.get_alt_setting() != alt_setting {
+                interface.set_alt_setting(alt_setting).wait()?;
+            }
+            interfaces.push(interface);
+        }
+        Ok(Connection {
+            info: info.clone(),
+            device,
+            interfaces,
+        })
+    }
+}
+
+/// Whether a [`ManagedDevice`] currently has an opened device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ConnectionState {
+    /// No matching device is connected, or it could not be opened and set up.
+    Disconnected,
+
+    /// A matching device is connected, opened, and set up.
+    Connected,
+}
+
+/// A handle to a device that is opened again each time it is connected.
+///
+/// Create a `ManagedDevice` with [`ManagedDevice::start`].
+///
+/// This type is reference-counted with an [`Arc`] internally, and can be
+/// cloned cheaply. The device is no longer managed, and is closed, once all
+/// clones and the [`ManagedEndpoint`]s and [`ConnectionStates`] created from
+/// them are dropped.
+#[derive(Clone)]
+pub struct ManagedDevice {
+    handle: Arc<Handle>,
+}
+
+/// Stops the background thread when the last user-facing handle is dropped.
+struct Handle {
+    shared: Arc<Shared>,
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        self.shared.close();
+    }
+}
+
+struct Shared {
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    connection: Option<Connection>,
+
+    /// Incremented each time a device is connected, so that endpoints can
+    /// tell that they belong to a previous connection.
+    generation: u64,
+
+    closed: bool,
+    worker: Option<Waker>,
+    watchers: Vec<Weak<Mutex<Watcher>>>,
+}
+
+struct Connection {
+    info: DeviceInfo,
+    device: Device,
+    interfaces: Vec<Interface>,
+}
+
+#[derive(Default)]
+struct Watcher {
+    states: VecDeque<ConnectionState>,
+    waker: Option<Waker>,
+}
+
+impl ManagedDevice {
+    /// Start watching for the device described by `setup`.
+    ///
+    /// A device that is already connected is opened immediately, but this
+    /// returns before it has been set up, so the state is initially
+    /// [`ConnectionState::Disconnected`] even if the device is present.
+    ///
+    /// This blocks while listing the connected devices.
+    pub fn start(setup: DeviceSetup) -> Result<ManagedDevice, Error> {
+        let watch = crate::watch_devices()?.with_initial_devices()?;
+        Ok(ManagedDevice::with_events(setup, Box::new(watch)))
+    }
+
+    pub(crate) fn with_events(setup: DeviceSetup, events: Events) -> ManagedDevice {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State::default()),
+        });
+        let worker = shared.clone();
+        thread::Builder::new()
+            .name("nusb-managed".into())
+            .spawn(move || worker.run(setup, events))
+            .expect("failed to spawn managed device thread");
+        ManagedDevice {
+            handle: Arc::new(Handle { shared }),
+        }
+    }
+
+    fn shared(&self) -> &Shared {
+        &self.handle.shared
+    }
+
+    /// Get whether the device is currently connected and set up.
+    pub fn state(&self) -> ConnectionState {
+        self.shared().state.lock().unwrap().connection_state()
+    }
+
+    /// Get a [`Stream`] of changes to the [`ConnectionState`], beginning
+    /// with the current state.
+    pub fn states(&self) -> ConnectionStates {
+        let watcher = Arc::new(Mutex::new(Watcher::default()));
+        let mut state = self.shared().state.lock().unwrap();
+        let current = state.connection_state();
+        watcher.lock().unwrap().states.push_back(current);
+        state.watchers.push(Arc::downgrade(&watcher));
+        ConnectionStates {
+            _handle: self.handle.clone(),
+            watcher,
+        }
+    }
+
+    /// Get the information for the currently connected device.
+    pub fn device_info(&self) -> Option<DeviceInfo> {
+        let state = self.shared().state.lock().unwrap();
+        state.connection.as_ref().map(|c| c.info.clone())
+    }
+
+    /// Get the currently connected device.
+    ///
+    /// Fails with [`ErrorKind::Disconnected`] if the device is not connected.
+    pub fn device(&self) -> Result<Device, Error> {
+        self.shared().connection(|c, _| Ok(c.device.clone()))
+    }
+
+    /// Get an interface of the currently connected device.
+    ///
+    /// Fails with [`ErrorKind::Disconnected`] if the device is not connected,
+    /// or [`ErrorKind::NotFound`] if `interface` was not listed in the
+    /// [`DeviceSetup`].
+    pub fn interface(&self, interface: u8) -> Result<Interface, Error> {
+        self.shared()
+            .connection(|c, _| c.interface(interface).cloned())
+    }
+
+    /// Get a handle for submitting transfers to an endpoint of `interface`,
+    /// which must be listed in the [`DeviceSetup`].
+    ///
+    /// The endpoint is opened when the first transfer is submitted, and
+    /// again after each reconnection.
+    pub fn endpoint<EpType: BulkOrInterrupt, Dir: EndpointDirection>(
+        &self,
+        interface: u8,
+        address: u8,
+    ) -> ManagedEndpoint<EpType, Dir> {
+        ManagedEndpoint {
+            handle: self.handle.clone(),
+            interface,
+            address,
+            queue: VecDeque::new(),
+        }
+    }
+}
+
+impl Debug for ManagedDevice {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ManagedDevice")
+            .field("state", &self.state())
+            .finish()
+    }
+}
+
+impl State {
+    fn connection_state(&self) -> ConnectionState {
+        match self.connection {
+            Some(_) => ConnectionState::Connected,
+            None => ConnectionState::Disconnected,
+        }
+    }
+}
+
+impl Connection {
+    fn interface(&self, interface: u8) -> Result<&Interface, Error> {
+        self.interfaces
+            .iter()
+            .find(|i| i.interface_number() == interface)
+            .ok_or(Error::new(
+                ErrorKind::NotFound,
+                "interface is not claimed by the managed device",
+            ))
+    }
+}
+
+impl Shared {
+    /// Call `f` with the current connection and its generation.
+    fn connection<T>(
+        &self,
+        f: impl FnOnce(&Connection, u64) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let state = self.state.lock().unwrap();
+        match &state.connection {
+            Some(c) => f(c, state.generation),
+            None => Err(Error::new(
+                ErrorKind::Disconnected,
+                "managed device is not connected",
+            )),
+        }
+    }
+
+    fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        let worker = state.worker.take();
+        drop(state);
+        if let Some(waker) = worker {
+            waker.wake();
+        }
+    }
+
+    /// Replace the connection and notify the watchers of the new state.
+    fn set_connection(&self, connection: Option<Connection>) {
+        let mut state = self.state.lock().unwrap();
+        if connection.is_some() {
+            state.generation += 1;
+        }
+        let old = std::mem::replace(&mut state.connection, connection);
+        let current = state.connection_state();
+        state.watchers.retain(|w| {
+            let Some(watcher) = w.upgrade() else {
+                return false;
+            };
+            let mut watcher = watcher.lock().unwrap();
+            watcher.states.push_back(current);
+            if let Some(waker) = watcher.waker.take() {
+                waker.wake();
+            }
+            true
+        });
+        drop(state);
+
+        // Release the interfaces and close the old device outside the lock
+        drop(old);
+    }
+
+    fn connected_id(&self) -> Option<DeviceId> {
+        let state = self.state.lock().unwrap();
+        state.connection.as_ref().map(|c| c.info.id())
+    }
+
+    fn poll_event(&self, events: &mut Events, cx: &mut Context<'_>) -> Poll<Option<HotplugEvent>> {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return Poll::Ready(None);
+        }
+        state.worker = Some(cx.waker().clone());
+        drop(state);
+        Pin::new(events).poll_next(cx)
+    }
+
+    /// Background thread that opens the device as it is connected.
+    ///
+    /// A device that fails to be set up is tried again after `RETRY_DELAY`,
+    /// in case its device node is not yet accessible right after it was
+    /// connected. The retries are timed from the event loop, so that hotplug
+    /// events and `close()` are still handled in the meantime.
+    fn run(self: Arc<Self>, setup: DeviceSetup, mut events: Events) {
+        // Matching devices that are connected, in the order they were seen,
+        // and the number of times each has failed to be set up.
+        let mut candidates: Vec<(DeviceInfo, u32)> = Vec::new();
+
+        // When to try again to set up the candidates that failed.
+        let mut retry: Option<Deadline> = None;
+
+        loop {
+            // `None` if woken up to retry rather than by an event
+            let event = block_on(poll_fn(|cx| {
+                if let Poll::Ready(event) = self.poll_event(&mut events, cx) {
+                    return Poll::Ready(Some(event));
+                }
+                match &mut retry {
+                    Some(deadline) => deadline.poll(cx).map(|()| None),
+                    None => Poll::Pending,
+                }
+            }));
+            match event {
+                Some(Some(HotplugEvent::Connected(info))) if setup.filter.matches(&info) => {
+                    candidates.push((info, 0));
+                }
+                Some(Some(HotplugEvent::Disconnected(id, _))) => {
+                    candidates.retain(|(c, _)| c.id() != id);
+                    if self.connected_id() == Some(id) {
+                        debug!("Managed device {id:?} disconnected");
+                        self.set_connection(None);
+                    }
+                }
+                Some(Some(_)) => continue,
+                Some(None) => break,
+                None => {}
+            }
+
+            retry = None;
+            if self.connected_id().is_some() {
+                continue;
+            }
+            for (info, failures) in &mut candidates {
+                if *failures >= SETUP_ATTEMPTS {
+                    continue;
+                }
+                match setup.open(info) {
+                    Ok(connection) => {
+                        debug!("Managed device {:?} connected", info.id());
+                        self.set_connection(Some(connection));
+                        break;
+                    }
+                    Err(e) => {
+                        *failures += 1;
+                        if e.kind() == ErrorKind::Disconnected || *failures == SETUP_ATTEMPTS {
+                            warn!("Failed to set up managed device {:?}: {e}", info.id());
+                            *failures = SETUP_ATTEMPTS;
+                        } else {
+                            debug!(
+                                "Failed to set up managed device {:?}, retrying: {e}",
+                                info.id()
+                            );
+                            retry = Some(Deadline::new(Instant::now() + RETRY_DELAY));
+                        }
+                    }
+                }
+            }
+        }
+
+        self.set_connection(None);
+    }
+}
+
+/// Stream of [`ConnectionState`] changes of a [`ManagedDevice`].
+///
+/// Obtain a `ConnectionStates` with [`ManagedDevice::states`]. Each change is
+/// returned once, in order; a device that reconnects is reported as
+/// `Disconnected` followed by `Connected`. The stream does not end while the
+/// `ConnectionStates` keeps the device managed.
+pub struct ConnectionStates {
+    _handle: Arc<Handle>,
+    watcher: Arc<Mutex<Watcher>>,
+}
+
+impl ConnectionStates {
+    /// Wait for the next state change.
+    ///
+    /// Blocks for up to `timeout`, or returns `None` if the timeout is
+    /// reached.
+    pub fn wait_next(&mut self, timeout: Duration) -> Option<ConnectionState> {
+        let mut deadline = Instant::now().checked_add(timeout).map(Deadline::new);
+        block_on(poll_fn(|cx| {
+            if let Poll::Ready(state) = self.poll_state(cx) {
+                return Poll::Ready(Some(state));
+            }
+            match &mut deadline {
+                Some(deadline) => deadline.poll(cx).map(|()| None),
+                None => Poll::Pending,
+            }
+        }))
+    }
+
+    fn poll_state(&mut self, cx: &mut Context<'_>) -> Poll<ConnectionState> {
+        let mut watcher = self.watcher.lock().unwrap();
+        match watcher.states.pop_front() {
+            Some(state) => Poll::Ready(state),
+            None => {
+                watcher.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Stream for ConnectionStates {
+    type Item = ConnectionState;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.poll_state(cx).map(Some)
+    }
+}
+
+impl Debug for ConnectionStates {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ConnectionStates").finish_non_exhaustive()
+    }
+}
+
+/// A bulk or interrupt endpoint of a [`ManagedDevice`].
+///
+/// Obtain a `ManagedEndpoint` with [`ManagedDevice::endpoint`].
+///
+/// Transfers are submitted and completed as with [`Endpoint`]. Each
+/// transfer is submitted to the device that is connected at the time, and
+/// transfers submitted while the device is not connected complete
+/// immediately with [`TransferError::Disconnected`]. Completions are
+/// returned in the order the transfers were submitted, across
+/// reconnections.
+pub struct ManagedEndpoint<EpType, Dir> {
+    handle: Arc<Handle>,
+    interface: u8,
+    address: u8,
+    queue: VecDeque<Queued<EpType, Dir>>,
+}
+
+enum Queued<EpType, Dir> {
+    /// Endpoint opened on the connection with the given generation.
+    Endpoint(u64, Endpoint<EpType, Dir>),
+
+    /// Transfer that could not be submitted.
+    Failed(Completion),
+}
+
+impl<EpType: BulkOrInterrupt, Dir: EndpointDirection> ManagedEndpoint<EpType, Dir> {
+    /// Get the endpoint address.
+    pub fn endpoint_address(&self) -> u8 {
+        self.address
+    }
+
+    /// Get the number of transfers that have been submitted with `submit`
+    /// that have not yet been returned from `next_complete`.
+    pub fn pending(&self) -> usize {
+        self.queue
+            .iter()
+            .map(|q| match q {
+                Queued::Endpoint(_, ep) => ep.pending(),
+                Queued::Failed(_) => 1,
+            })
+            .sum()
+    }
+
+    /// Request cancellation of all pending transfers.
+    pub fn cancel_all(&mut self) {
+        for q in &mut self.queue {
+            if let Queued::Endpoint(_, ep) = q {
+                ep.cancel_all();
+            }
+        }
+    }
+
+    /// Get the endpoint on the current connection, opening it if necessary.
+    fn current(&mut self) -> Result<&mut Endpoint<EpType, Dir>, Error> {
+        let (generation, interface) = self
+            .handle
+            .shared
+            .connection(|c, generation| Ok((generation, c.interface(self.interface)?.clone())))?;
+        let is_current =
+            matches!(self.queue.back(), Some(Queued::Endpoint(g, _)) if *g == generation);
+        if !is_current {
+            let ep = interface.endpoint(self.address)?;
+            self.queue.push_back(Queued::Endpoint(generation, ep));
+        }
+        match self.queue.back_mut() {
+            Some(Queued::Endpoint(_, ep)) => Ok(ep),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Begin a transfer on the endpoint of the currently connected device.
+    ///
+    /// See [`Endpoint::submit`]. If the device is not connected, or the
+    /// endpoint can't be opened, the transfer fails with
+    /// [`TransferError::Disconnected`] or
+    /// [`TransferError::InvalidArgument`] respectively.
+    pub fn submit(&mut self, buf: Buffer) {
+        match self.current() {
+            Ok(ep) => ep.submit(buf),
+            Err(e) => {
+                let status = if e.kind() == ErrorKind::Disconnected {
+                    TransferError::Disconnected
+                } else {
+                    warn!("Failed to open managed endpoint {:02x}: {e}", self.address);
+                    TransferError::InvalidArgument
+                };
+                self.queue.push_back(Queued::Failed(Completion {
+                    buffer: buf,
+                    actual_len: 0,
+                    status: Err(status),
+                    iso_status: Vec::new(),
+                }));
+            }
+        }
+    }
+
+    /// Remove endpoints of previous connections that have no transfers left.
+    fn pop_idle(&mut self) {
+        while self.queue.len() > 1
+            && matches!(self.queue.front(), Some(Queued::Endpoint(_, ep)) if ep.pending() == 0)
+        {
+            self.queue.pop_front();
+        }
+    }
+
+    /// Return a `Future` that waits for the next pending transfer to complete.
+    ///
+    /// See [`Endpoint::next_complete`].
+    ///
+    /// ## Panics
+    /// * if there are no transfers pending (that is, if [`Self::pending()`]
+    ///   would return 0).
+    pub fn next_complete(&mut self) -> impl Future<Output = Completion> + Send + Sync + '_ {
+        poll_fn(|cx| self.poll_next_complete(cx))
+    }
+
+    /// Poll for a pending transfer completion.
+    ///
+    /// ## Panics
+    ///  * if there are no transfers pending (that is, if [`Self::pending()`]
+    ///    would return 0).
+    pub fn poll_next_complete(&mut self, cx: &mut Context<'_>) -> Poll<Completion> {
+        self.pop_idle();
+        match self.queue.front_mut() {
+            Some(Queued::Endpoint(_, ep)) => ep.poll_next_complete(cx),
+            Some(Queued::Failed(_)) => match self.queue.pop_front() {
+                Some(Queued::Failed(c)) => Poll::Ready(c),
+                _ => unreachable!(),
+            },
+            None => panic!("no transfers pending"),
+        }
+    }
+
+    /// Wait for a pending transfer completion.
+    ///
+    /// Blocks for up to `timeout` waiting for a transfer to complete, or
+    /// returns `None` if the timeout is reached.
+    ///
+    /// ## Panics
+    ///  * if there are no transfers pending (that is, if [`Self::pending()`]
+    ///    would return 0).
+    pub fn wait_next_complete(&mut self, timeout: Duration) -> Option<Completion> {
+        self.pop_idle();
+        match self.queue.front_mut() {
+            Some(Queued::Endpoint(_, ep)) => ep.wait_next_complete(timeout),
+            Some(Queued::Failed(_)) => match self.queue.pop_front() {
+                Some(Queued::Failed(c)) => Some(c),
+                _ => unreachable!(),
+            },
+            None => panic!("no transfers pending"),
+        }
+    }
+
+    /// Clear the halt / stall condition of the endpoint on the currently
+    /// connected device.
+    ///
+    /// See [`Endpoint::clear_halt`].
+    pub fn clear_halt(&mut self) -> impl MaybeFuture<Output = Result<(), Error>> {
+        match self.current() {
+            Ok(ep) => Either::Left(ep.clear_halt()),
+            Err(e) => Either::Right(Ready(Err(e))),
+        }
+    }
+}
+
+impl<EpType: BulkOrInterrupt, Dir: EndpointDirection> Debug for ManagedEndpoint<EpType, Dir> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ManagedEndpoint")
+            .field("interface", &self.interface)
+            .field("address", &format_args!("0x{:02x}", self.address))
+            .field("pending", &self.pending())
+            .finish()
+    }
+}
+
+#[cfg(all(test, feature = "mock"))]
+mod tests {
+    use super::*;
+    use crate::{
+        mock::MockDevice,
+        transfer::{Bulk, In},
+    };
+
+    const DESCRIPTORS: &[u8] = &[
+        0x12, 0x01, 0x00, 0x02, 0xff, 0x00, 0x00, 0x40, 0xaa, 0xaa, 0x55, 0x55, 0x00, 0x01, 0x00,
+        0x00, 0x00, 0x01, 0x09, 0x02, 0x20, 0x00, 0x01, 0x01, 0x00, 0x80, 0x32, 0x09, 0x04, 0x00,
+        0x00, 0x02, 0xff, 0x00, 0x00, 0x00, 0x07, 0x05, 0x81, 0x02, 0x00, 0x02, 0x00, 0x07, 0x05,
+        0x02, 0x02, 0x00, 0x02, 0x00,
+    ];
+
+    const TIMEOUT: Duration = Duration::from_millis(1000);
+
+    /// Hotplug events sent by the test.
+    #[derive(Clone, Default)]
+    struct TestEvents(Arc<Mutex<(VecDeque<HotplugEvent>, Option<Waker>)>>);
+
+    impl TestEvents {
+        fn send(&self, event: HotplugEvent) {
+            let mut inner = self.0.lock().unwrap();
+            inner.0.push_back(event);
+            if let Some(waker) = inner.1.take() {
+                waker.wake();
+            }
+        }
+    }
+
+    impl Stream for TestEvents {
+        type Item = HotplugEvent;
+
+        fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<HotplugEvent>> {
+            let mut inner = self.0.lock().unwrap();
+            match inner.0.pop_front() {
+                Some(event) => Poll::Ready(Some(event)),
+                None => {
+                    inner.1 = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            }
+        }
+    }
+
+    fn read(ep: &mut ManagedEndpoint<Bulk, In>) -> Result<Vec<u8>, TransferError> {
+        ep.submit(Buffer::new(512));
+        let c = ep.wait_next_complete(TIMEOUT).unwrap();
+        c.status.map(|()| c.buffer.to_vec())
+    }
+
+    #[test]
+    fn reconnect() {
+        let events = TestEvents::default();
+        let setup =
+            DeviceSetup::new(DeviceFilter::new().with_vendor_id(0xaaaa)).with_interface(0, 0);
+        let managed = ManagedDevice::with_events(setup, Box::new(events.clone()));
+        let mut states = managed.states();
+        let mut ep = managed.endpoint::<Bulk, In>(0, 0x81);
+        assert_eq!(
+            states.wait_next(TIMEOUT),
+            Some(ConnectionState::Disconnected)
+        );
+        assert_eq!(read(&mut ep), Err(TransferError::Disconnected));
+
+        let mock = MockDevice::new(DESCRIPTORS);
+        let info = mock.device_info();
+        events.send(HotplugEvent::Connected(info.clone()));
+        assert_eq!(states.wait_next(TIMEOUT), Some(ConnectionState::Connected));
+        assert_eq!(managed.device_info().unwrap().id(), info.id());
+        mock.push_in(0x81, b"one".to_vec());
+        assert_eq!(read(&mut ep).unwrap(), b"one");
+        assert_eq!(
+            managed.interface(1).unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+
+        // A pending transfer fails when the device is unplugged, as do
+        // transfers submitted before it is back.
+        ep.submit(Buffer::new(512));
+        let mock = mock.replug();
+        events.send(HotplugEvent::Disconnected(info.id(), None));
+        assert_eq!(
+            states.wait_next(TIMEOUT),
+            Some(ConnectionState::Disconnected)
+        );
+        assert_eq!(
+            managed.device().unwrap_err().kind(),
+            ErrorKind::Disconnected
+        );
+        ep.submit(Buffer::new(512));
+        assert_eq!(ep.pending(), 2);
+        for _ in 0..2 {
+            let c = ep.wait_next_complete(TIMEOUT).unwrap();
+            assert_eq!(c.status, Err(TransferError::Disconnected));
+        }
+
+        events.send(HotplugEvent::Connected(mock.device_info()));
+        assert_eq!(states.wait_next(TIMEOUT), Some(ConnectionState::Connected));
+        assert!(managed.interface(0).is_ok());
+        mock.push_in(0x81, b"two".to_vec());
+        assert_eq!(read(&mut ep).unwrap(), b"two");
+        assert_eq!(ep.pending(), 0);
+        assert_eq!(states.wait_next(Duration::ZERO), None);
+    }
+
+    #[test]
+    fn retry_setup() {
+        let events = TestEvents::default();
+        let setup =
+            DeviceSetup::new(DeviceFilter::new().with_vendor_id(0xaaaa)).with_interface(0, 0);
+        let managed = ManagedDevice::with_events(setup, Box::new(events.clone()));
+        let mut states = managed.states();
+        assert_eq!(
+            states.wait_next(TIMEOUT),
+            Some(ConnectionState::Disconnected)
+        );
+
+        // Claiming the interface of an unconfigured device fails, and is
+        // retried until the device is configured.
+        let mock = MockDevice::new(DESCRIPTORS);
+        let info = mock.device_info();
+        let device = info.open().wait().unwrap();
+        device.set_configuration(0).wait().unwrap();
+        events.send(HotplugEvent::Connected(info.clone()));
+        assert_eq!(states.wait_next(RETRY_DELAY * 2), None);
+        device.set_configuration(1).wait().unwrap();
+        assert_eq!(states.wait_next(TIMEOUT), Some(ConnectionState::Connected));
+
+        // Events are still handled while a device is being retried
+        let unconfigured = MockDevice::new(DESCRIPTORS);
+        let device = unconfigured.device_info().open().wait().unwrap();
+        device.set_configuration(0).wait().unwrap();
+        let other = MockDevice::new(DESCRIPTORS).device_info();
+        events.send(HotplugEvent::Disconnected(info.id(), None));
+        events.send(HotplugEvent::Connected(unconfigured.device_info()));
+        assert_eq!(
+            states.wait_next(TIMEOUT),
+            Some(ConnectionState::Disconnected)
+        );
+        events.send(HotplugEvent::Connected(other.clone()));
+        assert_eq!(
+            states.wait_next(RETRY_DELAY * 3),
+            Some(ConnectionState::Connected)
+        );
+        assert_eq!(managed.device_info().unwrap().id(), other.id());
+    }
+}
+
+#[test]
+fn assert_send_sync() {
+    use crate::transfer::{Bulk, In, Interrupt, Out};
+
+    fn require_send_sync<T: Send + Sync>() {}
+    require_send_sync::<ManagedDevice>();
+    require_send_sync::<ConnectionStates>();
+    require_send_sync::<ManagedEndpoint<Bulk, In>>();
+    require_send_sync::<ManagedEndpoint<Interrupt, Out>>();
+}
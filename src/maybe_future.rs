@@ -140,6 +140,35 @@ pub mod blocking {
     }
 }
 
+/// Run a future that does not depend on an async runtime to completion on the
+/// current thread.
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
+pub(crate) fn block_on<F: Future>(fut: F) -> F::Output {
+    use std::{
+        sync::Arc,
+        task::{Wake, Waker},
+        thread::{self, Thread},
+    };
+
+    struct ThreadWaker(Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    let mut fut = std::pin::pin!(fut);
+    loop {
+        if let Poll::Ready(r) = fut.as_mut().poll(&mut cx) {
+            return r;
+        }
+        thread::park();
+    }
+}
+
 pub(crate) struct Ready<T>(pub(crate) T);
 
 impl<T> IntoFuture for Ready<T> {
@@ -202,3 +231,74 @@ impl<F: Future, T: FnOnce(F::Output) -> R, R> Future for MapFut<F, T> {
         })
     }
 }
+
+/// One of two `MaybeFuture`s or iterators with the same output, used where a
+/// handle can be backed by either the OS or an emulated device.
+pub enum Either<A, B> {
+    Left(A),
+    Right(B),
+}
+
+impl<A, B> IntoFuture for Either<A, B>
+where
+    A: MaybeFuture,
+    B: MaybeFuture<Output = A::Output>,
+{
+    type Output = A::Output;
+    type IntoFuture = EitherFut<A::IntoFuture, B::IntoFuture>;
+
+    fn into_future(self) -> Self::IntoFuture {
+        match self {
+            Either::Left(a) => EitherFut::Left(a.into_future()),
+            Either::Right(b) => EitherFut::Right(b.into_future()),
+        }
+    }
+}
+
+impl<A, B> MaybeFuture for Either<A, B>
+where
+    A: MaybeFuture,
+    B: MaybeFuture<Output = A::Output>,
+{
+    #[cfg(not(target_arch = "wasm32"))]
+    fn wait(self) -> Self::Output {
+        match self {
+            Either::Left(a) => a.wait(),
+            Either::Right(b) => b.wait(),
+        }
+    }
+}
+
+impl<A, B> Iterator for Either<A, B>
+where
+    A: Iterator,
+    B: Iterator<Item = A::Item>,
+{
+    type Item = A::Item;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            Either::Left(a) => a.next(),
+            Either::Right(b) => b.next(),
+        }
+    }
+}
+
+pub enum EitherFut<A, B> {
+    Left(A),
+    Right(B),
+}
+
+impl<A: Future, B: Future<Output = A::Output>> Future for EitherFut<A, B> {
+    type Output = A::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // SAFETY: structural pin projection: the active variant is never moved.
+        unsafe {
+            match self.get_unchecked_mut() {
+                EitherFut::Left(a) => Pin::new_unchecked(a).poll(cx),
+                EitherFut::Right(b) => Pin::new_unchecked(b).poll(cx),
+            }
+        }
+    }
+}
@@ -0,0 +1,897 @@
+//! In-process mock device for testing code built on `nusb` without hardware.
+//!
+//! This module requires the `mock` cargo feature.
+//!
+//! A [`MockDevice`] is created from the device's descriptors and provides a
+//! [`DeviceInfo`] that opens it. The resulting [`Device`][crate::Device],
+//! [`Interface`][crate::Interface] and [`Endpoint`][crate::Endpoint] are the
+//! same types used with real hardware, so the code under test doesn't need
+//! to be generic over the backend.
+//!
+//! The test controls the device's side of the conversation:
+//!
+//!  * Control requests are passed to handlers registered with
+//!    [`MockDevice::on_control_in`] and [`MockDevice::on_control_out`].
+//!    Standard `GET_DESCRIPTOR` requests for the device, configuration and
+//!    string descriptors are answered automatically.
+//!  * Data for IN endpoints is queued with [`MockDevice::push_in`]. IN
+//!    transfers submitted with no queued data stay pending until data arrives
+//!    or they are cancelled, like a real device with nothing to send.
+//!  * Data written to OUT endpoints is collected for [`MockDevice::pop_out`],
+//!    or passed to a handler registered with [`MockDevice::on_out`].
+//!  * [`MockDevice::stall`], [`MockDevice::set_unresponsive`] and
+//!    [`MockDevice::disconnect`] simulate the corresponding failures, and
+//!    [`MockDevice::replug`] simulates the device being plugged back in.
+//!
+//! Claiming interfaces and selecting configurations and alternate settings
+//! are validated against the descriptors.
+//!
+//! ### Example
+//!
+//! ```
+//! use std::time::Duration;
+//! use nusb::{
+//!     mock::MockDevice,
+//!     transfer::{Buffer, Bulk, ControlIn, ControlType, In, Out, Recipient},
+//!     MaybeFuture,
+//! };
+//!
+//! const DESCRIPTORS: &[u8] = &[
+//!     // device
+//!     0x12, 0x01, 0x00, 0x02, 0xff, 0x00, 0x00, 0x40, 0xaa, 0xaa, 0x55, 0x55,
+//!     0x00, 0x01, 0x00, 0x00, 0x00, 0x01,
+//!     // configuration 1
+//!     0x09, 0x02, 0x20, 0x00, 0x01, 0x01, 0x00, 0x80, 0x32,
+//!     // interface 0 with bulk endpoints 0x81 and 0x02
+//!     0x09, 0x04, 0x00, 0x00, 0x02, 0xff, 0x00, 0x00, 0x00,
+//!     0x07, 0x05, 0x81, 0x02, 0x00, 0x02, 0x00,
+//!     0x07, 0x05, 0x02, 0x02, 0x00, 0x02, 0x00,
+//! ];
+//!
+//! let mock = MockDevice::new(DESCRIPTORS);
+//! mock.on_control_in(|req| Ok(vec![req.request; req.length as usize]));
+//!
+//! let device = mock.device_info().open().wait().unwrap();
+//! let version = device.control_in(ControlIn {
+//!     control_type: ControlType::Vendor,
+//!     recipient: Recipient::Device,
+//!     request: 0x42,
+//!     value: 0,
+//!     index: 0,
+//!     length: 2,
+//! }, Duration::from_millis(100)).wait().unwrap();
+//! assert_eq!(version, [0x42, 0x42]);
+//!
+//! let interface = device.claim_interface(0).wait().unwrap();
+//!
+//! let mut ep_out = interface.endpoint::<Bulk, Out>(0x02).unwrap();
+//! ep_out.submit(b"ping".to_vec().into());
+//! ep_out.wait_next_complete(Duration::from_millis(100)).unwrap().status.unwrap();
+//! assert_eq!(mock.pop_out(0x02).unwrap(), b"ping");
+//!
+//! let mut ep_in = interface.endpoint::<Bulk, In>(0x81).unwrap();
+//! mock.push_in(0x81, b"pong".to_vec());
+//! ep_in.submit(Buffer::new(512));
+//! let c = ep_in.wait_next_complete(Duration::from_millis(100)).unwrap();
+//! assert_eq!(&c.buffer[..], b"pong");
+//! ```
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    mem,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+use crate::{
+    descriptors::{
+        language_id::US_ENGLISH, parse_concatenated_config_descriptors, ConfigurationDescriptor,
+        DeviceDescriptor, TransferType, DESCRIPTOR_LEN_DEVICE, DESCRIPTOR_TYPE_CONFIGURATION,
+        DESCRIPTOR_TYPE_DEVICE, DESCRIPTOR_TYPE_STRING,
+    },
+    emulated::{Completer, Model, Request},
+    signal::Signal,
+    transfer::{
+        parse_request_type, ControlIn, ControlOut, ControlType, Direction, Recipient, TransferError,
+    },
+    DeviceInfo, Error, ErrorKind, Speed,
+};
+
+/// Number of UTF-16 code units that fit in a string descriptor, whose length
+/// is a single byte including the 2-byte header.
+const MAX_STRING_LEN: usize = 126;
+
+type ControlInHandler = dyn FnMut(ControlIn) -> Result<Vec<u8>, TransferError> + Send;
+type ControlOutHandler = dyn FnMut(ControlOut) -> Result<(), TransferError> + Send;
+type OutHandler = dyn FnMut(&[u8]) -> Result<(), TransferError> + Send;
+
+/// A virtual USB device for use in tests.
+///
+/// `MockDevice` can be cloned cheaply, and all clones control the same
+/// device. It remains usable while the [`Device`][crate::Device] opened from
+/// it is in use, so the test can feed data and inject failures from the same
+/// or another thread.
+///
+/// See the [module documentation][self] for an example.
+#[derive(Clone)]
+pub struct MockDevice {
+    model: Arc<MockModel>,
+}
+
+struct MockModel {
+    descriptors: Vec<u8>,
+    state: Mutex<MockState>,
+    unplugged: Signal,
+}
+
+struct MockState {
+    speed: Option<Speed>,
+    configuration: u8,
+    alt_settings: BTreeMap<u8, u8>,
+    strings: BTreeMap<u8, String>,
+    control_in: Option<Arc<Mutex<ControlInHandler>>>,
+    control_out: Option<Arc<Mutex<ControlOutHandler>>>,
+    endpoints: BTreeMap<u8, EndpointState>,
+    unresponsive: bool,
+    disconnected: bool,
+
+    /// Transfers submitted while unresponsive.
+    held: Vec<Completer>,
+}
+
+#[derive(Default)]
+struct EndpointState {
+    halted: bool,
+    in_queue: VecDeque<Result<Vec<u8>, TransferError>>,
+    out_queue: VecDeque<Vec<u8>>,
+    out_handler: Option<Arc<Mutex<OutHandler>>>,
+
+    /// IN transfers waiting for data.
+    waiting: VecDeque<Completer>,
+}
+
+impl MockDevice {
+    /// Create a mock device from its device descriptor followed by its
+    /// configuration descriptors.
+    ///
+    /// The device starts out in the first configuration, as if it had been
+    /// configured by the OS, and reports [`Speed::High`].
+    ///
+    /// ### Panics
+    /// * if `descriptors` does not start with a valid device descriptor.
+    pub fn new(descriptors: impl Into<Vec<u8>>) -> MockDevice {
+        let descriptors = descriptors.into();
+        assert!(
+            DeviceDescriptor::new(&descriptors).is_some(),
+            "invalid device descriptor"
+        );
+        let configuration =
+            parse_concatenated_config_descriptors(&descriptors[DESCRIPTOR_LEN_DEVICE as usize..])
+                .next()
+                .map_or(0, |c| c.configuration_value());
+
+        MockDevice {
+            model: Arc::new(MockModel {
+                descriptors,
+                state: Mutex::new(MockState {
+                    speed: Some(Speed::High),
+                    configuration,
+                    alt_settings: BTreeMap::new(),
+                    strings: BTreeMap::new(),
+                    control_in: None,
+                    control_out: None,
+                    endpoints: BTreeMap::new(),
+                    unresponsive: false,
+                    disconnected: false,
+                    held: Vec::new(),
+                }),
+                unplugged: Signal::new(),
+            }),
+        }
+    }
+
+    /// Set the connection speed reported by the device.
+    pub fn with_speed(self, speed: Option<Speed>) -> Self {
+        self.model.state.lock().unwrap().speed = speed;
+        self
+    }
+
+    /// Set the string descriptor returned for `index`.
+    ///
+    /// Strings are served in US English only. A string descriptor holds at
+    /// most 126 UTF-16 code units, and longer strings are truncated to fit.
+    pub fn with_string(self, index: u8, string: &str) -> Self {
+        let mut len = 0;
+        let string = string
+            .chars()
+            .take_while(|c| {
+                len += c.len_utf16();
+                len <= MAX_STRING_LEN
+            })
+            .collect();
+        self.model
+            .state
+            .lock()
+            .unwrap()
+            .strings
+            .insert(index, string);
+        self
+    }
+
+    /// Get a [`DeviceInfo`] that opens this device.
+    ///
+    /// Each call returns a `DeviceInfo` with a distinct
+    /// [`DeviceId`][crate::DeviceId], as if the device had been reconnected.
+    pub fn device_info(&self) -> DeviceInfo {
+        DeviceInfo::emulated(self.model.clone())
+    }
+
+    /// Set the handler for control IN requests.
+    ///
+    /// The handler returns the data for the data stage, which is truncated to
+    /// the requested length, or an error such as [`TransferError::Stall`].
+    /// Without a handler, requests other than standard `GET_DESCRIPTOR` stall.
+    pub fn on_control_in(
+        &self,
+        handler: impl FnMut(ControlIn) -> Result<Vec<u8>, TransferError> + Send + 'static,
+    ) {
+        self.model.state.lock().unwrap().control_in = Some(Arc::new(Mutex::new(handler)));
+    }
+
+    /// Set the handler for control OUT requests.
+    ///
+    /// Without a handler, all control OUT requests stall.
+    pub fn on_control_out(
+        &self,
+        handler: impl FnMut(ControlOut) -> Result<(), TransferError> + Send + 'static,
+    ) {
+        self.model.state.lock().unwrap().control_out = Some(Arc::new(Mutex::new(handler)));
+    }
+
+    /// Set the handler for data written to an OUT endpoint.
+    ///
+    /// The handler is called with the data of each transfer as it is
+    /// submitted. While a handler is set, data is not collected for
+    /// [`pop_out`][Self::pop_out].
+    pub fn on_out(
+        &self,
+        endpoint: u8,
+        handler: impl FnMut(&[u8]) -> Result<(), TransferError> + Send + 'static,
+    ) {
+        self.model.endpoint(endpoint, |ep| {
+            ep.out_handler = Some(Arc::new(Mutex::new(handler)));
+        });
+    }
+
+    /// Queue a packet of data to be returned by an IN endpoint.
+    ///
+    /// Each call completes one transfer. Data beyond the transfer's requested
+    /// length is discarded.
+    pub fn push_in(&self, endpoint: u8, data: impl Into<Vec<u8>>) {
+        self.model.push_in(endpoint, Ok(data.into()));
+    }
+
+    /// Queue an error to be returned by the next transfer on an IN endpoint.
+    pub fn push_in_error(&self, endpoint: u8, error: TransferError) {
+        self.model.push_in(endpoint, Err(error));
+    }
+
+    /// Take the data of the oldest transfer written to an OUT endpoint.
+    pub fn pop_out(&self, endpoint: u8) -> Option<Vec<u8>> {
+        self.model.endpoint(endpoint, |ep| ep.out_queue.pop_front())
+    }
+
+    /// Halt an endpoint.
+    ///
+    /// Pending and future transfers on the endpoint fail with
+    /// [`TransferError::Stall`] until the host calls
+    /// [`Endpoint::clear_halt`][crate::Endpoint::clear_halt].
+    pub fn stall(&self, endpoint: u8) {
+        let waiting = self.model.endpoint(endpoint, |ep| {
+            ep.halted = true;
+            mem::take(&mut ep.waiting)
+        });
+        for completer in waiting {
+            completer.fail(TransferError::Stall);
+        }
+    }
+
+    /// Stop or resume responding to transfers.
+    ///
+    /// While unresponsive, newly submitted transfers are not completed, so
+    /// they time out or remain pending until cancelled.
+    pub fn set_unresponsive(&self, unresponsive: bool) {
+        self.model.state.lock().unwrap().unresponsive = unresponsive;
+    }
+
+    /// Simulate the device being unplugged.
+    ///
+    /// Pending transfers fail with [`TransferError::Disconnected`], as do
+    /// any further transfers. Other operations, including opening the
+    /// device, fail with [`ErrorKind::Disconnected`], and
+    /// [`Device::disconnected`][crate::Device::disconnected] completes.
+    pub fn disconnect(&self) {
+        let mut state = self.model.state.lock().unwrap();
+        state.disconnected = true;
+        let mut completers = mem::take(&mut state.held);
+        for ep in state.endpoints.values_mut() {
+            completers.extend(ep.waiting.drain(..));
+        }
+        drop(state);
+        self.model.unplugged.set();
+
+        for completer in completers {
+            completer.fail(TransferError::Disconnected);
+        }
+    }
+
+    /// Simulate the device being unplugged and plugged back in.
+    ///
+    /// This device is [disconnected][Self::disconnect], and the returned
+    /// `MockDevice` is the device after it reconnects. It has the same
+    /// descriptors, speed, strings and handlers, and starts out in its first
+    /// configuration with no queued data, like a newly enumerated device.
+    /// Handles opened from this device remain disconnected.
+    pub fn replug(&self) -> MockDevice {
+        self.disconnect();
+        let replugged = MockDevice::new(self.model.descriptors.clone());
+        let state = self.model.state.lock().unwrap();
+        let mut new_state = replugged.model.state.lock().unwrap();
+        new_state.speed = state.speed;
+        new_state.strings = state.strings.clone();
+        new_state.control_in = state.control_in.clone();
+        new_state.control_out = state.control_out.clone();
+        for (&endpoint, ep) in &state.endpoints {
+            if let Some(handler) = &ep.out_handler {
+                new_state.endpoints.entry(endpoint).or_default().out_handler =
+                    Some(handler.clone());
+            }
+        }
+        drop(new_state);
+        replugged
+    }
+
+    /// Get the configuration most recently selected by the host.
+    pub fn configuration(&self) -> u8 {
+        self.model.state.lock().unwrap().configuration
+    }
+
+    /// Get the alternate setting most recently selected by the host for an
+    /// interface.
+    pub fn alt_setting(&self, interface: u8) -> u8 {
+        let state = self.model.state.lock().unwrap();
+        state.alt_settings.get(&interface).copied().unwrap_or(0)
+    }
+}
+
+impl MockModel {
+    fn endpoint<R>(&self, endpoint: u8, f: impl FnOnce(&mut EndpointState) -> R) -> R {
+        f(self
+            .state
+            .lock()
+            .unwrap()
+            .endpoints
+            .entry(endpoint)
+            .or_default())
+    }
+
+    fn configuration(&self, configuration: u8) -> Option<ConfigurationDescriptor<'_>> {
+        parse_concatenated_config_descriptors(&self.descriptors[DESCRIPTOR_LEN_DEVICE as usize..])
+            .find(|c| c.configuration_value() == configuration)
+    }
+
+    fn check_connected(&self) -> Result<(), Error> {
+        if self.state.lock().unwrap().disconnected {
+            return Err(Error::new(ErrorKind::Disconnected, "device disconnected"));
+        }
+        Ok(())
+    }
+
+    fn push_in(&self, endpoint: u8, result: Result<Vec<u8>, TransferError>) {
+        loop {
+            let Some(completer) = self.endpoint(endpoint, |ep| {
+                let completer = ep.waiting.pop_front();
+                if completer.is_none() {
+                    ep.in_queue.push_back(result.clone());
+                }
+                completer
+            }) else {
+                return;
+            };
+
+            // If the transfer was cancelled concurrently, the data goes to
+            // the next one instead.
+            if complete_in(completer, &result) {
+                return;
+            }
+        }
+    }
+
+    /// Answer standard `GET_DESCRIPTOR` requests from the descriptors.
+    fn get_descriptor(&self, req: &ControlIn) -> Option<Vec<u8>> {
+        const STANDARD_REQUEST_GET_DESCRIPTOR: u8 = 0x06;
+
+        if req.control_type != ControlType::Standard
+            || req.recipient != Recipient::Device
+            || req.request != STANDARD_REQUEST_GET_DESCRIPTOR
+        {
+            return None;
+        }
+
+        let desc_type = (req.value >> 8) as u8;
+        let desc_index = req.value as u8;
+        match desc_type {
+            DESCRIPTOR_TYPE_DEVICE => {
+                Some(self.descriptors[..DESCRIPTOR_LEN_DEVICE as usize].to_vec())
+            }
+            DESCRIPTOR_TYPE_CONFIGURATION => parse_concatenated_config_descriptors(
+                &self.descriptors[DESCRIPTOR_LEN_DEVICE as usize..],
+            )
+            .nth(desc_index as usize)
+            .map(|c| c.as_bytes().to_vec()),
+            DESCRIPTOR_TYPE_STRING => {
+                let state = self.state.lock().unwrap();
+                if state.strings.is_empty() {
+                    return None;
+                }
+                let utf16: Vec<u16> = if desc_index == 0 {
+                    vec![US_ENGLISH]
+                } else {
+                    state.strings.get(&desc_index)?.encode_utf16().collect()
+                };
+                let mut desc = vec![(2 + utf16.len() * 2) as u8, DESCRIPTOR_TYPE_STRING];
+                desc.extend(utf16.iter().flat_map(|c| c.to_le_bytes()));
+                Some(desc)
+            }
+            _ => None,
+        }
+    }
+
+    fn control(&self, request: Request, completer: Completer) {
+        let setup = request
+            .setup
+            .expect("control transfer without setup packet");
+        // Like a real device, stall requests with a reserved type or recipient
+        let Some((_, control_type, recipient)) = parse_request_type(setup[0]) else {
+            completer.fail(TransferError::Stall);
+            return;
+        };
+        let value = u16::from_le_bytes([setup[2], setup[3]]);
+        let index = u16::from_le_bytes([setup[4], setup[5]]);
+
+        match request.direction() {
+            Direction::In => {
+                let req = ControlIn {
+                    control_type,
+                    recipient,
+                    request: setup[1],
+                    value,
+                    index,
+                    length: u16::from_le_bytes([setup[6], setup[7]]),
+                };
+                let handler = self.state.lock().unwrap().control_in.clone();
+                let result = match (self.get_descriptor(&req), handler) {
+                    (Some(desc), _) => Ok(desc),
+                    (None, Some(handler)) => (handler.lock().unwrap())(req),
+                    (None, None) => Err(TransferError::Stall),
+                };
+                complete_in(completer, &result);
+            }
+            Direction::Out => {
+                let req = ControlOut {
+                    control_type,
+                    recipient,
+                    request: setup[1],
+                    value,
+                    index,
+                    data: &request.data,
+                };
+                let handler = self.state.lock().unwrap().control_out.clone();
+                let result = match handler {
+                    Some(handler) => (handler.lock().unwrap())(req),
+                    None => Err(TransferError::Stall),
+                };
+                complete_out(completer, result, request.data.len());
+            }
+        }
+    }
+}
+
+fn complete_in(completer: Completer, result: &Result<Vec<u8>, TransferError>) -> bool {
+    match result {
+        Ok(data) => completer.complete_in(Ok(()), data),
+        Err(e) => completer.fail(*e),
+    }
+}
+
+fn complete_out(completer: Completer, result: Result<(), TransferError>, len: usize) -> bool {
+    match result {
+        Ok(()) => completer.complete_out(Ok(()), len),
+        Err(e) => completer.fail(e),
+    }
+}
+
+impl Model for MockModel {
+    fn descriptors(&self) -> Vec<u8> {
+        self.descriptors.clone()
+    }
+
+    fn speed(&self) -> Option<Speed> {
+        self.state.lock().unwrap().speed
+    }
+
+    fn active_configuration(&self) -> u8 {
+        self.state.lock().unwrap().configuration
+    }
+
+    fn open(&self) -> Result<(), Error> {
+        self.check_connected()
+    }
+
+    fn set_configuration(&self, configuration: u8) -> Result<(), Error> {
+        self.check_connected()?;
+        if configuration != 0 && self.configuration(configuration).is_none() {
+            return Err(Error::new(ErrorKind::NotFound, "configuration not found"));
+        }
+        let mut state = self.state.lock().unwrap();
+        state.configuration = configuration;
+        state.alt_settings.clear();
+        Ok(())
+    }
+
+    fn claim_interface(&self, interface: u8) -> Result<(), Error> {
+        self.check_connected()?;
+        let configuration = self.state.lock().unwrap().configuration;
+        self.configuration(configuration)
+            .into_iter()
+            .flat_map(|c| c.interfaces())
+            .find(|i| i.interface_number() == interface)
+            .ok_or(Error::new(ErrorKind::NotFound, "interface not found"))?;
+        Ok(())
+    }
+
+    fn set_alt_setting(&self, interface: u8, alt_setting: u8) -> Result<(), Error> {
+        self.check_connected()?;
+        let configuration = self.state.lock().unwrap().configuration;
+        self.configuration(configuration)
+            .into_iter()
+            .flat_map(|c| c.interface_alt_settings())
+            .find(|i| i.interface_number() == interface && i.alternate_setting() == alt_setting)
+            .ok_or(Error::new(
+                ErrorKind::NotFound,
+                "alternate setting not found",
+            ))?;
+        self.state
+            .lock()
+            .unwrap()
+            .alt_settings
+            .insert(interface, alt_setting);
+        Ok(())
+    }
+
+    fn clear_halt(&self, endpoint: u8) -> Result<(), Error> {
+        self.check_connected()?;
+        self.endpoint(endpoint, |ep| ep.halted = false);
+        Ok(())
+    }
+
+    fn reset(&self) -> Result<(), Error> {
+        self.check_connected()?;
+        let mut state = self.state.lock().unwrap();
+        for ep in state.endpoints.values_mut() {
+            ep.halted = false;
+        }
+        Ok(())
+    }
+
+    fn submit(&self, request: Request, completer: Completer) {
+        let mut state = self.state.lock().unwrap();
+        if state.disconnected {
+            drop(state);
+            completer.fail(TransferError::Disconnected);
+            return;
+        }
+        if state.unresponsive {
+            state.held.push(completer);
+            return;
+        }
+
+        match request.transfer_type {
+            TransferType::Control => {
+                drop(state);
+                self.control(request, completer);
+            }
+            TransferType::Bulk | TransferType::Interrupt => {
+                let ep = state.endpoints.entry(request.endpoint).or_default();
+                if ep.halted {
+                    drop(state);
+                    completer.fail(TransferError::Stall);
+                    return;
+                }
+                match request.direction() {
+                    Direction::In => match ep.in_queue.pop_front() {
+                        Some(result) => {
+                            drop(state);
+                            complete_in(completer, &result);
+                        }
+                        None => ep.waiting.push_back(completer),
+                    },
+                    Direction::Out => match ep.out_handler.clone() {
+                        Some(handler) => {
+                            drop(state);
+                            let result = (handler.lock().unwrap())(&request.data);
+                            complete_out(completer, result, request.data.len());
+                        }
+                        None => {
+                            let len = request.data.len();
+                            ep.out_queue.push_back(request.data);
+                            drop(state);
+                            completer.complete_out(Ok(()), len);
+                        }
+                    },
+                }
+            }
+            TransferType::Isochronous => {
+                drop(state);
+                completer.fail(TransferError::InvalidArgument);
+            }
+        }
+    }
+
+    fn cancel(&self, id: u64) {
+        let mut state = self.state.lock().unwrap();
+        let mut cancelled = Vec::new();
+        for ep in state.endpoints.values_mut() {
+            if let Some(pos) = ep.waiting.iter().position(|c| c.id() == id) {
+                cancelled.extend(ep.waiting.remove(pos));
+            }
+        }
+        if let Some(pos) = state.held.iter().position(|c| c.id() == id) {
+            cancelled.push(state.held.remove(pos));
+        }
+        drop(state);
+        drop(cancelled);
+    }
+
+    fn poll_disconnected(&self, cx: &mut Context<'_>) -> Poll<()> {
+        self.unplugged.poll(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{num::NonZeroU8, time::Duration};
+
+    use super::MockDevice;
+    #[cfg(not(target_os = "windows"))]
+    use crate::transfer::{ControlIn, ControlOut, ControlType, Out, Recipient};
+    use crate::{
+        descriptors::language_id::US_ENGLISH,
+        transfer::{Buffer, Bulk, In, TransferError},
+        ErrorKind, MaybeFuture,
+    };
+
+    const DESCRIPTORS: &[u8] = &[
+        0x12, 0x01, 0x00, 0x02, 0xff, 0x00, 0x00, 0x40, 0xaa, 0xaa, 0x55, 0x55, 0x00, 0x01, 0x00,
+        0x02, 0x00, 0x01, 0x09, 0x02, 0x29, 0x00, 0x01, 0x01, 0x00, 0x80, 0x32, 0x09, 0x04, 0x00,
+        0x00, 0x02, 0xff, 0x00, 0x00, 0x00, 0x07, 0x05, 0x81, 0x02, 0x00, 0x02, 0x00, 0x07, 0x05,
+        0x02, 0x02, 0x00, 0x02, 0x00, 0x09, 0x04, 0x00, 0x01, 0x00, 0xff, 0x00, 0x00, 0x00,
+    ];
+
+    const TIMEOUT: Duration = Duration::from_millis(100);
+
+    #[cfg(not(target_os = "windows"))]
+    fn vendor_in(request: u8) -> ControlIn {
+        ControlIn {
+            control_type: ControlType::Vendor,
+            recipient: Recipient::Device,
+            request,
+            value: 0,
+            index: 0,
+            length: 64,
+        }
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    #[test]
+    fn descriptors_and_control() {
+        let mock = MockDevice::new(DESCRIPTORS).with_string(2, "Widget");
+        mock.on_control_in(|req| match req.request {
+            1 => Ok(vec![1, 2, 3]),
+            _ => Err(TransferError::Stall),
+        });
+
+        let info = mock.device_info();
+        assert_eq!(info.vendor_id(), 0xAAAA);
+        assert_eq!(info.product_id(), 0x5555);
+        assert_eq!(info.device_descriptor().unwrap().product_id(), 0x5555);
+        assert_eq!(info.configurations().count(), 1);
+        let device = info.open().wait().unwrap();
+        assert_eq!(device.active_configuration().unwrap().num_interfaces(), 1);
+        assert_eq!(
+            device
+                .get_string_descriptor(NonZeroU8::new(2).unwrap(), US_ENGLISH, TIMEOUT)
+                .wait()
+                .unwrap(),
+            "Widget"
+        );
+
+        assert_eq!(
+            device.control_in(vendor_in(1), TIMEOUT).wait().unwrap(),
+            [1, 2, 3]
+        );
+        assert_eq!(
+            device.control_in(vendor_in(2), TIMEOUT).wait(),
+            Err(TransferError::Stall)
+        );
+        let out = ControlOut {
+            control_type: ControlType::Vendor,
+            recipient: Recipient::Device,
+            request: 1,
+            value: 0,
+            index: 0,
+            data: &[],
+        };
+        assert_eq!(
+            device.control_out(out, TIMEOUT).wait(),
+            Err(TransferError::Stall)
+        );
+
+        assert_eq!(
+            device.claim_interface(1).wait().unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+        let interface = device.claim_interface(0).wait().unwrap();
+        interface.set_alt_setting(1).wait().unwrap();
+        assert_eq!(mock.alt_setting(0), 1);
+        assert_eq!(
+            interface.set_alt_setting(2).wait().unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+    }
+
+    #[test]
+    fn long_string_truncated() {
+        let long = format!("{}\u{1F600}", "a".repeat(125));
+        let mock = MockDevice::new(DESCRIPTORS).with_string(1, &long);
+        let device = mock.device_info().open().wait().unwrap();
+        let s = device
+            .get_string_descriptor(NonZeroU8::new(1).unwrap(), US_ENGLISH, TIMEOUT)
+            .wait()
+            .unwrap();
+        assert_eq!(s, "a".repeat(125));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_device_info() {
+        let info = MockDevice::new(DESCRIPTORS).device_info();
+        let json = serde_json::to_value(&info).unwrap();
+        assert_eq!(json["vendor_id"], 0xAAAA);
+        assert!(json.get("emulated").is_none());
+
+        let back: crate::DeviceInfo = serde_json::from_value(json.clone()).unwrap();
+        assert_eq!(serde_json::to_value(&back).unwrap(), json);
+        assert_eq!(back.id(), info.id());
+        assert_eq!(back.product_id(), 0x5555);
+        let interface = back.interfaces().next().unwrap();
+        assert_eq!(interface.interface_number(), 0);
+        assert_eq!(interface.class(), info.interfaces().next().unwrap().class());
+    }
+
+    #[test]
+    fn stall_and_clear_halt() {
+        let mock = MockDevice::new(DESCRIPTORS);
+        let device = mock.device_info().open().wait().unwrap();
+        let interface = device.claim_interface(0).wait().unwrap();
+        let mut ep = interface.endpoint::<Bulk, In>(0x81).unwrap();
+
+        ep.submit(Buffer::new(512));
+        mock.stall(0x81);
+        let c = ep.wait_next_complete(TIMEOUT).unwrap();
+        assert_eq!(c.status, Err(TransferError::Stall));
+
+        mock.push_in(0x81, vec![1; 1000]);
+        ep.submit(Buffer::new(512));
+        let c = ep.wait_next_complete(TIMEOUT).unwrap();
+        assert_eq!(c.status, Err(TransferError::Stall));
+
+        ep.clear_halt().wait().unwrap();
+        ep.submit(Buffer::new(512));
+        let c = ep.wait_next_complete(TIMEOUT).unwrap();
+        assert_eq!(c.status, Ok(()));
+        assert_eq!(c.buffer.len(), 512);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn no_kernel_driver() {
+        let mock = MockDevice::new(DESCRIPTORS);
+        let device = mock.device_info().open().wait().unwrap();
+        assert_eq!(device.kernel_driver(0).unwrap(), None);
+        let interface = device.claim_interface(0).wait().unwrap();
+        assert_eq!(interface.kernel_driver().unwrap(), None);
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn drop_privileges_interface_number() {
+        let mock = MockDevice::new(DESCRIPTORS);
+        let device = mock.device_info().open().wait().unwrap();
+        let err = device.drop_privileges(&[0, 32]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Unsupported);
+        assert_eq!(err.to_string(), "interface number must be less than 32");
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
+    #[test]
+    fn reset_and_reopen() {
+        let mock = MockDevice::new(DESCRIPTORS);
+        let device = mock.device_info().open().wait().unwrap();
+        device.claim_interface(0).wait().unwrap();
+        let device = device.reset_and_reopen(TIMEOUT).wait().unwrap();
+        device.claim_interface(0).wait().unwrap();
+
+        mock.disconnect();
+        assert_eq!(
+            device.reset_and_reopen(TIMEOUT).wait().unwrap_err().kind(),
+            ErrorKind::Disconnected
+        );
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
+    #[test]
+    fn disconnected() {
+        let mock = MockDevice::new(DESCRIPTORS);
+        let device = mock.device_info().open().wait().unwrap();
+        let waiter = std::thread::spawn({
+            let disconnected = device.disconnected();
+            move || disconnected.wait()
+        });
+        mock.disconnect();
+        waiter.join().unwrap().unwrap();
+        device.disconnected().wait().unwrap();
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    #[test]
+    fn timeout_and_disconnect() {
+        let mock = MockDevice::new(DESCRIPTORS);
+        mock.on_control_in(|_| Ok(vec![0]));
+        let device = mock.device_info().open().wait().unwrap();
+        let interface = device.claim_interface(0).wait().unwrap();
+
+        mock.set_unresponsive(true);
+        assert_eq!(
+            device.control_in(vendor_in(1), TIMEOUT).wait(),
+            Err(TransferError::Cancelled)
+        );
+        mock.set_unresponsive(false);
+
+        let mut ep_in = interface.endpoint::<Bulk, In>(0x81).unwrap();
+        ep_in.submit(Buffer::new(512));
+        assert!(ep_in.wait_next_complete(TIMEOUT).is_none());
+
+        let mut ep_out = interface.endpoint::<Bulk, Out>(0x02).unwrap();
+        mock.on_out(0x02, |data| {
+            assert_eq!(data, b"hello");
+            Ok(())
+        });
+        ep_out.submit(b"hello".to_vec().into());
+        let c = ep_out.wait_next_complete(TIMEOUT).unwrap();
+        assert_eq!((c.status, c.actual_len), (Ok(()), 5));
+        assert_eq!(mock.pop_out(0x02), None);
+
+        mock.disconnect();
+        let c = ep_in.wait_next_complete(TIMEOUT).unwrap();
+        assert_eq!(c.status, Err(TransferError::Disconnected));
+        assert_eq!(
+            device.control_in(vendor_in(1), TIMEOUT).wait(),
+            Err(TransferError::Disconnected)
+        );
+        assert_eq!(
+            mock.device_info().open().wait().unwrap_err().kind(),
+            ErrorKind::Disconnected
+        );
+    }
+}
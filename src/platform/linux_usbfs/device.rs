@@ -29,7 +29,14 @@ use super::{
 };
 
 #[cfg(not(target_os = "android"))]
-use super::SysfsPath;
+use super::{FsRoots, SysfsPath};
+
+#[cfg(target_os = "linux")]
+use std::{
+    future::{poll_fn, ready, Future, IntoFuture},
+    pin::Pin,
+    thread,
+};
 
 use crate::{
     bitset::EndpointBitSet,
@@ -38,6 +45,7 @@ use crate::{
         EndpointDescriptor, TransferType, DESCRIPTOR_LEN_DEVICE,
     },
     maybe_future::{blocking::Blocking, MaybeFuture},
+    signal::Signal,
     transfer::{
         internal::{
             notify_completion, take_completed_from_queue, Idle, Notify, Pending,
@@ -46,7 +54,7 @@ use crate::{
         request_type, Buffer, Completion, ControlIn, ControlOut, ControlType, Direction, Recipient,
         TransferError,
     },
-    DeviceInfo, Error, ErrorKind, Speed,
+    Capabilities, ConnectionInfo, DeviceInfo, Error, ErrorKind, Speed,
 };
 
 #[derive(PartialEq, Eq, PartialOrd, Ord)]
@@ -74,6 +82,9 @@ pub(crate) struct LinuxDevice {
 
     timerfd: OwnedFd,
     timeouts: Mutex<BTreeMap<TimeoutEntry, ()>>,
+
+    /// Set when REAPURB reports that the device is gone.
+    disconnected: Signal,
 }
 
 impl LinuxDevice {
@@ -81,32 +92,40 @@ impl LinuxDevice {
     pub(crate) fn from_device_info(
         d: &DeviceInfo,
     ) -> impl MaybeFuture<Output = Result<Arc<LinuxDevice>, Error>> {
-        use rustix::fs::{Mode, OFlags};
-
         let busnum = d.busnum();
         let devnum = d.device_address();
         let sysfs_path = d.path.clone();
+        let path = d.roots.device_node(busnum, devnum);
 
         Blocking::new(move || {
-            let path = std::path::PathBuf::from(format!("/dev/bus/usb/{busnum:03}/{devnum:03}"));
-            let fd = rustix::fs::open(&path, OFlags::RDWR | OFlags::CLOEXEC, Mode::empty())
-                .map_err(|e| {
-                    match e {
-                        Errno::NOENT => {
-                            Error::new_os(ErrorKind::Disconnected, "device not found", e)
-                        }
-                        Errno::PERM => {
-                            Error::new_os(ErrorKind::PermissionDenied, "permission denied", e)
-                        }
-                        e => Error::new_os(ErrorKind::Other, "failed to open device", e),
-                    }
-                    .log_debug()
-                })?;
-
+            let fd = open_device_node(&path)?;
             Self::create_inner(fd, Some(sysfs_path))
         })
     }
 
+    /// Open the device node for a bus number and address, and find its sysfs
+    /// directory from the device number of the node.
+    #[cfg(not(target_os = "android"))]
+    pub(crate) fn from_id(
+        roots: Arc<FsRoots>,
+        busnum: u8,
+        devnum: u8,
+    ) -> impl MaybeFuture<Output = Result<Arc<LinuxDevice>, Error>> {
+        Blocking::new(move || {
+            let fd = open_device_node(&roots.device_node(busnum, devnum))?;
+            let sysfs_path = rustix::fs::fstat(&fd).ok().and_then(|st| {
+                let major = rustix::fs::major(st.st_rdev);
+                let minor = rustix::fs::minor(st.st_rdev);
+                let link = roots.sysfs().join(format!("dev/char/{major}:{minor}"));
+                std::fs::canonicalize(link).ok().map(SysfsPath)
+            });
+            if sysfs_path.is_none() {
+                debug!("no sysfs directory found for device {busnum:03}/{devnum:03}");
+            }
+            Self::create_inner(fd, sysfs_path)
+        })
+    }
+
     #[cfg(target_os = "android")]
     pub(crate) fn from_device_info(
         _d: &DeviceInfo,
@@ -169,6 +188,7 @@ impl LinuxDevice {
                 active_config: AtomicU8::new(active_config),
                 timerfd,
                 timeouts: Mutex::new(BTreeMap::new()),
+                disconnected: Signal::new(),
             }
         });
 
@@ -242,6 +262,7 @@ impl LinuxDevice {
                 // keep the event thread from spinning because we won't receive further events.
                 // The drop impl will try to unregister again, but that's ok.
                 events::unregister_fd(self.fd.as_fd());
+                self.disconnected.set();
             }
             Err(e) => {
                 error!("Unexpected error {e} from REAPURBNDELAY");
@@ -249,6 +270,10 @@ impl LinuxDevice {
         }
     }
 
+    pub(crate) fn poll_disconnected(&self, cx: &mut Context<'_>) -> Poll<()> {
+        self.disconnected.poll(cx)
+    }
+
     pub(crate) fn handle_timer_epoll(id: usize) {
         let device = DEVICES.lock().unwrap().get(id).and_then(|w| w.upgrade());
         if let Some(device) = device {
@@ -360,6 +385,7 @@ impl LinuxDevice {
         Blocking::new(move || {
             usbfs::reset(&self.fd).map_err(|e| match e {
                 Errno::BUSY => Error::new_os(ErrorKind::Busy, "device is busy", e),
+                Errno::ACCESS => Error::new_os(ErrorKind::PermissionDenied, "permission denied", e),
                 Errno::NODEV => Error::new_os(ErrorKind::Disconnected, "device disconnected", e),
                 _ => Error::new_os(ErrorKind::Other, "failed to reset device", e),
             })
@@ -402,6 +428,7 @@ impl LinuxDevice {
             match e {
                 Errno::INVAL => Error::new_os(ErrorKind::NotFound, "interface not found", e),
                 Errno::BUSY => Error::new_os(ErrorKind::Busy, "interface is busy", e),
+                Errno::ACCESS => Error::new_os(ErrorKind::PermissionDenied, "permission denied", e),
                 Errno::NODEV => Error::new_os(ErrorKind::Disconnected, "device disconnected", e),
                 _ => Error::new_os(ErrorKind::Other, "failed to claim interface", e),
             }
@@ -448,6 +475,7 @@ impl LinuxDevice {
             Errno::INVAL => Error::new_os(ErrorKind::NotFound, "interface not found", e),
             Errno::NODEV => Error::new_os(ErrorKind::Disconnected, "device disconnected", e),
             Errno::NODATA => Error::new_os(ErrorKind::Other, "no kernel driver attached", e),
+            Errno::ACCESS => Error::new_os(ErrorKind::PermissionDenied, "permission denied", e),
             _ => Error::new_os(ErrorKind::Other, "failed to detach kernel driver", e),
         })
     }
@@ -465,6 +493,39 @@ impl LinuxDevice {
         })
     }
 
+    #[cfg(target_os = "linux")]
+    pub(crate) fn kernel_driver(&self, interface_number: u8) -> Result<Option<String>, Error> {
+        match usbfs::get_driver(&self.fd, interface_number) {
+            Ok(driver) => Ok(Some(driver)),
+            Err(Errno::NODATA) => Ok(None),
+            Err(e) => Err(match e {
+                Errno::INVAL => Error::new_os(ErrorKind::NotFound, "interface not found", e),
+                Errno::NODEV => Error::new_os(ErrorKind::Disconnected, "device disconnected", e),
+                _ => Error::new_os(ErrorKind::Other, "failed to get kernel driver", e),
+            }),
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    pub(crate) fn forbid_suspend(&self) -> Result<(), Error> {
+        usbfs::forbid_suspend(&self.fd).map_err(|e| usbfs_error(e, "failed to forbid suspend"))
+    }
+
+    #[cfg(target_os = "linux")]
+    pub(crate) fn allow_suspend(&self) -> Result<(), Error> {
+        usbfs::allow_suspend(&self.fd).map_err(|e| usbfs_error(e, "failed to allow suspend"))
+    }
+
+    pub(crate) fn drop_privileges(&self, allowed_interfaces: u32) -> Result<(), Error> {
+        usbfs::drop_privileges(&self.fd, allowed_interfaces)
+            .map_err(|e| usbfs_error(e, "failed to drop privileges"))
+    }
+
+    #[cfg(target_os = "linux")]
+    pub(crate) fn wait_for_resume(self: Arc<Self>) -> WaitForResume {
+        WaitForResume(self)
+    }
+
     pub(crate) fn submit(&self, transfer: Idle<TransferData>) -> Pending<TransferData> {
         let len = transfer.urb().buffer_length;
         let pending = transfer.pre_submit();
@@ -530,15 +591,37 @@ impl LinuxDevice {
         usbfs::get_speed(&self.fd)
             .inspect_err(|e| log::error!("USBDEVFS_GET_SPEED failed: {e}"))
             .ok()
-            .and_then(|raw_speed| match raw_speed {
-                1 => Some(Speed::Low),
-                2 => Some(Speed::Full),
-                3 => Some(Speed::High),
-                // 4 is wireless USB, but we don't support it
-                5 => Some(Speed::Super),
-                6 => Some(Speed::SuperPlus),
-                _ => None,
-            })
+            .and_then(speed_from_raw)
+    }
+
+    pub(crate) fn capabilities(&self) -> Result<Capabilities, Error> {
+        usbfs::get_capabilities(&self.fd)
+            .map(Capabilities)
+            .map_err(|e| usbfs_error(e, "failed to get capabilities"))
+    }
+
+    pub(crate) fn connection_info(&self) -> Result<ConnectionInfo, Error> {
+        let info = usbfs::get_conninfo_ex(&self.fd)
+            .map_err(|e| usbfs_error(e, "failed to get connection info"))?;
+        let num_ports = (info.num_ports as usize).min(info.ports.len());
+        Ok(ConnectionInfo {
+            busnum: info.busnum as u8,
+            device_address: info.devnum as u8,
+            speed: speed_from_raw(info.speed as usize),
+            port_chain: info.ports[..num_ports].to_vec(),
+        })
+    }
+}
+
+fn speed_from_raw(raw_speed: usize) -> Option<Speed> {
+    match raw_speed {
+        1 => Some(Speed::Low),
+        2 => Some(Speed::Full),
+        3 => Some(Speed::High),
+        // 4 is wireless USB, but we don't support it
+        5 => Some(Speed::Super),
+        6 => Some(Speed::SuperPlus),
+        _ => None,
     }
 }
 
@@ -550,6 +633,81 @@ fn read_all_from_fd(fd: &OwnedFd) -> Result<Vec<u8>, std::io::Error> {
     Ok(buf)
 }
 
+#[cfg(not(target_os = "android"))]
+fn open_device_node(path: &std::path::Path) -> Result<OwnedFd, Error> {
+    use rustix::fs::{Mode, OFlags};
+
+    rustix::fs::open(path, OFlags::RDWR | OFlags::CLOEXEC, Mode::empty()).map_err(|e| {
+        match e {
+            Errno::NOENT => Error::new_os(ErrorKind::Disconnected, "device not found", e),
+            Errno::PERM => Error::new_os(ErrorKind::PermissionDenied, "permission denied", e),
+            e => Error::new_os(ErrorKind::Other, "failed to open device", e),
+        }
+        .log_debug()
+    })
+}
+
+fn usbfs_error(e: Errno, message: &'static str) -> Error {
+    match e {
+        Errno::NODEV => Error::new_os(ErrorKind::Disconnected, "device disconnected", e),
+        Errno::NOTTY => Error::new_os(ErrorKind::Unsupported, "not supported by kernel", e),
+        _ => Error::new_os(ErrorKind::Other, message, e),
+    }
+}
+
+/// Returned by [`LinuxDevice::wait_for_resume`].
+///
+/// `USBDEVFS_WAIT_FOR_RESUME` has no timeout, and a device that is never
+/// resumed blocks it until it is disconnected. Rather than tying up a thread
+/// of the async runtime's blocking pool for that long, the future runs the
+/// ioctl on a thread of its own.
+#[cfg(target_os = "linux")]
+pub(crate) struct WaitForResume(Arc<LinuxDevice>);
+
+#[cfg(target_os = "linux")]
+impl WaitForResume {
+    fn run(self) -> Result<(), Error> {
+        usbfs::wait_for_resume(&self.0.fd).map_err(|e| usbfs_error(e, "failed to wait for resume"))
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl IntoFuture for WaitForResume {
+    type Output = Result<(), Error>;
+    type IntoFuture = Pin<Box<dyn Future<Output = Self::Output> + Send>>;
+
+    fn into_future(self) -> Self::IntoFuture {
+        let done = Arc::new((Mutex::new(None), Signal::new()));
+        let spawned = thread::Builder::new()
+            .name("nusb-wait-for-resume".into())
+            .spawn({
+                let done = done.clone();
+                move || {
+                    *done.0.lock().unwrap() = Some(self.run());
+                    done.1.set();
+                }
+            });
+        if spawned.is_err() {
+            return Box::pin(ready(Err(Error::new(
+                ErrorKind::Other,
+                "failed to spawn thread",
+            ))));
+        }
+        Box::pin(poll_fn(move |cx| {
+            std::task::ready!(done.1.poll(cx));
+            let result = done.0.lock().unwrap().take();
+            Poll::Ready(result.expect("polled after completion"))
+        }))
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl MaybeFuture for WaitForResume {
+    fn wait(self) -> Self::Output {
+        self.run()
+    }
+}
+
 /// Try a request to get the active configuration or fall back to a guess.
 fn guess_active_configuration(fd: &OwnedFd, descriptors: &[u8]) -> u8 {
     request_configuration(fd).unwrap_or_else(|()| {
@@ -860,3 +1018,23 @@ impl Drop for EndpointInner {
         state.endpoints.clear(self.address);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn usbfs_error_kind() {
+        // Not a usbfs device node, so the kernel rejects the ioctls
+        let file = File::open("/dev/null").unwrap();
+        let e = usbfs::allow_suspend(&file).unwrap_err();
+        assert_eq!(e, Errno::NOTTY);
+        let err = usbfs_error(e, "failed to allow suspend");
+        assert_eq!(err.kind(), ErrorKind::Unsupported);
+        assert_eq!(err.os_error(), Some(Errno::NOTTY.raw_os_error() as u32));
+
+        let kind = |errno: Errno| usbfs_error(errno, "failed").kind();
+        assert_eq!(kind(Errno::NODEV), ErrorKind::Disconnected);
+        assert_eq!(kind(Errno::IO), ErrorKind::Other);
+    }
+}
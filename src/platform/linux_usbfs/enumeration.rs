@@ -3,13 +3,165 @@ use log::warn;
 use std::fs;
 use std::io;
 use std::num::ParseIntError;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::{Arc, OnceLock};
 
-use crate::enumeration::InterfaceInfo;
-use crate::maybe_future::{MaybeFuture, Ready};
+use crate::enumeration::{InterfaceInfo, Removable};
+use crate::hotplug::{HotplugSource, HotplugWatch};
+use crate::maybe_future::{blocking::Blocking, MaybeFuture, Ready};
 use crate::ErrorKind;
-use crate::{BusInfo, DeviceInfo, Error, Speed, UsbControllerType};
+use crate::{BusInfo, Device, DeviceInfo, Error, PortPath, Speed, UsbControllerType};
+
+/// *(Linux-only)* Filesystem locations used to list, open, and watch devices.
+///
+/// By default, devices are found in `/sys` and their device nodes are opened
+/// from `/dev/bus/usb`. Use this to work with a relocated sysfs, such as one
+/// bind-mounted into a container, or with a captured sysfs snapshot in tests.
+///
+/// Devices listed or watched through an `FsRoots` are opened from its
+/// `usbfs` directory.
+///
+/// ### Example
+///
+/// ```no_run
+/// use nusb::{FsRoots, MaybeFuture};
+///
+/// let roots = FsRoots::new()
+///     .with_sysfs("/host/sys")
+///     .with_usbfs("/host/dev/bus/usb");
+/// for device in roots.list_devices().wait().unwrap() {
+///     println!("{device:?}");
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FsRoots {
+    sysfs: PathBuf,
+    usbfs: PathBuf,
+}
+
+impl Default for FsRoots {
+    fn default() -> Self {
+        FsRoots {
+            sysfs: PathBuf::from("/sys"),
+            usbfs: PathBuf::from("/dev/bus/usb"),
+        }
+    }
+}
+
+impl FsRoots {
+    /// Create an `FsRoots` with the default locations.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the sysfs mount point, `/sys` by default.
+    pub fn with_sysfs(mut self, path: impl Into<PathBuf>) -> Self {
+        self.sysfs = path.into();
+        self
+    }
+
+    /// Set the directory containing the usbfs device nodes, `/dev/bus/usb`
+    /// by default.
+    pub fn with_usbfs(mut self, path: impl Into<PathBuf>) -> Self {
+        self.usbfs = path.into();
+        self
+    }
+
+    /// The sysfs mount point.
+    pub fn sysfs(&self) -> &Path {
+        &self.sysfs
+    }
+
+    /// The directory containing the usbfs device nodes.
+    pub fn usbfs(&self) -> &Path {
+        &self.usbfs
+    }
+
+    /// Get an iterator listing the connected devices, like
+    /// [`nusb::list_devices`][crate::list_devices].
+    pub fn list_devices(
+        &self,
+    ) -> impl MaybeFuture<Output = Result<impl Iterator<Item = DeviceInfo>, Error>> {
+        list_devices_in(Arc::new(self.clone()))
+    }
+
+    /// Get an iterator listing the system USB buses, like
+    /// [`nusb::list_buses`][crate::list_buses].
+    pub fn list_buses(
+        &self,
+    ) -> impl MaybeFuture<Output = Result<impl Iterator<Item = BusInfo>, Error>> {
+        list_buses_in(Arc::new(self.clone()))
+    }
+
+    /// Get a stream of device connection and disconnection events, like
+    /// [`nusb::watch_devices`][crate::watch_devices].
+    pub fn watch_devices(&self) -> Result<HotplugWatch, Error> {
+        self.watch_devices_with_source(HotplugSource::Auto)
+    }
+
+    /// Get a stream of device events from the specified source, like
+    /// [`nusb::watch_devices_with_source`][crate::watch_devices_with_source].
+    pub fn watch_devices_with_source(&self, source: HotplugSource) -> Result<HotplugWatch, Error> {
+        Ok(HotplugWatch::new(super::HotplugWatch::with_source(
+            Arc::new(self.clone()),
+            source,
+        )?))
+    }
+
+    /// Open the device connected at a port path such as `1-2.3`, like
+    /// [`Device::open_by_path`].
+    pub fn open_by_path(&self, path: &str) -> impl MaybeFuture<Output = Result<Device, Error>> {
+        let roots = Arc::new(self.clone());
+        let path = path.parse::<PortPath>();
+        Blocking::new(move || {
+            let path = path.map_err(|e| {
+                debug!("{e}");
+                Error::new(ErrorKind::Other, "invalid port path")
+            })?;
+            let sysfs = SysfsPath(roots.sysfs.join("bus/usb/devices").join(path.to_string()));
+            let info = probe_device(&roots, sysfs).map_err(|e| {
+                debug!("{e}");
+                Error::new(ErrorKind::NotFound, "no device found at port path")
+            })?;
+            info.open().wait()
+        })
+    }
+
+    /// Open the device with a bus number and address, like
+    /// [`Device::open_by_id`].
+    pub fn open_by_id(
+        &self,
+        busnum: u8,
+        address: u8,
+    ) -> impl MaybeFuture<Output = Result<Device, Error>> {
+        super::Device::from_id(Arc::new(self.clone()), busnum, address).map(|d| d.map(Device::wrap))
+    }
+
+    /// The shared instance with the default locations.
+    pub(crate) fn system() -> Arc<FsRoots> {
+        static SYSTEM: OnceLock<Arc<FsRoots>> = OnceLock::new();
+        SYSTEM.get_or_init(|| Arc::new(FsRoots::default())).clone()
+    }
+
+    /// Path of the usbfs device node for a device.
+    pub(crate) fn device_node(&self, busnum: u8, devnum: u8) -> PathBuf {
+        self.usbfs.join(format!("{busnum:03}/{devnum:03}"))
+    }
+
+    /// Sysfs link to the device directory for a usbfs node, by its
+    /// character device number.
+    pub(crate) fn device_node_sysfs_link(&self, busnum: u8, devnum: u8) -> Option<PathBuf> {
+        // usbfs nodes have major number 189, and minor numbers of 128 per bus
+        let minor = (u32::from(busnum.checked_sub(1)?) << 7) + u32::from(devnum.checked_sub(1)?);
+        Some(self.sysfs.join(format!("dev/char/189:{minor}")))
+    }
+
+    /// Sysfs directory for a uevent `DEVPATH` such as `/devices/pci0000:00/...`.
+    pub(crate) fn devpath(&self, devpath: &str) -> SysfsPath {
+        SysfsPath(self.sysfs.join(devpath.trim_start_matches('/')))
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct SysfsPath(pub(crate) PathBuf);
@@ -42,8 +194,35 @@ impl std::error::Error for SysfsError {
     }
 }
 
+impl From<SysfsError> for Error {
+    fn from(e: SysfsError) -> Self {
+        match e.1 {
+            SysfsErrorKind::Io(e) => {
+                let kind = match e.kind() {
+                    io::ErrorKind::NotFound => ErrorKind::NotFound,
+                    io::ErrorKind::PermissionDenied => ErrorKind::PermissionDenied,
+                    _ => ErrorKind::Other,
+                };
+                Error::new_io(kind, "failed to read sysfs attribute", e)
+            }
+            SysfsErrorKind::Parse(_) => {
+                Error::new(ErrorKind::Other, "failed to parse sysfs attribute")
+            }
+        }
+    }
+}
+
+fn write_attr_error(e: io::Error) -> Error {
+    let kind = match e.kind() {
+        io::ErrorKind::NotFound => ErrorKind::NotFound,
+        io::ErrorKind::PermissionDenied => ErrorKind::PermissionDenied,
+        _ => ErrorKind::Other,
+    };
+    Error::new_io(kind, "failed to write sysfs attribute", e)
+}
+
 impl SysfsPath {
-    fn parse_attr<T, E>(
+    pub(crate) fn parse_attr<T, E>(
         &self,
         attr: &str,
         parse: impl FnOnce(&str) -> Result<T, E>,
@@ -55,6 +234,10 @@ impl SysfsPath {
             .map_err(|e| SysfsError(attr_path, e))
     }
 
+    pub(crate) fn write_attr(&self, attr: &str, value: &str) -> Result<(), Error> {
+        fs::write(self.0.join(attr), value).map_err(write_attr_error)
+    }
+
     fn readlink_attr(&self, attr: &str) -> Result<PathBuf, SysfsError> {
         let attr_path = self.0.join(attr);
         fs::read_link(&attr_path).map_err(|e| SysfsError(attr_path, SysfsErrorKind::Io(e)))
@@ -111,8 +294,8 @@ impl FromHexStr for u16 {
     }
 }
 
-fn sysfs_list_usb() -> Result<fs::ReadDir, Error> {
-    fs::read_dir("/sys/bus/usb/devices/").map_err(|e| match e.kind() {
+fn sysfs_list_usb(roots: &FsRoots) -> Result<fs::ReadDir, Error> {
+    fs::read_dir(roots.sysfs.join("bus/usb/devices")).map_err(|e| match e.kind() {
         io::ErrorKind::NotFound => {
             Error::new_io(ErrorKind::Other, "/sys/bus/usb/devices/ not found", e)
         }
@@ -127,8 +310,14 @@ fn sysfs_list_usb() -> Result<fs::ReadDir, Error> {
 
 pub fn list_devices() -> impl MaybeFuture<Output = Result<impl Iterator<Item = DeviceInfo>, Error>>
 {
+    list_devices_in(FsRoots::system())
+}
+
+pub(crate) fn list_devices_in(
+    roots: Arc<FsRoots>,
+) -> impl MaybeFuture<Output = Result<impl Iterator<Item = DeviceInfo>, Error>> {
     Ready((|| {
-        Ok(sysfs_list_usb()?.flat_map(|entry| {
+        Ok(sysfs_list_usb(&roots)?.flat_map(move |entry| {
             let path = entry.ok()?.path();
             let name = path.file_name()?;
 
@@ -146,15 +335,15 @@ pub fn list_devices() -> impl MaybeFuture<Output = Result<impl Iterator<Item = D
 
             let path = path.canonicalize().ok()?;
 
-            probe_device(SysfsPath(path))
+            probe_device(&roots, SysfsPath(path))
                 .inspect_err(|e| warn!("{e}; ignoring device"))
                 .ok()
         }))
     })())
 }
 
-pub fn list_root_hubs() -> Result<impl Iterator<Item = DeviceInfo>, Error> {
-    Ok(sysfs_list_usb()?.filter_map(|entry| {
+pub fn list_root_hubs(roots: Arc<FsRoots>) -> Result<impl Iterator<Item = DeviceInfo>, Error> {
+    Ok(sysfs_list_usb(&roots)?.filter_map(move |entry| {
         let path = entry.ok()?.path();
         let name = path.file_name()?;
 
@@ -165,15 +354,21 @@ pub fn list_root_hubs() -> Result<impl Iterator<Item = DeviceInfo>, Error> {
 
         let path = path.canonicalize().ok()?;
 
-        probe_device(SysfsPath(path))
+        probe_device(&roots, SysfsPath(path))
             .inspect_err(|e| warn!("{e}; ignoring root hub"))
             .ok()
     }))
 }
 
 pub fn list_buses() -> impl MaybeFuture<Output = Result<impl Iterator<Item = BusInfo>, Error>> {
+    list_buses_in(FsRoots::system())
+}
+
+fn list_buses_in(
+    roots: Arc<FsRoots>,
+) -> impl MaybeFuture<Output = Result<impl Iterator<Item = BusInfo>, Error>> {
     Ready((|| {
-        Ok(list_root_hubs()?.filter_map(|rh| {
+        Ok(list_root_hubs(roots)?.filter_map(|rh| {
             // get the parent by following the absolute symlink; root hub in /bus/usb is a symlink to a dir in parent bus
             let parent_path = rh.path.0.parent().map(|p| SysfsPath(p.to_owned()))?;
 
@@ -191,7 +386,7 @@ pub fn list_buses() -> impl MaybeFuture<Output = Result<impl Iterator<Item = Bus
     })())
 }
 
-pub fn probe_device(path: SysfsPath) -> Result<DeviceInfo, SysfsError> {
+pub fn probe_device(roots: &Arc<FsRoots>, path: SysfsPath) -> Result<DeviceInfo, SysfsError> {
     debug!("Probing device {:?}", path.0);
 
     let busnum = path.read_attr("busnum")?;
@@ -252,12 +447,252 @@ pub fn probe_device(path: SysfsPath) -> Result<DeviceInfo, SysfsError> {
                         subclass: i.read_attr_hex("bInterfaceSubClass").ok()?,
                         protocol: i.read_attr_hex("bInterfaceProtocol").ok()?,
                         interface_string: i.read_attr("interface").ok(),
+                        driver: i.readlink_attr_filename("driver").ok(),
+                        authorized: i.read_attr::<u8>("authorized").map_or(true, |a| a != 0),
+                        path: i,
                     })
                 })
                 .collect();
             interfaces.sort_unstable_by_key(|i| i.interface_number);
             interfaces
         },
+        authorized: path.read_attr::<u8>("authorized").map_or(true, |a| a != 0),
+        removable: path
+            .read_attr::<String>("removable")
+            .map_or(Removable::Unknown, |s| Removable::from_str(&s)),
+        max_power: path
+            .read_attr::<String>("bMaxPower")
+            .ok()
+            .and_then(|s| s.strip_suffix("mA")?.trim().parse().ok()),
+        descriptors: std::fs::read(path.0.join("descriptors")).unwrap_or_default(),
         path,
+        roots: roots.clone(),
+        emulated_id: 0,
+        emulated: None,
     })
 }
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+    use crate::{AuthorizedDefault, PowerControl, RuntimeStatus};
+    use std::time::Duration;
+
+    /// Create a sysfs tree with one root hub and one device, as captured from
+    /// a real system.
+    pub(crate) fn fixture(root: &Path) {
+        let write = |dir: &Path, attrs: &[(&str, &str)]| {
+            fs::create_dir_all(dir).unwrap();
+            for (name, value) in attrs {
+                fs::write(dir.join(name), format!("{value}\n")).unwrap();
+            }
+        };
+        let link = |target: &str, link: &Path| {
+            fs::create_dir_all(link.parent().unwrap()).unwrap();
+            std::os::unix::fs::symlink(target, link).unwrap();
+        };
+
+        let hc = root.join("devices/pci0000:00/0000:00:14.0");
+        fs::create_dir_all(root.join("bus/pci/drivers/xhci_hcd")).unwrap();
+        link("../../../bus/pci/drivers/xhci_hcd", &hc.join("driver"));
+
+        let device = |path: &Path, devnum: &str, devpath: &str, id: (&str, &str)| {
+            write(
+                path,
+                &[
+                    ("busnum", "1"),
+                    ("devnum", devnum),
+                    ("devpath", devpath),
+                    ("idVendor", id.0),
+                    ("idProduct", id.1),
+                    ("bcdDevice", "0100"),
+                    ("version", " 2.00"),
+                    ("bDeviceClass", "00"),
+                    ("bDeviceSubClass", "00"),
+                    ("bDeviceProtocol", "00"),
+                    ("speed", "480"),
+                ],
+            )
+        };
+        device(&hc.join("usb1"), "1", "0", ("1d6b", "0002"));
+        write(&hc.join("usb1"), &[("authorized_default", "1")]);
+        device(&hc.join("usb1/1-2"), "3", "2", ("1234", "5678"));
+        write(
+            &hc.join("usb1/1-2"),
+            &[
+                ("product", "Widget"),
+                ("removable", "removable"),
+                ("bMaxPower", "100mA"),
+                ("authorized", "1"),
+            ],
+        );
+        write(
+            &hc.join("usb1/1-2/power"),
+            &[
+                ("control", "auto"),
+                ("autosuspend_delay_ms", "2000"),
+                ("runtime_status", "suspended"),
+                ("connected_duration", "1500"),
+            ],
+        );
+        write(
+            &hc.join("usb1/1-2/1-2:1.0"),
+            &[
+                ("bInterfaceNumber", "00"),
+                ("bInterfaceClass", "ff"),
+                ("bInterfaceSubClass", "00"),
+                ("bInterfaceProtocol", "00"),
+                ("authorized", "0"),
+            ],
+        );
+        fs::create_dir_all(root.join("bus/usb/drivers/widget")).unwrap();
+        link(
+            "../../../../../../bus/usb/drivers/widget",
+            &hc.join("usb1/1-2/1-2:1.0/driver"),
+        );
+
+        let devices = root.join("bus/usb/devices");
+        link(
+            "../../../devices/pci0000:00/0000:00:14.0/usb1",
+            &devices.join("usb1"),
+        );
+        link(
+            "../../../devices/pci0000:00/0000:00:14.0/usb1/1-2",
+            &devices.join("1-2"),
+        );
+        link(
+            "../../../devices/pci0000:00/0000:00:14.0/usb1/1-2/1-2:1.0",
+            &devices.join("1-2:1.0"),
+        );
+
+        let chardevs = root.join("dev/char");
+        link(
+            "../../devices/pci0000:00/0000:00:14.0/usb1",
+            &chardevs.join("189:0"),
+        );
+        link(
+            "../../devices/pci0000:00/0000:00:14.0/usb1/1-2",
+            &chardevs.join("189:2"),
+        );
+    }
+
+    #[test]
+    fn sysfs_fixture() {
+        let root = std::env::temp_dir().join(format!("nusb-sysfs-{}", std::process::id()));
+        fixture(&root.join("sys"));
+        let roots = FsRoots::new()
+            .with_sysfs(root.join("sys"))
+            .with_usbfs(root.join("usbfs"));
+
+        let devices: Vec<_> = roots.list_devices().wait().unwrap().collect();
+        assert_eq!(devices.len(), 1);
+        let device = &devices[0];
+        assert_eq!((device.vendor_id(), device.product_id()), (0x1234, 0x5678));
+        assert_eq!(device.port_chain(), [2]);
+        assert_eq!(device.speed(), Some(Speed::High));
+        assert_eq!(device.product_string(), Some("Widget"));
+        let interface = device.interfaces().next().unwrap();
+        assert_eq!(interface.class(), 0xff);
+        assert_eq!(interface.driver(), Some("widget"));
+
+        assert!(device.authorized());
+        assert!(!interface.authorized());
+        interface.set_authorized(true).unwrap();
+        device.set_authorized(false).unwrap();
+        assert_eq!(
+            fs::read_to_string(interface.sysfs_path().join("authorized")).unwrap(),
+            "1"
+        );
+        assert_eq!(
+            fs::read_to_string(device.sysfs_path().join("authorized")).unwrap(),
+            "0"
+        );
+
+        assert_eq!(device.removable(), Removable::Removable);
+        assert_eq!(device.max_power(), Some(100));
+        assert_eq!(device.power_control().unwrap(), PowerControl::Auto);
+        assert_eq!(device.runtime_status().unwrap(), RuntimeStatus::Suspended);
+        assert_eq!(
+            device.connected_duration().unwrap(),
+            Duration::from_millis(1500)
+        );
+        device.set_power_control(PowerControl::On).unwrap();
+        assert_eq!(device.power_control().unwrap(), PowerControl::On);
+        device.set_autosuspend_delay(None).unwrap();
+        assert_eq!(device.autosuspend_delay().unwrap(), None);
+        device
+            .set_autosuspend_delay(Some(Duration::from_secs(5)))
+            .unwrap();
+        assert_eq!(
+            device.autosuspend_delay().unwrap(),
+            Some(Duration::from_secs(5))
+        );
+
+        let buses: Vec<_> = roots.list_buses().wait().unwrap().collect();
+        assert_eq!(buses.len(), 1);
+        assert_eq!(buses[0].busnum(), 1);
+        assert_eq!(buses[0].driver(), Some("xhci_hcd"));
+        assert_eq!(buses[0].controller_type(), Some(UsbControllerType::XHCI));
+        assert_eq!(
+            buses[0].authorized_default().unwrap(),
+            AuthorizedDefault::Authorized
+        );
+        buses[0]
+            .set_authorized_default(AuthorizedDefault::Unauthorized)
+            .unwrap();
+        assert_eq!(
+            buses[0].authorized_default().unwrap(),
+            AuthorizedDefault::Unauthorized
+        );
+
+        // The device node is looked up in the usbfs root, which is empty.
+        let err = device.open().wait().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Disconnected);
+        assert_eq!(device.port_path().to_string(), "1-2");
+        let err = roots.open_by_path("1-2").wait().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Disconnected);
+        let err = roots.open_by_path("1-3").wait().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        let err = roots.open_by_path("../1-2").wait().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Other);
+        let err = roots.open_by_id(1, 3).wait().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Disconnected);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn interface_driver() {
+        let root = std::env::temp_dir().join(format!("nusb-driver-{}", std::process::id()));
+        fixture(&root.join("sys"));
+        let roots = FsRoots::new().with_sysfs(root.join("sys"));
+        let interface_driver = || {
+            let device = roots.list_devices().wait().unwrap().next().unwrap();
+            let interface = device.interfaces().next().unwrap();
+            interface.driver().map(str::to_owned)
+        };
+        assert_eq!(interface_driver().as_deref(), Some("widget"));
+
+        // Claimed through usbfs after detaching the driver
+        let link = root.join("sys/devices/pci0000:00/0000:00:14.0/usb1/1-2/1-2:1.0/driver");
+        fs::remove_file(&link).unwrap();
+        assert_eq!(interface_driver(), None);
+        fs::create_dir_all(root.join("sys/bus/usb/drivers/usbfs")).unwrap();
+        std::os::unix::fs::symlink("../../../../../../bus/usb/drivers/usbfs", &link).unwrap();
+        assert_eq!(interface_driver().as_deref(), Some("usbfs"));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn write_attr_error_kind() {
+        // Root bypasses permission bits, so inject the errors a read-only or
+        // missing attribute would produce rather than creating them.
+        use rustix::io::Errno;
+        let kind = |errno: Errno| write_attr_error(errno.into()).kind();
+        assert_eq!(kind(Errno::ACCESS), ErrorKind::PermissionDenied);
+        assert_eq!(kind(Errno::PERM), ErrorKind::PermissionDenied);
+        assert_eq!(kind(Errno::NOENT), ErrorKind::NotFound);
+        assert_eq!(kind(Errno::INVAL), ErrorKind::Other);
+    }
+}
@@ -1,6 +1,7 @@
-use log::{error, trace, warn};
+use log::{debug, error, trace, warn};
 use rustix::{
-    fd::{AsFd, OwnedFd},
+    fd::OwnedFd,
+    fs::inotify,
     io::Errno,
     net::{
         bind,
@@ -8,79 +9,512 @@ use rustix::{
         recvfrom, socket_with, AddressFamily, RecvFlags, SocketFlags, SocketType,
     },
 };
-use std::{mem::MaybeUninit, os::unix::prelude::BorrowedFd, path::Path, task::Poll};
+use std::{
+    collections::{HashMap, VecDeque},
+    mem::MaybeUninit,
+    path::Path,
+    sync::Arc,
+    task::Poll,
+};
 
-use crate::{hotplug::HotplugEvent, Error, ErrorKind};
+use crate::{
+    hotplug::{HotplugEvent, HotplugSource},
+    Error, ErrorKind,
+};
 
-use super::{enumeration::probe_device, events::Async, SysfsPath};
+use super::{
+    enumeration::{probe_device, SysfsPath},
+    events::Async,
+    DeviceId, FsRoots,
+};
 
 const UDEV_MAGIC: &[u8; 12] = b"libudev\0\xfe\xed\xca\xfe";
 const UDEV_MULTICAST_GROUP: u32 = 1 << 1;
+const KERNEL_MULTICAST_GROUP: u32 = 1 << 0;
+
+/// Inode number of the initial user namespace (`PROC_USER_INIT_INO`).
+const USER_NS_INIT: &str = "user:[4026531837]";
 
 pub(crate) struct LinuxHotplugWatch {
     fd: Async<OwnedFd>,
+    source: Source,
+    roots: Arc<FsRoots>,
+
+    /// Events received but not yet returned.
+    pending: VecDeque<HotplugEvent>,
+
+    /// `DEVPATH` of devices seen by the watch, with their ID and active
+    /// configuration.
+    devices: HashMap<String, (DeviceId, u8)>,
+
+    /// `DEVPATH` of interfaces with the name of their bound driver, which
+    /// is not included in `unbind` uevents.
+    drivers: HashMap<String, String>,
+}
+
+enum Source {
+    Udev,
+    Kernel,
+    Inotify {
+        /// Watch descriptor for `/dev/bus/usb`.
+        root: i32,
+
+        /// Watch descriptors for the `/dev/bus/usb/NNN` directories, with
+        /// their bus number.
+        buses: HashMap<i32, u8>,
+    },
 }
 
 impl LinuxHotplugWatch {
     pub(crate) fn new() -> Result<Self, Error> {
-        let fd = socket_with(
-            AddressFamily::NETLINK,
-            SocketType::RAW,
-            SocketFlags::CLOEXEC,
-            Some(netlink::KOBJECT_UEVENT),
-        )
-        .map_err(|e| {
-            Error::new_os(ErrorKind::Other, "failed to open udev netlink socket", e).log_error()
-        })?;
-
-        bind(&fd, &SocketAddrNetlink::new(0, UDEV_MULTICAST_GROUP)).map_err(|e| {
-            Error::new_os(ErrorKind::Other, "failed to bind udev netlink socket", e).log_error()
-        })?;
-
-        Ok(LinuxHotplugWatch {
+        Self::with_source(FsRoots::system(), HotplugSource::Auto)
+    }
+
+    pub(crate) fn with_source(roots: Arc<FsRoots>, source: HotplugSource) -> Result<Self, Error> {
+        let (fd, source) = match source {
+            HotplugSource::Auto => {
+                let source = detect_source();
+                debug!("Using {source:?} hotplug source");
+                return Self::with_source(roots, source);
+            }
+            HotplugSource::Udev => (netlink_socket(UDEV_MULTICAST_GROUP)?, Source::Udev),
+            HotplugSource::Kernel => (netlink_socket(KERNEL_MULTICAST_GROUP)?, Source::Kernel),
+            HotplugSource::Inotify => {
+                let fd =
+                    inotify::init(inotify::CreateFlags::CLOEXEC | inotify::CreateFlags::NONBLOCK)
+                        .map_err(|e| {
+                        Error::new_os(ErrorKind::Other, "failed to create inotify instance", e)
+                            .log_error()
+                    })?;
+                let root = inotify::add_watch(
+                    &fd,
+                    roots.usbfs(),
+                    inotify::WatchFlags::CREATE | inotify::WatchFlags::ONLYDIR,
+                )
+                .map_err(|e| {
+                    Error::new_os(ErrorKind::Other, "failed to watch /dev/bus/usb", e).log_error()
+                })?;
+                let source = Source::Inotify {
+                    root,
+                    buses: HashMap::new(),
+                };
+                (fd, source)
+            }
+        };
+
+        let mut watch = LinuxHotplugWatch {
             fd: Async::new(fd)?,
-        })
+            source,
+            roots,
+            pending: VecDeque::new(),
+            devices: HashMap::new(),
+            drivers: HashMap::new(),
+        };
+
+        if let Source::Inotify { .. } = watch.source {
+            for entry in std::fs::read_dir(watch.roots.usbfs())
+                .into_iter()
+                .flatten()
+                .flatten()
+            {
+                if let Some(name) = entry.file_name().to_str() {
+                    watch.watch_bus(name, false);
+                }
+            }
+        }
+
+        Ok(watch)
+    }
+
+    pub(crate) fn roots(&self) -> Arc<FsRoots> {
+        self.roots.clone()
     }
 
     pub(crate) fn poll_next(&mut self, cx: &mut std::task::Context<'_>) -> Poll<HotplugEvent> {
-        if let Some(event) = try_receive_event(self.fd.inner.as_fd()) {
-            return Poll::Ready(event);
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Poll::Ready(event);
+            }
+
+            let received = match self.source {
+                Source::Udev | Source::Kernel => self.receive_uevent(),
+                Source::Inotify { .. } => self.receive_inotify(),
+            };
+            if !received {
+                break;
+            }
         }
 
         if let Err(e) = self.fd.register(cx.waker()) {
-            log::error!("failed to register udev socket with epoll: {e}");
+            log::error!("failed to register hotplug socket with epoll: {e}");
         }
 
         Poll::Pending
     }
-}
 
-fn try_receive_event(fd: BorrowedFd) -> Option<HotplugEvent> {
-    let mut buf = [MaybeUninit::uninit(); 8192];
+    /// Receive and handle a netlink packet, returning `false` if none was
+    /// available.
+    fn receive_uevent(&mut self) -> bool {
+        let mut buf = [MaybeUninit::uninit(); 8192];
+        let (data, src) = match recvfrom(&self.fd.inner, &mut buf, RecvFlags::DONTWAIT) {
+            Ok(((buf, _), _, src)) => (buf, src),
+            Err(Errno::AGAIN | Errno::INTR) => return false,
+            Err(e) => {
+                error!("uevent netlink socket recvfrom failed with {e}");
+                return false;
+            }
+        };
 
-    let (data, src) = match recvfrom(fd, &mut buf, RecvFlags::DONTWAIT) {
-        Ok(((buf, _), _, src)) => (buf, src),
-        Err(Errno::AGAIN | Errno::INTR) => return None,
-        Err(e) => {
-            error!("udev netlink socket recvfrom failed with {e}");
+        // udev messages will normally be sent to a multicast group, which only
+        // root can send to, and kernel messages come from port 0. Reject
+        // unicast messages that may be from anywhere.
+        let kernel = matches!(self.source, Source::Kernel);
+        match src.map(SocketAddrNetlink::try_from).transpose() {
+            Ok(Some(nl)) if kernel && nl.groups() == KERNEL_MULTICAST_GROUP && nl.pid() == 0 => {}
+            Ok(Some(nl)) if !kernel && nl.groups() == UDEV_MULTICAST_GROUP => {}
+            src => {
+                warn!("uevent netlink socket received message from {src:?}");
+                return true;
+            }
+        }
+
+        let uevent = if kernel {
+            parse_kernel_packet(data)
+        } else {
+            parse_udev_packet(data)
+        };
+        if let Some(event) = uevent.and_then(|u| self.handle_uevent(u)) {
+            self.pending.push_back(event);
+        }
+        true
+    }
+
+    /// Receive and handle inotify events, returning `false` if none were
+    /// available.
+    fn receive_inotify(&mut self) -> bool {
+        let mut buf = [MaybeUninit::uninit(); 4096];
+        let mut reader = inotify::Reader::new(&self.fd.inner, &mut buf);
+        let mut events = Vec::new();
+        loop {
+            match reader.next() {
+                Ok(event) => {
+                    let name = event.file_name().and_then(|n| n.to_str().ok());
+                    events.push((event.wd(), event.events(), name.map(str::to_owned)));
+                }
+                Err(Errno::AGAIN | Errno::INTR) => break,
+                Err(e) => {
+                    error!("inotify read failed with {e}");
+                    break;
+                }
+            }
+            if reader.is_buffer_empty() {
+                break;
+            }
+        }
+
+        let received = !events.is_empty();
+        for (wd, flags, name) in events {
+            self.handle_inotify(wd, flags, name.as_deref());
+        }
+        received
+    }
+
+    fn handle_inotify(&mut self, wd: i32, flags: inotify::ReadFlags, name: Option<&str>) {
+        let Source::Inotify { root, buses } = &mut self.source else {
+            return;
+        };
+
+        if wd == *root {
+            if let Some(name) = name.filter(|_| flags.contains(inotify::ReadFlags::CREATE)) {
+                self.watch_bus(name, true);
+            }
+            return;
+        }
+
+        let Some(&bus) = buses.get(&wd) else {
+            return;
+        };
+        if flags.contains(inotify::ReadFlags::IGNORED) {
+            buses.remove(&wd);
+            return;
+        }
+        let Some(addr) = name.and_then(|n| n.parse::<u8>().ok()) else {
+            return;
+        };
+
+        if flags.contains(inotify::ReadFlags::CREATE) {
+            if let Some(event) = self.probe_device_node(bus, addr) {
+                self.pending.push_back(event);
+            }
+        } else if flags.contains(inotify::ReadFlags::DELETE) {
+            self.pending.push_back(HotplugEvent::Disconnected(
+                crate::DeviceId(DeviceId {
+                    bus,
+                    addr,
+                    emulated: 0,
+                }),
+                None,
+            ));
+        }
+    }
+
+    /// Watch a `/dev/bus/usb/NNN` directory.
+    ///
+    /// For a bus that appeared after the hotplug watch was created, the
+    /// devices that were created in it before the directory watch was added
+    /// are reported. Devices that existed when the hotplug watch was created
+    /// are not, like with the netlink sources.
+    fn watch_bus(&mut self, name: &str, report_existing: bool) {
+        let Source::Inotify { buses, .. } = &mut self.source else {
+            return;
+        };
+        let Ok(bus) = name.parse::<u8>() else {
+            return;
+        };
+
+        let path = self.roots.usbfs().join(name);
+        match inotify::add_watch(
+            &self.fd.inner,
+            &path,
+            inotify::WatchFlags::CREATE | inotify::WatchFlags::DELETE,
+        ) {
+            Ok(wd) => {
+                buses.insert(wd, bus);
+            }
+            Err(e) => {
+                error!("Failed to watch {path:?}: {e}");
+                return;
+            }
+        }
+
+        if !report_existing {
+            return;
+        }
+        for entry in std::fs::read_dir(&path).into_iter().flatten().flatten() {
+            if let Some(addr) = entry.file_name().to_str().and_then(|n| n.parse().ok()) {
+                if let Some(event) = self.probe_device_node(bus, addr) {
+                    self.pending.push_back(event);
+                }
+            }
+        }
+    }
+
+    fn handle_uevent(&mut self, uevent: Uevent) -> Option<HotplugEvent> {
+        if uevent.subsystem != "usb" {
             return None;
         }
-    };
 
-    // udev messages will normally be sent to a multicast group, which only
-    // root can send to. Reject unicast messages that may be from anywhere.
-    match src.map(SocketAddrNetlink::try_from).transpose() {
-        Ok(Some(nl)) if nl.groups() == UDEV_MULTICAST_GROUP => {}
-        src => {
-            warn!("udev netlink socket received message from {src:?}");
+        match (uevent.devtype, uevent.action) {
+            ("usb_device", "add") => {
+                let path = self.roots.devpath(uevent.devpath);
+                match probe_device(&self.roots, path.clone()) {
+                    Ok(d) => {
+                        let configuration = path.read_attr("bConfigurationValue").unwrap_or(0);
+                        self.devices
+                            .insert(uevent.devpath.to_owned(), (d.id().0, configuration));
+                        Some(HotplugEvent::Connected(d))
+                    }
+                    Err(e) => {
+                        warn!("Failed to probe device {path:?}: {e}");
+                        None
+                    }
+                }
+            }
+            ("usb_device", "remove") => {
+                self.devices.remove(uevent.devpath);
+                self.drivers
+                    .retain(|p, _| Path::new(p).parent() != Some(Path::new(uevent.devpath)));
+                Some(HotplugEvent::Disconnected(
+                    crate::DeviceId(DeviceId {
+                        bus: uevent.busnum?,
+                        addr: uevent.devnum?,
+                        emulated: 0,
+                    }),
+                    None,
+                ))
+            }
+            ("usb_interface", _) => self.handle_interface_uevent(uevent),
+            _ => None,
+        }
+    }
+
+    fn handle_interface_uevent(&mut self, uevent: Uevent) -> Option<HotplugEvent> {
+        let (device_path, name) = uevent.devpath.rsplit_once('/')?;
+        let (_, interface) = parse_interface_name(name)?;
+
+        let device = match uevent.action {
+            // Interfaces are added and removed when the configuration
+            // changes, or when the device is connected or disconnected.
+            "add" | "remove" => {
+                let (id, configuration) = self.read_device(device_path)?;
+                let previous = self
+                    .devices
+                    .insert(device_path.to_owned(), (id, configuration));
+                return match previous {
+                    Some((_, c)) if c == configuration => None,
+                    None if uevent.action == "remove" => None,
+                    _ => Some(HotplugEvent::ConfigurationChanged {
+                        device: crate::DeviceId(id),
+                        configuration,
+                    }),
+                };
+            }
+            _ => match self.devices.get(device_path) {
+                Some(&(id, _)) => id,
+                None => {
+                    let (id, configuration) = self.read_device(device_path)?;
+                    self.devices
+                        .insert(device_path.to_owned(), (id, configuration));
+                    id
+                }
+            },
+        };
+        let device = crate::DeviceId(device);
+
+        match uevent.action {
+            "bind" => {
+                let driver = uevent.driver?.to_owned();
+                self.drivers
+                    .insert(uevent.devpath.to_owned(), driver.clone());
+                Some(HotplugEvent::InterfaceBound {
+                    device,
+                    interface,
+                    driver,
+                })
+            }
+            "unbind" => Some(HotplugEvent::InterfaceUnbound {
+                device,
+                interface,
+                driver: self.drivers.remove(uevent.devpath),
+            }),
+            "change" => Some(HotplugEvent::InterfaceChanged {
+                device,
+                interface,
+                driver: uevent.driver.map(str::to_owned),
+            }),
+            _ => None,
+        }
+    }
+
+    /// Find the device for a node created in the usbfs directory.
+    fn probe_device_node(&self, bus: u8, addr: u8) -> Option<HotplugEvent> {
+        let path = self
+            .roots
+            .device_node_sysfs_link(bus, addr)
+            .and_then(|link| link.canonicalize().ok());
+        let Some(path) = path else {
+            debug!("No sysfs device found for usbfs node {bus:03}/{addr:03}");
             return None;
+        };
+
+        // Root hubs (`usb1`) are not reported, as in `list_devices`
+        if path
+            .file_name()
+            .is_some_and(|n| n.as_encoded_bytes().starts_with(b"usb"))
+        {
+            return None;
+        }
+
+        match probe_device(&self.roots, SysfsPath(path)) {
+            Ok(device) => Some(HotplugEvent::Connected(device)),
+            Err(e) => {
+                warn!("Failed to probe device for usbfs node {bus:03}/{addr:03}: {e}");
+                None
+            }
         }
     }
 
-    parse_packet(data)
+    /// Read the ID and active configuration of a device from sysfs, or `None`
+    /// if it has been removed.
+    fn read_device(&self, devpath: &str) -> Option<(DeviceId, u8)> {
+        let path = self.roots.devpath(devpath);
+        let id = DeviceId {
+            bus: path.read_attr("busnum").ok()?,
+            addr: path.read_attr("devnum").ok()?,
+            emulated: 0,
+        };
+        // Empty when the device is unconfigured
+        let configuration = path.read_attr("bConfigurationValue").unwrap_or(0);
+        Some((id, configuration))
+    }
+}
+
+fn netlink_socket(group: u32) -> Result<OwnedFd, Error> {
+    let fd = socket_with(
+        AddressFamily::NETLINK,
+        SocketType::RAW,
+        SocketFlags::CLOEXEC,
+        Some(netlink::KOBJECT_UEVENT),
+    )
+    .map_err(|e| {
+        Error::new_os(ErrorKind::Other, "failed to open uevent netlink socket", e).log_error()
+    })?;
+
+    bind(&fd, &SocketAddrNetlink::new(0, group)).map_err(|e| {
+        Error::new_os(ErrorKind::Other, "failed to bind uevent netlink socket", e).log_error()
+    })?;
+
+    Ok(fd)
+}
+
+fn detect_source() -> HotplugSource {
+    if Path::new("/run/udev/control").exists() {
+        HotplugSource::Udev
+    } else if std::fs::read_link("/proc/self/ns/user").is_ok_and(|ns| ns == Path::new(USER_NS_INIT))
+    {
+        HotplugSource::Kernel
+    } else {
+        HotplugSource::Inotify
+    }
+}
+
+/// Parse the configuration and interface number from an interface's sysfs
+/// name, such as `1-2.3:1.0`.
+fn parse_interface_name(name: &str) -> Option<(u8, u8)> {
+    let (_, suffix) = name.rsplit_once(':')?;
+    let (configuration, interface) = suffix.split_once('.')?;
+    Some((configuration.parse().ok()?, interface.parse().ok()?))
+}
+
+/// Properties of a uevent used by the watch.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Uevent<'a> {
+    action: &'a str,
+    devpath: &'a str,
+    subsystem: &'a str,
+    devtype: &'a str,
+    driver: Option<&'a str>,
+    busnum: Option<u8>,
+    devnum: Option<u8>,
 }
 
-fn parse_packet(buf: &[u8]) -> Option<HotplugEvent> {
+impl<'a> Uevent<'a> {
+    fn from_properties(buf: &'a [u8]) -> Option<Uevent<'a>> {
+        let mut uevent = Uevent::default();
+        for (k, v) in parse_properties(buf) {
+            trace!("uevent property {k} = {v}");
+            match k {
+                "ACTION" => uevent.action = v,
+                "DEVPATH" => uevent.devpath = v,
+                "SUBSYSTEM" => uevent.subsystem = v,
+                "DEVTYPE" => uevent.devtype = v,
+                "DRIVER" => uevent.driver = Some(v),
+                "BUSNUM" => uevent.busnum = v.parse().ok(),
+                "DEVNUM" => uevent.devnum = v.parse().ok(),
+                _ => {}
+            }
+        }
+
+        if uevent.action.is_empty() || uevent.devpath.is_empty() {
+            return None;
+        }
+        Some(uevent)
+    }
+}
+
+/// Parse a packet sent by udevd, with a binary header followed by the
+/// properties.
+fn parse_udev_packet(buf: &[u8]) -> Option<Uevent<'_>> {
     if buf.len() < 24 {
         error!("packet too short: {buf:x?}");
         return None;
@@ -98,58 +532,23 @@ fn parse_packet(buf: &[u8]) -> Option<HotplugEvent> {
         return None;
     };
 
-    let mut is_add = None;
-    let mut busnum = None;
-    let mut devnum = None;
-    let mut devpath = None;
+    Uevent::from_properties(properties_buf)
+}
 
-    for (k, v) in parse_properties(properties_buf) {
-        trace!("uevent property {k} = {v}");
-        match k {
-            "SUBSYSTEM" if v != "usb" => return None,
-            "DEVTYPE" if v != "usb_device" => return None,
-            "ACTION" => {
-                is_add = Some(match v {
-                    "add" => true,
-                    "remove" => false,
-                    _ => return None,
-                });
-            }
-            "BUSNUM" => {
-                busnum = v.parse::<u8>().ok();
-            }
-            "DEVNUM" => {
-                devnum = v.parse::<u8>().ok();
-            }
-            "DEVPATH" => {
-                devpath = Some(v);
-            }
-            _ => {}
-        }
+/// Parse a packet sent by the kernel, with an `ACTION@DEVPATH` summary line
+/// followed by the properties.
+fn parse_kernel_packet(buf: &[u8]) -> Option<Uevent<'_>> {
+    let Some(header_len) = buf.iter().position(|&b| b == 0) else {
+        error!("kernel uevent packet is not terminated: {buf:x?}");
+        return None;
+    };
+    if !buf[..header_len].contains(&b'@') {
+        // Messages from udevd or other userspace are prefixed differently
+        debug!("ignoring non-uevent packet: {buf:x?}");
+        return None;
     }
 
-    let is_add = is_add?;
-    let busnum = busnum?;
-    let devnum = devnum?;
-    let devpath = devpath?;
-
-    if is_add {
-        let path = Path::new("/sys/").join(devpath.trim_start_matches('/'));
-        match probe_device(SysfsPath(path.clone())) {
-            Ok(d) => Some(HotplugEvent::Connected(d)),
-            Err(e) => {
-                warn!("Failed to probe device {path:?}: {e}");
-                None
-            }
-        }
-    } else {
-        Some(HotplugEvent::Disconnected(crate::DeviceId(
-            super::DeviceId {
-                bus: busnum,
-                addr: devnum,
-            },
-        )))
-    }
+    Uevent::from_properties(&buf[header_len + 1..])
 }
 
 /// Split nul-separated key=value pairs
@@ -157,3 +556,224 @@ fn parse_properties(buf: &[u8]) -> impl Iterator<Item = (&str, &str)> + '_ {
     buf.split(|b| b == &0)
         .filter_map(|entry| std::str::from_utf8(entry).ok()?.split_once('='))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KERNEL_ADD: &[u8] = b"add@/devices/pci0000:00/0000:00:14.0/usb1/1-2\0\
+        ACTION=add\0DEVPATH=/devices/pci0000:00/0000:00:14.0/usb1/1-2\0SUBSYSTEM=usb\0\
+        MAJOR=189\0MINOR=3\0DEVNAME=bus/usb/001/004\0DEVTYPE=usb_device\0\
+        PRODUCT=1234/5678/100\0TYPE=239/2/1\0BUSNUM=001\0DEVNUM=004\0SEQNUM=5021\0";
+
+    const KERNEL_BIND: &[u8] = b"bind@/devices/pci0000:00/0000:00:14.0/usb1/1-2/1-2:1.0\0\
+        ACTION=bind\0DEVPATH=/devices/pci0000:00/0000:00:14.0/usb1/1-2/1-2:1.0\0\
+        SUBSYSTEM=usb\0DEVTYPE=usb_interface\0DRIVER=cdc_acm\0PRODUCT=1234/5678/100\0\
+        TYPE=239/2/1\0INTERFACE=2/2/1\0MODALIAS=usb:v1234p5678d0100\0SEQNUM=5030\0";
+
+    const KERNEL_UNBIND: &[u8] = b"unbind@/devices/pci0000:00/0000:00:14.0/usb1/1-2/1-2:1.0\0\
+        ACTION=unbind\0DEVPATH=/devices/pci0000:00/0000:00:14.0/usb1/1-2/1-2:1.0\0\
+        SUBSYSTEM=usb\0DEVTYPE=usb_interface\0PRODUCT=1234/5678/100\0\
+        TYPE=239/2/1\0INTERFACE=2/2/1\0MODALIAS=usb:v1234p5678d0100\0SEQNUM=5031\0";
+
+    const KERNEL_REMOVE_INTERFACE: &[u8] =
+        b"remove@/devices/pci0000:00/0000:00:14.0/usb1/1-2/1-2:1.0\0\
+        ACTION=remove\0DEVPATH=/devices/pci0000:00/0000:00:14.0/usb1/1-2/1-2:1.0\0\
+        SUBSYSTEM=usb\0DEVTYPE=usb_interface\0PRODUCT=1234/5678/100\0\
+        TYPE=239/2/1\0INTERFACE=2/2/1\0MODALIAS=usb:v1234p5678d0100\0SEQNUM=5040\0";
+
+    const KERNEL_REMOVE: &[u8] = b"remove@/devices/pci0000:00/0000:00:14.0/usb1/1-2\0\
+        ACTION=remove\0DEVPATH=/devices/pci0000:00/0000:00:14.0/usb1/1-2\0SUBSYSTEM=usb\0\
+        MAJOR=189\0MINOR=2\0DEVNAME=bus/usb/001/003\0DEVTYPE=usb_device\0\
+        PRODUCT=1234/5678/100\0TYPE=239/2/1\0BUSNUM=001\0DEVNUM=003\0SEQNUM=5050\0";
+
+    const KERNEL_ADD_INTERFACE: &[u8] = b"add@/devices/pci0000:00/0000:00:14.0/usb1/1-2/1-2:2.0\0\
+        ACTION=add\0DEVPATH=/devices/pci0000:00/0000:00:14.0/usb1/1-2/1-2:2.0\0\
+        SUBSYSTEM=usb\0DEVTYPE=usb_interface\0PRODUCT=1234/5678/100\0\
+        TYPE=239/2/1\0INTERFACE=255/0/0\0MODALIAS=usb:v1234p5678d0100\0SEQNUM=5042\0";
+
+    #[test]
+    fn kernel_packets() {
+        assert_eq!(
+            parse_kernel_packet(KERNEL_ADD),
+            Some(Uevent {
+                action: "add",
+                devpath: "/devices/pci0000:00/0000:00:14.0/usb1/1-2",
+                subsystem: "usb",
+                devtype: "usb_device",
+                driver: None,
+                busnum: Some(1),
+                devnum: Some(4),
+            })
+        );
+        assert_eq!(
+            parse_kernel_packet(KERNEL_BIND),
+            Some(Uevent {
+                action: "bind",
+                devpath: "/devices/pci0000:00/0000:00:14.0/usb1/1-2/1-2:1.0",
+                subsystem: "usb",
+                devtype: "usb_interface",
+                driver: Some("cdc_acm"),
+                busnum: None,
+                devnum: None,
+            })
+        );
+        assert_eq!(parse_kernel_packet(b"libudev\0\xfe\xed\xca\xfe\0"), None);
+    }
+
+    #[test]
+    fn udev_packet() {
+        // The kernel packet's properties with udev's header, and properties
+        // added by udev rules.
+        let properties_off = KERNEL_ADD.iter().position(|&b| b == 0).unwrap() + 1;
+        let properties = [&KERNEL_ADD[properties_off..], b"ID_VENDOR=Example\0"].concat();
+        let mut packet = UDEV_MAGIC.to_vec();
+        packet.extend_from_slice(&40u32.to_ne_bytes());
+        packet.extend_from_slice(&40u32.to_ne_bytes());
+        packet.extend_from_slice(&(properties.len() as u32).to_ne_bytes());
+        packet.extend_from_slice(&[0; 16]);
+        packet.extend_from_slice(&properties);
+
+        assert_eq!(parse_udev_packet(&packet), parse_kernel_packet(KERNEL_ADD));
+        assert_eq!(parse_udev_packet(KERNEL_ADD), None);
+    }
+
+    #[test]
+    fn interface_name() {
+        assert_eq!(parse_interface_name("1-2.3:1.0"), Some((1, 0)));
+        assert_eq!(parse_interface_name("3-1:2.11"), Some((2, 11)));
+        assert_eq!(parse_interface_name("usb1"), None);
+    }
+
+    #[test]
+    fn inotify_source() {
+        use std::fs;
+
+        let root = std::env::temp_dir().join(format!("nusb-inotify-{}", std::process::id()));
+        super::super::enumeration::tests::fixture(&root.join("sys"));
+        let usbfs = root.join("usbfs");
+        fs::create_dir_all(usbfs.join("001")).unwrap();
+        fs::write(usbfs.join("001/001"), "").unwrap();
+        fs::write(usbfs.join("001/003"), "").unwrap();
+        let roots = Arc::new(
+            FsRoots::new()
+                .with_sysfs(root.join("sys"))
+                .with_usbfs(&usbfs),
+        );
+
+        // Devices that already exist are not reported
+        let mut watch = LinuxHotplugWatch::with_source(roots, HotplugSource::Inotify).unwrap();
+        assert!(watch.pending.is_empty());
+
+        fs::remove_file(usbfs.join("001/003")).unwrap();
+        fs::write(usbfs.join("001/003"), "").unwrap();
+        assert!(watch.receive_inotify());
+        let id = crate::DeviceId(DeviceId {
+            bus: 1,
+            addr: 3,
+            emulated: 0,
+        });
+        assert!(matches!(
+            watch.pending.pop_front(),
+            Some(HotplugEvent::Disconnected(d, _)) if d == id
+        ));
+        assert!(matches!(
+            watch.pending.pop_front(),
+            Some(HotplugEvent::Connected(d)) if d.id() == id && d.product_id() == 0x5678
+        ));
+        assert!(watch.pending.is_empty());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn interface_uevents() {
+        use std::fs;
+
+        let root = std::env::temp_dir().join(format!("nusb-uevents-{}", std::process::id()));
+        super::super::enumeration::tests::fixture(&root.join("sys"));
+        let device_path = root.join("sys/devices/pci0000:00/0000:00:14.0/usb1/1-2");
+        fs::write(device_path.join("bConfigurationValue"), "1\n").unwrap();
+        fs::create_dir_all(root.join("usbfs")).unwrap();
+        let roots = Arc::new(
+            FsRoots::new()
+                .with_sysfs(root.join("sys"))
+                .with_usbfs(root.join("usbfs")),
+        );
+        let mut watch = LinuxHotplugWatch::with_source(roots, HotplugSource::Inotify).unwrap();
+        let mut handle = |packet| watch.handle_uevent(parse_kernel_packet(packet).unwrap());
+        let id = crate::DeviceId(DeviceId {
+            bus: 1,
+            addr: 3,
+            emulated: 0,
+        });
+
+        assert!(matches!(
+            handle(KERNEL_BIND),
+            Some(HotplugEvent::InterfaceBound { device, interface: 0, driver })
+                if device == id && driver == "cdc_acm"
+        ));
+        assert!(matches!(
+            handle(KERNEL_UNBIND),
+            Some(HotplugEvent::InterfaceUnbound { device, interface: 0, driver: Some(driver) })
+                if device == id && driver == "cdc_acm"
+        ));
+
+        // Switching to configuration 2 removes the interfaces of the old
+        // configuration while the device is unconfigured, and then adds the
+        // new ones.
+        fs::write(device_path.join("bConfigurationValue"), "\n").unwrap();
+        assert!(matches!(
+            handle(KERNEL_REMOVE_INTERFACE),
+            Some(HotplugEvent::ConfigurationChanged { device, configuration: 0 }) if device == id
+        ));
+        fs::write(device_path.join("bConfigurationValue"), "2\n").unwrap();
+        assert!(matches!(
+            handle(KERNEL_ADD_INTERFACE),
+            Some(HotplugEvent::ConfigurationChanged { device, configuration: 2 }) if device == id
+        ));
+        assert!(handle(KERNEL_ADD_INTERFACE).is_none());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn unbind_on_disconnect() {
+        use std::fs;
+
+        let root = std::env::temp_dir().join(format!("nusb-unplug-{}", std::process::id()));
+        super::super::enumeration::tests::fixture(&root.join("sys"));
+        let device_path = root.join("sys/devices/pci0000:00/0000:00:14.0/usb1/1-2");
+        fs::write(device_path.join("bConfigurationValue"), "1\n").unwrap();
+        fs::create_dir_all(root.join("usbfs")).unwrap();
+        let roots = Arc::new(
+            FsRoots::new()
+                .with_sysfs(root.join("sys"))
+                .with_usbfs(root.join("usbfs")),
+        );
+        let mut watch = LinuxHotplugWatch::with_source(roots, HotplugSource::Inotify).unwrap();
+        let mut handle = |packet| watch.handle_uevent(parse_kernel_packet(packet).unwrap());
+        let id = crate::DeviceId(DeviceId {
+            bus: 1,
+            addr: 3,
+            emulated: 0,
+        });
+
+        // The kernel unbinds and removes the interfaces while the device is
+        // still in sysfs, and then removes the device
+        handle(KERNEL_BIND);
+        assert!(matches!(
+            handle(KERNEL_UNBIND),
+            Some(HotplugEvent::InterfaceUnbound { device, interface: 0, driver: Some(driver) })
+                if device == id && driver == "cdc_acm"
+        ));
+        assert!(handle(KERNEL_REMOVE_INTERFACE).is_none());
+        fs::remove_dir_all(&device_path).unwrap();
+        assert!(matches!(
+            handle(KERNEL_REMOVE),
+            Some(HotplugEvent::Disconnected(device, _)) if device == id
+        ));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
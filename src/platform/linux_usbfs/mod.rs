@@ -10,7 +10,13 @@ mod usbfs;
 mod enumeration;
 
 #[cfg(not(target_os = "android"))]
-pub use enumeration::{list_buses, list_devices, SysfsPath};
+pub use enumeration::{list_buses, list_devices, FsRoots, SysfsPath};
+
+#[cfg(not(target_os = "android"))]
+pub(crate) use enumeration::list_devices_in;
+
+#[cfg(all(test, not(target_os = "android")))]
+pub(crate) use enumeration::tests::fixture as sysfs_fixture;
 
 #[cfg(not(target_os = "android"))]
 mod hotplug;
@@ -19,6 +25,8 @@ mod hotplug;
 pub(crate) use hotplug::LinuxHotplugWatch as HotplugWatch;
 
 mod events;
+#[cfg(feature = "gadget")]
+pub(crate) use events::Async;
 
 mod device;
 pub(crate) use device::LinuxDevice as Device;
@@ -29,12 +37,15 @@ use crate::transfer::TransferError;
 use crate::ErrorKind;
 
 #[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DeviceId {
     pub(crate) bus: u8,
     pub(crate) addr: u8,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub(crate) emulated: u32,
 }
 
-fn errno_to_transfer_error(e: Errno) -> TransferError {
+pub(crate) fn errno_to_transfer_error(e: Errno) -> TransferError {
     match e {
         Errno::NODEV | Errno::SHUTDOWN => TransferError::Disconnected,
         Errno::PIPE => TransferError::Stall,
@@ -5,15 +5,17 @@
 //! [uapi]: https://github.com/torvalds/linux/blob/master/tools/include/uapi/linux/usbdevice_fs.h
 #![allow(dead_code)]
 use std::{
-    ffi::{c_int, c_uchar, c_uint, c_void},
+    ffi::{c_int, c_uchar, c_uint, c_void, CStr},
     fmt::Debug,
 };
 
 use linux_raw_sys::ioctl::{
-    USBDEVFS_CLAIMINTERFACE, USBDEVFS_CLEAR_HALT, USBDEVFS_CONNECT, USBDEVFS_CONTROL,
-    USBDEVFS_DISCARDURB, USBDEVFS_DISCONNECT, USBDEVFS_DISCONNECT_CLAIM, USBDEVFS_GET_SPEED,
-    USBDEVFS_IOCTL, USBDEVFS_REAPURBNDELAY, USBDEVFS_RELEASEINTERFACE, USBDEVFS_RESET,
-    USBDEVFS_SETCONFIGURATION, USBDEVFS_SETINTERFACE, USBDEVFS_SUBMITURB,
+    USBDEVFS_ALLOW_SUSPEND, USBDEVFS_CLAIMINTERFACE, USBDEVFS_CLEAR_HALT, USBDEVFS_CONNECT,
+    USBDEVFS_CONTROL, USBDEVFS_DISCARDURB, USBDEVFS_DISCONNECT, USBDEVFS_DISCONNECT_CLAIM,
+    USBDEVFS_DROP_PRIVILEGES, USBDEVFS_FORBID_SUSPEND, USBDEVFS_GETDRIVER,
+    USBDEVFS_GET_CAPABILITIES, USBDEVFS_GET_SPEED, USBDEVFS_IOCTL, USBDEVFS_REAPURBNDELAY,
+    USBDEVFS_RELEASEINTERFACE, USBDEVFS_RESET, USBDEVFS_SETCONFIGURATION, USBDEVFS_SETINTERFACE,
+    USBDEVFS_SUBMITURB, USBDEVFS_WAIT_FOR_RESUME,
 };
 use log::trace;
 use rustix::{
@@ -101,6 +103,25 @@ pub fn attach_kernel_driver<Fd: AsFd>(fd: Fd, interface: u8) -> io::Result<()> {
     }
 }
 
+#[repr(C)]
+struct GetDriver {
+    interface: c_uint,
+    driver: [c_uchar; 255 + 1],
+}
+
+pub fn get_driver<Fd: AsFd>(fd: Fd, interface: u8) -> io::Result<String> {
+    let mut gd = GetDriver {
+        interface: interface.into(),
+        driver: [0; 256],
+    };
+    unsafe {
+        let ctl = PassPtr::<{ USBDEVFS_GETDRIVER as _ }, GetDriver>::new(&mut gd);
+        ioctl::ioctl(fd, ctl)?;
+    }
+    let driver = CStr::from_bytes_until_nul(&gd.driver).unwrap_or_default();
+    Ok(driver.to_string_lossy().into_owned())
+}
+
 #[repr(C)]
 struct SetAltSetting {
     interface: c_int,
@@ -183,6 +204,34 @@ pub fn reset<Fd: AsFd>(fd: Fd) -> io::Result<()> {
     }
 }
 
+pub fn forbid_suspend<Fd: AsFd>(fd: Fd) -> io::Result<()> {
+    unsafe {
+        let ctl = ioctl::NoArg::<{ USBDEVFS_FORBID_SUSPEND as _ }>::new();
+        ioctl::ioctl(fd, ctl)
+    }
+}
+
+pub fn allow_suspend<Fd: AsFd>(fd: Fd) -> io::Result<()> {
+    unsafe {
+        let ctl = ioctl::NoArg::<{ USBDEVFS_ALLOW_SUSPEND as _ }>::new();
+        ioctl::ioctl(fd, ctl)
+    }
+}
+
+pub fn drop_privileges<Fd: AsFd>(fd: Fd, allowed_interfaces: u32) -> io::Result<()> {
+    unsafe {
+        let ctl = ioctl::Setter::<{ USBDEVFS_DROP_PRIVILEGES as _ }, u32>::new(allowed_interfaces);
+        ioctl::ioctl(fd, ctl)
+    }
+}
+
+pub fn wait_for_resume<Fd: AsFd>(fd: Fd) -> io::Result<()> {
+    unsafe {
+        let ctl = ioctl::NoArg::<{ USBDEVFS_WAIT_FOR_RESUME as _ }>::new();
+        ioctl::ioctl(fd, ctl)
+    }
+}
+
 const USBDEVFS_URB_SHORT_NOT_OK: c_uint = 0x01;
 const USBDEVFS_URB_ISO_ASAP: c_uint = 0x02;
 const USBDEVFS_URB_BULK_CONTINUATION: c_uint = 0x04;
@@ -307,3 +356,46 @@ pub fn get_speed<Fd: AsFd>(fd: Fd) -> io::Result<usize> {
         ioctl::ioctl(fd, ctl)
     }
 }
+
+pub fn get_capabilities<Fd: AsFd>(fd: Fd) -> io::Result<u32> {
+    unsafe {
+        let ctl = ioctl::Getter::<{ USBDEVFS_GET_CAPABILITIES as _ }, u32>::new();
+        ioctl::ioctl(fd, ctl)
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct ConnInfoEx {
+    /// Size of the structure from the kernel's point of view.
+    pub size: u32,
+    pub busnum: u32,
+    pub devnum: u32,
+    /// `USB_SPEED_*` constant, as returned by `USBDEVFS_GET_SPEED`.
+    pub speed: u32,
+    pub num_ports: u8,
+    pub ports: [u8; 7],
+}
+
+/// `USBDEVFS_CONNINFO_EX(len)` encodes the size of the structure, so it is not
+/// in `linux_raw_sys`.
+const USBDEVFS_CONNINFO_EX: Opcode = ioctl::opcode::read::<ConnInfoEx>(b'U', 32);
+
+pub fn get_conninfo_ex<Fd: AsFd>(fd: Fd) -> io::Result<ConnInfoEx> {
+    unsafe {
+        let ctl = ioctl::Getter::<USBDEVFS_CONNINFO_EX, ConnInfoEx>::new();
+        ioctl::ioctl(fd, ctl)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn conninfo_ex_size() {
+        // The size is part of the ioctl number, so it must match
+        // `struct usbdevfs_conninfo_ex` in the kernel headers
+        assert_eq!(std::mem::size_of::<ConnInfoEx>(), 24);
+    }
+}
@@ -154,6 +154,8 @@ pub(crate) fn probe_device(device: IoService) -> Option<DeviceInfo> {
             })
             .collect()
         }),
+        descriptors: Vec::new(),
+        emulated: None,
     })
 }
 
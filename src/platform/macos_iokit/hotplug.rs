@@ -129,7 +129,7 @@ impl MacHotplugWatch {
             if let Some(registry_id) = get_registry_id(&s) {
                 debug!("device {registry_id} disconnected");
                 let id = DeviceId(registry_id);
-                return Poll::Ready(HotplugEvent::Disconnected(id));
+                return Poll::Ready(HotplugEvent::Disconnected(id, None));
             } else {
                 debug!("failed to get registry ID for disconnected device")
             }
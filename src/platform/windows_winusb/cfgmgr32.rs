@@ -28,9 +28,17 @@ use super::{
 };
 
 #[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DevInst(u32);
 
 impl DevInst {
+    /// Placeholder for a device that is emulated by nusb rather than known to
+    /// the configuration manager. Real device instance handles are small
+    /// indices, so the high bit keeps these distinct.
+    pub(crate) fn emulated(n: u32) -> DevInst {
+        DevInst(0x8000_0000 | n)
+    }
+
     pub fn from_instance_id(id: &WCStr) -> Option<DevInst> {
         let mut devinst = 0;
         let c = unsafe { CM_Locate_DevNodeW(&mut devinst, id.as_ptr(), CM_LOCATE_DEVNODE_PHANTOM) };
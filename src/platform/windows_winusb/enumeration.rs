@@ -143,6 +143,8 @@ pub fn probe_device(devinst: DevInst) -> Option<DeviceInfo> {
         product_string,
         serial_number,
         interfaces,
+        descriptors: Vec::new(),
+        emulated: None,
     })
 }
 
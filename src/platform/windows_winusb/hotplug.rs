@@ -110,7 +110,7 @@ impl WindowsHotplugWatch {
                 };
             }
             Some((Action::Disconnect, devinst)) => {
-                return Poll::Ready(HotplugEvent::Disconnected(DeviceId(devinst)));
+                return Poll::Ready(HotplugEvent::Disconnected(DeviceId(devinst), None));
             }
             None => {}
         }
@@ -0,0 +1,382 @@
+//! Waiting for an opened device to disconnect, and finding a device again
+//! after it resets or re-enumerates.
+//!
+//! The reconnection operations watch for hotplug events from the time they are created,
+//! so that a device that reconnects before they are awaited is not missed.
+//! The waiting itself only depends on the hotplug stream and the timer thread,
+//! so it is run on the calling thread for `.wait()`, while opening the device
+//! uses the same blocking or async path as [`DeviceInfo::open`].
+
+use std::{
+    future::{poll_fn, Future, IntoFuture},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    thread,
+    time::{Duration, Instant},
+};
+
+use futures_core::Stream;
+
+use crate::{
+    emulated::EmulatedDevice,
+    hotplug::{HotplugEvent, HotplugWatch},
+    maybe_future::block_on,
+    timer::Deadline,
+    Device, DeviceFilter, DeviceInfo, Error, ErrorKind, MaybeFuture,
+};
+
+/// Delay before retrying to open a device that was found but could not be
+/// opened, e.g. because udev has not yet set the permissions of its node.
+pub(crate) const RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Waits for a `Connected` event for the same physical device as `old`.
+struct Waiter {
+    watch: HotplugWatch,
+    old: DeviceInfo,
+    filter: DeviceFilter,
+    deadline: Deadline,
+}
+
+impl Waiter {
+    fn new(old: &DeviceInfo, filter: DeviceFilter, timeout: Duration) -> Result<Waiter, Error> {
+        #[cfg(target_os = "linux")]
+        let watch = old.roots.watch_devices()?;
+        #[cfg(not(target_os = "linux"))]
+        let watch = crate::watch_devices()?;
+
+        Ok(Waiter {
+            watch,
+            old: old.clone(),
+            filter,
+            deadline: Deadline::new(Instant::now() + timeout),
+        })
+    }
+
+    /// Whether `device` is connected to the same port as the old device or
+    /// has the same serial number, and matches the filter.
+    fn is_same_device(&self, device: &DeviceInfo) -> bool {
+        let old = &self.old;
+        let same_port = !old.port_chain.is_empty()
+            && device.bus_id == old.bus_id
+            && device.port_chain == old.port_chain;
+        let same_serial = old.serial_number.is_some() && device.serial_number == old.serial_number;
+        (same_port || same_serial) && self.filter.matches(device)
+    }
+
+    fn poll_next(&mut self, cx: &mut Context<'_>) -> Poll<Result<DeviceInfo, Error>> {
+        while let Poll::Ready(event) = Pin::new(&mut self.watch).poll_next(cx) {
+            match event {
+                Some(HotplugEvent::Connected(device)) if self.is_same_device(&device) => {
+                    return Poll::Ready(Ok(device));
+                }
+                Some(_) => {}
+                None => {
+                    return Poll::Ready(Err(Error::new(ErrorKind::Other, "hotplug watch ended")))
+                }
+            }
+        }
+        if self.deadline.poll(cx).is_ready() {
+            return Poll::Ready(Err(Error::new(
+                ErrorKind::NotFound,
+                "device did not reconnect before the timeout",
+            )));
+        }
+        Poll::Pending
+    }
+
+    fn next(&mut self) -> impl Future<Output = Result<DeviceInfo, Error>> + '_ {
+        poll_fn(|cx| self.poll_next(cx))
+    }
+
+    /// Whether there is time left to retry opening a device after a delay.
+    fn can_retry(&self) -> bool {
+        Instant::now() + RETRY_DELAY < self.deadline.at()
+    }
+}
+
+/// Future that completes after `RETRY_DELAY`.
+fn sleep() -> impl Future<Output = ()> {
+    let mut until = Deadline::new(Instant::now() + RETRY_DELAY);
+    poll_fn(move |cx| until.poll(cx))
+}
+
+/// Returned by [`DeviceInfo::wait_for_reconnect`].
+pub(crate) struct WaitForReconnect(Result<Waiter, Error>);
+
+impl WaitForReconnect {
+    pub(crate) fn new(old: &DeviceInfo, filter: DeviceFilter, timeout: Duration) -> Self {
+        WaitForReconnect(Waiter::new(old, filter, timeout))
+    }
+
+    async fn run(self) -> Result<DeviceInfo, Error> {
+        self.0?.next().await
+    }
+}
+
+impl IntoFuture for WaitForReconnect {
+    type Output = Result<DeviceInfo, Error>;
+    type IntoFuture = Pin<Box<dyn Future<Output = Self::Output> + Send>>;
+
+    fn into_future(self) -> Self::IntoFuture {
+        Box::pin(self.run())
+    }
+}
+
+impl MaybeFuture for WaitForReconnect {
+    fn wait(self) -> Self::Output {
+        block_on(self.run())
+    }
+}
+
+/// Returned by [`Device::reset_and_reopen`].
+pub(crate) struct ResetAndReopen {
+    device: Device,
+
+    /// The information the device was opened from, and a waiter if the
+    /// device may come back as a different device after the reset.
+    state: Result<(Arc<DeviceInfo>, Option<Waiter>), Error>,
+}
+
+impl ResetAndReopen {
+    pub(crate) fn new(device: &Device, info: Option<Arc<DeviceInfo>>, timeout: Duration) -> Self {
+        let state = match info {
+            None => Err(Error::new(
+                ErrorKind::Unsupported,
+                "device was not opened from a `DeviceInfo`",
+            )),
+            // Emulated devices are reset in place and aren't reported by
+            // hotplug events.
+            Some(info) if info.emulated.is_some() => Ok((info, None)),
+            Some(info) => Waiter::new(&info, DeviceFilter::new(), timeout).map(|w| (info, Some(w))),
+        };
+        ResetAndReopen {
+            device: device.clone(),
+            state,
+        }
+    }
+}
+
+/// On Linux, a reset that doesn't change the descriptors keeps the device
+/// at the same address, so it can be opened again from the same information.
+/// Its node can still be opened for a moment when it is about to
+/// re-enumerate, so this checks that sysfs still reports the same address.
+/// On macOS, the old device remains briefly after it is re-enumerated, so
+/// only a new one is accepted.
+#[cfg(target_os = "linux")]
+fn reopen_in_place(info: &DeviceInfo) -> bool {
+    info.path
+        .read_attr::<u8>("devnum")
+        .is_ok_and(|devnum| devnum == info.device_address)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn reopen_in_place(_info: &DeviceInfo) -> bool {
+    false
+}
+
+fn reset_error_ok(r: Result<(), Error>) -> Result<(), Error> {
+    match r {
+        // The device may disconnect before the reset request completes
+        Err(e) if e.kind() != ErrorKind::Disconnected => Err(e),
+        _ => Ok(()),
+    }
+}
+
+impl IntoFuture for ResetAndReopen {
+    type Output = Result<Device, Error>;
+    type IntoFuture = Pin<Box<dyn Future<Output = Self::Output> + Send>>;
+
+    fn into_future(self) -> Self::IntoFuture {
+        Box::pin(async move {
+            let (info, waiter) = self.state?;
+            reset_error_ok(self.device.reset().await)?;
+            drop(self.device);
+
+            let Some(mut waiter) = waiter else {
+                return info.open().await;
+            };
+            if reopen_in_place(&info) {
+                if let Ok(device) = info.open().await {
+                    return Ok(device);
+                }
+            }
+            let mut found = waiter.next().await?;
+            loop {
+                match found.open().await {
+                    Ok(device) => return Ok(device),
+                    Err(e) if e.kind() == ErrorKind::Disconnected => {
+                        found = waiter.next().await?;
+                    }
+                    Err(e) if !waiter.can_retry() => return Err(e),
+                    Err(_) => sleep().await,
+                }
+            }
+        })
+    }
+}
+
+impl MaybeFuture for ResetAndReopen {
+    fn wait(self) -> Self::Output {
+        let (info, waiter) = self.state?;
+        reset_error_ok(self.device.reset().wait())?;
+        drop(self.device);
+
+        let Some(mut waiter) = waiter else {
+            return info.open().wait();
+        };
+        if reopen_in_place(&info) {
+            if let Ok(device) = info.open().wait() {
+                return Ok(device);
+            }
+        }
+        let mut found = block_on(waiter.next())?;
+        loop {
+            match found.open().wait() {
+                Ok(device) => return Ok(device),
+                Err(e) if e.kind() == ErrorKind::Disconnected => {
+                    found = block_on(waiter.next())?;
+                }
+                Err(e) if !waiter.can_retry() => return Err(e),
+                Err(_) => thread::sleep(RETRY_DELAY),
+            }
+        }
+    }
+}
+
+/// Returned by [`Device::disconnected`].
+pub(crate) enum Disconnected {
+    #[cfg(target_os = "linux")]
+    Platform(Arc<crate::platform::Device>),
+    Emulated(Arc<EmulatedDevice>),
+
+    /// Watch for the hotplug event on platforms that don't report the
+    /// disconnection through the opened device.
+    #[cfg(any(not(target_os = "linux"), test))]
+    Watch(crate::DeviceId, HotplugWatch),
+
+    /// Already known to be disconnected, or failed to start watching.
+    #[cfg(any(not(target_os = "linux"), test))]
+    Done(Option<Result<(), Error>>),
+}
+
+impl Disconnected {
+    #[cfg(not(target_os = "linux"))]
+    pub(crate) fn watch(info: Option<&DeviceInfo>) -> Self {
+        let Some(info) = info else {
+            return Disconnected::Done(Some(Err(Error::new(
+                ErrorKind::Unsupported,
+                "device was not opened from a `DeviceInfo`",
+            ))));
+        };
+        Disconnected::from_watch(info.id(), crate::watch_devices(), || {
+            crate::list_devices().wait()
+        })
+    }
+
+    /// The device may have been unplugged before the watch was created, in
+    /// which case the event was missed, so check that it is still listed.
+    #[cfg(any(not(target_os = "linux"), test))]
+    fn from_watch<I: Iterator<Item = DeviceInfo>>(
+        id: crate::DeviceId,
+        watch: Result<HotplugWatch, Error>,
+        list: impl FnOnce() -> Result<I, Error>,
+    ) -> Self {
+        let watch = match watch {
+            Ok(watch) => watch,
+            Err(e) => return Disconnected::Done(Some(Err(e))),
+        };
+        match list().map(|mut devices| devices.any(|d| d.id() == id)) {
+            Ok(true) => Disconnected::Watch(id, watch),
+            Ok(false) => Disconnected::Done(Some(Ok(()))),
+            Err(e) => Disconnected::Done(Some(Err(e))),
+        }
+    }
+
+    fn poll(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        match self {
+            #[cfg(target_os = "linux")]
+            Disconnected::Platform(device) => device.poll_disconnected(cx).map(Ok),
+            Disconnected::Emulated(device) => device.poll_disconnected(cx).map(Ok),
+            #[cfg(any(not(target_os = "linux"), test))]
+            Disconnected::Watch(id, watch) => loop {
+                match std::task::ready!(Pin::new(&mut *watch).poll_next(cx)) {
+                    Some(HotplugEvent::Disconnected(d, _)) if d == *id => {
+                        return Poll::Ready(Ok(()))
+                    }
+                    Some(_) => {}
+                    None => {
+                        return Poll::Ready(Err(Error::new(
+                            ErrorKind::Other,
+                            "hotplug watch ended",
+                        )))
+                    }
+                }
+            },
+            #[cfg(any(not(target_os = "linux"), test))]
+            Disconnected::Done(r) => Poll::Ready(r.take().expect("polled after completion")),
+        }
+    }
+}
+
+impl IntoFuture for Disconnected {
+    type Output = Result<(), Error>;
+    type IntoFuture = Pin<Box<dyn Future<Output = Self::Output> + Send>>;
+
+    fn into_future(mut self) -> Self::IntoFuture {
+        Box::pin(poll_fn(move |cx| self.poll(cx)))
+    }
+}
+
+impl MaybeFuture for Disconnected {
+    fn wait(mut self) -> Self::Output {
+        block_on(poll_fn(|cx| self.poll(cx)))
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use std::fs;
+
+    use super::Disconnected;
+    use crate::{hotplug::HotplugSource, platform::sysfs_fixture, FsRoots, MaybeFuture};
+
+    #[test]
+    fn disconnected_before_watch() {
+        let root = std::env::temp_dir().join(format!("nusb-disconnected-{}", std::process::id()));
+        sysfs_fixture(&root.join("sys"));
+        fs::create_dir_all(root.join("usbfs")).unwrap();
+        let roots = FsRoots::new()
+            .with_sysfs(root.join("sys"))
+            .with_usbfs(root.join("usbfs"));
+        let info = roots.list_devices().wait().unwrap().next().unwrap();
+        let watch = || roots.watch_devices_with_source(HotplugSource::Inotify);
+        let list = || roots.list_devices().wait();
+
+        let disconnected = Disconnected::from_watch(info.id(), watch(), list);
+        assert!(matches!(disconnected, Disconnected::Watch(..)));
+
+        // Unplugged before the watch was created, so no event will follow
+        fs::remove_file(root.join("sys/bus/usb/devices/1-2")).unwrap();
+        let disconnected = Disconnected::from_watch(info.id(), watch(), list);
+        disconnected.wait().unwrap();
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn reopen_in_place() {
+        let root = std::env::temp_dir().join(format!("nusb-reopen-{}", std::process::id()));
+        sysfs_fixture(&root.join("sys"));
+        let roots = FsRoots::new().with_sysfs(root.join("sys"));
+        let info = roots.list_devices().wait().unwrap().next().unwrap();
+        assert!(super::reopen_in_place(&info));
+
+        // Re-enumerated with a new address
+        let devnum = root.join("sys/bus/usb/devices/1-2/devnum");
+        fs::write(devnum, "4\n").unwrap();
+        assert!(!super::reopen_in_place(&info));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,1155 @@
+//! Record a session with a device and replay it without hardware.
+//!
+//! A [`Recorder`] captures the operations performed through a [`Device`] and
+//! the [`Interface`][crate::Interface]s and [`Endpoint`][crate::Endpoint]s
+//! opened from it: control transfers with their setup packets and data, bulk
+//! and interrupt transfers with their payloads and status, and operations
+//! like claiming interfaces, along with their timing.
+//!
+//! A [`Replay`] loads a recording and provides a [`DeviceInfo`] that opens an
+//! emulated device responding to the same requests in the same way. The
+//! application code under test uses the resulting `Device` exactly as it
+//! would a real one.
+//!
+//! ### Example
+//!
+//! Capture a session with real hardware:
+//!
+//! ```no_run
+//! use nusb::{replay::Recorder, MaybeFuture};
+//! # fn run(device: &nusb::Device) {}
+//! let device_info = nusb::list_devices().wait().unwrap()
+//!     .find(|dev| dev.vendor_id() == 0xAAAA && dev.product_id() == 0xBBBB)
+//!     .expect("device not connected");
+//! let recorder = Recorder::create("session.nusb").unwrap();
+//! let device = device_info.open().wait().unwrap().record(&recorder);
+//! run(&device);
+//! recorder.finish().unwrap();
+//! ```
+//!
+//! Then replay it in a test:
+//!
+//! ```no_run
+//! use nusb::{replay::Replay, MaybeFuture};
+//! # fn run(device: &nusb::Device) {}
+//! let replay = Replay::open("session.nusb").unwrap();
+//! let device = replay.device_info().open().wait().unwrap();
+//! run(&device);
+//! replay.finish().unwrap();
+//! ```
+//!
+//! ### Matching
+//!
+//! Control transfers and operations are matched against the first unused
+//! recorded event with the same setup packet or arguments. Transfers on each
+//! bulk or interrupt endpoint are matched in order.
+//!
+//! A control transfer or operation that does not appear in the recording
+//! fails with [`TransferError::Stall`] or an error of kind
+//! [`ErrorKind::Other`]. A transfer on an endpoint with no remaining
+//! recorded events stays pending until cancelled, like a device that has
+//! nothing more to send. Differences in OUT data are reported by
+//! [`Replay::finish`], but the recorded result is still returned.
+//!
+//! Isochronous transfers can't be recorded.
+//! [`Interface::endpoint`][crate::Interface::endpoint] fails with
+//! [`ErrorKind::Unsupported`] for an isochronous endpoint of a device that is
+//! being recorded, rather than leaving a recording that can't be replayed.
+//!
+//! ### Format
+//!
+//! Recordings are line-oriented text, so they can be reviewed and edited by
+//! hand. A header with the descriptors and state of the device is followed by
+//! one line per event, in the order they completed:
+//!
+//! ```text
+//! nusb-recording 1
+//! speed high
+//! configuration 1
+//! descriptors 12010002000000400...
+//! 120+35 claim-interface 0 ok
+//! 210+502 control-in c030000000004000 ok 0102
+//! 800+1250 out 02 68656c6c6f ok 5
+//! 820+4030 in 81 512 ok 776f726c64
+//! ```
+//!
+//! Each event starts with its start time and duration in microseconds. Empty
+//! data is written as `-`.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt::{Display, Write as _},
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::Path,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use log::{error, warn};
+
+use crate::{
+    descriptors::TransferType,
+    emulated::{Completer, Model, Request},
+    timer,
+    transfer::{Direction, TransferError, SETUP_PACKET_SIZE},
+    Device, DeviceInfo, Error, ErrorKind, Speed,
+};
+
+const MAGIC: &str = "nusb-recording";
+const VERSION: u32 = 1;
+
+/// Writes a recording of a device session.
+///
+/// Attach a `Recorder` to a [`Device`] with [`Device::record`]. The recorder
+/// can be cloned cheaply, and all clones write to the same file. A recording
+/// describes a single device.
+///
+/// Events are written as they complete. Errors writing the recording are
+/// logged and returned from [`Recorder::finish`].
+#[derive(Clone)]
+pub struct Recorder {
+    inner: Arc<Mutex<RecorderState>>,
+}
+
+struct RecorderState {
+    out: Box<dyn Write + Send>,
+    start: Instant,
+    header_written: bool,
+    error: Option<io::Error>,
+}
+
+impl Recorder {
+    /// Create a recording at the specified path, replacing any existing file.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Recorder> {
+        Ok(Recorder::new(BufWriter::new(File::create(path)?)))
+    }
+
+    /// Create a recording that writes to `writer`.
+    pub fn new(writer: impl Write + Send + 'static) -> Recorder {
+        Recorder {
+            inner: Arc::new(Mutex::new(RecorderState {
+                out: Box::new(writer),
+                start: Instant::now(),
+                header_written: false,
+                error: None,
+            })),
+        }
+    }
+
+    /// Flush the recording, and return the first error that occurred while
+    /// writing it.
+    pub fn finish(&self) -> io::Result<()> {
+        let mut state = self.inner.lock().unwrap();
+        if let Some(e) = state.error.take() {
+            return Err(e);
+        }
+        state.out.flush()
+    }
+
+    fn write_line(state: &mut RecorderState, line: std::fmt::Arguments) {
+        if state.error.is_some() {
+            return;
+        }
+        if let Err(e) = writeln!(state.out, "{line}") {
+            error!("Failed to write recording: {e}");
+            state.error = Some(e);
+        }
+    }
+
+    pub(crate) fn header(&self, device: &Device) {
+        let mut state = self.inner.lock().unwrap();
+        if state.header_written {
+            return;
+        }
+        state.header_written = true;
+
+        let mut descriptors = device.device_descriptor().as_bytes().to_vec();
+        for config in device.configurations() {
+            descriptors.extend_from_slice(config.as_bytes());
+        }
+        let configuration = device
+            .active_configuration()
+            .map_or(0, |c| c.configuration_value());
+
+        Self::write_line(&mut state, format_args!("{MAGIC} {VERSION}"));
+        Self::write_line(
+            &mut state,
+            format_args!("speed {}", SpeedField(device.speed())),
+        );
+        Self::write_line(&mut state, format_args!("configuration {configuration}"));
+        Self::write_line(
+            &mut state,
+            format_args!("descriptors {}", Hex(&descriptors)),
+        );
+    }
+
+    pub(crate) fn event(&self, start: Instant, kind: EventKind) {
+        let mut state = self.inner.lock().unwrap();
+        let event = Event {
+            start: start.saturating_duration_since(state.start),
+            duration: start.elapsed(),
+            kind,
+        };
+        Self::write_line(&mut state, format_args!("{event}"));
+    }
+}
+
+impl std::fmt::Debug for Recorder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Recorder").finish()
+    }
+}
+
+/// A transfer submitted on a recorded endpoint, waiting for its completion
+/// to be recorded.
+pub(crate) struct Submitted {
+    pub(crate) start: Instant,
+    pub(crate) requested_len: usize,
+    pub(crate) data: Vec<u8>,
+}
+
+/// Operation other than a transfer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Operation {
+    SetConfiguration(u8),
+    ClaimInterface(u8),
+    SetAltSetting(u8, u8),
+    ClearHalt(u8),
+    Reset,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum EventKind {
+    ControlIn {
+        setup: [u8; SETUP_PACKET_SIZE],
+        status: Result<(), TransferError>,
+        data: Vec<u8>,
+    },
+    ControlOut {
+        setup: [u8; SETUP_PACKET_SIZE],
+        data: Vec<u8>,
+        status: Result<(), TransferError>,
+    },
+    In {
+        endpoint: u8,
+        requested_len: usize,
+        status: Result<(), TransferError>,
+        data: Vec<u8>,
+    },
+    Out {
+        endpoint: u8,
+        data: Vec<u8>,
+        status: Result<(), TransferError>,
+        actual_len: usize,
+    },
+    Operation {
+        op: Operation,
+        result: Result<(), ErrorKind>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Event {
+    start: Duration,
+    duration: Duration,
+    kind: EventKind,
+}
+
+struct Hex<'a>(&'a [u8]);
+
+impl Display for Hex<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.0.is_empty() {
+            return f.write_char('-');
+        }
+        for b in self.0 {
+            write!(f, "{b:02x}")?;
+        }
+        Ok(())
+    }
+}
+
+fn parse_hex(s: &str) -> Result<Vec<u8>, &'static str> {
+    if s == "-" {
+        return Ok(Vec::new());
+    }
+    if s.len() % 2 != 0 {
+        return Err("odd number of hex digits");
+    }
+    let digit = |c: u8| char::from(c).to_digit(16).ok_or("invalid hex data");
+    s.as_bytes()
+        .chunks(2)
+        .map(|pair| Ok((digit(pair[0])? << 4 | digit(pair[1])?) as u8))
+        .collect()
+}
+
+struct SpeedField(Option<Speed>);
+
+impl Display for SpeedField {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self.0 {
+            None => "unknown",
+            Some(Speed::Low) => "low",
+            Some(Speed::Full) => "full",
+            Some(Speed::High) => "high",
+            Some(Speed::Super) => "super",
+            Some(Speed::SuperPlus) => "super+",
+        })
+    }
+}
+
+struct Status(Result<(), TransferError>);
+
+impl Display for Status {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.0 {
+            Ok(()) => f.write_str("ok"),
+            Err(TransferError::Cancelled) => f.write_str("cancelled"),
+            Err(TransferError::Stall) => f.write_str("stall"),
+            Err(TransferError::Disconnected) => f.write_str("disconnected"),
+            Err(TransferError::Fault) => f.write_str("fault"),
+            Err(TransferError::InvalidArgument) => f.write_str("invalid-argument"),
+            Err(TransferError::Unknown(code)) => write!(f, "unknown:{code}"),
+        }
+    }
+}
+
+fn parse_status(s: &str) -> Result<Result<(), TransferError>, &'static str> {
+    Ok(Err(match s {
+        "ok" => return Ok(Ok(())),
+        "cancelled" => TransferError::Cancelled,
+        "stall" => TransferError::Stall,
+        "disconnected" => TransferError::Disconnected,
+        "fault" => TransferError::Fault,
+        "invalid-argument" => TransferError::InvalidArgument,
+        _ => TransferError::Unknown(
+            s.strip_prefix("unknown:")
+                .and_then(|c| c.parse().ok())
+                .ok_or("invalid transfer status")?,
+        ),
+    }))
+}
+
+struct OpResult(Result<(), ErrorKind>);
+
+impl Display for OpResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self.0 {
+            Ok(()) => "ok",
+            Err(ErrorKind::Disconnected) => "err:disconnected",
+            Err(ErrorKind::Busy) => "err:busy",
+            Err(ErrorKind::PermissionDenied) => "err:permission-denied",
+            Err(ErrorKind::NotFound) => "err:not-found",
+            Err(ErrorKind::Unsupported) => "err:unsupported",
+            Err(ErrorKind::Other) => "err:other",
+        })
+    }
+}
+
+fn parse_op_result(s: &str) -> Result<Result<(), ErrorKind>, &'static str> {
+    Ok(Err(match s {
+        "ok" => return Ok(Ok(())),
+        "err:disconnected" => ErrorKind::Disconnected,
+        "err:busy" => ErrorKind::Busy,
+        "err:permission-denied" => ErrorKind::PermissionDenied,
+        "err:not-found" => ErrorKind::NotFound,
+        "err:unsupported" => ErrorKind::Unsupported,
+        "err:other" => ErrorKind::Other,
+        _ => return Err("invalid operation result"),
+    }))
+}
+
+impl Display for Event {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}+{} ",
+            self.start.as_micros(),
+            self.duration.as_micros()
+        )?;
+        match &self.kind {
+            EventKind::ControlIn {
+                setup,
+                status,
+                data,
+            } => write!(
+                f,
+                "control-in {} {} {}",
+                Hex(setup),
+                Status(*status),
+                Hex(data)
+            ),
+            EventKind::ControlOut {
+                setup,
+                data,
+                status,
+            } => write!(
+                f,
+                "control-out {} {} {}",
+                Hex(setup),
+                Hex(data),
+                Status(*status)
+            ),
+            EventKind::In {
+                endpoint,
+                requested_len,
+                status,
+                data,
+            } => write!(
+                f,
+                "in {endpoint:02x} {requested_len} {} {}",
+                Status(*status),
+                Hex(data)
+            ),
+            EventKind::Out {
+                endpoint,
+                data,
+                status,
+                actual_len,
+            } => write!(
+                f,
+                "out {endpoint:02x} {} {} {actual_len}",
+                Hex(data),
+                Status(*status)
+            ),
+            EventKind::Operation { op, result } => {
+                match op {
+                    Operation::SetConfiguration(c) => write!(f, "set-configuration {c}")?,
+                    Operation::ClaimInterface(i) => write!(f, "claim-interface {i}")?,
+                    Operation::SetAltSetting(i, a) => write!(f, "set-alt-setting {i} {a}")?,
+                    Operation::ClearHalt(ep) => write!(f, "clear-halt {ep:02x}")?,
+                    Operation::Reset => write!(f, "reset")?,
+                }
+                write!(f, " {}", OpResult(*result))
+            }
+        }
+    }
+}
+
+impl FromStr for Event {
+    type Err = &'static str;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let mut fields = line.split_ascii_whitespace();
+        let mut next = || fields.next().ok_or("missing field");
+
+        let (start, duration) = next()?.split_once('+').ok_or("invalid timestamp")?;
+        let start = Duration::from_micros(start.parse().map_err(|_| "invalid timestamp")?);
+        let duration = Duration::from_micros(duration.parse().map_err(|_| "invalid timestamp")?);
+
+        let setup = |s: &str| -> Result<[u8; SETUP_PACKET_SIZE], &'static str> {
+            parse_hex(s)?
+                .try_into()
+                .map_err(|_| "setup packet must be 8 bytes")
+        };
+        let endpoint = |s: &str| u8::from_str_radix(s, 16).map_err(|_| "invalid endpoint");
+        let number = |s: &str| s.parse::<u8>().map_err(|_| "invalid number");
+        let len = |s: &str| s.parse::<usize>().map_err(|_| "invalid length");
+
+        let kind = match next()? {
+            "control-in" => EventKind::ControlIn {
+                setup: setup(next()?)?,
+                status: parse_status(next()?)?,
+                data: parse_hex(next()?)?,
+            },
+            "control-out" => EventKind::ControlOut {
+                setup: setup(next()?)?,
+                data: parse_hex(next()?)?,
+                status: parse_status(next()?)?,
+            },
+            "in" => EventKind::In {
+                endpoint: endpoint(next()?)?,
+                requested_len: len(next()?)?,
+                status: parse_status(next()?)?,
+                data: parse_hex(next()?)?,
+            },
+            "out" => EventKind::Out {
+                endpoint: endpoint(next()?)?,
+                data: parse_hex(next()?)?,
+                status: parse_status(next()?)?,
+                actual_len: len(next()?)?,
+            },
+            op => {
+                let op = match op {
+                    "set-configuration" => Operation::SetConfiguration(number(next()?)?),
+                    "claim-interface" => Operation::ClaimInterface(number(next()?)?),
+                    "set-alt-setting" => {
+                        Operation::SetAltSetting(number(next()?)?, number(next()?)?)
+                    }
+                    "clear-halt" => Operation::ClearHalt(endpoint(next()?)?),
+                    "reset" => Operation::Reset,
+                    _ => return Err("unknown event"),
+                };
+                EventKind::Operation {
+                    op,
+                    result: parse_op_result(next()?)?,
+                }
+            }
+        };
+
+        if fields.next().is_some() {
+            return Err("unexpected trailing fields");
+        }
+
+        Ok(Event {
+            start,
+            duration,
+            kind,
+        })
+    }
+}
+
+/// Replays a recording made by [`Recorder`] as an emulated device.
+///
+/// This type is reference-counted with an [`Arc`] internally, and can be
+/// cloned cheaply. All devices opened from [`Replay::device_info`] consume
+/// events from the same recording.
+#[derive(Clone)]
+pub struct Replay {
+    model: Arc<ReplayModel>,
+}
+
+impl Replay {
+    /// Load a recording from a file.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Replay> {
+        Replay::from_reader(BufReader::new(File::open(path)?))
+    }
+
+    /// Load a recording from a reader.
+    ///
+    /// Returns an error of kind [`io::ErrorKind::InvalidData`] if the
+    /// recording can't be parsed.
+    pub fn from_reader(reader: impl BufRead) -> io::Result<Replay> {
+        let invalid = |line: usize, msg: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid recording at line {line}: {msg}"),
+            )
+        };
+
+        let mut lines = reader
+            .lines()
+            .enumerate()
+            .map(|(i, l)| l.map(|l| (i + 1, l)))
+            .filter(|l| {
+                l.as_ref()
+                    .map_or(true, |(_, l)| !l.trim().is_empty() && !l.starts_with('#'))
+            });
+
+        // Returns the line number along with the value, for reporting errors
+        let mut header = |key: &str| -> io::Result<(usize, String)> {
+            let (n, line) = lines.next().transpose()?.ok_or_else(|| {
+                io::Error::new(io::ErrorKind::UnexpectedEof, "recording header missing")
+            })?;
+            match line.split_once(' ') {
+                Some((k, v)) if k == key => Ok((n, v.trim().to_owned())),
+                _ => Err(invalid(n, "unexpected header line")),
+            }
+        };
+
+        let (n, version) = header(MAGIC)?;
+        if version != VERSION.to_string() {
+            return Err(invalid(n, "unsupported recording version"));
+        }
+        let (n, speed) = header("speed")?;
+        let speed = match speed.as_str() {
+            "unknown" => None,
+            s => Some(Speed::from_str(s).ok_or_else(|| invalid(n, "invalid speed"))?),
+        };
+        let (n, configuration) = header("configuration")?;
+        let configuration = configuration
+            .parse()
+            .map_err(|_| invalid(n, "invalid configuration"))?;
+        let (n, descriptors) = header("descriptors")?;
+        let descriptors = parse_hex(&descriptors).map_err(|e| invalid(n, e))?;
+
+        let mut state = ReplayState::default();
+        for line in lines {
+            let (n, line) = line?;
+            let event: Event = line.parse().map_err(|e| invalid(n, e))?;
+            let queue = match &event.kind {
+                EventKind::ControlIn { .. } | EventKind::ControlOut { .. } => &mut state.control,
+                EventKind::In { endpoint, .. } | EventKind::Out { endpoint, .. } => {
+                    state.endpoints.entry(*endpoint).or_default()
+                }
+                EventKind::Operation { .. } => &mut state.operations,
+            };
+            queue.push_back(event);
+        }
+
+        Ok(Replay {
+            model: Arc::new(ReplayModel {
+                descriptors,
+                speed,
+                configuration,
+                realtime: AtomicBool::new(false),
+                state: Mutex::new(state),
+            }),
+        })
+    }
+
+    /// Delay each completion by the duration it took in the recording.
+    ///
+    /// By default, transfers complete as soon as they are submitted.
+    pub fn with_realtime(self, realtime: bool) -> Self {
+        self.model.realtime.store(realtime, Ordering::Relaxed);
+        self
+    }
+
+    /// Get a [`DeviceInfo`] that opens a device replaying this recording.
+    pub fn device_info(&self) -> DeviceInfo {
+        DeviceInfo::emulated(self.model.clone())
+    }
+
+    /// Check that the replayed session matched the recording.
+    ///
+    /// Returns an error if any request did not match the recording, or if
+    /// any recorded events were not replayed.
+    pub fn finish(&self) -> Result<(), ReplayError> {
+        let state = self.model.state.lock().unwrap();
+        let unconsumed: Vec<String> = state
+            .control
+            .iter()
+            .chain(state.endpoints.values().flatten())
+            .chain(state.operations.iter())
+            .map(|e| e.to_string())
+            .collect();
+
+        if state.mismatches.is_empty() && unconsumed.is_empty() {
+            Ok(())
+        } else {
+            Err(ReplayError {
+                mismatches: state.mismatches.clone(),
+                unconsumed,
+            })
+        }
+    }
+}
+
+impl std::fmt::Debug for Replay {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Replay").finish()
+    }
+}
+
+/// Error from [`Replay::finish`] when the replayed session diverged from the
+/// recording.
+#[derive(Debug, Clone)]
+pub struct ReplayError {
+    mismatches: Vec<String>,
+    unconsumed: Vec<String>,
+}
+
+impl ReplayError {
+    /// Descriptions of requests that did not match the recording.
+    pub fn mismatches(&self) -> &[String] {
+        &self.mismatches
+    }
+
+    /// Recorded events that were never requested, in the recording format.
+    pub fn unconsumed(&self) -> &[String] {
+        &self.unconsumed
+    }
+}
+
+impl Display for ReplayError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "replay diverged from recording: {} mismatched requests, {} unreplayed events",
+            self.mismatches.len(),
+            self.unconsumed.len()
+        )?;
+        if let Some(first) = self.mismatches.first().or(self.unconsumed.first()) {
+            write!(f, " (first: {first})")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ReplayError {}
+
+struct ReplayModel {
+    descriptors: Vec<u8>,
+    speed: Option<Speed>,
+    configuration: u8,
+    realtime: AtomicBool,
+    state: Mutex<ReplayState>,
+}
+
+#[derive(Default)]
+struct ReplayState {
+    control: VecDeque<Event>,
+    endpoints: BTreeMap<u8, VecDeque<Event>>,
+    operations: VecDeque<Event>,
+
+    /// Transfers that the recording never completed, kept until cancelled.
+    held: BTreeMap<u64, Completer>,
+
+    mismatches: Vec<String>,
+}
+
+impl ReplayState {
+    fn mismatch(&mut self, message: String) {
+        warn!("Replay mismatch: {message}");
+        self.mismatches.push(message);
+    }
+}
+
+impl ReplayModel {
+    fn operation(&self, op: Operation) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap();
+        let pos = state
+            .operations
+            .iter()
+            .position(|e| matches!(e.kind, EventKind::Operation { op: o, .. } if o == op));
+        let Some(event) = pos.and_then(|pos| state.operations.remove(pos)) else {
+            state.mismatch(format!("{op:?} not in recording"));
+            return Err(Error::new(ErrorKind::Other, "operation not in recording"));
+        };
+        let EventKind::Operation { result, .. } = event.kind else {
+            unreachable!()
+        };
+        result.map_err(|kind| Error::new(kind, "recorded operation failed"))
+    }
+
+    fn take_control(&self, state: &mut ReplayState, request: &Request) -> Option<Event> {
+        let setup = request
+            .setup
+            .expect("control transfer without setup packet");
+        let pos = state.control.iter().position(|e| match &e.kind {
+            EventKind::ControlIn { setup: s, .. } | EventKind::ControlOut { setup: s, .. } => {
+                *s == setup
+            }
+            _ => false,
+        });
+        let Some(event) = pos.and_then(|pos| state.control.remove(pos)) else {
+            state.mismatch(format!("control transfer {} not in recording", Hex(&setup)));
+            return None;
+        };
+        if let EventKind::ControlOut { data, .. } = &event.kind {
+            if *data != request.data {
+                state.mismatch(format!(
+                    "control transfer {} sent {} but recording has {}",
+                    Hex(&setup),
+                    Hex(&request.data),
+                    Hex(data)
+                ));
+            }
+        }
+        Some(event)
+    }
+
+    fn take_endpoint(&self, state: &mut ReplayState, request: &Request) -> Option<Event> {
+        let event = state.endpoints.get_mut(&request.endpoint)?.pop_front()?;
+        if let EventKind::Out { data, .. } = &event.kind {
+            if *data != request.data {
+                state.mismatch(format!(
+                    "transfer on endpoint {:02x} sent {} but recording has {}",
+                    request.endpoint,
+                    Hex(&request.data),
+                    Hex(data)
+                ));
+            }
+        }
+        Some(event)
+    }
+}
+
+fn complete(event: EventKind, completer: Completer) {
+    match event {
+        EventKind::ControlIn { status, data, .. } | EventKind::In { status, data, .. } => {
+            completer.complete_in(status, &data);
+        }
+        EventKind::ControlOut { status, data, .. } => {
+            completer.complete_out(status, data.len());
+        }
+        EventKind::Out {
+            status, actual_len, ..
+        } => {
+            completer.complete_out(status, actual_len);
+        }
+        EventKind::Operation { .. } => unreachable!(),
+    }
+}
+
+impl Model for ReplayModel {
+    fn descriptors(&self) -> Vec<u8> {
+        self.descriptors.clone()
+    }
+
+    fn speed(&self) -> Option<Speed> {
+        self.speed
+    }
+
+    fn active_configuration(&self) -> u8 {
+        self.configuration
+    }
+
+    fn set_configuration(&self, configuration: u8) -> Result<(), Error> {
+        self.operation(Operation::SetConfiguration(configuration))
+    }
+
+    fn claim_interface(&self, interface: u8) -> Result<(), Error> {
+        self.operation(Operation::ClaimInterface(interface))
+    }
+
+    fn set_alt_setting(&self, interface: u8, alt_setting: u8) -> Result<(), Error> {
+        self.operation(Operation::SetAltSetting(interface, alt_setting))
+    }
+
+    fn clear_halt(&self, endpoint: u8) -> Result<(), Error> {
+        self.operation(Operation::ClearHalt(endpoint))
+    }
+
+    fn reset(&self) -> Result<(), Error> {
+        self.operation(Operation::Reset)
+    }
+
+    fn submit(&self, request: Request, completer: Completer) {
+        let mut state = self.state.lock().unwrap();
+        let event = match request.transfer_type {
+            TransferType::Control => match self.take_control(&mut state, &request) {
+                Some(event) => event,
+                None => {
+                    drop(state);
+                    completer.fail(TransferError::Stall);
+                    return;
+                }
+            },
+            TransferType::Bulk | TransferType::Interrupt => {
+                match self.take_endpoint(&mut state, &request) {
+                    Some(event) => event,
+                    None => {
+                        state.held.insert(request.id, completer);
+                        return;
+                    }
+                }
+            }
+            TransferType::Isochronous => {
+                drop(state);
+                completer.fail(TransferError::InvalidArgument);
+                return;
+            }
+        };
+
+        let direction_matches = matches!(
+            (&event.kind, request.direction()),
+            (
+                EventKind::ControlIn { .. } | EventKind::In { .. },
+                Direction::In
+            ) | (
+                EventKind::ControlOut { .. } | EventKind::Out { .. },
+                Direction::Out
+            )
+        );
+        if !direction_matches {
+            state.mismatch(format!("{request:?} does not match recorded {event}"));
+            drop(state);
+            completer.fail(TransferError::Stall);
+            return;
+        }
+
+        // A transfer that was cancelled or timed out in the recording is
+        // left pending until it is cancelled again.
+        let status = match &event.kind {
+            EventKind::ControlIn { status, .. }
+            | EventKind::ControlOut { status, .. }
+            | EventKind::In { status, .. }
+            | EventKind::Out { status, .. } => *status,
+            EventKind::Operation { .. } => unreachable!(),
+        };
+        if status == Err(TransferError::Cancelled) {
+            state.held.insert(request.id, completer);
+            return;
+        }
+        drop(state);
+
+        if self.realtime.load(Ordering::Relaxed) {
+            timer::schedule(Instant::now() + event.duration, move || {
+                complete(event.kind, completer)
+            });
+        } else {
+            complete(event.kind, completer);
+        }
+    }
+
+    fn cancel(&self, id: u64) {
+        let completer = self.state.lock().unwrap().held.remove(&id);
+        drop(completer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Write,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use super::{Event, EventKind, Operation, Recorder, Replay};
+    use crate::{
+        transfer::{
+            Buffer, Bulk, ControlIn, ControlOut, ControlType, In, Isochronous, Out, Recipient,
+        },
+        ErrorKind, MaybeFuture,
+    };
+
+    const HEADER: &str = "nusb-recording 1
+speed high
+configuration 1
+descriptors 12010002ff000040aaaa55550001000000010902200001010080320904000002ff0000000705810200020007050202000200
+";
+
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn strip_timestamps(s: &str) -> Vec<String> {
+        s.lines()
+            .map(|l| match l.split_once(' ') {
+                Some((t, rest)) if t.contains('+') => rest.to_owned(),
+                _ => l.to_owned(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn event_format_round_trip() {
+        let lines = [
+            "0+10 control-in c030000000004000 ok 0102",
+            "5+0 control-out 4032000000000400 01020304 stall",
+            "17+400 in 81 512 unknown:71 -",
+            "30+1 out 02 68656c6c6f cancelled 3",
+            "31+2 set-alt-setting 0 1 err:busy",
+            "32+2 clear-halt 81 ok",
+            "33+2 reset err:disconnected",
+        ];
+        for line in lines {
+            let event: Event = line.parse().unwrap();
+            assert_eq!(event.to_string(), line);
+        }
+
+        let event: Event = "1+2 claim-interface 3 err:not-found".parse().unwrap();
+        assert_eq!(
+            event.kind,
+            EventKind::Operation {
+                op: Operation::ClaimInterface(3),
+                result: Err(ErrorKind::NotFound)
+            }
+        );
+
+        assert!("1+2 control-in c030 ok -".parse::<Event>().is_err());
+        assert!("1+2 in 81 512 ok - extra".parse::<Event>().is_err());
+        assert!("in 81 512 ok -".parse::<Event>().is_err());
+    }
+
+    #[test]
+    fn replay_and_rerecord() {
+        let events = "\
+0+10 claim-interface 0 ok
+10+20 control-in c030000000000400 ok 0a0b
+30+20 control-out 4031010000000200 cafe ok
+50+20 out 02 68656c6c6f ok 5
+70+20 in 81 512 ok 776f726c64
+90+20 in 81 512 stall -
+110+5 clear-halt 81 ok
+";
+        let replay = Replay::from_reader(format!("{HEADER}{events}").as_bytes()).unwrap();
+        let info = replay.device_info();
+        assert_eq!(info.vendor_id(), 0xAAAA);
+        assert_eq!(info.product_id(), 0x5555);
+
+        let buf = SharedBuf::default();
+        let recorder = Recorder::new(buf.clone());
+        let device = info.open().wait().unwrap().record(&recorder);
+        let interface = device.claim_interface(0).wait().unwrap();
+
+        let data = interface
+            .control_in(
+                ControlIn {
+                    control_type: ControlType::Vendor,
+                    recipient: Recipient::Device,
+                    request: 0x30,
+                    value: 0,
+                    index: 0,
+                    length: 4,
+                },
+                Duration::from_secs(1),
+            )
+            .wait()
+            .unwrap();
+        assert_eq!(data, [0x0a, 0x0b]);
+
+        interface
+            .control_out(
+                ControlOut {
+                    control_type: ControlType::Vendor,
+                    recipient: Recipient::Device,
+                    request: 0x31,
+                    value: 1,
+                    index: 0,
+                    data: &[0xca, 0xfe],
+                },
+                Duration::from_secs(1),
+            )
+            .wait()
+            .unwrap();
+
+        let mut ep_out = interface.endpoint::<Bulk, Out>(0x02).unwrap();
+        ep_out.submit(b"hello".to_vec().into());
+        let c = ep_out.wait_next_complete(Duration::from_secs(1)).unwrap();
+        assert_eq!(c.status, Ok(()));
+        assert_eq!(c.actual_len, 5);
+
+        let mut ep_in = interface.endpoint::<Bulk, In>(0x81).unwrap();
+        ep_in.submit(Buffer::new(512));
+        ep_in.submit(Buffer::new(512));
+        ep_in.submit(Buffer::new(512));
+        let c = ep_in.wait_next_complete(Duration::from_secs(1)).unwrap();
+        assert_eq!(&c.buffer[..], b"world");
+        let c = ep_in.wait_next_complete(Duration::from_secs(1)).unwrap();
+        assert_eq!(c.status, Err(crate::transfer::TransferError::Stall));
+
+        // No more recorded data, so the third transfer stays pending
+        assert!(ep_in
+            .wait_next_complete(Duration::from_millis(10))
+            .is_none());
+        ep_in.clear_halt().wait().unwrap();
+
+        replay.finish().unwrap();
+        recorder.finish().unwrap();
+
+        // The re-recorded session matches the original apart from timing
+        let recorded = String::from_utf8(buf.0.lock().unwrap().clone()).unwrap();
+        let expected = format!("{HEADER}{events}");
+        assert_eq!(strip_timestamps(&recorded), strip_timestamps(&expected));
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    #[test]
+    fn replay_reports_mismatches() {
+        let events = "\
+0+10 claim-interface 0 ok
+5+10 control-out 4031010000000200 cafe ok
+10+10 reset ok
+";
+        let replay = Replay::from_reader(format!("{HEADER}{events}").as_bytes()).unwrap();
+        let device = replay.device_info().open().wait().unwrap();
+
+        let _interface = device.claim_interface(0).wait().unwrap();
+        let e = device.claim_interface(0).wait().unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::Busy);
+
+        // Different data is reported, but the recorded result is returned
+        device
+            .control_out(
+                ControlOut {
+                    control_type: ControlType::Vendor,
+                    recipient: Recipient::Device,
+                    request: 0x31,
+                    value: 1,
+                    index: 0,
+                    data: &[0xbe, 0xef],
+                },
+                Duration::from_secs(1),
+            )
+            .wait()
+            .unwrap();
+
+        // A request that isn't in the recording stalls
+        let r = device
+            .control_in(
+                ControlIn {
+                    control_type: ControlType::Vendor,
+                    recipient: Recipient::Device,
+                    request: 0x99,
+                    value: 0,
+                    index: 0,
+                    length: 4,
+                },
+                Duration::from_secs(1),
+            )
+            .wait();
+        assert_eq!(r, Err(crate::transfer::TransferError::Stall));
+
+        let err = replay.finish().unwrap_err();
+        assert_eq!(err.mismatches().len(), 2);
+        assert_eq!(err.unconsumed(), ["10+10 reset ok"]);
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    #[test]
+    fn replay_timeout() {
+        let events = "0+1000000 control-in c030000000000400 cancelled -\n";
+        let replay = Replay::from_reader(format!("{HEADER}{events}").as_bytes()).unwrap();
+        let device = replay.device_info().open().wait().unwrap();
+        let r = device
+            .control_in(
+                ControlIn {
+                    control_type: ControlType::Vendor,
+                    recipient: Recipient::Device,
+                    request: 0x30,
+                    value: 0,
+                    index: 0,
+                    length: 4,
+                },
+                Duration::from_millis(20),
+            )
+            .wait();
+        assert_eq!(r, Err(crate::transfer::TransferError::Cancelled));
+        replay.finish().unwrap();
+    }
+
+    #[test]
+    fn isochronous_not_recorded() {
+        let recording = "\
+nusb-recording 1
+speed high
+configuration 1
+descriptors 12010002ff000040aaaa55550001000000010902190001010080320904000001ff00000007058301000201
+0+1 claim-interface 0 ok
+1+1 claim-interface 0 ok
+";
+        let replay = Replay::from_reader(recording.as_bytes()).unwrap();
+        let device = replay.device_info().open().wait().unwrap();
+        let recorder = Recorder::new(std::io::sink());
+        let interface = device.record(&recorder).claim_interface(0).wait().unwrap();
+        let e = interface.endpoint::<Isochronous, In>(0x83).err().unwrap();
+        assert_eq!(e.kind(), ErrorKind::Unsupported);
+        drop(interface);
+
+        let interface = device.claim_interface(0).wait().unwrap();
+        assert!(interface.endpoint::<Isochronous, In>(0x83).is_ok());
+    }
+
+    #[test]
+    fn header_error_line() {
+        let recording = "# comment\n\nnusb-recording 1\nspeed warp\n";
+        let e = Replay::from_reader(recording.as_bytes()).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(e.to_string(), "invalid recording at line 4: invalid speed");
+    }
+
+    #[test]
+    fn non_ascii_hex() {
+        let events = "0+10 control-out 4031010000000200 0\u{e9}0 ok\n";
+        let e = Replay::from_reader(format!("{HEADER}{events}").as_bytes()).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+    }
+}
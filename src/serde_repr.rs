@@ -0,0 +1,595 @@
+//! Serialized form of [`DeviceInfo`], [`InterfaceInfo`] and [`BusInfo`].
+//!
+//! These types are serialized through the `*Repr` structs here rather than
+//! by deriving on their fields, so that the format only changes when these
+//! structs do. Fields that don't apply to every device, and any field added
+//! after the format was introduced, have a default so that older data still
+//! deserializes. Platform-specific fields are skipped when deserializing on
+//! another platform, and the cached descriptors are not serialized.
+
+#[cfg(target_os = "windows")]
+use std::ffi::OsString;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+#[cfg(target_os = "linux")]
+use crate::{platform::SysfsPath, Removable};
+use crate::{DeviceInfo, InterfaceInfo, Speed};
+
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
+use crate::{BusInfo, UsbControllerType};
+
+#[cfg(target_os = "linux")]
+fn yes() -> bool {
+    true
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn is_zero(n: &u32) -> bool {
+    *n == 0
+}
+
+#[cfg(target_os = "windows")]
+fn lossy(s: &OsString) -> String {
+    s.to_string_lossy().into_owned()
+}
+
+#[derive(Serialize, Deserialize)]
+struct DeviceInfoRepr {
+    vendor_id: u16,
+    product_id: u16,
+    usb_version: u16,
+    class: u8,
+    subclass: u8,
+    protocol: u8,
+
+    #[serde(default)]
+    speed: Option<Speed>,
+    #[serde(default)]
+    manufacturer_string: Option<String>,
+    #[serde(default)]
+    product_string: Option<String>,
+    #[serde(default)]
+    serial_number: Option<String>,
+    #[serde(default)]
+    interfaces: Vec<InterfaceInfo>,
+
+    #[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
+    #[serde(default)]
+    bus_id: String,
+
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "macos",
+        target_os = "windows",
+        target_os = "android"
+    ))]
+    #[serde(default)]
+    device_address: u8,
+
+    #[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
+    #[serde(default)]
+    port_chain: Vec<u8>,
+
+    #[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
+    #[serde(default)]
+    device_version: u16,
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[serde(default)]
+    busnum: u8,
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[serde(default, skip_serializing_if = "is_zero")]
+    emulated_id: u32,
+
+    #[cfg(target_os = "linux")]
+    #[serde(default)]
+    sysfs_path: std::path::PathBuf,
+
+    #[cfg(target_os = "linux")]
+    #[serde(default = "yes")]
+    authorized: bool,
+
+    #[cfg(target_os = "linux")]
+    #[serde(default)]
+    removable: Option<Removable>,
+
+    #[cfg(target_os = "linux")]
+    #[serde(default)]
+    max_power: Option<u16>,
+
+    #[cfg(target_os = "windows")]
+    devinst: crate::platform::DevInst,
+
+    #[cfg(target_os = "windows")]
+    #[serde(default)]
+    instance_id: String,
+
+    #[cfg(target_os = "windows")]
+    #[serde(default)]
+    parent_instance_id: String,
+
+    #[cfg(target_os = "windows")]
+    #[serde(default)]
+    location_paths: Vec<String>,
+
+    #[cfg(target_os = "windows")]
+    #[serde(default)]
+    port_number: u32,
+
+    #[cfg(target_os = "windows")]
+    #[serde(default)]
+    driver: Option<String>,
+
+    #[cfg(target_os = "macos")]
+    registry_entry_id: u64,
+
+    #[cfg(target_os = "macos")]
+    #[serde(default)]
+    location_id: u32,
+}
+
+impl Serialize for DeviceInfo {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        DeviceInfoRepr {
+            vendor_id: self.vendor_id,
+            product_id: self.product_id,
+            usb_version: self.usb_version,
+            class: self.class,
+            subclass: self.subclass,
+            protocol: self.protocol,
+            speed: self.speed,
+            manufacturer_string: self.manufacturer_string.clone(),
+            product_string: self.product_string.clone(),
+            serial_number: self.serial_number.clone(),
+            interfaces: self.interfaces.clone(),
+
+            #[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
+            bus_id: self.bus_id.clone(),
+
+            #[cfg(any(
+                target_os = "linux",
+                target_os = "macos",
+                target_os = "windows",
+                target_os = "android"
+            ))]
+            device_address: self.device_address,
+
+            #[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
+            port_chain: self.port_chain.clone(),
+
+            #[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
+            device_version: self.device_version,
+
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            busnum: self.busnum,
+
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            emulated_id: self.emulated_id,
+
+            #[cfg(target_os = "linux")]
+            sysfs_path: self.path.0.clone(),
+
+            #[cfg(target_os = "linux")]
+            authorized: self.authorized,
+
+            #[cfg(target_os = "linux")]
+            removable: Some(self.removable),
+
+            #[cfg(target_os = "linux")]
+            max_power: self.max_power,
+
+            #[cfg(target_os = "windows")]
+            devinst: self.devinst,
+
+            #[cfg(target_os = "windows")]
+            instance_id: lossy(&self.instance_id),
+
+            #[cfg(target_os = "windows")]
+            parent_instance_id: lossy(&self.parent_instance_id),
+
+            #[cfg(target_os = "windows")]
+            location_paths: self.location_paths.iter().map(lossy).collect(),
+
+            #[cfg(target_os = "windows")]
+            port_number: self.port_number,
+
+            #[cfg(target_os = "windows")]
+            driver: self.driver.clone(),
+
+            #[cfg(target_os = "macos")]
+            registry_entry_id: self.registry_id,
+
+            #[cfg(target_os = "macos")]
+            location_id: self.location_id,
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for DeviceInfo {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let r = DeviceInfoRepr::deserialize(deserializer)?;
+        Ok(DeviceInfo {
+            #[cfg(target_os = "linux")]
+            path: SysfsPath(r.sysfs_path),
+
+            #[cfg(target_os = "linux")]
+            roots: crate::platform::FsRoots::system(),
+
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            busnum: r.busnum,
+
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            emulated_id: r.emulated_id,
+
+            #[cfg(target_os = "windows")]
+            instance_id: r.instance_id.into(),
+
+            #[cfg(target_os = "windows")]
+            location_paths: r.location_paths.into_iter().map(Into::into).collect(),
+
+            #[cfg(target_os = "windows")]
+            parent_instance_id: r.parent_instance_id.into(),
+
+            #[cfg(target_os = "windows")]
+            port_number: r.port_number,
+
+            #[cfg(target_os = "windows")]
+            devinst: r.devinst,
+
+            #[cfg(target_os = "windows")]
+            driver: r.driver,
+
+            #[cfg(target_os = "macos")]
+            registry_id: r.registry_entry_id,
+
+            #[cfg(target_os = "macos")]
+            location_id: r.location_id,
+
+            #[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
+            bus_id: r.bus_id,
+
+            #[cfg(any(
+                target_os = "linux",
+                target_os = "macos",
+                target_os = "windows",
+                target_os = "android"
+            ))]
+            device_address: r.device_address,
+
+            #[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
+            port_chain: r.port_chain,
+
+            vendor_id: r.vendor_id,
+            product_id: r.product_id,
+
+            #[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
+            device_version: r.device_version,
+
+            usb_version: r.usb_version,
+            class: r.class,
+            subclass: r.subclass,
+            protocol: r.protocol,
+            speed: r.speed,
+            manufacturer_string: r.manufacturer_string,
+            product_string: r.product_string,
+            serial_number: r.serial_number,
+            interfaces: r.interfaces,
+
+            #[cfg(target_os = "linux")]
+            authorized: r.authorized,
+
+            #[cfg(target_os = "linux")]
+            removable: r.removable.unwrap_or(Removable::Unknown),
+
+            #[cfg(target_os = "linux")]
+            max_power: r.max_power,
+
+            descriptors: Vec::new(),
+            emulated: None,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct InterfaceInfoRepr {
+    interface_number: u8,
+    class: u8,
+    subclass: u8,
+    protocol: u8,
+
+    #[serde(default)]
+    interface_string: Option<String>,
+
+    #[cfg(target_os = "linux")]
+    #[serde(default)]
+    sysfs_path: std::path::PathBuf,
+
+    #[cfg(target_os = "linux")]
+    #[serde(default)]
+    driver: Option<String>,
+
+    #[cfg(target_os = "linux")]
+    #[serde(default = "yes")]
+    authorized: bool,
+}
+
+impl Serialize for InterfaceInfo {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        InterfaceInfoRepr {
+            interface_number: self.interface_number,
+            class: self.class,
+            subclass: self.subclass,
+            protocol: self.protocol,
+            interface_string: self.interface_string.clone(),
+
+            #[cfg(target_os = "linux")]
+            sysfs_path: self.path.0.clone(),
+
+            #[cfg(target_os = "linux")]
+            driver: self.driver.clone(),
+
+            #[cfg(target_os = "linux")]
+            authorized: self.authorized,
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for InterfaceInfo {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let r = InterfaceInfoRepr::deserialize(deserializer)?;
+        Ok(InterfaceInfo {
+            interface_number: r.interface_number,
+            class: r.class,
+            subclass: r.subclass,
+            protocol: r.protocol,
+            interface_string: r.interface_string,
+
+            #[cfg(target_os = "linux")]
+            path: SysfsPath(r.sysfs_path),
+
+            #[cfg(target_os = "linux")]
+            driver: r.driver,
+
+            #[cfg(target_os = "linux")]
+            authorized: r.authorized,
+        })
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
+#[derive(Serialize, Deserialize)]
+struct BusInfoRepr {
+    bus_id: String,
+
+    #[serde(default)]
+    driver: Option<String>,
+
+    #[serde(default)]
+    controller_type: Option<UsbControllerType>,
+
+    #[cfg(target_os = "linux")]
+    busnum: u8,
+
+    #[cfg(target_os = "linux")]
+    root_hub: DeviceInfo,
+
+    #[cfg(target_os = "linux")]
+    #[serde(default)]
+    sysfs_path: std::path::PathBuf,
+
+    #[cfg(target_os = "windows")]
+    devinst: crate::platform::DevInst,
+
+    #[cfg(target_os = "windows")]
+    #[serde(default)]
+    instance_id: String,
+
+    #[cfg(target_os = "windows")]
+    #[serde(default)]
+    parent_instance_id: String,
+
+    #[cfg(target_os = "windows")]
+    #[serde(default)]
+    location_paths: Vec<String>,
+
+    #[cfg(target_os = "windows")]
+    #[serde(default)]
+    root_hub_description: String,
+
+    #[cfg(target_os = "macos")]
+    registry_entry_id: u64,
+
+    #[cfg(target_os = "macos")]
+    #[serde(default)]
+    location_id: u32,
+
+    #[cfg(target_os = "macos")]
+    #[serde(default)]
+    provider_class_name: String,
+
+    #[cfg(target_os = "macos")]
+    #[serde(default)]
+    class_name: String,
+
+    #[cfg(target_os = "macos")]
+    #[serde(default)]
+    name: Option<String>,
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
+impl Serialize for BusInfo {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        BusInfoRepr {
+            bus_id: self.bus_id.clone(),
+            driver: self.driver.clone(),
+            controller_type: self.controller_type,
+
+            #[cfg(target_os = "linux")]
+            busnum: self.busnum,
+
+            #[cfg(target_os = "linux")]
+            root_hub: self.root_hub.clone(),
+
+            #[cfg(target_os = "linux")]
+            sysfs_path: self.path.0.clone(),
+
+            #[cfg(target_os = "windows")]
+            devinst: self.devinst,
+
+            #[cfg(target_os = "windows")]
+            instance_id: lossy(&self.instance_id),
+
+            #[cfg(target_os = "windows")]
+            parent_instance_id: lossy(&self.parent_instance_id),
+
+            #[cfg(target_os = "windows")]
+            location_paths: self.location_paths.iter().map(lossy).collect(),
+
+            #[cfg(target_os = "windows")]
+            root_hub_description: self.root_hub_description.clone(),
+
+            #[cfg(target_os = "macos")]
+            registry_entry_id: self.registry_id,
+
+            #[cfg(target_os = "macos")]
+            location_id: self.location_id,
+
+            #[cfg(target_os = "macos")]
+            provider_class_name: self.provider_class_name.clone(),
+
+            #[cfg(target_os = "macos")]
+            class_name: self.class_name.clone(),
+
+            #[cfg(target_os = "macos")]
+            name: self.name.clone(),
+        }
+        .serialize(serializer)
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
+impl<'de> Deserialize<'de> for BusInfo {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let r = BusInfoRepr::deserialize(deserializer)?;
+        Ok(BusInfo {
+            #[cfg(target_os = "linux")]
+            path: SysfsPath(r.sysfs_path),
+
+            #[cfg(target_os = "linux")]
+            root_hub: r.root_hub,
+
+            #[cfg(target_os = "linux")]
+            busnum: r.busnum,
+
+            #[cfg(target_os = "windows")]
+            instance_id: r.instance_id.into(),
+
+            #[cfg(target_os = "windows")]
+            location_paths: r.location_paths.into_iter().map(Into::into).collect(),
+
+            #[cfg(target_os = "windows")]
+            devinst: r.devinst,
+
+            #[cfg(target_os = "windows")]
+            root_hub_description: r.root_hub_description,
+
+            #[cfg(target_os = "windows")]
+            parent_instance_id: r.parent_instance_id.into(),
+
+            #[cfg(target_os = "macos")]
+            registry_id: r.registry_entry_id,
+
+            #[cfg(target_os = "macos")]
+            location_id: r.location_id,
+
+            #[cfg(target_os = "macos")]
+            provider_class_name: r.provider_class_name,
+
+            #[cfg(target_os = "macos")]
+            class_name: r.class_name,
+
+            #[cfg(target_os = "macos")]
+            name: r.name,
+
+            driver: r.driver,
+            bus_id: r.bus_id,
+            controller_type: r.controller_type,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::DeviceInfo;
+
+    #[test]
+    fn minimal_device_info() {
+        let json = r#"{
+            "vendor_id": 43690,
+            "product_id": 21845,
+            "usb_version": 512,
+            "class": 255,
+            "subclass": 0,
+            "protocol": 0,
+            "devinst": 1,
+            "registry_entry_id": 1,
+            "interfaces": [
+                { "interface_number": 0, "class": 255, "subclass": 0, "protocol": 0 }
+            ]
+        }"#;
+        let info: DeviceInfo = serde_json::from_str(json).unwrap();
+        assert_eq!((info.vendor_id(), info.product_id()), (0xaaaa, 0x5555));
+        assert_eq!(info.speed(), None);
+        assert_eq!(info.interfaces().next().unwrap().class(), 0xff);
+
+        #[cfg(target_os = "linux")]
+        {
+            assert!(info.authorized());
+            assert_eq!(info.removable(), crate::Removable::Unknown);
+            assert!(info.interfaces().next().unwrap().authorized());
+
+            // Without a sysfs path, nothing is written relative to the
+            // current directory
+            let unsupported = crate::ErrorKind::Unsupported;
+            assert_eq!(info.set_authorized(true).unwrap_err().kind(), unsupported);
+            let interface = info.interfaces().next().unwrap();
+            assert_eq!(
+                interface.set_authorized(true).unwrap_err().kind(),
+                unsupported
+            );
+        }
+
+        let value = serde_json::to_value(&info).unwrap();
+        assert_eq!(value["vendor_id"], 0xaaaa);
+        assert!(value.get("descriptors").is_none());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn linux_device_info() {
+        use crate::{platform::sysfs_fixture, FsRoots, MaybeFuture};
+
+        let root = std::env::temp_dir().join(format!("nusb-serde-{}", std::process::id()));
+        sysfs_fixture(&root.join("sys"));
+        let roots = FsRoots::new().with_sysfs(root.join("sys"));
+        let info = roots.list_devices().wait().unwrap().next().unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+
+        let value = serde_json::to_value(&info).unwrap();
+        let back: DeviceInfo = serde_json::from_value(value.clone()).unwrap();
+        assert_eq!(serde_json::to_value(&back).unwrap(), value);
+        assert_eq!(format!("{back:?}"), format!("{info:?}"));
+        assert_eq!(back.id(), info.id());
+        assert_eq!((back.busnum(), back.device_address()), (1, 3));
+        assert_eq!(back.sysfs_path(), info.sysfs_path());
+        assert_eq!(back.port_chain(), [2]);
+        assert_eq!(back.max_power(), Some(100));
+        let interface = back.interfaces().next().unwrap();
+        assert_eq!(interface.driver(), Some("widget"));
+        assert!(!interface.authorized());
+    }
+}
@@ -0,0 +1,48 @@
+use std::{
+    mem,
+    sync::Mutex,
+    task::{Context, Poll, Waker},
+};
+
+/// A flag that is set once, and can be waited for by any number of tasks.
+pub(crate) struct Signal {
+    state: Mutex<SignalState>,
+}
+
+#[derive(Default)]
+struct SignalState {
+    set: bool,
+    wakers: Vec<Waker>,
+}
+
+impl Signal {
+    pub(crate) fn new() -> Signal {
+        Signal {
+            state: Mutex::new(SignalState::default()),
+        }
+    }
+
+    /// Set the flag and wake all waiting tasks.
+    pub(crate) fn set(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.set = true;
+        let wakers = mem::take(&mut state.wakers);
+        drop(state);
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+
+    /// Return `Ready` if the flag is set, or arrange for the context's waker
+    /// to be notified when it is.
+    pub(crate) fn poll(&self, cx: &mut Context<'_>) -> Poll<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.set {
+            return Poll::Ready(());
+        }
+        if !state.wakers.iter().any(|w| w.will_wake(cx.waker())) {
+            state.wakers.push(cx.waker().clone());
+        }
+        Poll::Pending
+    }
+}
@@ -0,0 +1,155 @@
+//! Deadlines for operations that aren't backed by an OS timer.
+//!
+//! The platform backends get timeouts from the OS (timerfd on Linux, the
+//! transfer APIs on Windows and macOS). Emulated devices and other code that
+//! only runs in user-space instead register a callback here, which is invoked
+//! from a thread that is started on first use.
+
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Condvar, Mutex},
+    task::{Context, Poll, Waker},
+    thread,
+    time::Instant,
+};
+
+use once_cell::sync::OnceCell;
+
+type Callback = Box<dyn FnOnce() + Send>;
+
+struct Timers {
+    state: Mutex<TimerState>,
+    cond: Condvar,
+}
+
+#[derive(Default)]
+struct TimerState {
+    next_id: u64,
+    entries: BTreeMap<(Instant, u64), Callback>,
+}
+
+static TIMERS: OnceCell<Timers> = OnceCell::new();
+
+/// Call `f` from the timer thread once `deadline` has passed.
+///
+/// The callback should be short, and must not block on other timers.
+pub(crate) fn schedule(deadline: Instant, f: impl FnOnce() + Send + 'static) {
+    let timers = TIMERS.get_or_init(|| {
+        thread::spawn(timer_loop);
+        Timers {
+            state: Mutex::new(TimerState::default()),
+            cond: Condvar::new(),
+        }
+    });
+
+    let mut state = timers.state.lock().unwrap();
+    let id = state.next_id;
+    state.next_id += 1;
+    state.entries.insert((deadline, id), Box::new(f));
+    timers.cond.notify_one();
+}
+
+/// A deadline that a future can wait for.
+///
+/// Unlike calling [`schedule`] on every poll, this schedules a single timer
+/// when first polled, and wakes whichever task polled it last.
+pub(crate) struct Deadline {
+    at: Instant,
+    waker: Option<Arc<Mutex<Waker>>>,
+}
+
+impl Deadline {
+    pub(crate) fn new(at: Instant) -> Deadline {
+        Deadline { at, waker: None }
+    }
+
+    pub(crate) fn at(&self) -> Instant {
+        self.at
+    }
+
+    /// Return `Ready` if the deadline has passed, or arrange for the
+    /// context's waker to be notified when it does.
+    pub(crate) fn poll(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        if Instant::now() >= self.at {
+            return Poll::Ready(());
+        }
+        match &self.waker {
+            Some(waker) => {
+                let mut waker = waker.lock().unwrap();
+                if !waker.will_wake(cx.waker()) {
+                    *waker = cx.waker().clone();
+                }
+            }
+            None => {
+                let waker = Arc::new(Mutex::new(cx.waker().clone()));
+                self.waker = Some(waker.clone());
+                schedule(self.at, move || waker.lock().unwrap().wake_by_ref());
+            }
+        }
+        Poll::Pending
+    }
+}
+
+fn timer_loop() {
+    let timers = TIMERS.wait();
+    let mut state = timers.state.lock().unwrap();
+    loop {
+        let now = Instant::now();
+        match state.entries.first_key_value() {
+            Some((&(deadline, _), _)) if deadline <= now => {
+                let (_, f) = state.entries.pop_first().unwrap();
+                drop(state);
+                f();
+                state = timers.state.lock().unwrap();
+            }
+            Some((&(deadline, _), _)) => {
+                state = timers.cond.wait_timeout(state, deadline - now).unwrap().0;
+            }
+            None => {
+                state = timers.cond.wait(state).unwrap();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        task::{Context, Wake, Waker},
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use super::Deadline;
+
+    struct CountWakes(AtomicUsize);
+
+    impl Wake for CountWakes {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn deadline_wakes_once() {
+        let first = Arc::new(CountWakes(AtomicUsize::new(0)));
+        let second = Arc::new(CountWakes(AtomicUsize::new(0)));
+        let mut deadline = Deadline::new(Instant::now() + Duration::from_millis(20));
+
+        let waker = Waker::from(first.clone());
+        for _ in 0..3 {
+            assert!(deadline.poll(&mut Context::from_waker(&waker)).is_pending());
+        }
+        let waker = Waker::from(second.clone());
+        assert!(deadline.poll(&mut Context::from_waker(&waker)).is_pending());
+
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(first.0.load(Ordering::SeqCst), 0);
+        assert_eq!(second.0.load(Ordering::SeqCst), 1);
+        assert!(deadline.poll(&mut Context::from_waker(&waker)).is_ready());
+    }
+}
@@ -182,3 +182,31 @@ pub(crate) fn request_type(
 ) -> u8 {
     (direction as u8) | ((control_type as u8) << 5) | (recipient as u8)
 }
+
+/// Decode a `bmRequestType` field, returning `None` if it uses a reserved
+/// type or recipient.
+#[cfg(any(
+    feature = "mock",
+    feature = "usbip",
+    all(feature = "gadget", any(target_os = "linux", target_os = "android"))
+))]
+pub(crate) fn parse_request_type(bmrequesttype: u8) -> Option<(Direction, ControlType, Recipient)> {
+    let control_type = match (bmrequesttype >> 5) & 0x03 {
+        0 => ControlType::Standard,
+        1 => ControlType::Class,
+        2 => ControlType::Vendor,
+        _ => return None,
+    };
+    let recipient = match bmrequesttype & 0x1f {
+        0 => Recipient::Device,
+        1 => Recipient::Interface,
+        2 => Recipient::Endpoint,
+        3 => Recipient::Other,
+        _ => return None,
+    };
+    Some((
+        Direction::from_address(bmrequesttype),
+        control_type,
+        recipient,
+    ))
+}
@@ -6,6 +6,12 @@
 use std::{fmt::Display, io};
 
 mod control;
+#[cfg(any(
+    feature = "mock",
+    feature = "usbip",
+    all(feature = "gadget", any(target_os = "linux", target_os = "android"))
+))]
+pub(crate) use control::parse_request_type;
 #[allow(unused)]
 pub(crate) use control::{request_type, SETUP_PACKET_SIZE};
 pub use control::{ControlIn, ControlOut, ControlType, Direction, Recipient};
@@ -0,0 +1,456 @@
+use std::{collections::HashMap, fmt::Display};
+
+use crate::{
+    maybe_future::blocking::Blocking, platform, BusInfo, DeviceId, DeviceInfo, Error,
+    InterfaceInfo, MaybeFuture, Speed,
+};
+
+/// Snapshot of the tree of USB buses, hubs, and devices.
+///
+/// The tree is assembled from the [`bus_id`][DeviceInfo::bus_id] and
+/// [`port_chain`][DeviceInfo::port_chain] of each device, and does not
+/// change after it is created.
+///
+/// The `Display` implementation formats the tree like `lsusb -t`, with a line
+/// for each interface of each device.
+///
+/// ### Example
+///
+/// ```no_run
+/// use nusb::{MaybeFuture, UsbTree};
+///
+/// let tree = UsbTree::new().wait().unwrap();
+/// print!("{tree}");
+///
+/// for hub in tree.devices().filter(|d| d.is_hub()) {
+///     println!("{:?} has {} devices", hub.port_chain(), hub.children().count());
+/// }
+/// ```
+pub struct UsbTree {
+    buses: Vec<Bus>,
+    nodes: Vec<Node>,
+}
+
+struct Bus {
+    bus_id: String,
+    info: Option<BusInfo>,
+    children: Vec<usize>,
+}
+
+struct Node {
+    info: DeviceInfo,
+    bus: usize,
+    parent: Option<usize>,
+    children: Vec<usize>,
+}
+
+impl UsbTree {
+    /// Build a tree of the buses and devices currently connected to the
+    /// system.
+    pub fn new() -> impl MaybeFuture<Output = Result<UsbTree, Error>> {
+        Blocking::new(|| {
+            let buses = platform::list_buses().wait()?.collect();
+            let devices = platform::list_devices().wait()?;
+            Ok(UsbTree::build(buses, devices))
+        })
+    }
+
+    /// Build a tree from previously listed devices.
+    ///
+    /// Each device is a child of the device on the same bus with the longest
+    /// port chain that is a prefix of its own, or of the bus if there is none.
+    pub fn from_devices(devices: impl IntoIterator<Item = DeviceInfo>) -> UsbTree {
+        UsbTree::build(Vec::new(), devices)
+    }
+
+    fn build(bus_infos: Vec<BusInfo>, devices: impl IntoIterator<Item = DeviceInfo>) -> UsbTree {
+        // Sorting by port chain puts each hub before the devices connected
+        // to it, so the nodes are in depth-first order.
+        let mut devices: Vec<DeviceInfo> = devices.into_iter().collect();
+        devices.sort_by(|a, b| (a.bus_id(), a.port_chain()).cmp(&(b.bus_id(), b.port_chain())));
+
+        let mut buses: Vec<Bus> = bus_infos
+            .into_iter()
+            .map(|info| Bus {
+                bus_id: info.bus_id().to_owned(),
+                info: Some(info),
+                children: Vec::new(),
+            })
+            .collect();
+        for device in &devices {
+            if !buses.iter().any(|b| b.bus_id == device.bus_id()) {
+                buses.push(Bus {
+                    bus_id: device.bus_id().to_owned(),
+                    info: None,
+                    children: Vec::new(),
+                });
+            }
+        }
+        buses.sort_by(|a, b| a.bus_id.cmp(&b.bus_id));
+
+        let mut nodes: Vec<Node> = Vec::with_capacity(devices.len());
+        let mut by_port: HashMap<(usize, Vec<u8>), usize> = HashMap::new();
+        for info in devices {
+            let index = nodes.len();
+            let bus = buses
+                .iter()
+                .position(|b| b.bus_id == info.bus_id())
+                .unwrap_or_default();
+            let chain = info.port_chain();
+            let parent = (1..chain.len())
+                .rev()
+                .find_map(|len| by_port.get(&(bus, chain[..len].to_vec())).copied());
+            match parent {
+                Some(parent) => nodes[parent].children.push(index),
+                None => buses[bus].children.push(index),
+            }
+            by_port.entry((bus, chain.to_vec())).or_insert(index);
+            nodes.push(Node {
+                info,
+                bus,
+                parent,
+                children: Vec::new(),
+            });
+        }
+
+        UsbTree { buses, nodes }
+    }
+
+    /// Iterator over the buses, in order of bus ID.
+    pub fn buses(&self) -> impl Iterator<Item = UsbTreeBus<'_>> {
+        (0..self.buses.len()).map(|index| UsbTreeBus { tree: self, index })
+    }
+
+    /// Iterator over all devices, in depth-first order.
+    pub fn devices(&self) -> impl Iterator<Item = UsbTreeNode<'_>> {
+        (0..self.nodes.len()).map(|index| UsbTreeNode { tree: self, index })
+    }
+
+    /// Find a device by its [`DeviceId`].
+    pub fn device(&self, id: DeviceId) -> Option<UsbTreeNode<'_>> {
+        self.devices().find(|d| d.info().id() == id)
+    }
+
+    /// Find the device connected at a physical port, identified by bus ID and
+    /// port chain.
+    pub fn find_port(&self, bus_id: &str, port_chain: &[u8]) -> Option<UsbTreeNode<'_>> {
+        self.devices()
+            .find(|d| d.info().bus_id() == bus_id && d.port_chain() == port_chain)
+    }
+}
+
+impl std::fmt::Debug for UsbTree {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list().entries(self.buses()).finish()
+    }
+}
+
+impl Display for UsbTree {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for bus in self.buses() {
+            write!(f, "/:  Bus {}", bus.bus_id())?;
+            if let Some(driver) = bus.info().and_then(|b| b.driver()) {
+                write!(f, ", Driver={driver}")?;
+            }
+            writeln!(f)?;
+            for device in bus.children() {
+                write_device(f, device)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn write_device(f: &mut std::fmt::Formatter<'_>, device: UsbTreeNode) -> std::fmt::Result {
+    let info = device.info();
+    let indent = 4 * device.depth().max(1);
+    let port = device.port().unwrap_or(0);
+    let speed = match info.speed() {
+        Some(Speed::Low) => ", 1.5M",
+        Some(Speed::Full) => ", 12M",
+        Some(Speed::High) => ", 480M",
+        Some(Speed::Super) => ", 5000M",
+        Some(Speed::SuperPlus) => ", 10000M",
+        None => "",
+    };
+    let line = format!(
+        "{:indent$}|__ Port {port}: Dev {}, ID {:04x}:{:04x}",
+        "",
+        info.device_address(),
+        info.vendor_id(),
+        info.product_id(),
+    );
+
+    if info.interfaces().next().is_none() {
+        let driver = device_driver(info).unwrap_or("[none]");
+        writeln!(
+            f,
+            "{line}, Class={:02x}, Driver={driver}{speed}",
+            info.class()
+        )?;
+    }
+    for interface in info.interfaces() {
+        let driver = interface_driver(info, interface).unwrap_or("[none]");
+        writeln!(
+            f,
+            "{line}, If {}, Class={:02x}, Driver={driver}{speed}",
+            interface.interface_number(),
+            interface.class(),
+        )?;
+    }
+
+    for child in device.children() {
+        write_device(f, child)?;
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn device_driver(_device: &DeviceInfo) -> Option<&str> {
+    None
+}
+
+#[cfg(target_os = "linux")]
+fn interface_driver<'a>(_device: &'a DeviceInfo, interface: &'a InterfaceInfo) -> Option<&'a str> {
+    interface.driver()
+}
+
+#[cfg(target_os = "windows")]
+fn device_driver(device: &DeviceInfo) -> Option<&str> {
+    device.driver()
+}
+
+#[cfg(target_os = "windows")]
+fn interface_driver<'a>(device: &'a DeviceInfo, _interface: &'a InterfaceInfo) -> Option<&'a str> {
+    device.driver()
+}
+
+#[cfg(target_os = "macos")]
+fn device_driver(_device: &DeviceInfo) -> Option<&str> {
+    None
+}
+
+#[cfg(target_os = "macos")]
+fn interface_driver<'a>(_device: &'a DeviceInfo, _interface: &'a InterfaceInfo) -> Option<&'a str> {
+    None
+}
+
+/// A bus in a [`UsbTree`].
+#[derive(Clone, Copy)]
+pub struct UsbTreeBus<'a> {
+    tree: &'a UsbTree,
+    index: usize,
+}
+
+impl<'a> UsbTreeBus<'a> {
+    fn bus(&self) -> &'a Bus {
+        &self.tree.buses[self.index]
+    }
+
+    /// Identifier for the bus, matching [`DeviceInfo::bus_id`].
+    pub fn bus_id(&self) -> &'a str {
+        &self.bus().bus_id
+    }
+
+    /// Information about the bus and its host controller, or `None` if the
+    /// tree was built with [`UsbTree::from_devices`].
+    pub fn info(&self) -> Option<&'a BusInfo> {
+        self.bus().info.as_ref()
+    }
+
+    /// Iterator over the devices connected to the root hub of the bus.
+    pub fn children(&self) -> impl Iterator<Item = UsbTreeNode<'a>> {
+        let tree = self.tree;
+        self.bus()
+            .children
+            .iter()
+            .map(move |&index| UsbTreeNode { tree, index })
+    }
+}
+
+impl std::fmt::Debug for UsbTreeBus<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UsbTreeBus")
+            .field("bus_id", &self.bus_id())
+            .field("children", &self.children().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+/// A device in a [`UsbTree`].
+#[derive(Clone, Copy)]
+pub struct UsbTreeNode<'a> {
+    tree: &'a UsbTree,
+    index: usize,
+}
+
+impl<'a> UsbTreeNode<'a> {
+    fn node(&self) -> &'a Node {
+        &self.tree.nodes[self.index]
+    }
+
+    /// Information about the device.
+    pub fn info(&self) -> &'a DeviceInfo {
+        &self.node().info
+    }
+
+    /// The bus the device is connected to.
+    pub fn bus(&self) -> UsbTreeBus<'a> {
+        UsbTreeBus {
+            tree: self.tree,
+            index: self.node().bus,
+        }
+    }
+
+    /// The hub the device is connected to, or `None` if it is connected to
+    /// the root hub of the bus.
+    pub fn parent(&self) -> Option<UsbTreeNode<'a>> {
+        self.node().parent.map(|index| UsbTreeNode {
+            tree: self.tree,
+            index,
+        })
+    }
+
+    /// Iterator over the devices connected to this device, if it is a hub.
+    pub fn children(&self) -> impl Iterator<Item = UsbTreeNode<'a>> {
+        let tree = self.tree;
+        self.node()
+            .children
+            .iter()
+            .map(move |&index| UsbTreeNode { tree, index })
+    }
+
+    /// Whether the device is a hub, from its `bDeviceClass`.
+    pub fn is_hub(&self) -> bool {
+        self.info().class() == crate::hub::CLASS_HUB
+    }
+
+    /// Port chain of the device. See [`DeviceInfo::port_chain`].
+    pub fn port_chain(&self) -> &'a [u8] {
+        self.info().port_chain()
+    }
+
+    /// Port number on the parent hub or root hub, or `None` if the platform
+    /// does not report it.
+    pub fn port(&self) -> Option<u8> {
+        self.port_chain().last().copied()
+    }
+
+    /// Number of hops from the root hub: 1 for a device connected directly to
+    /// the root hub, 2 for a device connected to a hub on a root hub port,
+    /// and so on.
+    pub fn depth(&self) -> usize {
+        self.port_chain().len()
+    }
+}
+
+impl std::fmt::Debug for UsbTreeNode<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut s = f.debug_struct("UsbTreeNode");
+        s.field("port_chain", &self.port_chain())
+            .field(
+                "vendor_id",
+                &format_args!("0x{:04X}", self.info().vendor_id()),
+            )
+            .field(
+                "product_id",
+                &format_args!("0x{:04X}", self.info().product_id()),
+            );
+        if !self.node().children.is_empty() {
+            s.field("children", &self.children().collect::<Vec<_>>());
+        }
+        s.finish()
+    }
+}
+
+#[cfg(all(test, feature = "mock"))]
+mod tests {
+    use super::*;
+    use crate::mock::MockDevice;
+
+    fn device(class: u8, port_chain: &[u8]) -> DeviceInfo {
+        let mut info = MockDevice::new([
+            18,
+            0x01,
+            0x00,
+            0x02,
+            class,
+            0,
+            0,
+            64,
+            0x34,
+            0x12,
+            port_chain.len() as u8,
+            0,
+            0,
+            1,
+            0,
+            0,
+            0,
+            1, //
+            9,
+            0x02,
+            18,
+            0,
+            1,
+            1,
+            0,
+            0x80,
+            50, //
+            9,
+            0x04,
+            0,
+            0,
+            0,
+            class,
+            0,
+            0,
+            0,
+        ])
+        .device_info();
+        info.bus_id = "1".into();
+        info.port_chain = port_chain.to_vec();
+        info
+    }
+
+    #[test]
+    fn tree() {
+        let tree = UsbTree::from_devices([
+            device(0x03, &[1, 4, 2]),
+            device(0x09, &[1]),
+            device(0xff, &[2]),
+            device(0x09, &[1, 4]),
+            device(0x03, &[1, 3]),
+        ]);
+
+        let bus = tree.buses().next().unwrap();
+        assert_eq!(bus.bus_id(), "1");
+        assert_eq!(
+            bus.children().map(|d| d.port()).collect::<Vec<_>>(),
+            [Some(1), Some(2)]
+        );
+
+        let hub = tree.find_port("1", &[1, 4]).unwrap();
+        assert!(hub.is_hub());
+        assert_eq!(hub.depth(), 2);
+        assert_eq!(hub.parent().unwrap().port_chain(), [1]);
+        assert_eq!(
+            hub.children().map(|d| d.port_chain()).collect::<Vec<_>>(),
+            [[1, 4, 2]]
+        );
+        assert!(tree.find_port("1", &[3]).is_none());
+
+        assert_eq!(
+            tree.devices().map(|d| d.port_chain()).collect::<Vec<_>>(),
+            [&[1][..], &[1, 3], &[1, 4], &[1, 4, 2], &[2]]
+        );
+
+        let text = tree.to_string();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 6);
+        assert_eq!(lines[0], "/:  Bus 1");
+        assert!(lines[3].starts_with("        |__ Port 4: Dev "));
+        assert!(lines[4].starts_with("            |__ Port 2: Dev "));
+        assert!(lines[4].contains(", ID 1234:0003, If 0, Class=03, Driver=[none]"));
+    }
+}
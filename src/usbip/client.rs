@@ -0,0 +1,885 @@
+use std::{
+    collections::BTreeMap,
+    io::{self, Read, Write},
+    net::{Shutdown, SocketAddr, TcpStream, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    task::{Context, Poll},
+    thread,
+    time::Duration,
+};
+
+use log::{debug, warn};
+
+use super::{
+    io_error,
+    proto::{
+        invalid_data, ExportedDevice, IsoPacket, OpHeader, Pdu, DIR_IN, DIR_OUT, OP_REP_DEVLIST,
+        OP_REP_IMPORT, OP_REQ_DEVLIST, OP_REQ_IMPORT, ST_OK, URB_ISO_ASAP,
+    },
+};
+use crate::{
+    descriptors::{
+        parse_concatenated_config_descriptors, TransferType, DESCRIPTOR_LEN_CONFIGURATION,
+        DESCRIPTOR_LEN_DEVICE, DESCRIPTOR_TYPE_CONFIGURATION, DESCRIPTOR_TYPE_DEVICE,
+    },
+    emulated::{Completer, Model, Request},
+    maybe_future::blocking::Blocking,
+    signal::Signal,
+    transfer::{
+        ControlIn, ControlOut, ControlType, Direction, IsoStatus, Recipient, TransferError,
+    },
+    DeviceInfo, Error, ErrorKind, MaybeFuture, Speed,
+};
+
+/// Timeout for the control transfers performed by operations like
+/// [`Device::set_configuration`][crate::Device::set_configuration].
+const OPERATION_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// List the devices exported by a USB/IP server.
+///
+/// The returned [`DeviceInfo`]s describe the devices as reported by the
+/// server. Opening one imports it, which fails with [`ErrorKind::Busy`] if it
+/// is already imported by another client. Fields like
+/// [`manufacturer_string`][DeviceInfo::manufacturer_string] that are not part
+/// of the USB/IP device list are `None`.
+///
+/// The [`bus_id`][DeviceInfo::bus_id] of a remote device is the server's
+/// address followed by the bus number on the server, like
+/// `192.168.1.2:3240/1`, and the [`port_chain`][DeviceInfo::port_chain] is
+/// taken from the USB/IP bus ID, so a device exported as `1-1.4` has port
+/// chain `[1, 4]`.
+pub fn list_devices(
+    server: impl ToSocketAddrs,
+) -> impl MaybeFuture<Output = Result<impl Iterator<Item = DeviceInfo>, Error>> {
+    let addrs = server.to_socket_addrs().map(|a| a.collect::<Vec<_>>());
+    Blocking::new(move || {
+        let addrs = addrs.map_err(|e| io_error(e, "failed to resolve USB/IP server address"))?;
+        let mut stream = TcpStream::connect(&addrs[..])
+            .map_err(|e| io_error(e, "failed to connect to USB/IP server"))?;
+        let server = stream
+            .peer_addr()
+            .map_err(|e| io_error(e, "failed to connect to USB/IP server"))?;
+        let devices = request_devlist(&mut stream)
+            .map_err(|e| io_error(e, "failed to list USB/IP devices"))?;
+        debug!("USB/IP server {server} exports {} devices", devices.len());
+
+        Ok(devices
+            .into_iter()
+            .map(move |d| RemoteDevice::device_info(server, d)))
+    })
+}
+
+fn request_devlist(stream: &mut TcpStream) -> io::Result<Vec<ExportedDevice>> {
+    let mut req = Vec::new();
+    OpHeader {
+        code: OP_REQ_DEVLIST,
+        status: 0,
+    }
+    .write(&mut req);
+    stream.write_all(&req)?;
+
+    let mut r = io::BufReader::new(stream);
+    let header = OpHeader::read(&mut r)?;
+    if header.code != OP_REP_DEVLIST || header.status != ST_OK {
+        return Err(invalid_data("unexpected reply to OP_REQ_DEVLIST"));
+    }
+    let count = super::proto::read_u32(&mut r)?;
+    (0..count)
+        .map(|_| ExportedDevice::read(&mut r, true))
+        .collect()
+}
+
+fn request_import(server: SocketAddr, busid: &str) -> Result<(TcpStream, ExportedDevice), Error> {
+    let mut stream = TcpStream::connect(server)
+        .map_err(|e| io_error(e, "failed to connect to USB/IP server"))?;
+    let _ = stream.set_nodelay(true);
+
+    let mut req = Vec::new();
+    OpHeader {
+        code: OP_REQ_IMPORT,
+        status: 0,
+    }
+    .write(&mut req);
+    super::proto::write_busid(&mut req, busid);
+
+    let header = stream
+        .write_all(&req)
+        .and_then(|()| OpHeader::read(&mut stream))
+        .map_err(|e| io_error(e, "failed to import USB/IP device"))?;
+    if header.code != OP_REP_IMPORT {
+        return Err(Error::new(
+            ErrorKind::Other,
+            "unexpected reply to OP_REQ_IMPORT",
+        ));
+    }
+    if header.status != ST_OK {
+        return Err(Error::new(
+            ErrorKind::Busy,
+            "USB/IP device is not available for import",
+        ));
+    }
+    let device = ExportedDevice::read(&mut stream, false)
+        .map_err(|e| io_error(e, "failed to import USB/IP device"))?;
+    Ok((stream, device))
+}
+
+/// A device exported by a USB/IP server.
+struct RemoteDevice {
+    server: SocketAddr,
+    exported: ExportedDevice,
+
+    /// Descriptors built from the device list, used until the device is
+    /// opened and the real descriptors are read.
+    summary_descriptors: Vec<u8>,
+
+    connected: Mutex<Option<Connected>>,
+
+    /// Set while `open` is importing the device.
+    opening: AtomicBool,
+}
+
+struct Connected {
+    connection: Arc<Connection>,
+    descriptors: Vec<u8>,
+    configuration: u8,
+}
+
+impl RemoteDevice {
+    fn device_info(server: SocketAddr, exported: ExportedDevice) -> DeviceInfo {
+        let summary_descriptors = summary_descriptors(&exported);
+
+        #[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
+        let (bus_id, port_chain) = (
+            format!("{server}/{}", exported.busnum),
+            exported
+                .busid
+                .split_once('-')
+                .map(|(_, ports)| ports.split('.').filter_map(|p| p.parse().ok()).collect())
+                .unwrap_or_default(),
+        );
+
+        #[allow(unused_mut)]
+        let mut info = DeviceInfo::emulated(Arc::new(RemoteDevice {
+            server,
+            exported,
+            summary_descriptors,
+            connected: Mutex::new(None),
+            opening: AtomicBool::new(false),
+        }));
+
+        #[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
+        {
+            info.bus_id = bus_id;
+            info.port_chain = port_chain;
+        }
+
+        info
+    }
+
+    fn connection(&self) -> Option<Arc<Connection>> {
+        let connected = self.connected.lock().unwrap();
+        connected.as_ref().map(|c| c.connection.clone())
+    }
+
+    fn control_out(&self, data: ControlOut) -> Result<(), Error> {
+        let connection = self
+            .connection()
+            .ok_or(Error::new(ErrorKind::Disconnected, "device disconnected"))?;
+        connection
+            .control_blocking(data.setup_packet(), data.data)
+            .map(|_| ())
+            .map_err(|e| match e {
+                TransferError::Disconnected => {
+                    Error::new(ErrorKind::Disconnected, "device disconnected")
+                }
+                TransferError::Stall => Error::new(ErrorKind::NotFound, "request stalled"),
+                _ => Error::new(ErrorKind::Other, "USB/IP control transfer failed"),
+            })
+    }
+
+    /// Import the device from the server and read its descriptors.
+    fn import(&self) -> Result<Connected, Error> {
+        let (stream, device) = request_import(self.server, &self.exported.busid)?;
+        debug!(
+            "Imported USB/IP device {} ({}) from {}",
+            device.busid, device.path, self.server
+        );
+        let connection = Connection::start(stream, device.devid())
+            .map_err(|e| io_error(e, "failed to import USB/IP device"))?;
+        let descriptors = match read_descriptors(&connection, device.num_configurations) {
+            Ok(d) => d,
+            Err(e) => {
+                connection.shutdown();
+                return Err(e);
+            }
+        };
+
+        Ok(Connected {
+            connection,
+            descriptors,
+            configuration: device.configuration_value,
+        })
+    }
+
+    /// Polling interval of an interrupt or isochronous endpoint, in frames or
+    /// microframes as expected by the Linux USB core.
+    fn interval(&self, endpoint: u8, descriptors: &[u8], configuration: u8) -> u32 {
+        let Some(ep) =
+            parse_concatenated_config_descriptors(&descriptors[DESCRIPTOR_LEN_DEVICE as usize..])
+                .filter(|c| c.configuration_value() == configuration)
+                .flat_map(|c| c.interface_alt_settings())
+                .flat_map(|i| i.endpoints())
+                .find(|e| e.address() == endpoint)
+        else {
+            return 0;
+        };
+
+        let interval = ep.interval().clamp(1, 16) as u32;
+        let fast = matches!(
+            self.exported.speed,
+            Some(Speed::High | Speed::Super | Speed::SuperPlus)
+        );
+        if fast || ep.transfer_type() == TransferType::Isochronous {
+            1 << (interval - 1)
+        } else {
+            ep.interval() as u32
+        }
+    }
+}
+
+/// Build a device descriptor and a configuration descriptor with the
+/// interfaces listed by the server, so that a `DeviceInfo` can be created
+/// without importing the device.
+fn summary_descriptors(d: &ExportedDevice) -> Vec<u8> {
+    let mut desc = vec![DESCRIPTOR_LEN_DEVICE, DESCRIPTOR_TYPE_DEVICE, 0x00, 0x02];
+    desc.extend([d.class, d.subclass, d.protocol, 64]);
+    desc.extend(d.vendor_id.to_le_bytes());
+    desc.extend(d.product_id.to_le_bytes());
+    desc.extend(d.device_version.to_le_bytes());
+    desc.extend([0, 0, 0, d.num_configurations]);
+
+    let total_len = DESCRIPTOR_LEN_CONFIGURATION as usize + 9 * d.interfaces.len();
+    desc.extend([DESCRIPTOR_LEN_CONFIGURATION, DESCRIPTOR_TYPE_CONFIGURATION]);
+    desc.extend((total_len as u16).to_le_bytes());
+    desc.extend([d.interfaces.len() as u8, d.configuration_value, 0, 0x80, 0]);
+    for (number, &(class, subclass, protocol)) in d.interfaces.iter().enumerate() {
+        desc.extend([9, 0x04, number as u8, 0, 0, class, subclass, protocol, 0]);
+    }
+    desc
+}
+
+fn get_descriptor(
+    connection: &Connection,
+    desc_type: u8,
+    desc_index: u8,
+    length: u16,
+) -> Result<Vec<u8>, TransferError> {
+    const STANDARD_REQUEST_GET_DESCRIPTOR: u8 = 0x06;
+    let setup = ControlIn {
+        control_type: ControlType::Standard,
+        recipient: Recipient::Device,
+        request: STANDARD_REQUEST_GET_DESCRIPTOR,
+        value: ((desc_type as u16) << 8) | desc_index as u16,
+        index: 0,
+        length,
+    }
+    .setup_packet();
+    connection.control_blocking(setup, &[])
+}
+
+fn read_descriptors(connection: &Connection, num_configurations: u8) -> Result<Vec<u8>, Error> {
+    let failed = |_| Error::new(ErrorKind::Other, "failed to read USB/IP device descriptors");
+
+    let mut descriptors = get_descriptor(
+        connection,
+        DESCRIPTOR_TYPE_DEVICE,
+        0,
+        DESCRIPTOR_LEN_DEVICE as u16,
+    )
+    .map_err(failed)?;
+    for i in 0..num_configurations {
+        let header = get_descriptor(
+            connection,
+            DESCRIPTOR_TYPE_CONFIGURATION,
+            i,
+            DESCRIPTOR_LEN_CONFIGURATION as u16,
+        )
+        .map_err(failed)?;
+        let Some(total_len) = header.get(2..4) else {
+            return Err(failed(TransferError::Fault));
+        };
+        let total_len = u16::from_le_bytes([total_len[0], total_len[1]]);
+        descriptors.extend(
+            get_descriptor(connection, DESCRIPTOR_TYPE_CONFIGURATION, i, total_len)
+                .map_err(failed)?,
+        );
+    }
+    Ok(descriptors)
+}
+
+impl Model for RemoteDevice {
+    fn descriptors(&self) -> Vec<u8> {
+        match &*self.connected.lock().unwrap() {
+            Some(c) => c.descriptors.clone(),
+            None => self.summary_descriptors.clone(),
+        }
+    }
+
+    fn speed(&self) -> Option<Speed> {
+        self.exported.speed
+    }
+
+    fn active_configuration(&self) -> u8 {
+        match &*self.connected.lock().unwrap() {
+            Some(c) => c.configuration,
+            None => self.exported.configuration_value,
+        }
+    }
+
+    fn open(&self) -> Result<(), Error> {
+        let busy = || Error::new(ErrorKind::Busy, "USB/IP device is already open");
+        if self.opening.swap(true, Ordering::AcqRel) {
+            return Err(busy());
+        }
+        let result = if self.connected.lock().unwrap().is_some() {
+            Err(busy())
+        } else {
+            // Import without holding the lock, so that other users of the
+            // model aren't blocked on the network.
+            self.import()
+                .map(|c| *self.connected.lock().unwrap() = Some(c))
+        };
+        self.opening.store(false, Ordering::Release);
+        result
+    }
+
+    fn close(&self) {
+        if let Some(c) = self.connected.lock().unwrap().take() {
+            debug!("Closing USB/IP device {}", self.exported.busid);
+            c.connection.shutdown();
+        }
+    }
+
+    fn set_configuration(&self, configuration: u8) -> Result<(), Error> {
+        const STANDARD_REQUEST_SET_CONFIGURATION: u8 = 0x09;
+        self.control_out(ControlOut {
+            control_type: ControlType::Standard,
+            recipient: Recipient::Device,
+            request: STANDARD_REQUEST_SET_CONFIGURATION,
+            value: configuration as u16,
+            index: 0,
+            data: &[],
+        })?;
+        if let Some(c) = &mut *self.connected.lock().unwrap() {
+            c.configuration = configuration;
+        }
+        Ok(())
+    }
+
+    fn claim_interface(&self, _interface: u8) -> Result<(), Error> {
+        // The server claims all interfaces when the device is imported.
+        match self.connection() {
+            Some(_) => Ok(()),
+            None => Err(Error::new(ErrorKind::Disconnected, "device disconnected")),
+        }
+    }
+
+    fn set_alt_setting(&self, interface: u8, alt_setting: u8) -> Result<(), Error> {
+        const STANDARD_REQUEST_SET_INTERFACE: u8 = 0x0b;
+        self.control_out(ControlOut {
+            control_type: ControlType::Standard,
+            recipient: Recipient::Interface,
+            request: STANDARD_REQUEST_SET_INTERFACE,
+            value: alt_setting as u16,
+            index: interface as u16,
+            data: &[],
+        })
+    }
+
+    fn clear_halt(&self, endpoint: u8) -> Result<(), Error> {
+        const STANDARD_REQUEST_CLEAR_FEATURE: u8 = 0x01;
+        const ENDPOINT_HALT: u16 = 0;
+        self.control_out(ControlOut {
+            control_type: ControlType::Standard,
+            recipient: Recipient::Endpoint,
+            request: STANDARD_REQUEST_CLEAR_FEATURE,
+            value: ENDPOINT_HALT,
+            index: endpoint as u16,
+            data: &[],
+        })
+    }
+
+    fn reset(&self) -> Result<(), Error> {
+        // The Linux server performs a port reset when it sees a hub class
+        // `SET_FEATURE(PORT_RESET)` request.
+        const HUB_REQUEST_SET_FEATURE: u8 = 0x03;
+        const PORT_RESET: u16 = 4;
+        self.control_out(ControlOut {
+            control_type: ControlType::Class,
+            recipient: Recipient::Other,
+            request: HUB_REQUEST_SET_FEATURE,
+            value: PORT_RESET,
+            index: 0,
+            data: &[],
+        })
+    }
+
+    fn submit(&self, request: Request, completer: Completer) {
+        let connected = self.connected.lock().unwrap();
+        let Some(c) = &*connected else {
+            drop(connected);
+            completer.fail(TransferError::Disconnected);
+            return;
+        };
+        let connection = c.connection.clone();
+        let interval = match request.transfer_type {
+            TransferType::Interrupt | TransferType::Isochronous => {
+                self.interval(request.endpoint, &c.descriptors, c.configuration)
+            }
+            _ => 0,
+        };
+        drop(connected);
+
+        let iso_packet_lengths = match request.transfer_type {
+            TransferType::Isochronous => request.iso_packet_lengths.clone(),
+            _ => Vec::new(),
+        };
+        connection.submit(
+            Urb {
+                id: Some(request.id),
+                direction: request.direction(),
+                requested_len: request.requested_len,
+                iso_packet_lengths: iso_packet_lengths.clone(),
+                waiter: Some(Waiter::Transfer(completer)),
+            },
+            Submit {
+                endpoint: request.endpoint,
+                requested_len: request.requested_len,
+                setup: request.setup.unwrap_or_default(),
+                data: &request.data,
+                iso_packet_lengths: &iso_packet_lengths,
+                interval,
+            },
+        );
+    }
+
+    fn cancel(&self, id: u64) {
+        if let Some(connection) = self.connection() {
+            connection.unlink(|_, urb| urb.id == Some(id));
+        }
+    }
+
+    fn poll_disconnected(&self, cx: &mut Context<'_>) -> Poll<()> {
+        match self.connection() {
+            Some(connection) => connection.disconnected.poll(cx),
+            None => Poll::Ready(()),
+        }
+    }
+}
+
+/// An imported device's connection to the server.
+struct Connection {
+    devid: u32,
+    stream: Mutex<TcpStream>,
+    state: Mutex<ConnectionState>,
+
+    /// Set once the connection is closed.
+    disconnected: Signal,
+}
+
+#[derive(Default)]
+struct ConnectionState {
+    next_seqnum: u32,
+    closed: bool,
+
+    /// Submitted URBs by sequence number. URBs that are being unlinked stay
+    /// here until the server responds, but without a waiter.
+    urbs: BTreeMap<u32, Urb>,
+
+    /// Sequence numbers of pending `CMD_UNLINK`s and the URBs they unlink.
+    unlinks: BTreeMap<u32, u32>,
+}
+
+struct Urb {
+    /// [`Request::id`] of the transfer, if not an internal request.
+    id: Option<u64>,
+    direction: Direction,
+
+    /// Transfer buffer length, which bounds the length in the reply.
+    requested_len: usize,
+    iso_packet_lengths: Vec<u32>,
+    waiter: Option<Waiter>,
+}
+
+enum Waiter {
+    Transfer(Completer),
+    Blocking(mpsc::Sender<Result<Vec<u8>, TransferError>>),
+}
+
+impl Waiter {
+    fn fail(self, error: TransferError) {
+        match self {
+            Waiter::Transfer(c) => {
+                c.fail(error);
+            }
+            Waiter::Blocking(tx) => {
+                let _ = tx.send(Err(error));
+            }
+        }
+    }
+}
+
+struct Submit<'a> {
+    endpoint: u8,
+    requested_len: usize,
+    setup: [u8; 8],
+    data: &'a [u8],
+    iso_packet_lengths: &'a [u32],
+    interval: u32,
+}
+
+impl Connection {
+    fn start(stream: TcpStream, devid: u32) -> io::Result<Arc<Connection>> {
+        let reader = stream.try_clone()?;
+        let connection = Arc::new(Connection {
+            devid,
+            stream: Mutex::new(stream),
+            state: Mutex::new(ConnectionState {
+                next_seqnum: 1,
+                ..Default::default()
+            }),
+            disconnected: Signal::new(),
+        });
+
+        let c = connection.clone();
+        thread::Builder::new()
+            .name("nusb-usbip".into())
+            .spawn(move || c.read_loop(reader))?;
+        Ok(connection)
+    }
+
+    fn send(&self, msg: &[u8]) {
+        if let Err(e) = self.stream.lock().unwrap().write_all(msg) {
+            warn!("Failed to write to USB/IP server: {e}");
+            self.shutdown();
+        }
+    }
+
+    fn submit(&self, urb: Urb, submit: Submit) -> Option<u32> {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            drop(state);
+            if let Some(waiter) = urb.waiter {
+                waiter.fail(TransferError::Disconnected);
+            }
+            return None;
+        }
+        let seqnum = state.next_seqnum;
+        state.next_seqnum = state.next_seqnum.wrapping_add(1).max(1);
+        let direction = urb.direction;
+        state.urbs.insert(seqnum, urb);
+        drop(state);
+
+        let mut msg = Vec::new();
+        Pdu::CmdSubmit {
+            seqnum,
+            devid: self.devid,
+            direction: match direction {
+                Direction::In => DIR_IN,
+                Direction::Out => DIR_OUT,
+            },
+            ep: (submit.endpoint & 0x0f) as u32,
+            transfer_flags: if submit.iso_packet_lengths.is_empty() {
+                0
+            } else {
+                URB_ISO_ASAP
+            },
+            transfer_buffer_length: submit.requested_len as u32,
+            number_of_packets: submit.iso_packet_lengths.len() as u32,
+            interval: submit.interval,
+            setup: submit.setup,
+        }
+        .write(&mut msg);
+        if direction == Direction::Out {
+            msg.extend_from_slice(submit.data);
+        }
+        let mut offset = 0;
+        for &length in submit.iso_packet_lengths {
+            IsoPacket {
+                offset,
+                length,
+                actual_length: 0,
+                status: 0,
+            }
+            .write(&mut msg);
+            offset += length;
+        }
+        self.send(&msg);
+        Some(seqnum)
+    }
+
+    /// Ask the server to cancel a URB. Its waiter is dropped immediately.
+    fn unlink(&self, f: impl Fn(u32, &Urb) -> bool) {
+        let mut state = self.state.lock().unwrap();
+        let Some((&target, urb)) = state.urbs.iter_mut().find(|(&s, urb)| f(s, urb)) else {
+            return;
+        };
+        let waiter = urb.waiter.take();
+        let seqnum = state.next_seqnum;
+        state.next_seqnum = state.next_seqnum.wrapping_add(1).max(1);
+        state.unlinks.insert(seqnum, target);
+        drop(state);
+        drop(waiter);
+
+        let mut msg = Vec::new();
+        Pdu::CmdUnlink {
+            seqnum,
+            devid: self.devid,
+            unlink_seqnum: target,
+        }
+        .write(&mut msg);
+        self.send(&msg);
+    }
+
+    /// Perform a control transfer on behalf of the model itself.
+    fn control_blocking(&self, setup: [u8; 8], data: &[u8]) -> Result<Vec<u8>, TransferError> {
+        let (tx, rx) = mpsc::channel();
+        let direction = Direction::from_address(setup[0]);
+        let requested_len = match direction {
+            Direction::In => u16::from_le_bytes([setup[6], setup[7]]) as usize,
+            Direction::Out => data.len(),
+        };
+        let seqnum = self.submit(
+            Urb {
+                id: None,
+                direction,
+                requested_len,
+                iso_packet_lengths: Vec::new(),
+                waiter: Some(Waiter::Blocking(tx)),
+            },
+            Submit {
+                endpoint: 0,
+                requested_len,
+                setup,
+                data,
+                iso_packet_lengths: &[],
+                interval: 0,
+            },
+        );
+
+        match rx.recv_timeout(OPERATION_TIMEOUT) {
+            Ok(result) => result,
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                if let Some(seqnum) = seqnum {
+                    self.unlink(|s, _| s == seqnum);
+                }
+                Err(TransferError::Cancelled)
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => Err(TransferError::Disconnected),
+        }
+    }
+
+    /// Close the connection and fail all pending transfers.
+    fn shutdown(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        let urbs = std::mem::take(&mut state.urbs);
+        state.unlinks.clear();
+        drop(state);
+
+        let _ = self.stream.lock().unwrap().shutdown(Shutdown::Both);
+        for (_, urb) in urbs {
+            if let Some(waiter) = urb.waiter {
+                waiter.fail(TransferError::Disconnected);
+            }
+        }
+        self.disconnected.set();
+    }
+
+    fn read_loop(&self, stream: TcpStream) {
+        let mut r = io::BufReader::new(stream);
+        loop {
+            if let Err(e) = self.read_one(&mut r) {
+                if !self.state.lock().unwrap().closed {
+                    warn!("USB/IP connection failed: {e}");
+                }
+                break;
+            }
+        }
+        self.shutdown();
+    }
+
+    fn read_one(&self, r: &mut impl Read) -> io::Result<()> {
+        match Pdu::read(r)? {
+            Pdu::RetSubmit {
+                seqnum,
+                status,
+                actual_length,
+                number_of_packets,
+                ..
+            } => {
+                let Some(urb) = self.state.lock().unwrap().urbs.remove(&seqnum) else {
+                    return Err(invalid_data("RET_SUBMIT for unknown sequence number"));
+                };
+                if actual_length as usize > urb.requested_len
+                    || number_of_packets as usize > urb.iso_packet_lengths.len()
+                {
+                    if let Some(waiter) = urb.waiter {
+                        waiter.fail(TransferError::Fault);
+                    }
+                    return Err(invalid_data("RET_SUBMIT longer than the transfer"));
+                }
+
+                let mut data = Vec::new();
+                if urb.direction == Direction::In {
+                    data.resize(actual_length as usize, 0);
+                    r.read_exact(&mut data)?;
+                }
+                let mut packets = Vec::new();
+                if !urb.iso_packet_lengths.is_empty() {
+                    for _ in 0..number_of_packets {
+                        packets.push(IsoPacket::read(r)?);
+                    }
+                }
+
+                let result = super::proto::status_to_result(status);
+                match urb.waiter {
+                    Some(Waiter::Transfer(c)) if !urb.iso_packet_lengths.is_empty() => {
+                        complete_iso(c, result, &urb.iso_packet_lengths, &data, &packets);
+                    }
+                    Some(Waiter::Transfer(c)) if urb.direction == Direction::In => {
+                        c.complete_in(result, &data);
+                    }
+                    Some(Waiter::Transfer(c)) => {
+                        c.complete_out(result, actual_length as usize);
+                    }
+                    Some(Waiter::Blocking(tx)) => {
+                        let _ = tx.send(result.map(|()| data));
+                    }
+                    None => {}
+                }
+            }
+            Pdu::RetUnlink { seqnum, .. } => {
+                // Either the URB was cancelled, or its RET_SUBMIT was already
+                // received. In both cases it is done.
+                let mut state = self.state.lock().unwrap();
+                if let Some(target) = state.unlinks.remove(&seqnum) {
+                    state.urbs.remove(&target);
+                }
+            }
+            _ => return Err(invalid_data("unexpected USB/IP command from server")),
+        }
+        Ok(())
+    }
+}
+
+/// Complete an isochronous transfer, moving the packed data of an IN
+/// transfer to the offset of each packet.
+fn complete_iso(
+    completer: Completer,
+    result: Result<(), TransferError>,
+    lengths: &[u32],
+    data: &[u8],
+    packets: &[IsoPacket],
+) {
+    let mut buf = vec![0; lengths.iter().sum::<u32>() as usize];
+    let mut pos = 0;
+    let mut status = Vec::with_capacity(packets.len());
+    for p in packets {
+        let len = p.actual_length as usize;
+        let offset = p.offset as usize;
+        if let (Some(src), Some(dst)) =
+            (data.get(pos..pos + len), buf.get_mut(offset..offset + len))
+        {
+            dst.copy_from_slice(src);
+        }
+        pos += len;
+        status.push(IsoStatus {
+            length: p.length,
+            actual_length: p.actual_length,
+            status: p.status as u32,
+        });
+    }
+    completer.complete_iso(result, &buf, status);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use super::*;
+
+    #[test]
+    fn list_from_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let header = OpHeader::read(&mut stream).unwrap();
+            assert_eq!(header.code, OP_REQ_DEVLIST);
+
+            let mut reply = Vec::new();
+            OpHeader {
+                code: OP_REP_DEVLIST,
+                status: ST_OK,
+            }
+            .write(&mut reply);
+            reply.extend(1u32.to_be_bytes());
+            reply.extend([0; 256]); // path
+            reply.extend(b"1-1.4\0");
+            reply.extend([0; 26]);
+            reply.extend(1u32.to_be_bytes()); // busnum
+            reply.extend(3u32.to_be_bytes()); // devnum
+            reply.extend(3u32.to_be_bytes()); // speed
+            reply.extend([0xaa, 0xaa, 0x55, 0x55, 0x01, 0x00]);
+            reply.extend([0, 0, 0, 1, 1, 1]);
+            reply.extend([0xff, 0, 0, 0]);
+            stream.write_all(&reply).unwrap();
+        });
+
+        let devices: Vec<_> = list_devices(addr).wait().unwrap().collect();
+        server.join().unwrap();
+
+        assert_eq!(devices.len(), 1);
+        let d = &devices[0];
+        assert_eq!((d.vendor_id(), d.product_id()), (0xaaaa, 0x5555));
+        assert_eq!(d.speed(), Some(Speed::High));
+        assert_eq!(d.port_chain(), &[1, 4]);
+        assert_eq!(d.bus_id(), format!("{addr}/1"));
+        assert_eq!(d.interfaces().next().unwrap().class(), 0xff);
+    }
+
+    #[test]
+    fn reply_longer_than_request() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+        let connection = Connection::start(client, 1).unwrap();
+
+        let server = thread::spawn(move || {
+            let Pdu::CmdSubmit { seqnum, .. } = Pdu::read(&mut server).unwrap() else {
+                panic!("expected CMD_SUBMIT");
+            };
+            let mut reply = Vec::new();
+            Pdu::RetSubmit {
+                seqnum,
+                status: 0,
+                actual_length: u32::MAX,
+                number_of_packets: 0,
+                error_count: 0,
+            }
+            .write(&mut reply);
+            server.write_all(&reply).unwrap();
+        });
+
+        let setup = [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 18, 0];
+        assert_eq!(
+            connection.control_blocking(setup, &[]),
+            Err(TransferError::Fault)
+        );
+        server.join().unwrap();
+    }
+}
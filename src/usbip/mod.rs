@@ -0,0 +1,70 @@
+//! Access to devices exported over the network by a USB/IP server.
+//!
+//! This module requires the `usbip` cargo feature.
+//!
+//! [USB/IP] forwards USB requests over TCP. A server such as Linux `usbipd`
+//! exports devices attached to one machine, and this module imports them
+//! directly, without the kernel `vhci` driver or root privileges on the
+//! client.
+//!
+//! [`list_devices`] returns a [`DeviceInfo`][crate::DeviceInfo] for each
+//! device the server exports. Opening it imports the device, and the
+//! resulting [`Device`][crate::Device] supports control, bulk, interrupt and
+//! isochronous transfers like a local device. Cancelled transfers are
+//! unlinked on the server. The device is released back to the server when the
+//! last handle is dropped.
+//!
+//! [`Server`] works in the other direction, exporting devices opened with
+//! nusb to USB/IP clients such as Linux `usbip attach` or [`list_devices`].
+//!
+//! [USB/IP]: https://docs.kernel.org/usb/usbip_protocol.html
+//!
+//! ### Example
+//!
+//! ```no_run
+//! use nusb::{usbip, MaybeFuture};
+//!
+//! # fn main() -> Result<(), nusb::Error> {
+//! let device = usbip::list_devices("192.168.1.2:3240")
+//!     .wait()?
+//!     .find(|d| d.vendor_id() == 0xAAAA && d.product_id() == 0xBBBB)
+//!     .expect("device not exported");
+//!
+//! let device = device.open().wait()?;
+//! let interface = device.claim_interface(0).wait()?;
+//! # Ok(()) }
+//! ```
+
+use std::{io, num::NonZeroU32};
+
+use crate::{Error, ErrorKind};
+
+mod proto;
+
+mod client;
+pub use client::list_devices;
+
+mod server;
+pub use server::Server;
+
+/// The TCP port used by USB/IP servers by default.
+pub const DEFAULT_PORT: u16 = 3240;
+
+fn io_error(e: io::Error, message: &'static str) -> Error {
+    let kind = match e.kind() {
+        io::ErrorKind::ConnectionRefused
+        | io::ErrorKind::ConnectionReset
+        | io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::NotConnected
+        | io::ErrorKind::BrokenPipe
+        | io::ErrorKind::UnexpectedEof => ErrorKind::Disconnected,
+        io::ErrorKind::NotFound => ErrorKind::NotFound,
+        io::ErrorKind::PermissionDenied => ErrorKind::PermissionDenied,
+        _ => ErrorKind::Other,
+    };
+    Error {
+        kind,
+        code: e.raw_os_error().and_then(|i| NonZeroU32::new(i as u32)),
+        message,
+    }
+}
@@ -0,0 +1,455 @@
+//! Wire format of the USB/IP protocol, as implemented by the Linux `usbip`
+//! drivers and documented in `Documentation/usb/usbip_protocol.rst` in the
+//! kernel tree. All fields are big-endian.
+
+use std::io::{self, Read};
+
+use crate::{transfer::TransferError, Speed};
+
+pub(crate) const VERSION: u16 = 0x0111;
+
+pub(crate) const OP_REQ_DEVLIST: u16 = 0x8005;
+pub(crate) const OP_REP_DEVLIST: u16 = 0x0005;
+pub(crate) const OP_REQ_IMPORT: u16 = 0x8003;
+pub(crate) const OP_REP_IMPORT: u16 = 0x0003;
+
+pub(crate) const ST_OK: u32 = 0;
+pub(crate) const ST_NA: u32 = 1;
+
+pub(crate) const CMD_SUBMIT: u32 = 1;
+pub(crate) const CMD_UNLINK: u32 = 2;
+pub(crate) const RET_SUBMIT: u32 = 3;
+pub(crate) const RET_UNLINK: u32 = 4;
+
+pub(crate) const DIR_OUT: u32 = 0;
+pub(crate) const DIR_IN: u32 = 1;
+
+pub(crate) const URB_ISO_ASAP: u32 = 0x0002;
+
+const PATH_LEN: usize = 256;
+const BUSID_LEN: usize = 32;
+
+pub(crate) const ECONNRESET: i32 = 104;
+
+fn read_u8(r: &mut impl Read) -> io::Result<u8> {
+    let mut b = [0; 1];
+    r.read_exact(&mut b)?;
+    Ok(b[0])
+}
+
+fn read_u16(r: &mut impl Read) -> io::Result<u16> {
+    let mut b = [0; 2];
+    r.read_exact(&mut b)?;
+    Ok(u16::from_be_bytes(b))
+}
+
+pub(crate) fn read_u32(r: &mut impl Read) -> io::Result<u32> {
+    let mut b = [0; 4];
+    r.read_exact(&mut b)?;
+    Ok(u32::from_be_bytes(b))
+}
+
+fn read_string(r: &mut impl Read, len: usize) -> io::Result<String> {
+    let mut b = vec![0; len];
+    r.read_exact(&mut b)?;
+    let end = b.iter().position(|&c| c == 0).unwrap_or(len);
+    Ok(String::from_utf8_lossy(&b[..end]).into_owned())
+}
+
+fn write_string(w: &mut Vec<u8>, s: &str, len: usize) {
+    let s = &s.as_bytes()[..s.len().min(len - 1)];
+    w.extend_from_slice(s);
+    w.resize(w.len() + len - s.len(), 0);
+}
+
+pub(crate) fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Header of the `OP_*` messages exchanged before a device is imported.
+pub(crate) struct OpHeader {
+    pub code: u16,
+    pub status: u32,
+}
+
+impl OpHeader {
+    pub(crate) fn read(r: &mut impl Read) -> io::Result<OpHeader> {
+        let version = read_u16(r)?;
+        if version != VERSION {
+            return Err(invalid_data("unsupported USB/IP version"));
+        }
+        Ok(OpHeader {
+            code: read_u16(r)?,
+            status: read_u32(r)?,
+        })
+    }
+
+    pub(crate) fn write(&self, w: &mut Vec<u8>) {
+        w.extend_from_slice(&VERSION.to_be_bytes());
+        w.extend_from_slice(&self.code.to_be_bytes());
+        w.extend_from_slice(&self.status.to_be_bytes());
+    }
+}
+
+pub(crate) fn read_busid(r: &mut impl Read) -> io::Result<String> {
+    read_string(r, BUSID_LEN)
+}
+
+pub(crate) fn write_busid(w: &mut Vec<u8>, busid: &str) {
+    write_string(w, busid, BUSID_LEN)
+}
+
+/// Description of an exported device in `OP_REP_DEVLIST` and
+/// `OP_REP_IMPORT`.
+#[derive(Clone, Debug)]
+pub(crate) struct ExportedDevice {
+    pub path: String,
+    pub busid: String,
+    pub busnum: u32,
+    pub devnum: u32,
+    pub speed: Option<Speed>,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub device_version: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub protocol: u8,
+    pub configuration_value: u8,
+    pub num_configurations: u8,
+
+    /// Class, subclass and protocol of each interface of the active
+    /// configuration. Only sent in `OP_REP_DEVLIST`, but the count is sent in
+    /// both.
+    pub interfaces: Vec<(u8, u8, u8)>,
+}
+
+impl ExportedDevice {
+    pub(crate) fn read(r: &mut impl Read, with_interfaces: bool) -> io::Result<Self> {
+        let mut d = ExportedDevice {
+            path: read_string(r, PATH_LEN)?,
+            busid: read_busid(r)?,
+            busnum: read_u32(r)?,
+            devnum: read_u32(r)?,
+            speed: speed_from_usbip(read_u32(r)?),
+            vendor_id: read_u16(r)?,
+            product_id: read_u16(r)?,
+            device_version: read_u16(r)?,
+            class: read_u8(r)?,
+            subclass: read_u8(r)?,
+            protocol: read_u8(r)?,
+            configuration_value: read_u8(r)?,
+            num_configurations: read_u8(r)?,
+            interfaces: Vec::new(),
+        };
+        let num_interfaces = read_u8(r)?;
+        d.interfaces = vec![(0, 0, 0); num_interfaces as usize];
+        if with_interfaces {
+            for i in &mut d.interfaces {
+                let mut b = [0; 4];
+                r.read_exact(&mut b)?;
+                *i = (b[0], b[1], b[2]);
+            }
+        }
+        Ok(d)
+    }
+
+    pub(crate) fn write(&self, w: &mut Vec<u8>, with_interfaces: bool) {
+        write_string(w, &self.path, PATH_LEN);
+        write_busid(w, &self.busid);
+        w.extend_from_slice(&self.busnum.to_be_bytes());
+        w.extend_from_slice(&self.devnum.to_be_bytes());
+        w.extend_from_slice(&speed_to_usbip(self.speed).to_be_bytes());
+        w.extend_from_slice(&self.vendor_id.to_be_bytes());
+        w.extend_from_slice(&self.product_id.to_be_bytes());
+        w.extend_from_slice(&self.device_version.to_be_bytes());
+        w.extend_from_slice(&[
+            self.class,
+            self.subclass,
+            self.protocol,
+            self.configuration_value,
+            self.num_configurations,
+            self.interfaces.len() as u8,
+        ]);
+        if with_interfaces {
+            for &(class, subclass, protocol) in &self.interfaces {
+                w.extend_from_slice(&[class, subclass, protocol, 0]);
+            }
+        }
+    }
+
+    /// Device ID used in URB headers.
+    pub(crate) fn devid(&self) -> u32 {
+        (self.busnum << 16) | self.devnum
+    }
+}
+
+fn speed_from_usbip(speed: u32) -> Option<Speed> {
+    match speed {
+        1 => Some(Speed::Low),
+        2 => Some(Speed::Full),
+        3 => Some(Speed::High),
+        5 => Some(Speed::Super),
+        6 => Some(Speed::SuperPlus),
+        _ => None,
+    }
+}
+
+fn speed_to_usbip(speed: Option<Speed>) -> u32 {
+    match speed {
+        None => 0,
+        Some(Speed::Low) => 1,
+        Some(Speed::Full) => 2,
+        Some(Speed::High) => 3,
+        Some(Speed::Super) => 5,
+        Some(Speed::SuperPlus) => 6,
+    }
+}
+
+/// Fixed-size header of the messages exchanged after a device is imported.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Pdu {
+    CmdSubmit {
+        seqnum: u32,
+        devid: u32,
+        direction: u32,
+        ep: u32,
+        transfer_flags: u32,
+        transfer_buffer_length: u32,
+        number_of_packets: u32,
+        interval: u32,
+        setup: [u8; 8],
+    },
+    RetSubmit {
+        seqnum: u32,
+        status: i32,
+        actual_length: u32,
+        number_of_packets: u32,
+        error_count: u32,
+    },
+    CmdUnlink {
+        seqnum: u32,
+        devid: u32,
+        unlink_seqnum: u32,
+    },
+    RetUnlink {
+        seqnum: u32,
+        status: i32,
+    },
+}
+
+pub(crate) const PDU_LEN: usize = 48;
+
+impl Pdu {
+    pub(crate) fn read(r: &mut impl Read) -> io::Result<Pdu> {
+        let mut b = [0; PDU_LEN];
+        r.read_exact(&mut b)?;
+        let field = |i: usize| u32::from_be_bytes(b[i * 4..i * 4 + 4].try_into().unwrap());
+
+        // Basic header: command, seqnum, devid, direction, ep
+        let seqnum = field(1);
+        match field(0) {
+            CMD_SUBMIT => Ok(Pdu::CmdSubmit {
+                seqnum,
+                devid: field(2),
+                direction: field(3),
+                ep: field(4),
+                transfer_flags: field(5),
+                transfer_buffer_length: field(6),
+                // start_frame: field(7)
+                number_of_packets: field(8),
+                interval: field(9),
+                setup: b[40..48].try_into().unwrap(),
+            }),
+            RET_SUBMIT => Ok(Pdu::RetSubmit {
+                seqnum,
+                status: field(5) as i32,
+                actual_length: field(6),
+                // start_frame: field(7)
+                number_of_packets: field(8),
+                error_count: field(9),
+            }),
+            CMD_UNLINK => Ok(Pdu::CmdUnlink {
+                seqnum,
+                devid: field(2),
+                unlink_seqnum: field(5),
+            }),
+            RET_UNLINK => Ok(Pdu::RetUnlink {
+                seqnum,
+                status: field(5) as i32,
+            }),
+            _ => Err(invalid_data("unknown USB/IP command")),
+        }
+    }
+
+    pub(crate) fn write(&self, w: &mut Vec<u8>) {
+        let mut f = [0u32; 10];
+        let mut setup = [0; 8];
+        match *self {
+            Pdu::CmdSubmit {
+                seqnum,
+                devid,
+                direction,
+                ep,
+                transfer_flags,
+                transfer_buffer_length,
+                number_of_packets,
+                interval,
+                setup: s,
+            } => {
+                f = [
+                    CMD_SUBMIT,
+                    seqnum,
+                    devid,
+                    direction,
+                    ep,
+                    transfer_flags,
+                    transfer_buffer_length,
+                    0,
+                    number_of_packets,
+                    interval,
+                ];
+                setup = s;
+            }
+            Pdu::RetSubmit {
+                seqnum,
+                status,
+                actual_length,
+                number_of_packets,
+                error_count,
+            } => {
+                f[..2].copy_from_slice(&[RET_SUBMIT, seqnum]);
+                f[5..10].copy_from_slice(&[
+                    status as u32,
+                    actual_length,
+                    0,
+                    number_of_packets,
+                    error_count,
+                ]);
+            }
+            Pdu::CmdUnlink {
+                seqnum,
+                devid,
+                unlink_seqnum,
+            } => {
+                f[..3].copy_from_slice(&[CMD_UNLINK, seqnum, devid]);
+                f[5] = unlink_seqnum;
+            }
+            Pdu::RetUnlink { seqnum, status } => {
+                f[..2].copy_from_slice(&[RET_UNLINK, seqnum]);
+                f[5] = status as u32;
+            }
+        }
+        for v in f {
+            w.extend_from_slice(&v.to_be_bytes());
+        }
+        w.extend_from_slice(&setup);
+    }
+}
+
+/// Isochronous packet descriptor following the data of `CMD_SUBMIT` and
+/// `RET_SUBMIT`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub(crate) struct IsoPacket {
+    pub offset: u32,
+    pub length: u32,
+    pub actual_length: u32,
+    pub status: i32,
+}
+
+impl IsoPacket {
+    pub(crate) fn read(r: &mut impl Read) -> io::Result<IsoPacket> {
+        Ok(IsoPacket {
+            offset: read_u32(r)?,
+            length: read_u32(r)?,
+            actual_length: read_u32(r)?,
+            status: read_u32(r)? as i32,
+        })
+    }
+
+    pub(crate) fn write(&self, w: &mut Vec<u8>) {
+        for v in [
+            self.offset,
+            self.length,
+            self.actual_length,
+            self.status as u32,
+        ] {
+            w.extend_from_slice(&v.to_be_bytes());
+        }
+    }
+}
+
+/// Convert a URB status, which is a negated Linux `errno`.
+pub(crate) fn status_to_result(status: i32) -> Result<(), TransferError> {
+    match -status {
+        0 => Ok(()),
+        32 => Err(TransferError::Stall),                     // EPIPE
+        2 | ECONNRESET => Err(TransferError::Cancelled),     // ENOENT
+        19 | 108 => Err(TransferError::Disconnected),        // ENODEV, ESHUTDOWN
+        62 | 70 | 71 | 75 | 84 => Err(TransferError::Fault), // ETIME, ECOMM, EPROTO, EOVERFLOW, EILSEQ
+        22 => Err(TransferError::InvalidArgument),           // EINVAL
+        e => Err(TransferError::Unknown(e as u32)),
+    }
+}
+
+/// Convert a transfer result to a URB status.
+pub(crate) fn result_to_status(result: Result<(), TransferError>) -> i32 {
+    -match result {
+        Ok(()) => 0,
+        Err(TransferError::Stall) => 32,
+        Err(TransferError::Cancelled) => ECONNRESET,
+        Err(TransferError::Disconnected) => 108,
+        Err(TransferError::Fault) => 71,
+        Err(TransferError::InvalidArgument) => 22,
+        #[cfg(target_os = "linux")]
+        Err(TransferError::Unknown(e)) => e as i32,
+        #[cfg(not(target_os = "linux"))]
+        Err(TransferError::Unknown(_)) => 5, // EIO
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pdu_round_trip() {
+        let pdus = [
+            Pdu::CmdSubmit {
+                seqnum: 1,
+                devid: 0x0001_0002,
+                direction: DIR_IN,
+                ep: 0,
+                transfer_flags: 0,
+                transfer_buffer_length: 18,
+                number_of_packets: 0,
+                interval: 0,
+                setup: [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x12, 0x00],
+            },
+            Pdu::RetSubmit {
+                seqnum: 1,
+                status: -32,
+                actual_length: 0,
+                number_of_packets: 0,
+                error_count: 0,
+            },
+            Pdu::CmdUnlink {
+                seqnum: 2,
+                devid: 0x0001_0002,
+                unlink_seqnum: 1,
+            },
+            Pdu::RetUnlink {
+                seqnum: 2,
+                status: -ECONNRESET,
+            },
+        ];
+        for pdu in pdus {
+            let mut buf = Vec::new();
+            pdu.write(&mut buf);
+            assert_eq!(buf.len(), PDU_LEN);
+            assert_eq!(Pdu::read(&mut &buf[..]).unwrap(), pdu);
+        }
+
+        assert_eq!(status_to_result(-32), Err(TransferError::Stall));
+        assert_eq!(status_to_result(-ECONNRESET), Err(TransferError::Cancelled));
+    }
+}
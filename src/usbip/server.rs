@@ -0,0 +1,1193 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    future::{Future, IntoFuture},
+    io::{self, BufReader, Read, Write},
+    net::{Shutdown, TcpListener, TcpStream},
+    pin::Pin,
+    sync::{mpsc, Arc, Mutex},
+    task::{Context, Poll, Wake, Waker},
+    thread::{self, Thread},
+    time::Duration,
+};
+
+use log::{debug, warn};
+
+use super::proto::{
+    invalid_data, read_busid, result_to_status, ExportedDevice, IsoPacket, OpHeader, Pdu, DIR_IN,
+    ECONNRESET, OP_REP_DEVLIST, OP_REP_IMPORT, OP_REQ_DEVLIST, OP_REQ_IMPORT, ST_NA, ST_OK,
+};
+use crate::{
+    descriptors::TransferType,
+    transfer::{
+        parse_request_type, Buffer, Bulk, BulkOrInterrupt, Completion, ControlIn, ControlOut,
+        Direction, EndpointDirection, In, Interrupt, Out, TransferError,
+    },
+    Device, DeviceInfo, Endpoint, Error, Interface, MaybeFuture,
+};
+
+/// Timeout passed to nusb for control transfers. USB/IP has no timeout on
+/// URBs; the client unlinks transfers that it gives up on.
+const CONTROL_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+
+/// Largest transfer accepted from a client.
+const MAX_TRANSFER_LEN: u32 = 16 * 1024 * 1024;
+
+/// Largest number of isochronous packets accepted in one transfer, the same
+/// limit as the Linux USB/IP drivers.
+const MAX_ISO_PACKETS: u32 = 1024;
+
+/// A USB/IP server exporting devices opened with nusb.
+///
+/// Devices are added with [`with_device_info`][Self::with_device_info] or
+/// [`with_device`][Self::with_device], and exported under the USB/IP bus IDs
+/// `1-1`, `1-2`, etc. in the order they were added. A device added as a
+/// [`DeviceInfo`] is opened when a client imports it, and closed when the
+/// client disconnects. For a [`Device`], the server claims its interfaces
+/// while it is imported, so the import fails if another handle has already
+/// claimed one of them.
+///
+/// Each device can be imported by one client at a time. Control, bulk and
+/// interrupt transfers are supported. Isochronous transfers fail with
+/// `-EINVAL`.
+///
+/// Because nusb cancels transfers per endpoint, unlinking a transfer cancels
+/// all transfers pending on its endpoint. Those that had not yet transferred
+/// any data are resubmitted in their original order, while the others
+/// complete with `-ECONNRESET`.
+///
+/// ### Example
+///
+/// ```no_run
+/// use std::net::TcpListener;
+/// use nusb::{usbip, MaybeFuture};
+///
+/// # fn main() -> Result<(), std::io::Error> {
+/// let device = nusb::list_devices()
+///     .wait()?
+///     .find(|d| d.vendor_id() == 0xAAAA && d.product_id() == 0xBBBB)
+///     .expect("device not connected");
+///
+/// let server = usbip::Server::new().with_device_info(device);
+/// server.serve(TcpListener::bind(("0.0.0.0", usbip::DEFAULT_PORT))?)?;
+/// # Ok(()) }
+/// ```
+#[derive(Clone, Default)]
+pub struct Server {
+    exports: Vec<Arc<Export>>,
+}
+
+struct Export {
+    source: Source,
+    imported: Mutex<bool>,
+}
+
+enum Source {
+    Info(Box<DeviceInfo>),
+    Device(Device),
+}
+
+impl Server {
+    /// Create a server with no exported devices.
+    pub fn new() -> Server {
+        Server::default()
+    }
+
+    /// Export a device that is opened when a client imports it.
+    pub fn with_device_info(self, device: DeviceInfo) -> Server {
+        self.with_source(Source::Info(Box::new(device)))
+    }
+
+    /// Export an opened device.
+    pub fn with_device(self, device: Device) -> Server {
+        self.with_source(Source::Device(device))
+    }
+
+    fn with_source(mut self, source: Source) -> Server {
+        self.exports.push(Arc::new(Export {
+            source,
+            imported: Mutex::new(false),
+        }));
+        self
+    }
+
+    /// Accept connections on `listener`, handling each on a new thread.
+    ///
+    /// This only returns if accepting a connection fails.
+    pub fn serve(&self, listener: TcpListener) -> io::Result<()> {
+        loop {
+            let (stream, peer) = listener.accept()?;
+            debug!("USB/IP connection from {peer}");
+            let server = self.clone();
+            thread::Builder::new()
+                .name("nusb-usbip-server".into())
+                .spawn(move || {
+                    if let Err(e) = server.handle_connection(stream) {
+                        warn!("USB/IP connection from {peer} failed: {e}");
+                    }
+                })?;
+        }
+    }
+
+    /// Handle a single client connection, returning when it is closed.
+    ///
+    /// A connection either lists the exported devices, or imports one of
+    /// them and then carries its transfers until the client disconnects.
+    pub fn handle_connection(&self, stream: TcpStream) -> io::Result<()> {
+        let _ = stream.set_nodelay(true);
+        let mut r = BufReader::new(stream.try_clone()?);
+        let header = OpHeader::read(&mut r)?;
+        match header.code {
+            OP_REQ_DEVLIST => self.send_devlist(stream),
+            OP_REQ_IMPORT => {
+                let busid = read_busid(&mut r)?;
+                self.import(&busid, r, stream)
+            }
+            _ => Err(invalid_data("unknown USB/IP operation")),
+        }
+    }
+
+    fn send_devlist(&self, mut stream: TcpStream) -> io::Result<()> {
+        let mut reply = Vec::new();
+        OpHeader {
+            code: OP_REP_DEVLIST,
+            status: ST_OK,
+        }
+        .write(&mut reply);
+        reply.extend((self.exports.len() as u32).to_be_bytes());
+        for (i, export) in self.exports.iter().enumerate() {
+            let device = match &export.source {
+                Source::Info(info) => describe_info(info, i),
+                Source::Device(device) => describe_device(device, i),
+            };
+            device.write(&mut reply, true);
+        }
+        stream.write_all(&reply)
+    }
+
+    fn import(
+        &self,
+        busid: &str,
+        r: BufReader<TcpStream>,
+        mut stream: TcpStream,
+    ) -> io::Result<()> {
+        let reject = |mut stream: TcpStream| {
+            let mut reply = Vec::new();
+            OpHeader {
+                code: OP_REP_IMPORT,
+                status: ST_NA,
+            }
+            .write(&mut reply);
+            stream.write_all(&reply)
+        };
+
+        let Some((index, export)) = self
+            .exports
+            .iter()
+            .enumerate()
+            .find(|(i, _)| export_busid(*i) == busid)
+        else {
+            debug!("USB/IP client requested unknown device {busid}");
+            return reject(stream);
+        };
+
+        let Some(_imported) = ImportGuard::acquire(export) else {
+            debug!("USB/IP device {busid} is already imported");
+            return reject(stream);
+        };
+
+        let session = match Session::open(&export.source, stream.try_clone()?) {
+            Ok(session) => session,
+            Err(e) => {
+                warn!("Failed to open device {busid} for USB/IP import: {e}");
+                return reject(stream);
+            }
+        };
+
+        let mut reply = Vec::new();
+        OpHeader {
+            code: OP_REP_IMPORT,
+            status: ST_OK,
+        }
+        .write(&mut reply);
+        describe_device(&session.device, index).write(&mut reply, false);
+        stream.write_all(&reply)?;
+        debug!("USB/IP device {busid} imported");
+
+        let result = session.run(r);
+        let _ = stream.shutdown(Shutdown::Both);
+        debug!("USB/IP device {busid} released");
+        result
+    }
+}
+
+fn export_busid(index: usize) -> String {
+    format!("1-{}", index + 1)
+}
+
+fn describe_info(info: &DeviceInfo, index: usize) -> ExportedDevice {
+    ExportedDevice {
+        #[cfg(target_os = "linux")]
+        path: info.sysfs_path().to_string_lossy().into_owned(),
+        #[cfg(not(target_os = "linux"))]
+        path: String::new(),
+        busid: export_busid(index),
+        busnum: 1,
+        devnum: index as u32 + 1,
+        speed: info.speed(),
+        vendor_id: info.vendor_id(),
+        product_id: info.product_id(),
+        #[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
+        device_version: info.device_version(),
+        #[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
+        device_version: 0,
+        class: info.class(),
+        subclass: info.subclass(),
+        protocol: info.protocol(),
+        // Not known without opening the device.
+        configuration_value: 1,
+        num_configurations: 1,
+        interfaces: info
+            .interfaces()
+            .map(|i| (i.class(), i.subclass(), i.protocol()))
+            .collect(),
+    }
+}
+
+fn describe_device(device: &Device, index: usize) -> ExportedDevice {
+    let desc = device.device_descriptor();
+    let config = device.active_configuration().ok();
+    ExportedDevice {
+        path: String::new(),
+        busid: export_busid(index),
+        busnum: 1,
+        devnum: index as u32 + 1,
+        speed: device.speed(),
+        vendor_id: desc.vendor_id(),
+        product_id: desc.product_id(),
+        device_version: desc.device_version(),
+        class: desc.class(),
+        subclass: desc.subclass(),
+        protocol: desc.protocol(),
+        configuration_value: config.as_ref().map_or(0, |c| c.configuration_value()),
+        num_configurations: desc.num_configurations(),
+        interfaces: config
+            .into_iter()
+            .flat_map(|c| c.interfaces())
+            .map(|i| {
+                let alt = i.first_alt_setting();
+                (alt.class(), alt.subclass(), alt.protocol())
+            })
+            .collect(),
+    }
+}
+
+/// Marks an export as imported for as long as it is held.
+struct ImportGuard<'a>(&'a Export);
+
+impl<'a> ImportGuard<'a> {
+    fn acquire(export: &'a Export) -> Option<ImportGuard<'a>> {
+        let mut imported = export.imported.lock().unwrap();
+        if *imported {
+            return None;
+        }
+        *imported = true;
+        Some(ImportGuard(export))
+    }
+}
+
+impl Drop for ImportGuard<'_> {
+    fn drop(&mut self) {
+        *self.0.imported.lock().unwrap() = false;
+    }
+}
+
+/// A `CMD_SUBMIT` or `CMD_UNLINK` received from the client.
+enum Command {
+    Submit {
+        seqnum: u32,
+        endpoint: u8,
+        length: u32,
+        setup: [u8; 8],
+        data: Vec<u8>,
+        iso_packets: Vec<IsoPacket>,
+    },
+    Unlink {
+        seqnum: u32,
+        target: u32,
+    },
+}
+
+fn read_command(r: &mut impl Read) -> io::Result<Command> {
+    match Pdu::read(r)? {
+        Pdu::CmdSubmit {
+            seqnum,
+            direction,
+            ep,
+            transfer_buffer_length: length,
+            number_of_packets,
+            setup,
+            ..
+        } => {
+            if length > MAX_TRANSFER_LEN {
+                return Err(invalid_data("USB/IP transfer too large"));
+            }
+            let direction = if direction == DIR_IN {
+                Direction::In
+            } else {
+                Direction::Out
+            };
+            let mut data = Vec::new();
+            if direction == Direction::Out {
+                data.resize(length as usize, 0);
+                r.read_exact(&mut data)?;
+            }
+            // Non-isochronous URBs have 0 or 0xffffffff packets, depending
+            // on the client.
+            let mut iso_packets = Vec::new();
+            if number_of_packets != u32::MAX {
+                if number_of_packets > MAX_ISO_PACKETS {
+                    return Err(invalid_data("too many USB/IP isochronous packets"));
+                }
+                for _ in 0..number_of_packets {
+                    iso_packets.push(IsoPacket::read(r)?);
+                }
+            }
+            Ok(Command::Submit {
+                seqnum,
+                endpoint: (ep as u8 & 0x0f) | direction as u8,
+                length,
+                setup,
+                data,
+                iso_packets,
+            })
+        }
+        Pdu::CmdUnlink {
+            seqnum,
+            unlink_seqnum,
+            ..
+        } => Ok(Command::Unlink {
+            seqnum,
+            target: unlink_seqnum,
+        }),
+        _ => Err(invalid_data("unexpected USB/IP reply from client")),
+    }
+}
+
+/// Wakes the session thread when a transfer completes.
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+type ControlFuture = Pin<Box<dyn Future<Output = Result<Vec<u8>, TransferError>> + Send>>;
+
+/// An imported device, driven by a dedicated thread.
+struct Session {
+    device: Device,
+    interfaces: BTreeMap<u8, Interface>,
+    endpoints: BTreeMap<u8, ServedEndpoint>,
+    controls: Vec<PendingControl>,
+    stream: TcpStream,
+    failed: bool,
+}
+
+struct PendingControl {
+    seqnum: u32,
+    direction: Direction,
+
+    /// Length requested by the client for an IN transfer, or sent for OUT.
+    length: usize,
+
+    transfer: ControlFuture,
+}
+
+struct ServedEndpoint {
+    interface: u8,
+    endpoint: Box<dyn AnyEndpoint>,
+
+    /// Transfers submitted to `endpoint`, in order.
+    queue: VecDeque<Queued>,
+
+    /// Number of transfers in `queue` that were pending when the endpoint was
+    /// cancelled to unlink one of them.
+    cancelling: usize,
+
+    /// Transfers that were cancelled as a side effect of an unlink, and will
+    /// be resubmitted once the cancellation finishes.
+    resubmit: Vec<(Queued, Buffer)>,
+
+    /// Transfers received while the endpoint was being cancelled.
+    deferred: Vec<(Queued, Buffer)>,
+}
+
+struct Queued {
+    seqnum: u32,
+
+    /// Length requested by the client, which may be less than the buffer's
+    /// `requested_len` for IN transfers.
+    length: usize,
+
+    unlinked: bool,
+}
+
+/// Object-safe subset of the methods of bulk and interrupt endpoints.
+trait AnyEndpoint: Send {
+    fn direction(&self) -> Direction;
+    fn max_packet_size(&self) -> usize;
+    fn submit(&mut self, buf: Buffer);
+    fn cancel_all(&mut self);
+    fn poll_next_complete(&mut self, cx: &mut Context<'_>) -> Poll<Completion>;
+    fn clear_halt(&mut self) -> Result<(), Error>;
+}
+
+impl<EpType: BulkOrInterrupt, Dir: EndpointDirection> AnyEndpoint for Endpoint<EpType, Dir> {
+    fn direction(&self) -> Direction {
+        Dir::DIR
+    }
+
+    fn max_packet_size(&self) -> usize {
+        Endpoint::max_packet_size(self)
+    }
+
+    fn submit(&mut self, buf: Buffer) {
+        Endpoint::submit(self, buf)
+    }
+
+    fn cancel_all(&mut self) {
+        Endpoint::cancel_all(self)
+    }
+
+    fn poll_next_complete(&mut self, cx: &mut Context<'_>) -> Poll<Completion> {
+        Endpoint::poll_next_complete(self, cx)
+    }
+
+    fn clear_halt(&mut self) -> Result<(), Error> {
+        Endpoint::clear_halt(self).wait()
+    }
+}
+
+impl Session {
+    fn open(source: &Source, stream: TcpStream) -> Result<Session, Error> {
+        let device = match source {
+            Source::Info(info) => info.open().wait()?,
+            Source::Device(device) => device.clone(),
+        };
+        let mut session = Session {
+            device,
+            interfaces: BTreeMap::new(),
+            endpoints: BTreeMap::new(),
+            controls: Vec::new(),
+            stream,
+            failed: false,
+        };
+        session.claim_interfaces()?;
+        Ok(session)
+    }
+
+    fn claim_interfaces(&mut self) -> Result<(), Error> {
+        let numbers: Vec<u8> = match self.device.active_configuration() {
+            Ok(config) => config.interfaces().map(|i| i.interface_number()).collect(),
+            Err(_) => Vec::new(),
+        };
+        for number in numbers {
+            let interface = self.device.detach_and_claim_interface(number).wait()?;
+            self.interfaces.insert(number, interface);
+        }
+        Ok(())
+    }
+
+    /// Process commands read from `r` until the client disconnects.
+    fn run(self, mut r: BufReader<TcpStream>) -> io::Result<()> {
+        let (tx, rx) = mpsc::channel();
+        let worker = thread::Builder::new()
+            .name("nusb-usbip-session".into())
+            .spawn(move || self.process(rx))?;
+        let worker_thread = worker.thread().clone();
+
+        let result = loop {
+            match read_command(&mut r) {
+                Ok(command) => {
+                    if tx.send(command).is_err() {
+                        break Ok(());
+                    }
+                    worker_thread.unpark();
+                }
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break Ok(()),
+                Err(e) => break Err(e),
+            }
+        };
+        drop(tx);
+        worker_thread.unpark();
+        let _ = worker.join();
+        result
+    }
+
+    fn process(mut self, rx: mpsc::Receiver<Command>) {
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+
+        while !self.failed {
+            loop {
+                match rx.try_recv() {
+                    Ok(Command::Submit {
+                        seqnum,
+                        endpoint,
+                        length,
+                        setup,
+                        data,
+                        iso_packets,
+                    }) => self.submit(seqnum, endpoint, length, setup, data, iso_packets),
+                    Ok(Command::Unlink { seqnum, target }) => self.unlink(seqnum, target),
+                    Err(mpsc::TryRecvError::Empty) => break,
+                    Err(mpsc::TryRecvError::Disconnected) => return self.close(),
+                }
+            }
+            self.poll(&mut cx);
+            thread::park();
+        }
+        self.close();
+    }
+
+    fn close(self) {
+        // Unblock the thread reading commands if the session ended because
+        // writing failed. Pending transfers are cancelled when the endpoints
+        // and interfaces are dropped.
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+
+    fn reply(&mut self, seqnum: u32, result: Result<(), TransferError>, data: &[u8], len: usize) {
+        let mut msg = Vec::new();
+        Pdu::RetSubmit {
+            seqnum,
+            status: result_to_status(result),
+            actual_length: len as u32,
+            number_of_packets: 0,
+            error_count: 0,
+        }
+        .write(&mut msg);
+        msg.extend_from_slice(data);
+        self.send(&msg);
+    }
+
+    fn reply_unlink(&mut self, seqnum: u32, status: i32) {
+        let mut msg = Vec::new();
+        Pdu::RetUnlink { seqnum, status }.write(&mut msg);
+        self.send(&msg);
+    }
+
+    fn send(&mut self, msg: &[u8]) {
+        if self.failed {
+            return;
+        }
+        if let Err(e) = self.stream.write_all(msg) {
+            debug!("Failed to write to USB/IP client: {e}");
+            self.failed = true;
+        }
+    }
+
+    fn submit(
+        &mut self,
+        seqnum: u32,
+        endpoint: u8,
+        length: u32,
+        setup: [u8; 8],
+        data: Vec<u8>,
+        iso_packets: Vec<IsoPacket>,
+    ) {
+        if !iso_packets.is_empty() {
+            let status = result_to_status(Err(TransferError::InvalidArgument));
+            let mut msg = Vec::new();
+            Pdu::RetSubmit {
+                seqnum,
+                status,
+                actual_length: 0,
+                number_of_packets: iso_packets.len() as u32,
+                error_count: iso_packets.len() as u32,
+            }
+            .write(&mut msg);
+            for p in iso_packets {
+                IsoPacket {
+                    actual_length: 0,
+                    status,
+                    ..p
+                }
+                .write(&mut msg);
+            }
+            self.send(&msg);
+            return;
+        }
+
+        if endpoint & 0x7f == 0 {
+            return self.submit_control(seqnum, length, setup, data);
+        }
+
+        let length = length as usize;
+        let Some(ep) = self.endpoint(endpoint) else {
+            debug!("USB/IP client submitted to unknown endpoint {endpoint:02x}");
+            return self.reply(seqnum, Err(TransferError::InvalidArgument), &[], 0);
+        };
+        let buf = match ep.endpoint.direction() {
+            Direction::In => {
+                // nusb requires IN transfers to be a multiple of the packet
+                // size, while clients may ask for any length.
+                let mps = ep.endpoint.max_packet_size();
+                Buffer::new(length.max(1).div_ceil(mps) * mps)
+            }
+            Direction::Out => Buffer::from(data),
+        };
+        let queued = Queued {
+            seqnum,
+            length,
+            unlinked: false,
+        };
+        if ep.cancelling > 0 {
+            ep.deferred.push((queued, buf));
+        } else {
+            ep.endpoint.submit(buf);
+            ep.queue.push_back(queued);
+        }
+    }
+
+    fn submit_control(&mut self, seqnum: u32, length: u32, setup: [u8; 8], data: Vec<u8>) {
+        const STANDARD_REQUEST_CLEAR_FEATURE: u8 = 0x01;
+        const STANDARD_REQUEST_SET_CONFIGURATION: u8 = 0x09;
+        const STANDARD_REQUEST_SET_INTERFACE: u8 = 0x0b;
+        const HUB_REQUEST_SET_FEATURE: u8 = 0x03;
+        const ENDPOINT_HALT: u16 = 0;
+        const PORT_RESET: u16 = 4;
+
+        let value = u16::from_le_bytes([setup[2], setup[3]]);
+        let index = u16::from_le_bytes([setup[4], setup[5]]);
+
+        // Like the Linux `usbip-host` driver, perform requests that change
+        // the state of the device through the corresponding nusb operations,
+        // so that the OS knows about the change.
+        let result = match (setup[0], setup[1], value) {
+            (0x00, STANDARD_REQUEST_SET_CONFIGURATION, _) => self.set_configuration(value as u8),
+            (0x01, STANDARD_REQUEST_SET_INTERFACE, _) => {
+                self.set_alt_setting(index as u8, value as u8)
+            }
+            (0x02, STANDARD_REQUEST_CLEAR_FEATURE, ENDPOINT_HALT) => self.clear_halt(index as u8),
+            (0x23, HUB_REQUEST_SET_FEATURE, PORT_RESET) => self.reset(),
+            _ => {
+                let direction = Direction::from_address(setup[0]);
+                let length = match direction {
+                    Direction::In => length as usize,
+                    Direction::Out => data.len(),
+                };
+                let transfer = self.control(setup, data);
+                self.controls.push(PendingControl {
+                    seqnum,
+                    direction,
+                    length,
+                    transfer,
+                });
+                return;
+            }
+        };
+
+        let result = result.map_err(|e| {
+            debug!("USB/IP control request failed: {e}");
+            TransferError::Stall
+        });
+        self.reply(seqnum, result, &[], 0);
+    }
+
+    fn control(&self, setup: [u8; 8], data: Vec<u8>) -> ControlFuture {
+        // Like a real device, stall requests with a reserved type or recipient
+        let Some((direction, control_type, recipient)) = parse_request_type(setup[0]) else {
+            return Box::pin(std::future::ready(Err(TransferError::Stall)));
+        };
+        let request = setup[1];
+        let value = u16::from_le_bytes([setup[2], setup[3]]);
+        let index = u16::from_le_bytes([setup[4], setup[5]]);
+        let length = u16::from_le_bytes([setup[6], setup[7]]);
+
+        // Windows only allows control transfers through an interface.
+        #[cfg(target_os = "windows")]
+        let target = {
+            let interface = match recipient {
+                crate::transfer::Recipient::Interface => self.interfaces.get(&(index as u8)),
+                _ => None,
+            };
+            match interface.or_else(|| self.interfaces.values().next()) {
+                Some(interface) => interface,
+                None => return Box::pin(std::future::ready(Err(TransferError::InvalidArgument))),
+            }
+        };
+        #[cfg(not(target_os = "windows"))]
+        let target = &self.device;
+
+        match direction {
+            Direction::In => Box::pin(
+                target
+                    .control_in(
+                        ControlIn {
+                            control_type,
+                            recipient,
+                            request,
+                            value,
+                            index,
+                            length,
+                        },
+                        CONTROL_TIMEOUT,
+                    )
+                    .into_future(),
+            ),
+            Direction::Out => {
+                let transfer = target
+                    .control_out(
+                        ControlOut {
+                            control_type,
+                            recipient,
+                            request,
+                            value,
+                            index,
+                            data: &data,
+                        },
+                        CONTROL_TIMEOUT,
+                    )
+                    .into_future();
+                Box::pin(async move { transfer.await.map(|()| Vec::new()) })
+            }
+        }
+    }
+
+    fn set_configuration(&mut self, configuration: u8) -> Result<(), Error> {
+        self.close_endpoints(|_| true);
+        self.interfaces.clear();
+        let result = self.device.set_configuration(configuration).wait();
+        self.claim_interfaces()?;
+        result
+    }
+
+    fn set_alt_setting(&mut self, interface: u8, alt_setting: u8) -> Result<(), Error> {
+        self.close_endpoints(|ep| ep.interface == interface);
+        match self.interfaces.get(&interface) {
+            Some(i) => i.set_alt_setting(alt_setting).wait(),
+            None => Err(Error::new(
+                crate::ErrorKind::NotFound,
+                "interface is not part of the active configuration",
+            )),
+        }
+    }
+
+    fn clear_halt(&mut self, endpoint: u8) -> Result<(), Error> {
+        match self.endpoint(endpoint) {
+            Some(ep) => ep.endpoint.clear_halt(),
+            None => Err(Error::new(
+                crate::ErrorKind::NotFound,
+                "endpoint is not part of the active configuration",
+            )),
+        }
+    }
+
+    fn reset(&mut self) -> Result<(), Error> {
+        self.close_endpoints(|_| true);
+        self.device.reset().wait()
+    }
+
+    /// Get an endpoint, opening it if necessary.
+    fn endpoint(&mut self, address: u8) -> Option<&mut ServedEndpoint> {
+        if !self.endpoints.contains_key(&address) {
+            let (interface, endpoint) = self
+                .interfaces
+                .values()
+                .find_map(|i| Some((i.interface_number(), open_endpoint(i, address)?)))?;
+            self.endpoints.insert(
+                address,
+                ServedEndpoint {
+                    interface,
+                    endpoint,
+                    queue: VecDeque::new(),
+                    cancelling: 0,
+                    resubmit: Vec::new(),
+                    deferred: Vec::new(),
+                },
+            );
+        }
+        self.endpoints.get_mut(&address)
+    }
+
+    /// Close endpoints before an operation that invalidates them, completing
+    /// their transfers as cancelled.
+    fn close_endpoints(&mut self, f: impl Fn(&ServedEndpoint) -> bool) {
+        let addresses: Vec<u8> = self
+            .endpoints
+            .iter()
+            .filter(|(_, ep)| f(ep))
+            .map(|(&a, _)| a)
+            .collect();
+        for address in addresses {
+            let ep = self.endpoints.remove(&address).unwrap();
+            let seqnums = ep
+                .queue
+                .iter()
+                .filter(|q| !q.unlinked)
+                .chain(ep.resubmit.iter().map(|(q, _)| q))
+                .chain(ep.deferred.iter().map(|(q, _)| q))
+                .map(|q| q.seqnum)
+                .collect::<Vec<_>>();
+            for seqnum in seqnums {
+                self.reply(seqnum, Err(TransferError::Cancelled), &[], 0);
+            }
+        }
+    }
+
+    fn unlink(&mut self, seqnum: u32, target: u32) {
+        if let Some(i) = self.controls.iter().position(|c| c.seqnum == target) {
+            self.controls.remove(i);
+            return self.reply_unlink(seqnum, -ECONNRESET);
+        }
+
+        for ep in self.endpoints.values_mut() {
+            if let Some(q) = ep
+                .queue
+                .iter_mut()
+                .find(|q| q.seqnum == target && !q.unlinked)
+            {
+                q.unlinked = true;
+                if ep.cancelling == 0 {
+                    ep.cancelling = ep.queue.len();
+                    ep.endpoint.cancel_all();
+                }
+                return self.reply_unlink(seqnum, -ECONNRESET);
+            }
+
+            let len = ep.resubmit.len() + ep.deferred.len();
+            ep.resubmit.retain(|(q, _)| q.seqnum != target);
+            ep.deferred.retain(|(q, _)| q.seqnum != target);
+            if ep.resubmit.len() + ep.deferred.len() != len {
+                return self.reply_unlink(seqnum, -ECONNRESET);
+            }
+        }
+
+        // Already completed
+        self.reply_unlink(seqnum, 0);
+    }
+
+    fn poll(&mut self, cx: &mut Context<'_>) {
+        let mut i = 0;
+        while i < self.controls.len() {
+            let Poll::Ready(result) = self.controls[i].transfer.as_mut().poll(cx) else {
+                i += 1;
+                continue;
+            };
+            let c = self.controls.remove(i);
+            match (result, c.direction) {
+                (Ok(data), Direction::In) => {
+                    let len = data.len().min(c.length);
+                    self.reply(c.seqnum, Ok(()), &data[..len], len);
+                }
+                (Ok(_), Direction::Out) => self.reply(c.seqnum, Ok(()), &[], c.length),
+                (Err(e), _) => self.reply(c.seqnum, Err(e), &[], 0),
+            }
+        }
+
+        let mut completions = Vec::new();
+        for ep in self.endpoints.values_mut() {
+            while !ep.queue.is_empty() {
+                let Poll::Ready(c) = ep.endpoint.poll_next_complete(cx) else {
+                    break;
+                };
+                let queued = ep.queue.pop_front().unwrap();
+                let cancelled = ep.cancelling > 0;
+                ep.cancelling = ep.cancelling.saturating_sub(1);
+
+                if queued.unlinked {
+                    // RET_UNLINK was already sent
+                } else if cancelled
+                    && c.status == Err(TransferError::Cancelled)
+                    && c.actual_len == 0
+                {
+                    ep.resubmit.push((queued, c.buffer));
+                } else {
+                    completions.push((ep.endpoint.direction(), queued, c));
+                }
+
+                if ep.cancelling == 0 {
+                    for (queued, buf) in ep.resubmit.drain(..).chain(ep.deferred.drain(..)) {
+                        ep.endpoint.submit(buf);
+                        ep.queue.push_back(queued);
+                    }
+                }
+            }
+        }
+
+        for (direction, queued, c) in completions {
+            let mut status = c.status;
+            let mut len = c.actual_len;
+            if len > queued.length {
+                // The device sent more than the client asked for.
+                status = status.and(Err(TransferError::Fault));
+                len = queued.length;
+            }
+            match direction {
+                Direction::In => self.reply(queued.seqnum, status, &c.buffer[..len], len),
+                Direction::Out => self.reply(queued.seqnum, status, &[], len),
+            }
+        }
+    }
+}
+
+fn open_endpoint(interface: &Interface, address: u8) -> Option<Box<dyn AnyEndpoint>> {
+    let desc = interface.descriptor()?;
+    let ep = desc.endpoints().find(|e| e.address() == address)?;
+
+    fn boxed(ep: Result<impl AnyEndpoint + 'static, Error>) -> Result<Box<dyn AnyEndpoint>, Error> {
+        ep.map(|ep| Box::new(ep) as Box<dyn AnyEndpoint>)
+    }
+
+    let ep = match (ep.transfer_type(), ep.direction()) {
+        (TransferType::Bulk, Direction::In) => boxed(interface.endpoint::<Bulk, In>(address)),
+        (TransferType::Bulk, Direction::Out) => boxed(interface.endpoint::<Bulk, Out>(address)),
+        (TransferType::Interrupt, Direction::In) => {
+            boxed(interface.endpoint::<Interrupt, In>(address))
+        }
+        (TransferType::Interrupt, Direction::Out) => {
+            boxed(interface.endpoint::<Interrupt, Out>(address))
+        }
+        _ => return None,
+    };
+    ep.map_err(|e| warn!("Failed to open endpoint {address:02x} for USB/IP: {e}"))
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::VecDeque,
+        net::TcpListener,
+        sync::{Arc, Mutex},
+        thread,
+        time::Duration,
+    };
+
+    use super::{read_command, Server};
+    use crate::{
+        emulated::{Completer, Model, Request},
+        transfer::{Buffer, Bulk, ControlIn, ControlType, In, Out, Recipient, TransferError},
+        usbip, DeviceInfo, Error, ErrorKind, MaybeFuture, Speed,
+    };
+
+    const DESCRIPTORS: &[u8] = &[
+        0x12, 0x01, 0x00, 0x02, 0xff, 0x00, 0x00, 0x40, 0xaa, 0xaa, 0x55, 0x55, 0x00, 0x01, 0x00,
+        0x00, 0x00, 0x01, // device
+        0x09, 0x02, 0x20, 0x00, 0x01, 0x01, 0x00, 0x80, 0x32, // configuration
+        0x09, 0x04, 0x00, 0x00, 0x02, 0xff, 0x00, 0x00, 0x00, // interface
+        0x07, 0x05, 0x01, 0x02, 0x00, 0x02, 0x00, // bulk OUT
+        0x07, 0x05, 0x81, 0x02, 0x00, 0x02, 0x00, // bulk IN
+    ];
+
+    /// Device that returns data written to its OUT endpoint on its IN
+    /// endpoint.
+    #[derive(Default)]
+    struct Loopback {
+        state: Mutex<LoopbackState>,
+    }
+
+    #[derive(Default)]
+    struct LoopbackState {
+        data: VecDeque<Vec<u8>>,
+        waiting: VecDeque<(u64, Completer)>,
+    }
+
+    impl Model for Loopback {
+        fn descriptors(&self) -> Vec<u8> {
+            DESCRIPTORS.to_vec()
+        }
+
+        fn speed(&self) -> Option<Speed> {
+            Some(Speed::High)
+        }
+
+        fn active_configuration(&self) -> u8 {
+            1
+        }
+
+        fn set_configuration(&self, _: u8) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn claim_interface(&self, _: u8) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn set_alt_setting(&self, _: u8, _: u8) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn clear_halt(&self, _: u8) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn reset(&self) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn submit(&self, request: Request, completer: Completer) {
+            let mut state = self.state.lock().unwrap();
+            match (request.setup, request.endpoint) {
+                // GET_DESCRIPTOR(configuration) returns the rest
+                (Some([0x80, 0x06, _, 0x02, ..]), _) => {
+                    completer.complete_in(Ok(()), &DESCRIPTORS[18..]);
+                }
+                (Some([0x80, 0x06, ..]), _) => {
+                    completer.complete_in(Ok(()), DESCRIPTORS);
+                }
+                (Some(_), _) => {
+                    completer.complete_in(Ok(()), &[1, 2, 3]);
+                }
+                (None, 0x01) => {
+                    state.data.push_back(request.data);
+                    if let Some((_, c)) = state.waiting.pop_front() {
+                        c.complete_in(Ok(()), &state.data.pop_front().unwrap());
+                    }
+                    completer.complete_out(Ok(()), request.requested_len);
+                }
+                _ => match state.data.pop_front() {
+                    Some(data) => {
+                        completer.complete_in(Ok(()), &data);
+                    }
+                    None => state.waiting.push_back((request.id, completer)),
+                },
+            }
+        }
+
+        fn cancel(&self, id: u64) {
+            self.state.lock().unwrap().waiting.retain(|(i, _)| *i != id);
+        }
+    }
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    #[test]
+    fn loopback() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server =
+            Server::new().with_device_info(DeviceInfo::emulated(Arc::new(Loopback::default())));
+        thread::spawn(move || server.serve(listener));
+
+        let info = usbip::list_devices(addr).wait().unwrap().next().unwrap();
+        assert_eq!((info.vendor_id(), info.product_id()), (0xaaaa, 0x5555));
+        let device = info.open().wait().unwrap();
+        assert_eq!(device.device_descriptor().vendor_id(), 0xaaaa);
+
+        // A device can only be imported once
+        let other = usbip::list_devices(addr).wait().unwrap().next().unwrap();
+        assert_eq!(other.open().wait().unwrap_err().kind(), ErrorKind::Busy);
+
+        let interface = device.claim_interface(0).wait().unwrap();
+        let control = ControlIn {
+            control_type: ControlType::Vendor,
+            recipient: Recipient::Device,
+            request: 1,
+            value: 0,
+            index: 0,
+            length: 64,
+        };
+        assert_eq!(
+            interface.control_in(control, TIMEOUT).wait().unwrap(),
+            [1, 2, 3]
+        );
+
+        let mut ep_out = interface.endpoint::<Bulk, Out>(0x01).unwrap();
+        let mut ep_in = interface.endpoint::<Bulk, In>(0x81).unwrap();
+
+        // An unlinked transfer is cancelled on the server
+        ep_in.submit(Buffer::new(512));
+        ep_in.cancel_all();
+        let c = ep_in.wait_next_complete(TIMEOUT).unwrap();
+        assert_eq!(c.status, Err(TransferError::Cancelled));
+
+        ep_in.submit(Buffer::new(512));
+        ep_out.submit(Buffer::from(b"hello".to_vec()));
+        let c = ep_out.wait_next_complete(TIMEOUT).unwrap();
+        assert_eq!((c.status, c.actual_len), (Ok(()), 5));
+        let c = ep_in.wait_next_complete(TIMEOUT).unwrap();
+        assert_eq!(c.status, Ok(()));
+        assert_eq!(&c.buffer[..], b"hello");
+
+        // Closing the device releases it on the server
+        drop((ep_in, ep_out, interface, device));
+        let info = usbip::list_devices(addr).wait().unwrap().next().unwrap();
+        let mut retries = 0;
+        while let Err(e) = info.open().wait() {
+            assert_eq!(e.kind(), ErrorKind::Busy);
+            retries += 1;
+            assert!(retries < 50);
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn reserved_recipient_stalls() {
+        use std::{io::Write, net::TcpStream};
+
+        use usbip::proto::{ExportedDevice, OpHeader, Pdu};
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server =
+            Server::new().with_device_info(DeviceInfo::emulated(Arc::new(Loopback::default())));
+        thread::spawn(move || server.serve(listener));
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let mut msg = Vec::new();
+        OpHeader {
+            code: usbip::proto::OP_REQ_IMPORT,
+            status: 0,
+        }
+        .write(&mut msg);
+        usbip::proto::write_busid(&mut msg, &super::export_busid(0));
+        stream.write_all(&msg).unwrap();
+        assert_eq!(OpHeader::read(&mut stream).unwrap().status, 0);
+        let device = ExportedDevice::read(&mut stream, false).unwrap();
+
+        // The client's `Recipient` can't express recipients 4-31, so send the
+        // submit by hand.
+        let mut msg = Vec::new();
+        Pdu::CmdSubmit {
+            seqnum: 1,
+            devid: device.devid(),
+            direction: usbip::proto::DIR_IN,
+            ep: 0,
+            transfer_flags: 0,
+            transfer_buffer_length: 64,
+            number_of_packets: 0,
+            interval: 0,
+            setup: [0xc4, 0x01, 0, 0, 0, 0, 64, 0],
+        }
+        .write(&mut msg);
+        stream.write_all(&msg).unwrap();
+        let reply = Pdu::read(&mut stream).unwrap();
+        assert!(matches!(
+            reply,
+            Pdu::RetSubmit { seqnum: 1, status, .. }
+                if status == usbip::proto::result_to_status(Err(TransferError::Stall))
+        ));
+    }
+
+    #[test]
+    fn too_many_iso_packets() {
+        let mut msg = Vec::new();
+        usbip::proto::Pdu::CmdSubmit {
+            seqnum: 1,
+            devid: 0,
+            direction: usbip::proto::DIR_IN,
+            ep: 1,
+            transfer_flags: 0,
+            transfer_buffer_length: 1024,
+            number_of_packets: 0x00ff_ffff,
+            interval: 1,
+            setup: [0; 8],
+        }
+        .write(&mut msg);
+        let err = read_command(&mut &msg[..]).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+}